@@ -0,0 +1,167 @@
+use std::{fs, path::Path};
+
+use crate::db::{
+    users::{NewUser, RegistrationError},
+    SqliteDb,
+};
+use crate::validation::{Policy, ValidationErrors};
+
+pub const USAGE: &str = "usage: report-generator-server [command]
+
+commands:
+    serve                               start http server (default)
+    migrate                             apply pending schema migrations
+    user create <name> <username> <password>
+    user password <username> <password>
+    user admin <username> [--revoke]
+    app list
+    app transfer <app_id> <username>
+    app delete <app_id>
+    broker list
+    backup <file>                       online copy of the database into file
+    restore <file>                      replace database with a backup
+    snapshot                            take snapshot into backups directory
+    snapshots                           list snapshots
+    export <file>                       dump database as json
+    import <file>                       load json dump into empty database,
+                                        also used to move data between backends";
+
+/**
+    Admin commands, work directly on the database file,
+    so server does not need to be running.
+*/
+pub fn run(db: &SqliteDb, args: &[String]) -> Result<(), String> {
+    let args: Vec<&str> = args.iter().map(String::as_str).collect();
+
+    match args.as_slice() {
+        ["migrate"] => {
+            // pending migrations are applied on open already, see `main`
+            db.migrate().map_err(sql_error)?;
+            let version = db.schema_version().map_err(sql_error)?;
+            println!("schema version is {version}");
+        }
+        ["user", "create", name, username, password] => {
+            let user = NewUser::new(
+                name.to_string(),
+                username.to_string(),
+                password.to_string(),
+                None,
+            )
+            .map_err(|e| e.to_string())?;
+            let id = match db.users.register_user(&user) {
+                Ok(id) => id,
+                Err(RegistrationError::UserAlreadyExists) => {
+                    return Err(format!("user {username} already exists"))
+                }
+                Err(RegistrationError::SqliteError(e)) => return Err(sql_error(e)),
+            };
+            println!("created user {username} with id {id}");
+        }
+        ["user", "password", username, password] => {
+            let mut errors = ValidationErrors::default();
+            Policy::current().check_password(password, username, &mut errors);
+            errors.into_result(()).map_err(|e| e.to_string())?;
+            expect_one(db.users.set_password(username, password), "user", username)?;
+            println!("password of {username} was reset");
+        }
+        ["user", "admin", username] => {
+            expect_one(db.users.set_admin(username, true), "user", username)?;
+            println!("{username} is admin now");
+        }
+        ["user", "admin", username, "--revoke"] => {
+            expect_one(db.users.set_admin(username, false), "user", username)?;
+            println!("{username} is not admin anymore");
+        }
+        ["app", "list"] => {
+            for app in db.apps.select_all().map_err(sql_error)? {
+                println!(
+                    "{}\t{}\tauthor={}\tversion={}\tpublic={}\tstatus={:?}",
+                    app.id, app.title, app.author, app.version, app.public, app.status
+                );
+            }
+        }
+        ["app", "transfer", app_id, username] => {
+            let app_id = parse_id(app_id)?;
+            let user = db
+                .users
+                .find_user_by_name(username)
+                .map_err(|_| format!("user {username} not found"))?;
+            expect_one(db.apps.transfer(app_id, user.id), "app", &app_id.to_string())?;
+            println!("app {app_id} now belongs to {username}");
+        }
+        ["app", "delete", app_id] => {
+            let app_id = parse_id(app_id)?;
+            expect_one(db.apps.delete(app_id), "app", &app_id.to_string())?;
+            println!("app {app_id} deleted");
+        }
+        ["broker", "list"] => {
+            for broker in db.brokers.all().map_err(sql_error)? {
+                let status = match (broker.stopped, broker.active) {
+                    (true, _) => "stopped",
+                    (false, true) => "active",
+                    (false, false) => "inactive",
+                };
+                println!(
+                    "{}\tapp={}\t{}\tversion={}\t{status}",
+                    broker.id, broker.app_id, broker.name, broker.version
+                );
+            }
+        }
+        ["backup", file] => {
+            db.backup_to(Path::new(file)).map_err(sql_error)?;
+            println!("database copied to {file}");
+        }
+        ["restore", file] => {
+            db.restore_from(Path::new(file))
+                .map_err(|e| format!("restore failed: {e:?}"))?;
+            println!("database restored from {file}");
+        }
+        ["snapshot"] => {
+            let snapshot = db
+                .snapshots
+                .create()
+                .map_err(|e| format!("snapshot failed: {e:?}"))?;
+            println!("created snapshot {}", snapshot.name);
+        }
+        ["snapshots"] => {
+            let snapshots = db
+                .snapshots
+                .list()
+                .map_err(|e| format!("could not list snapshots: {e:?}"))?;
+            for snapshot in snapshots {
+                println!("{}\t{} bytes", snapshot.name, snapshot.size);
+            }
+        }
+        ["export", file] => {
+            let data = db.export().map_err(sql_error)?;
+            let json = serde_json::to_string_pretty(&data).map_err(|e| e.to_string())?;
+            fs::write(file, json).map_err(|e| e.to_string())?;
+            println!("exported database to {file}");
+        }
+        ["import", file] => {
+            let json = fs::read_to_string(file).map_err(|e| e.to_string())?;
+            let data = serde_json::from_str(&json).map_err(|e| e.to_string())?;
+            db.import(data).map_err(sql_error)?;
+            println!("imported {file}");
+        }
+        _ => return Err(USAGE.to_string()),
+    }
+
+    Ok(())
+}
+
+fn sql_error(e: rusqlite::Error) -> String {
+    format!("sqlite reported error: {e}")
+}
+
+fn parse_id(id: &str) -> Result<i32, String> {
+    id.parse().map_err(|_| format!("'{id}' is not a valid id"))
+}
+
+/// Update/delete by key has to touch exactly one row, otherwise target does not exist.
+fn expect_one(result: rusqlite::Result<usize>, what: &str, key: &str) -> Result<(), String> {
+    match result.map_err(sql_error)? {
+        0 => Err(format!("{what} {key} not found")),
+        _ => Ok(()),
+    }
+}
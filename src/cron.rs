@@ -0,0 +1,324 @@
+use std::{fmt, str::FromStr};
+
+use jiff::{
+    civil::{Date, DateTime, Time},
+    tz::TimeZone,
+    Timestamp,
+};
+
+const MONTHS: [&str; 12] = [
+    "jan", "feb", "mar", "apr", "may", "jun", "jul", "aug", "sep", "oct", "nov", "dec",
+];
+const WEEKDAYS: [&str; 7] = ["sun", "mon", "tue", "wed", "thu", "fri", "sat"];
+
+/// Far enough to reach Feb 29 of the next leap year, also across 2100.
+const MAX_SEARCH_DAYS: usize = 8 * 366;
+
+/**
+    Cron expression with the five usual fields: minute, hour, day of month,
+    month and day of week. Fields take `*`, numbers, ranges `1-5`, steps like `10-40/10`,
+    also after `*` or a single number, and comma separated lists of those.
+    Months and weekdays also take three letter names, Sunday is 0 or 7.
+    `@yearly`, `@monthly`, `@weekly`, `@daily` and `@hourly` are shorthands.
+
+    Like cron, a day matches if either day of month or day of week does
+    when both are restricted.
+*/
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Cron {
+    text: String,
+    /// Bit per allowed value of each field.
+    minutes: u64,
+    hours: u64,
+    days: u64,
+    months: u64,
+    weekdays: u64,
+    any_day: bool,
+    any_weekday: bool,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub struct CronError(String);
+
+impl fmt::Display for CronError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl FromStr for Cron {
+    type Err = CronError;
+
+    fn from_str(text: &str) -> Result<Self, Self::Err> {
+        let expanded = match text.trim().to_lowercase().as_str() {
+            "@yearly" | "@annually" => "0 0 1 1 *".to_string(),
+            "@monthly" => "0 0 1 * *".to_string(),
+            "@weekly" => "0 0 * * 0".to_string(),
+            "@daily" | "@midnight" => "0 0 * * *".to_string(),
+            "@hourly" => "0 * * * *".to_string(),
+            other => other.to_string(),
+        };
+        let fields: Vec<&str> = expanded.split_whitespace().collect();
+        let [minutes, hours, days, months, weekdays] = fields[..] else {
+            return Err(CronError(format!(
+                "'{}' must have 5 fields: minute, hour, day of month, month and day of week",
+                text.trim()
+            )));
+        };
+
+        let field = |name: &str, value: &str, min: u32, max: u32, names: &[&str]| {
+            parse_field(value, min, max, names)
+                .ok_or_else(|| CronError(format!("'{value}' is not a valid {name}")))
+        };
+        let weekday_bits = field("day of week", weekdays, 0, 7, &WEEKDAYS)?;
+        Ok(Self {
+            text: fields.join(" "),
+            minutes: field("minute", minutes, 0, 59, &[])?,
+            hours: field("hour", hours, 0, 23, &[])?,
+            days: field("day of month", days, 1, 31, &[])?,
+            months: field("month", months, 1, 12, &MONTHS)?,
+            // 7 is Sunday too
+            weekdays: (weekday_bits | weekday_bits >> 7) & 0x7f,
+            any_day: days.starts_with('*'),
+            any_weekday: weekdays.starts_with('*'),
+        })
+    }
+}
+
+/// Bits of the values a comma separated field allows, `None` if anything is off.
+fn parse_field(field: &str, min: u32, max: u32, names: &[&str]) -> Option<u64> {
+    let value = |text: &str| match names.iter().position(|name| *name == text) {
+        // names start at the lowest value, january is 1 and sunday 0
+        Some(index) => Some(index as u32 + min),
+        None => text.parse::<u32>().ok(),
+    };
+
+    let mut bits = 0u64;
+    for part in field.split(',') {
+        let (range, step) = match part.split_once('/') {
+            Some((range, step)) => (
+                range,
+                Some(step.parse::<u32>().ok().filter(|step| *step > 0)?),
+            ),
+            None => (part, None),
+        };
+        let (start, end) = match range.split_once('-') {
+            _ if range == "*" => (min, max),
+            Some((start, end)) => (value(start)?, value(end)?),
+            // `5/15` runs from 5 to the end
+            None if step.is_some() => (value(range)?, max),
+            None => (value(range)?, value(range)?),
+        };
+        if start < min || end > max || start > end {
+            return None;
+        }
+        for value in (start..=end).step_by(step.unwrap_or(1) as usize) {
+            bits |= 1 << value;
+        }
+    }
+    Some(bits)
+}
+
+impl Cron {
+    fn allows(bits: u64, value: i8) -> bool {
+        bits & (1 << value) != 0
+    }
+
+    fn matches_day(&self, date: Date) -> bool {
+        if !Self::allows(self.months, date.month()) {
+            return false;
+        }
+        let day = Self::allows(self.days, date.day());
+        let weekday = Self::allows(self.weekdays, date.weekday().to_sunday_zero_offset());
+        match (self.any_day, self.any_weekday) {
+            (true, true) => true,
+            (true, false) => weekday,
+            (false, true) => day,
+            (false, false) => day || weekday,
+        }
+    }
+
+    /**
+        First time the expression matches after the unix time `after`, in seconds.
+        Times skipped by a daylight saving change are moved forward by the length
+        of the gap, 02:30 on the night clocks go from 02:00 to 03:00 runs at 03:30.
+        Times repeated by one only run the first time.
+        `None` if it never matches, like `0 0 30 2 *`.
+    */
+    pub fn next_after(&self, after: u64, tz: &TimeZone) -> Option<u64> {
+        let after = Timestamp::from_second(after as i64).ok()?;
+        let start = tz.to_datetime(after);
+        let mut date = start.date();
+        for _ in 0..MAX_SEARCH_DAYS {
+            if self.matches_day(date) {
+                for hour in (0..24).filter(|hour| Self::allows(self.hours, *hour)) {
+                    for minute in (0..60).filter(|minute| Self::allows(self.minutes, *minute)) {
+                        let time = DateTime::from_parts(date, Time::new(hour, minute, 0, 0).ok()?);
+                        if time <= start {
+                            continue;
+                        }
+                        let timestamp = tz.to_timestamp(time).ok()?;
+                        if timestamp > after {
+                            return Some(timestamp.as_second() as u64);
+                        }
+                    }
+                }
+            }
+            date = date.tomorrow().ok()?;
+        }
+        None
+    }
+}
+
+impl fmt::Display for Cron {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.text)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Local times of the next `count` runs after the local time `from`.
+    fn runs(cron: &str, tz: &str, from: &str, count: usize) -> Vec<String> {
+        let cron: Cron = cron.parse().unwrap();
+        let tz = TimeZone::get(tz).unwrap();
+        let from: DateTime = from.parse().unwrap();
+        let mut after = tz.to_timestamp(from).unwrap().as_second() as u64;
+        (0..count)
+            .map(|_| {
+                after = cron.next_after(after, &tz).unwrap();
+                let zoned = Timestamp::from_second(after as i64)
+                    .unwrap()
+                    .to_zoned(tz.clone());
+                zoned.strftime("%Y-%m-%dT%H:%M%:z").to_string()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn steps() {
+        assert_eq!(
+            runs("*/15 * * * *", "UTC", "2026-01-01T10:07", 3),
+            [
+                "2026-01-01T10:15+00:00",
+                "2026-01-01T10:30+00:00",
+                "2026-01-01T10:45+00:00"
+            ]
+        );
+        assert_eq!(
+            runs("10-30/10 8 * * *", "UTC", "2026-01-01T00:00", 4),
+            [
+                "2026-01-01T08:10+00:00",
+                "2026-01-01T08:20+00:00",
+                "2026-01-01T08:30+00:00",
+                "2026-01-02T08:10+00:00"
+            ]
+        );
+        assert_eq!(
+            runs("5/20 0 * * *", "UTC", "2026-01-01T00:00", 3),
+            [
+                "2026-01-01T00:05+00:00",
+                "2026-01-01T00:25+00:00",
+                "2026-01-01T00:45+00:00"
+            ]
+        );
+    }
+
+    #[test]
+    fn names() {
+        assert_eq!(
+            runs("0 9 * MAR-apr mon-fri", "UTC", "2026-01-01T00:00", 3),
+            runs("0 9 * 3-4 1-5", "UTC", "2026-01-01T00:00", 3)
+        );
+        assert_eq!(
+            runs("0 9 * mar-apr mon-fri", "UTC", "2026-01-01T00:00", 1),
+            ["2026-03-02T09:00+00:00"]
+        );
+    }
+
+    #[test]
+    fn sunday_is_0_and_7() {
+        let sundays = ["2026-01-04T00:00+00:00", "2026-01-11T00:00+00:00"];
+        assert_eq!(runs("0 0 * * 7", "UTC", "2026-01-01T00:00", 2), sundays);
+        assert_eq!(runs("0 0 * * 0", "UTC", "2026-01-01T00:00", 2), sundays);
+        assert_eq!(
+            runs("0 0 * * 5-7", "UTC", "2026-01-01T00:00", 3)[2],
+            sundays[0]
+        );
+    }
+
+    #[test]
+    fn day_of_month_or_day_of_week() {
+        assert_eq!(
+            runs("0 0 13 * fri", "UTC", "2026-01-01T00:00", 4),
+            [
+                "2026-01-02T00:00+00:00",
+                "2026-01-09T00:00+00:00",
+                "2026-01-13T00:00+00:00",
+                "2026-01-16T00:00+00:00"
+            ]
+        );
+        // a star step restricts nothing, so only the weekday counts
+        assert_eq!(
+            runs("0 0 */1 * fri", "UTC", "2026-01-01T00:00", 1),
+            ["2026-01-02T00:00+00:00"]
+        );
+    }
+
+    #[test]
+    fn never_matching_date() {
+        let cron: Cron = "0 0 30 2 *".parse().unwrap();
+        assert_eq!(cron.next_after(0, &TimeZone::UTC), None);
+        // only leap years, found across the search window
+        assert_eq!(
+            runs("0 0 29 2 *", "UTC", "2026-01-01T00:00", 1),
+            ["2028-02-29T00:00+00:00"]
+        );
+    }
+
+    #[test]
+    fn invalid_expressions() {
+        for text in [
+            "* * * *",
+            "60 * * * *",
+            "* 24 * * *",
+            "*/0 * * * *",
+            "5-1 * * * *",
+        ] {
+            assert!(text.parse::<Cron>().is_err(), "{text}");
+        }
+        assert_eq!("@daily".parse::<Cron>(), "0 0 * * *".parse::<Cron>());
+    }
+
+    #[test]
+    fn spring_forward_moves_skipped_times_by_the_gap() {
+        // clocks go from 02:00 to 03:00 on 2026-03-08
+        assert_eq!(
+            runs("30 2 * * *", "America/New_York", "2026-03-07T12:00", 2),
+            ["2026-03-08T03:30-04:00", "2026-03-09T02:30-04:00"]
+        );
+        assert_eq!(
+            runs("30 * * * *", "America/New_York", "2026-03-08T01:00", 3),
+            [
+                "2026-03-08T01:30-05:00",
+                "2026-03-08T03:30-04:00",
+                "2026-03-08T04:30-04:00"
+            ]
+        );
+    }
+
+    #[test]
+    fn fall_back_runs_repeated_times_once() {
+        // clocks go from 02:00 back to 01:00 on 2026-11-01
+        assert_eq!(
+            runs("30 1 * * *", "America/New_York", "2026-10-31T12:00", 2),
+            ["2026-11-01T01:30-04:00", "2026-11-02T01:30-05:00"]
+        );
+        assert_eq!(
+            runs("30 * * * *", "America/New_York", "2026-11-01T00:45", 2),
+            ["2026-11-01T01:30-04:00", "2026-11-01T02:30-05:00"]
+        );
+    }
+}
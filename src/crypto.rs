@@ -0,0 +1,170 @@
+use std::{env, fs, io::Write, os::unix::fs::OpenOptionsExt, sync::OnceLock};
+
+use data_encoding::{BASE32_NOPAD, BASE64};
+use hmac::{Hmac, Mac};
+use rand::RngCore;
+use ring::aead::{Aad, LessSafeKey, Nonce, UnboundKey, CHACHA20_POLY1305, NONCE_LEN};
+use sha1::Sha1;
+use sha2::{Digest, Sha256};
+
+/// Seconds a TOTP code is valid for.
+pub const TOTP_PERIOD: u64 = 30;
+
+/// Where the secrets key is generated if `SECRETS_KEY` is not set.
+const SECRETS_KEY_FILE: &str = "secrets.key";
+/// Marks the format of encrypted values, so it can be changed later.
+const SEALED_PREFIX: &str = "v1.";
+
+/// Random hex string made of `bytes` random bytes, for secrets and one-time tokens.
+pub fn random_token(bytes: usize) -> String {
+    let mut buf = vec![0u8; bytes];
+    rand::thread_rng().fill_bytes(&mut buf);
+    hex::encode(buf)
+}
+
+pub fn hmac_sha256(key: &[u8], data: &[u8]) -> Vec<u8> {
+    let mut mac = Hmac::<Sha256>::new_from_slice(key).unwrap();
+    mac.update(data);
+    mac.finalize().into_bytes().to_vec()
+}
+
+/// Tokens are stored only as sha256, they are random enough to not need bcrypt.
+pub fn sha256_hex(data: &str) -> String {
+    hex::encode(Sha256::digest(data.as_bytes()))
+}
+
+/// Random TOTP secret, base32 encoded as authenticator apps expect it.
+pub fn totp_secret() -> String {
+    let mut buf = [0u8; 20];
+    rand::thread_rng().fill_bytes(&mut buf);
+    BASE32_NOPAD.encode(&buf)
+}
+
+/// Six digit code of RFC 6238 for the time step `step`, `None` if secret is not valid base32.
+pub fn totp(secret: &str, step: u64) -> Option<String> {
+    let key = BASE32_NOPAD.decode(secret.as_bytes()).ok()?;
+    let mut mac = Hmac::<Sha1>::new_from_slice(&key).ok()?;
+    mac.update(&step.to_be_bytes());
+    let hash = mac.finalize().into_bytes();
+
+    let offset = (hash[hash.len() - 1] & 0x0f) as usize;
+    let binary = u32::from_be_bytes(hash[offset..offset + 4].try_into().unwrap()) & 0x7fff_ffff;
+    Some(format!("{:06}", binary % 1_000_000))
+}
+
+/**
+    Key for secrets stored in the database, 32 bytes hex encoded.
+    Taken from `SECRETS_KEY`, otherwise read from or generated into `secrets.key`.
+    Backups of the database are useless for the secrets without it.
+*/
+fn secrets_key_bytes() -> &'static [u8] {
+    static KEY: OnceLock<Vec<u8>> = OnceLock::new();
+    KEY.get_or_init(|| {
+        // tests must not leave a key file behind
+        if cfg!(test) {
+            return vec![7; 32];
+        }
+        let key = env::var("SECRETS_KEY")
+            .or_else(|_| fs::read_to_string(SECRETS_KEY_FILE))
+            .unwrap_or_else(|_| {
+                let key = random_token(32);
+                fs::OpenOptions::new()
+                    .write(true)
+                    .create_new(true)
+                    .mode(0o600)
+                    .open(SECRETS_KEY_FILE)
+                    .and_then(|mut file| file.write_all(key.as_bytes()))
+                    .expect("secrets key file can't be written");
+                key
+            });
+        hex::decode(key.trim()).expect("secrets key is not hex")
+    })
+}
+
+fn secrets_key() -> &'static LessSafeKey {
+    static KEY: OnceLock<LessSafeKey> = OnceLock::new();
+    KEY.get_or_init(|| {
+        LessSafeKey::new(
+            UnboundKey::new(&CHACHA20_POLY1305, secrets_key_bytes())
+                .expect("secrets key must be 32 bytes"),
+        )
+    })
+}
+
+/// Derived from the secrets key, so signatures break when it changes.
+fn signing_key() -> &'static [u8] {
+    static KEY: OnceLock<Vec<u8>> = OnceLock::new();
+    KEY.get_or_init(|| hmac_sha256(secrets_key_bytes(), b"signing"))
+}
+
+/// Hex HMAC-SHA256 of `data`, for values handed out that must not be forged.
+pub fn sign(data: &str) -> String {
+    hex::encode(hmac_sha256(signing_key(), data.as_bytes()))
+}
+
+/// Whether `signature` was made by `sign` for `data`, compared in constant time.
+pub fn verify_signature(data: &str, signature: &str) -> bool {
+    let Ok(signature) = hex::decode(signature) else {
+        return false;
+    };
+    let mut mac = Hmac::<Sha256>::new_from_slice(signing_key()).unwrap();
+    mac.update(data.as_bytes());
+    mac.verify_slice(&signature).is_ok()
+}
+
+/// Encrypts `plain` with the secrets key, the result is safe to store.
+pub fn encrypt_secret(plain: &str) -> String {
+    let mut nonce = [0u8; NONCE_LEN];
+    rand::thread_rng().fill_bytes(&mut nonce);
+    let mut sealed = plain.as_bytes().to_vec();
+    secrets_key()
+        .seal_in_place_append_tag(
+            Nonce::assume_unique_for_key(nonce),
+            Aad::empty(),
+            &mut sealed,
+        )
+        .unwrap();
+    format!(
+        "{SEALED_PREFIX}{}",
+        BASE64.encode(&[&nonce[..], &sealed].concat())
+    )
+}
+
+/// Whether `value` looks like the output of `encrypt_secret`, for sealing values stored before.
+pub fn is_sealed(value: &str) -> bool {
+    value.starts_with(SEALED_PREFIX)
+}
+
+/// Inverse of `encrypt_secret`, `None` if the value was encrypted with another key or tampered with.
+pub fn decrypt_secret(sealed: &str) -> Option<String> {
+    let data = BASE64
+        .decode(sealed.strip_prefix(SEALED_PREFIX)?.as_bytes())
+        .ok()?;
+    if data.len() < NONCE_LEN {
+        return None;
+    }
+    let (nonce, ciphertext) = data.split_at(NONCE_LEN);
+    let nonce = Nonce::try_assume_unique_for_key(nonce).ok()?;
+    let mut buf = ciphertext.to_vec();
+    let plain = secrets_key()
+        .open_in_place(nonce, Aad::empty(), &mut buf)
+        .ok()?;
+    String::from_utf8(plain.to_vec()).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn totp_matches_rfc_6238() {
+        // "12345678901234567890" from the appendix of the rfc, codes truncated to 6 digits
+        let secret = BASE32_NOPAD.encode(b"12345678901234567890");
+        assert_eq!(totp(&secret, 59 / TOTP_PERIOD).as_deref(), Some("287082"));
+        assert_eq!(
+            totp(&secret, 1111111109 / TOTP_PERIOD).as_deref(),
+            Some("081804")
+        );
+        assert_eq!(totp("not base32!", 1), None);
+    }
+}
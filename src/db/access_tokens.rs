@@ -0,0 +1,322 @@
+use axum_utils::{copy, copy_mut};
+use rusqlite::{Connection, OptionalExtension, Row};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+
+use super::apps::has_permission;
+use super::audit::{self, Change};
+use super::events::now;
+use super::{query_execute, query_rows, Con, SqlResult};
+use crate::crypto::sha256_hex;
+
+/// Makes access tokens recognizable, in headers as well as in leaked logs.
+pub const ACCESS_TOKEN_PREFIX: &str = "rgp_";
+
+/**
+    Named personal access tokens for scripts and CI.
+    Only sha256 of the token is stored, the token itself is shown once on creation.
+*/
+pub struct AccessTokens {
+    con: Con,
+}
+
+/// What a token may do, `write` includes `read` of the same resource.
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Scope {
+    #[serde(rename = "apps:read")]
+    AppsRead,
+    #[serde(rename = "apps:write")]
+    AppsWrite,
+    #[serde(rename = "brokers:read")]
+    BrokersRead,
+    #[serde(rename = "brokers:write")]
+    BrokersWrite,
+}
+
+impl Scope {
+    const ALL: [Scope; 4] = [
+        Scope::AppsRead,
+        Scope::AppsWrite,
+        Scope::BrokersRead,
+        Scope::BrokersWrite,
+    ];
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Scope::AppsRead => "apps:read",
+            Scope::AppsWrite => "apps:write",
+            Scope::BrokersRead => "brokers:read",
+            Scope::BrokersWrite => "brokers:write",
+        }
+    }
+
+    fn parse(scope: &str) -> Option<Scope> {
+        Scope::ALL.into_iter().find(|known| known.as_str() == scope)
+    }
+
+    fn covers(self, needed: Scope) -> bool {
+        self == needed
+            || matches!(
+                (self, needed),
+                (Scope::AppsWrite, Scope::AppsRead) | (Scope::BrokersWrite, Scope::BrokersRead)
+            )
+    }
+}
+
+/// Scopes are stored space separated, unknown ones are ignored.
+fn parse_scopes(scopes: &str) -> Vec<Scope> {
+    scopes.split_whitespace().filter_map(Scope::parse).collect()
+}
+
+fn join_scopes(scopes: &[Scope]) -> String {
+    scopes
+        .iter()
+        .map(|scope| scope.as_str())
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+/// Listing view, never contains the token or its hash.
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct AccessToken {
+    pub id: i32,
+    pub name: String,
+    pub scopes: Vec<Scope>,
+    /// Token only works for this app when set.
+    pub app_id: Option<i32>,
+    pub created_at: u64,
+    pub expires_at: Option<u64>,
+    pub last_used_at: Option<u64>,
+}
+
+impl AccessToken {
+    fn from_row(row: &Row) -> SqlResult<Self> {
+        Ok(Self {
+            id: row.get("id")?,
+            name: row.get("name")?,
+            scopes: parse_scopes(&row.get::<_, String>("scopes")?),
+            app_id: row.get("app_id")?,
+            created_at: row.get("created_at")?,
+            expires_at: row.get("expires_at")?,
+            last_used_at: row.get("last_used_at")?,
+        })
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct NewAccessToken {
+    pub name: String,
+    pub scopes: Vec<Scope>,
+    #[serde(default)]
+    pub app_id: Option<i32>,
+    /// Unix time, the token never expires without it.
+    #[serde(default)]
+    pub expires_at: Option<u64>,
+}
+
+/// What a valid token allows, enforced by `token_scopes`.
+#[derive(Clone, Debug)]
+pub struct TokenGrant {
+    pub token_id: i32,
+    pub user_id: i32,
+    pub scopes: Vec<Scope>,
+    pub app_id: Option<i32>,
+}
+
+impl TokenGrant {
+    /// `app_id` is the app the request is about, `None` for routes about all apps of the user.
+    pub fn allows(&self, needed: Scope, app_id: Option<i32>) -> bool {
+        let app_allowed = match self.app_id {
+            Some(restricted) => app_id == Some(restricted),
+            None => true,
+        };
+        app_allowed && self.scopes.iter().any(|scope| scope.covers(needed))
+    }
+}
+
+impl AccessTokens {
+    pub fn new(con: &Con) -> Self {
+        Self { con: con.clone() }
+    }
+
+    copy!(create_table() -> SqlResult<usize>);
+    copy_mut!(create(user_id: i32, new_token: &NewAccessToken, token: &str) -> SqlResult<i32>);
+    copy!(for_user(user_id: i32) -> SqlResult<Vec<AccessToken>>);
+    copy_mut!(revoke(user_id: i32, token_id: i32) -> SqlResult<usize>);
+    copy!(authenticate(token: &str) -> SqlResult<Option<TokenGrant>>);
+}
+
+fn create_table(con: &Connection) -> SqlResult<usize> {
+    con.execute(
+        "CREATE TABLE IF NOT EXISTS access_tokens (
+            id INTEGER PRIMARY KEY,
+            user_id INTEGER,
+            name TEXT,
+            token_hash TEXT UNIQUE,
+            scopes TEXT,
+            app_id INTEGER,
+            created_at INTEGER,
+            expires_at INTEGER,
+            last_used_at INTEGER,
+            revoked_at INTEGER,
+            FOREIGN KEY(user_id) REFERENCES users(id),
+            FOREIGN KEY(app_id) REFERENCES apps(id)
+        )",
+        [],
+    )
+}
+
+/// Tokens restricted to an app need the user to have permission for that app.
+fn create(
+    con: &mut Connection,
+    user_id: i32,
+    new_token: &NewAccessToken,
+    token: &str,
+) -> SqlResult<i32> {
+    let tx = con.transaction()?;
+    if let Some(app_id) = new_token.app_id {
+        has_permission(&tx, app_id, user_id)?;
+    }
+    query_execute!(tx => "
+        INSERT INTO access_tokens(user_id, name, token_hash, scopes, app_id, created_at, expires_at) VALUES (?, ?, ?, ?, ?, ?, ?)",
+        (user_id, &new_token.name, sha256_hex(token), join_scopes(&new_token.scopes), new_token.app_id, now(), new_token.expires_at)
+    )?;
+    let token_id = tx.last_insert_rowid() as i32;
+    let change = Change {
+        actor_id: Some(user_id),
+        app_id: new_token.app_id,
+        action: "accessToken.create",
+        target: format!("accessToken:{token_id}"),
+        before: None,
+        after: Some(json!({
+            "name": new_token.name,
+            "scopes": new_token.scopes,
+            "expiresAt": new_token.expires_at,
+        })),
+    };
+    audit::record(&tx, change)?;
+    tx.commit()?;
+    Ok(token_id)
+}
+
+/// Tokens that were not revoked, expired ones included so they can be cleaned up.
+fn for_user(con: &Connection, user_id: i32) -> SqlResult<Vec<AccessToken>> {
+    Ok(query_rows!(con => "
+        SELECT * FROM access_tokens WHERE user_id = ? AND revoked_at IS NULL ORDER BY id",
+        [user_id], AccessToken))
+}
+
+fn revoke(con: &mut Connection, user_id: i32, token_id: i32) -> SqlResult<usize> {
+    let tx = con.transaction()?;
+    let result = query_execute!(tx => "
+        UPDATE access_tokens SET revoked_at = ? WHERE id = ? AND user_id = ? AND revoked_at IS NULL",
+        (now(), token_id, user_id)
+    )?;
+    if result == 1 {
+        let change = Change {
+            actor_id: Some(user_id),
+            app_id: None,
+            action: "accessToken.revoke",
+            target: format!("accessToken:{token_id}"),
+            before: None,
+            after: None,
+        };
+        audit::record(&tx, change)?;
+    }
+    tx.commit()?;
+    Ok(result)
+}
+
+/// Grant of a valid token, also remembers when it was last used.
+fn authenticate(con: &Connection, token: &str) -> SqlResult<Option<TokenGrant>> {
+    let now = now();
+    let mut stmt = con.prepare_cached(
+        "SELECT access_tokens.id, access_tokens.user_id, scopes, app_id FROM access_tokens
+        JOIN users ON users.id = access_tokens.user_id
+        WHERE token_hash = ? AND revoked_at IS NULL
+            AND (expires_at IS NULL OR expires_at > ?) AND users.deleted_at IS NULL",
+    )?;
+    let grant = stmt
+        .query_row((sha256_hex(token), now), |row| {
+            Ok(TokenGrant {
+                token_id: row.get(0)?,
+                user_id: row.get(1)?,
+                scopes: parse_scopes(&row.get::<_, String>(2)?),
+                app_id: row.get(3)?,
+            })
+        })
+        .optional()?;
+
+    if let Some(grant) = &grant {
+        query_execute!(con => "UPDATE access_tokens SET last_used_at = ? WHERE id = ?", (now, grant.token_id))?;
+    }
+    Ok(grant)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::testing;
+
+    fn grant(scopes: &[Scope], app_id: Option<i32>) -> TokenGrant {
+        TokenGrant {
+            token_id: 1,
+            user_id: 1,
+            scopes: scopes.to_vec(),
+            app_id,
+        }
+    }
+
+    #[test]
+    fn write_covers_read_of_the_same_resource_only() {
+        let grant = grant(&[Scope::AppsWrite, Scope::BrokersRead], None);
+        assert!(grant.allows(Scope::AppsRead, Some(1)));
+        assert!(grant.allows(Scope::AppsWrite, None));
+        assert!(grant.allows(Scope::BrokersRead, Some(1)));
+        assert!(!grant.allows(Scope::BrokersWrite, Some(1)));
+    }
+
+    #[test]
+    fn app_restricted_grant_needs_that_app() {
+        let grant = grant(&[Scope::AppsRead], Some(2));
+        assert!(grant.allows(Scope::AppsRead, Some(2)));
+        assert!(!grant.allows(Scope::AppsRead, Some(3)));
+        assert!(!grant.allows(Scope::AppsRead, None));
+    }
+
+    #[test]
+    fn only_valid_tokens_authenticate() {
+        let db = testing::db();
+        let user_id = testing::user(&db, "alice");
+        let new_token = |expires_at| NewAccessToken {
+            name: "ci".to_string(),
+            scopes: vec![Scope::AppsRead],
+            app_id: None,
+            expires_at,
+        };
+        let token_id = db
+            .access_tokens
+            .create(user_id, &new_token(None), "rgp_a")
+            .unwrap();
+        db.access_tokens
+            .create(user_id, &new_token(Some(now() - 1)), "rgp_b")
+            .unwrap();
+
+        let grant = db.access_tokens.authenticate("rgp_a").unwrap().unwrap();
+        assert_eq!((grant.token_id, grant.user_id), (token_id, user_id));
+        assert_eq!(grant.scopes, [Scope::AppsRead]);
+        let used: Option<u64> = {
+            let con = db.con.lock().unwrap();
+            let sql = "SELECT last_used_at FROM access_tokens WHERE id = ?";
+            con.query_row(sql, [token_id], |row| row.get(0)).unwrap()
+        };
+        assert!(used.is_some());
+
+        assert!(db.access_tokens.authenticate("rgp_b").unwrap().is_none());
+        assert!(db.access_tokens.authenticate("rgp_c").unwrap().is_none());
+        db.access_tokens.revoke(user_id, token_id).unwrap();
+        assert!(db.access_tokens.authenticate("rgp_a").unwrap().is_none());
+    }
+}
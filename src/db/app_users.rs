@@ -7,9 +7,11 @@ use serde::Deserialize;
 use serde::Serialize;
 
 use super::apps::has_permission;
+use super::audit::{self, snapshot, Change};
+use super::events::{self, AppEvent};
 use super::query_execute;
 // for_app, create, delete
-use super::{query_rows, Con, SqlResult};
+use super::{query_row, query_rows, Con, SqlResult};
 
 pub struct AppUsers {
     con: Con,
@@ -46,6 +48,8 @@ pub struct AppUser {
     pub user_id: i32,
     pub name: String,
     pub username: String,
+    pub created_at: u64,
+    pub updated_at: u64,
 }
 
 impl_from_row!(AppUser {
@@ -53,13 +57,15 @@ impl_from_row!(AppUser {
     app_id,
     user_id,
     name,
-    username
+    username,
+    created_at,
+    updated_at
 });
 
 fn for_app(con: &mut Connection, app_id: i32, user_id: i32) -> SqlResult<Vec<AppUser>> {
     let tx = con.transaction()?;
     has_permission(&tx, app_id, user_id)?;
-    let users = query_rows!(tx => "SELECT * FROM app_users JOIN users ON app_users.user_id = users.id WHERE app_id = ?", [app_id], AppUser);
+    let users = query_rows!(tx => "SELECT * FROM app_users JOIN users ON app_users.user_id = users.id WHERE app_id = ? AND app_users.deleted_at IS NULL", [app_id], AppUser);
     Ok(users)
 }
 
@@ -67,6 +73,20 @@ fn create(con: &mut Connection, app_id: i32, user_id: i32, new_user_id: i32) ->
     let tx = con.transaction()?;
     has_permission(&tx, app_id, user_id)?;
     let result = unchecked_create(&tx, app_id, new_user_id)?;
+    let event = AppEvent::AppUserAdded {
+        app_user_id: result,
+        user_id: new_user_id,
+    };
+    events::record(&tx, app_id, &event)?;
+    let change = Change {
+        actor_id: Some(user_id),
+        app_id: Some(app_id),
+        action: "appUser.create",
+        target: format!("appUser:{result}"),
+        before: None,
+        after: snapshot(&by_id(&tx, result)?),
+    };
+    audit::record(&tx, change)?;
     tx.commit()?;
     Ok(result)
 }
@@ -74,16 +94,33 @@ fn create(con: &mut Connection, app_id: i32, user_id: i32, new_user_id: i32) ->
 fn delete(con: &mut Connection, app_id: i32, user_id: i32, app_user_id: i32) -> SqlResult<usize> {
     let tx = con.transaction()?;
     has_permission(&tx, app_id, user_id)?;
-    let result = unchecked_delete(&tx, app_user_id)?;
+    let before = by_id(&tx, app_user_id).ok();
+    let result = unchecked_delete(&tx, app_id, app_user_id)?;
+    events::record(&tx, app_id, &AppEvent::AppUserRemoved { app_user_id })?;
+    let change = Change {
+        actor_id: Some(user_id),
+        app_id: Some(app_id),
+        action: "appUser.delete",
+        target: format!("appUser:{app_user_id}"),
+        before: before.as_ref().and_then(snapshot),
+        after: None,
+    };
+    audit::record(&tx, change)?;
     tx.commit()?;
     Ok(result)
 }
 
+fn by_id(con: &Connection, app_user_id: i32) -> SqlResult<AppUser> {
+    query_row!(con => "SELECT app_users.id AS id, * FROM app_users JOIN users ON app_users.user_id = users.id WHERE app_users.id = ?", [app_user_id], AppUser)
+}
+
 fn unchecked_create(con: &Connection, app_id: i32, new_user_id: i32) -> SqlResult<i32> {
+    // soft deleted membership would violate UNIQUE(app_id, user_id)
+    query_execute!(con => "DELETE FROM app_users WHERE app_id = ? AND user_id = ? AND deleted_at IS NOT NULL", [app_id, new_user_id])?;
     query_execute!(con => "INSERT INTO app_users(app_id, user_id) VALUES (?, ?)", [app_id, new_user_id])?;
     Ok(con.last_insert_rowid() as i32)
 }
 
-fn unchecked_delete(con: &Connection, app_user_id: i32) -> SqlResult<usize> {
-    query_execute!(con => "DELETE FROM app_users WHERE id = ?", [app_user_id])
+fn unchecked_delete(con: &Connection, app_id: i32, app_user_id: i32) -> SqlResult<usize> {
+    query_execute!(con => "UPDATE app_users SET deleted_at = ? WHERE id = ? AND app_id = ? AND deleted_at IS NULL", (events::now(), app_user_id, app_id))
 }
@@ -1,11 +1,13 @@
 use std::mem::transmute;
 
-use axum_utils::copy;
+use axum_utils::{copy, copy_mut};
 use rusqlite::{Connection, OptionalExtension, Row};
 use serde::{Deserialize, Serialize};
 use serde_repr::{Deserialize_repr, Serialize_repr};
 
-use super::{query_row, Con, SqlResult};
+use super::audit::{self, snapshot, Change};
+use super::events::{self, AppEvent};
+use super::{query_execute, query_row, query_rows, Con, SqlResult};
 
 pub struct Apps {
     con: Con,
@@ -20,10 +22,26 @@ pub struct NewApp {
     pub version: String,
     pub public: bool,
     pub status: AppStatus,
+    /// Operators without 2FA lose access while this is set, see `has_permission`.
+    #[serde(default)]
+    pub require_two_factor: bool,
+    /// Oldest broker version that works with the app, see `brokers::VersionPolicy`.
+    #[serde(default)]
+    pub min_broker_version: Option<String>,
+    /// First broker version that does not work anymore.
+    #[serde(default)]
+    pub max_broker_version: Option<String>,
+    /// Brokers outside the range are turned away instead of only warned.
+    #[serde(default)]
+    pub reject_incompatible_brokers: bool,
+    #[serde(default, skip_deserializing, skip_serializing_if = "Option::is_none")]
+    pub created_at: Option<u64>,
+    #[serde(default, skip_deserializing, skip_serializing_if = "Option::is_none")]
+    pub updated_at: Option<u64>,
 }
 
 #[repr(usize)]
-#[derive(Serialize_repr, Deserialize_repr, PartialEq, Debug)]
+#[derive(Serialize_repr, Deserialize_repr, PartialEq, Clone, Copy, Debug)]
 pub enum AppStatus {
     Active = 0,
     Passive,
@@ -47,6 +65,8 @@ pub struct AppEntity {
     pub version: String,
     pub public: bool,
     pub status: AppStatus,
+    pub created_at: u64,
+    pub updated_at: u64,
 }
 
 impl AppEntity {
@@ -61,6 +81,8 @@ impl AppEntity {
             version: row.get(5)?,
             public: row.get(6)?,
             status: status.into(),
+            created_at: row.get("created_at")?,
+            updated_at: row.get("updated_at")?,
         })
     }
 }
@@ -102,11 +124,13 @@ impl Apps {
             version,
             public,
             status,
+            ..
         }: NewApp,
     ) -> Result<usize, rusqlite::Error> {
-        let con = self.con.lock().unwrap();
+        let mut con = self.con.lock().unwrap();
+        let tx = con.transaction()?;
 
-        con.execute(
+        let inserted = tx.execute(
             "INSERT INTO apps(
                 author_id,
                 title, 
@@ -116,7 +140,7 @@ impl Apps {
                 public,
                 status
             ) SELECT
-            id,?,?,?,?,?,? FROM users WHERE users.id = ?",
+            id,?,?,?,?,?,? FROM users WHERE users.id = ? AND users.deleted_at IS NULL",
             (
                 title,
                 description,
@@ -126,13 +150,27 @@ impl Apps {
                 status as usize,
                 author_id,
             ),
-        )
+        )?;
+        if inserted == 1 {
+            let app_id = tx.last_insert_rowid() as usize;
+            let change = Change {
+                actor_id: Some(author_id),
+                app_id: Some(app_id as i32),
+                action: "app.create",
+                target: format!("app:{app_id}"),
+                before: None,
+                after: get_app_by_id(&tx, app_id)?.as_ref().and_then(snapshot),
+            };
+            audit::record(&tx, change)?;
+        }
+        tx.commit()?;
+        Ok(inserted)
     }
 
     pub fn select_all(&self) -> Result<Vec<AppEntity>, rusqlite::Error> {
         let con = self.con.lock().unwrap();
 
-        let mut stmt = con.prepare_cached("SELECT * FROM apps")?;
+        let mut stmt = con.prepare_cached("SELECT * FROM apps WHERE deleted_at IS NULL")?;
         let apps: Vec<AppEntity> = stmt
             .query(())?
             .mapped(AppEntity::from_row)
@@ -146,7 +184,7 @@ impl Apps {
         let con = self.con.lock().unwrap();
 
         let mut stmt =
-            con.prepare_cached("SELECT * FROM apps WHERE apps.title LIKE '%' || ? || '%'")?;
+            con.prepare_cached("SELECT * FROM apps WHERE apps.title LIKE '%' || ? || '%' AND deleted_at IS NULL")?;
 
         let apps = stmt
             .query([app_title])?
@@ -163,6 +201,11 @@ impl Apps {
     }
 
     copy!(by_id_for_user(app_id: i32, user_id: i32) -> SqlResult<Option<NewApp>>);
+    copy!(has_permission(app_id: i32, user_id: i32) -> SqlResult<()>);
+    copy_mut!(update(app_id: i32, user_id: i32, changes: AppUpdate) -> SqlResult<Option<NewApp>>);
+    copy_mut!(transfer(app_id: i32, new_author_id: i32) -> SqlResult<usize>);
+    copy!(for_user(user_id: i32) -> SqlResult<UserApps>);
+    copy_mut!(delete(app_id: i32) -> SqlResult<usize>);
 }
 
 impl NewApp {
@@ -175,15 +218,23 @@ impl NewApp {
             version: row.get("version")?,
             public: row.get("public")?,
             status: usize::into(row.get("status")?),
+            require_two_factor: row.get("require_two_factor")?,
+            min_broker_version: row.get("min_broker_version")?,
+            max_broker_version: row.get("max_broker_version")?,
+            reject_incompatible_brokers: row.get("reject_incompatible_brokers")?,
+            created_at: row.get("created_at")?,
+            updated_at: row.get("updated_at")?,
         })
     }
 }
 
 fn get_app_by_id(con: &Connection, app_id: usize) -> Result<Option<NewApp>, rusqlite::Error> {
     let mut stmt = con.prepare_cached(
-        "SELECT users.username as author, title, description, weblink, version, public, status
+        "SELECT users.username as author, author_id, title, description, weblink, version, public, status,
+                require_two_factor, min_broker_version, max_broker_version, reject_incompatible_brokers,
+                apps.created_at as created_at, apps.updated_at as updated_at
              FROM apps JOIN users ON apps.author_id = users.id
-             WHERE apps.id = ?",
+             WHERE apps.id = ? AND apps.deleted_at IS NULL",
     )?;
     let app = stmt.query_row([app_id], NewApp::from_row).optional()?;
     Ok(app)
@@ -192,20 +243,194 @@ fn get_app_by_id(con: &Connection, app_id: usize) -> Result<Option<NewApp>, rusq
 fn by_id_for_user(con: &Connection, app_id: i32, user_id: i32) -> SqlResult<Option<NewApp>> {
     query_row!(con => "
         SELECT 
-            users.username as author, author_id, title, description, weblink, version, public, status 
+            users.username as author, author_id, title, description, weblink, version, public, status,
+            require_two_factor, min_broker_version, max_broker_version, reject_incompatible_brokers,
+            apps.created_at as created_at, apps.updated_at as updated_at
         FROM apps 
         JOIN users ON apps.author_id = users.id
-        WHERE apps.id = ? AND (apps.public = TRUE OR apps.author_id = ?)",
+        WHERE apps.id = ? AND apps.deleted_at IS NULL AND (apps.public = TRUE OR apps.author_id = ?)",
         [app_id, user_id],
         NewApp
     ).optional()
 }
 
+/// Apps a user has a role in, by role.
+#[derive(Serialize, Deserialize)]
+pub struct UserApps {
+    pub owned: Vec<AppEntity>,
+    pub operating: Vec<AppEntity>,
+    pub member: Vec<AppEntity>,
+}
+
+fn for_user(con: &Connection, user_id: i32) -> SqlResult<UserApps> {
+    Ok(UserApps {
+        owned: query_rows!(con => "
+            SELECT * FROM apps WHERE author_id = ? AND deleted_at IS NULL ORDER BY id",
+            [user_id], AppEntity),
+        operating: query_rows!(con => "
+            SELECT apps.* FROM apps JOIN operators ON operators.app_id = apps.id
+            WHERE operators.user_id = ? AND apps.deleted_at IS NULL AND operators.deleted_at IS NULL
+            ORDER BY apps.id",
+            [user_id], AppEntity),
+        member: query_rows!(con => "
+            SELECT apps.* FROM apps JOIN app_users ON app_users.app_id = apps.id
+            WHERE app_users.user_id = ? AND apps.deleted_at IS NULL AND app_users.deleted_at IS NULL
+            ORDER BY apps.id",
+            [user_id], AppEntity),
+    })
+}
+
+/// Fields missing from the request stay as they are.
+#[derive(Serialize, Deserialize)]
+pub struct AppUpdate {
+    pub title: Option<String>,
+    pub description: Option<String>,
+    pub weblink: Option<String>,
+    pub version: Option<String>,
+    pub public: Option<bool>,
+    pub status: Option<AppStatus>,
+    pub require_two_factor: Option<bool>,
+    /// Empty string removes the bound.
+    pub min_broker_version: Option<String>,
+    pub max_broker_version: Option<String>,
+    pub reject_incompatible_brokers: Option<bool>,
+}
+
+/// Only author can update the app, `None` if app does not exist or belongs to someone else.
+fn update(
+    con: &mut Connection,
+    app_id: i32,
+    user_id: i32,
+    update: AppUpdate,
+) -> SqlResult<Option<NewApp>> {
+    let tx = con.transaction()?;
+    let Some(before) = by_id_for_user(&tx, app_id, user_id)? else {
+        return Ok(None);
+    };
+    if before.author_id != user_id {
+        return Ok(None);
+    }
+
+    let status = update.status.map(|status| status as usize);
+    query_execute!(tx => "
+        UPDATE apps SET
+            title = COALESCE(?, title),
+            description = COALESCE(?, description),
+            weblink = COALESCE(?, weblink),
+            version = COALESCE(?, version),
+            public = COALESCE(?, public),
+            status = COALESCE(?, status),
+            require_two_factor = COALESCE(?, require_two_factor),
+            min_broker_version = NULLIF(COALESCE(?, min_broker_version), ''),
+            max_broker_version = NULLIF(COALESCE(?, max_broker_version), ''),
+            reject_incompatible_brokers = COALESCE(?, reject_incompatible_brokers)
+        WHERE id = ?",
+        (
+            update.title, update.description, update.weblink, update.version, update.public, status, update.require_two_factor,
+            update.min_broker_version, update.max_broker_version, update.reject_incompatible_brokers, app_id
+        )
+    )?;
+    let after = by_id_for_user(&tx, app_id, user_id)?.unwrap();
+
+    let changed = before.title != after.title
+        || before.description != after.description
+        || before.weblink != after.weblink
+        || before.version != after.version
+        || before.public != after.public;
+    if changed {
+        let event = AppEvent::AppUpdated {
+            title: after.title.clone(),
+            version: after.version.clone(),
+        };
+        events::record(&tx, app_id, &event)?;
+    }
+    if before.status != after.status {
+        let event = AppEvent::StatusChanged {
+            status: after.status,
+        };
+        events::record(&tx, app_id, &event)?;
+    }
+    let change = Change {
+        actor_id: Some(user_id),
+        app_id: Some(app_id),
+        action: "app.update",
+        target: format!("app:{app_id}"),
+        before: snapshot(&before),
+        after: snapshot(&after),
+    };
+    audit::record(&tx, change)?;
+
+    tx.commit()?;
+    Ok(Some(after))
+}
+
+/// Makes another user the author of the app, no permission checks.
+fn transfer(con: &mut Connection, app_id: i32, new_author_id: i32) -> SqlResult<usize> {
+    let tx = con.transaction()?;
+    let result = unchecked_transfer(&tx, app_id, new_author_id, None)?;
+    tx.commit()?;
+    Ok(result)
+}
+
+/// Soft deletes the app together with its operators, users and brokers, no permission checks.
+fn delete(con: &mut Connection, app_id: i32) -> SqlResult<usize> {
+    let tx = con.transaction()?;
+    let result = unchecked_delete(&tx, app_id, None)?;
+    tx.commit()?;
+    Ok(result)
+}
+
+/// Meant to be called inside a transaction, `actor_id` is `None` for admin cli.
+pub fn unchecked_transfer(
+    con: &Connection,
+    app_id: i32,
+    new_author_id: i32,
+    actor_id: Option<i32>,
+) -> SqlResult<usize> {
+    let before = get_app_by_id(con, app_id as usize)?;
+    let result = query_execute!(con => "UPDATE apps SET author_id = ? WHERE id = ? AND deleted_at IS NULL", [new_author_id, app_id])?;
+    let change = Change {
+        actor_id,
+        app_id: Some(app_id),
+        action: "app.transfer",
+        target: format!("app:{app_id}"),
+        before: before.as_ref().and_then(snapshot),
+        after: get_app_by_id(con, app_id as usize)?
+            .as_ref()
+            .and_then(snapshot),
+    };
+    audit::record(con, change)?;
+    Ok(result)
+}
+
+/**
+    Meant to be called inside a transaction, `actor_id` is `None` for admin cli.
+    Everything gets the same `deleted_at`, so restoring the app brings back exactly these rows.
+*/
+pub fn unchecked_delete(con: &Connection, app_id: i32, actor_id: Option<i32>) -> SqlResult<usize> {
+    let before = get_app_by_id(con, app_id as usize)?;
+    let now = events::now();
+    query_execute!(con => "UPDATE operators SET deleted_at = ? WHERE app_id = ? AND deleted_at IS NULL", (now, app_id))?;
+    query_execute!(con => "UPDATE app_users SET deleted_at = ? WHERE app_id = ? AND deleted_at IS NULL", (now, app_id))?;
+    query_execute!(con => "UPDATE brokers SET deleted_at = ? WHERE app_id = ? AND deleted_at IS NULL", (now, app_id))?;
+    let result = query_execute!(con => "UPDATE apps SET deleted_at = ? WHERE id = ? AND deleted_at IS NULL", (now, app_id))?;
+    let change = Change {
+        actor_id,
+        app_id: Some(app_id),
+        action: "app.delete",
+        target: format!("app:{app_id}"),
+        before: before.as_ref().and_then(snapshot),
+        after: None,
+    };
+    audit::record(con, change)?;
+    Ok(result)
+}
+
 pub fn has_permission(con: &Connection, app_id: i32, user_id: i32) -> SqlResult<()> {
     let mut stmt = con.prepare_cached(
         "
     SELECT * FROM apps 
-    WHERE apps.id = ? AND apps.author_id = ?",
+    WHERE apps.id = ? AND apps.author_id = ? AND apps.deleted_at IS NULL",
     )?;
     let is_owner = stmt.query_row([app_id, user_id], |_| Ok(()));
 
@@ -214,7 +439,10 @@ pub fn has_permission(con: &Connection, app_id: i32, user_id: i32) -> SqlResult<
             "
         SELECT * FROM apps 
         JOIN operators ON operators.app_id = apps.id 
-        WHERE apps.id = ? AND operators.user_id = ?",
+        LEFT JOIN user_totp ON user_totp.user_id = operators.user_id
+        WHERE apps.id = ? AND operators.user_id = ?
+            AND apps.deleted_at IS NULL AND operators.deleted_at IS NULL
+            AND (apps.require_two_factor = 0 OR user_totp.enabled = 1)",
         )?;
         stmt.query_row([app_id, user_id], |_| Ok(()))
     })
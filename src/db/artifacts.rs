@@ -0,0 +1,544 @@
+use std::{env, sync::OnceLock};
+
+use axum_utils::{copy, copy_mut};
+use rusqlite::{Connection, OptionalExtension, Row};
+use serde::{Deserialize, Serialize};
+
+use super::apps::has_permission;
+use super::audit::{self, snapshot, Change};
+use super::events::{self, now, AppEvent};
+use super::share_links;
+use super::{query_execute, query_row, query_rows, Con, SqlResult};
+use crate::crypto::{sign, verify_signature};
+use crate::validation::ValidationErrors;
+
+const DAY_SECS: u64 = 24 * 60 * 60;
+pub const MAX_NAME_LENGTH: usize = 255;
+pub const MAX_RETENTION_DAYS: u32 = 3650;
+const DEFAULT_LIMIT: usize = 50;
+const MAX_LIMIT: usize = 500;
+
+/**
+    Files brokers produce while running jobs, the reports themselves.
+    Rows describe them, contents are in `storage::store` under their sha256.
+    Every app has a quota on the bytes it keeps and artifacts expire after
+    the retention of the app, see `ArtifactDefaults` for the server wide values.
+*/
+pub struct Artifacts {
+    con: Con,
+}
+
+/**
+    Quota and retention of apps without their own.
+    `ARTIFACT_QUOTA_BYTES` defaults to 1 GiB, `ARTIFACT_RETENTION_DAYS` to 30.
+*/
+#[derive(Clone, Debug)]
+pub struct ArtifactDefaults {
+    pub quota_bytes: u64,
+    pub retention_days: u32,
+}
+
+impl ArtifactDefaults {
+    pub fn current() -> &'static ArtifactDefaults {
+        static DEFAULTS: OnceLock<ArtifactDefaults> = OnceLock::new();
+        DEFAULTS.get_or_init(ArtifactDefaults::from_env)
+    }
+
+    fn from_env() -> Self {
+        let quota_bytes = env::var("ARTIFACT_QUOTA_BYTES")
+            .ok()
+            .and_then(|value| value.parse().ok())
+            .unwrap_or(1 << 30);
+        let retention_days = env::var("ARTIFACT_RETENTION_DAYS")
+            .ok()
+            .and_then(|value| value.parse().ok())
+            .filter(|days| (1..=MAX_RETENTION_DAYS).contains(days))
+            .unwrap_or(30);
+        Self {
+            quota_bytes,
+            retention_days,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct Artifact {
+    pub id: i64,
+    pub app_id: i32,
+    pub job_id: i64,
+    /// Broker that uploaded it.
+    pub broker_id: Option<i32>,
+    pub name: String,
+    pub content_type: String,
+    pub size: u64,
+    /// Hex sha256 of the content, also its key in the store.
+    pub sha256: String,
+    pub created_by: Option<i32>,
+    pub created_at: u64,
+    pub expires_at: u64,
+}
+
+impl Artifact {
+    pub fn from_row(row: &Row) -> SqlResult<Self> {
+        Ok(Self {
+            id: row.get("id")?,
+            app_id: row.get("app_id")?,
+            job_id: row.get("job_id")?,
+            broker_id: row.get("broker_id")?,
+            name: row.get("name")?,
+            content_type: row.get("content_type")?,
+            size: row.get("size")?,
+            sha256: row.get("sha256")?,
+            created_by: row.get("created_by")?,
+            created_at: row.get("created_at")?,
+            expires_at: row.get("expires_at")?,
+        })
+    }
+}
+
+/// Limits that apply to the app and how much of its quota is used.
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ArtifactUsage {
+    pub artifacts: usize,
+    pub used_bytes: u64,
+    pub quota_bytes: u64,
+    pub retention_days: u32,
+}
+
+/// Received and checksummed upload, see `handlers::artifacts::upload`.
+pub struct NewArtifact {
+    pub job_id: i64,
+    pub broker_id: i32,
+    pub name: String,
+    pub content_type: String,
+    pub size: u64,
+    pub sha256: String,
+}
+
+/**
+    Proof that the job was given to the broker, sent along with the job over the
+    session of the broker. Uploads need it, an operator token alone can't name the broker.
+*/
+pub fn upload_token(app_id: i32, broker_id: i32, job_id: i64) -> String {
+    sign(&format!("upload.{app_id}.{broker_id}.{job_id}"))
+}
+
+pub fn verify_upload_token(app_id: i32, broker_id: i32, job_id: i64, token: &str) -> bool {
+    verify_signature(&format!("upload.{app_id}.{broker_id}.{job_id}"), token)
+}
+
+/// Names become the file name of downloads, so no paths or control characters.
+pub fn check_name(name: &str, errors: &mut ValidationErrors) {
+    if name.trim().is_empty() {
+        errors.add("name", "required", "name is required");
+    } else if name.chars().count() > MAX_NAME_LENGTH {
+        let message = format!("name must be at most {MAX_NAME_LENGTH} characters");
+        errors.add("name", "tooLong", &message);
+    } else if name == "."
+        || name == ".."
+        || name
+            .chars()
+            .any(|c| matches!(c, '/' | '\\') || c.is_control())
+    {
+        let message = "name must not contain slashes or control characters";
+        errors.add("name", "invalid", message);
+    }
+}
+
+/// Filters of the artifact listing, newest artifacts come first.
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ArtifactQuery {
+    pub job_id: Option<i64>,
+    /// For paging, artifacts older than this one.
+    pub before_id: Option<i64>,
+    pub limit: Option<usize>,
+}
+
+impl Artifacts {
+    pub fn new(con: &Con) -> Self {
+        Self { con: con.clone() }
+    }
+
+    copy!(create_table() -> SqlResult<usize>);
+    copy!(usage(app_id: i32, user_id: i32) -> SqlResult<ArtifactUsage>);
+    copy!(for_app(app_id: i32, user_id: i32, query: &ArtifactQuery) -> SqlResult<Vec<Artifact>>);
+    copy!(get(app_id: i32, user_id: i32, artifact_id: i64) -> SqlResult<Option<Artifact>>);
+    copy!(allowance(app_id: i32, user_id: i32, broker_id: i32, job_id: i64) -> SqlResult<Option<u64>>);
+    copy_mut!(create(app_id: i32, user_id: i32, new_artifact: NewArtifact) -> SqlResult<Option<Artifact>>);
+    copy_mut!(delete(app_id: i32, user_id: i32, artifact_id: i64) -> SqlResult<Option<Vec<String>>>);
+    copy_mut!(set_retention(app_id: i32, user_id: i32, days: Option<u32>) -> SqlResult<ArtifactUsage>);
+    copy_mut!(set_quota(app_id: i32, user_id: i32, bytes: Option<u64>) -> SqlResult<Option<ArtifactUsage>>);
+    copy_mut!(expire(at: u64, app_deleted_before: u64) -> SqlResult<Vec<String>>);
+    copy!(is_unused(sha256: &str) -> SqlResult<bool>);
+}
+
+fn create_table(con: &Connection) -> SqlResult<usize> {
+    con.execute(
+        "CREATE TABLE IF NOT EXISTS artifacts (
+            id INTEGER PRIMARY KEY AUTOINCREMENT,
+            app_id INTEGER,
+            job_id INTEGER,
+            broker_id INTEGER,
+            name TEXT,
+            content_type TEXT,
+            size INTEGER,
+            sha256 TEXT,
+            created_by INTEGER,
+            created_at INTEGER,
+            expires_at INTEGER,
+            FOREIGN KEY(app_id) REFERENCES apps(id),
+            FOREIGN KEY(job_id) REFERENCES jobs(id),
+            FOREIGN KEY(broker_id) REFERENCES brokers(id)
+        )",
+        [],
+    )?;
+    con.execute(
+        "CREATE INDEX IF NOT EXISTS artifacts_sha256 ON artifacts(sha256)",
+        [],
+    )?;
+    con.execute(
+        "CREATE INDEX IF NOT EXISTS artifacts_expires_at ON artifacts(expires_at)",
+        [],
+    )?;
+    // only apps that differ from `ArtifactDefaults` have a row
+    con.execute(
+        "CREATE TABLE IF NOT EXISTS artifact_policies (
+            app_id INTEGER PRIMARY KEY,
+            quota_bytes INTEGER,
+            retention_days INTEGER,
+            updated_at INTEGER,
+            FOREIGN KEY(app_id) REFERENCES apps(id)
+        )",
+        [],
+    )
+}
+
+/// Quota and retention of the app, its own or the defaults.
+fn limits(con: &Connection, app_id: i32) -> SqlResult<(u64, u32)> {
+    let defaults = ArtifactDefaults::current();
+    let policy: Option<(Option<u64>, Option<u32>)> = con
+        .query_row(
+            "SELECT quota_bytes, retention_days FROM artifact_policies WHERE app_id = ?",
+            [app_id],
+            |row| Ok((row.get(0)?, row.get(1)?)),
+        )
+        .optional()?;
+    let (quota_bytes, retention_days) = policy.unwrap_or_default();
+    Ok((
+        quota_bytes.unwrap_or(defaults.quota_bytes),
+        retention_days.unwrap_or(defaults.retention_days),
+    ))
+}
+
+/// Usage without permission checks.
+fn usage_of(con: &Connection, app_id: i32) -> SqlResult<ArtifactUsage> {
+    let (quota_bytes, retention_days) = limits(con, app_id)?;
+    let (artifacts, used_bytes) = con.query_row(
+        "SELECT COUNT(*), COALESCE(SUM(size), 0) FROM artifacts WHERE app_id = ?",
+        [app_id],
+        |row| Ok((row.get(0)?, row.get(1)?)),
+    )?;
+    Ok(ArtifactUsage {
+        artifacts,
+        used_bytes,
+        quota_bytes,
+        retention_days,
+    })
+}
+
+fn usage(con: &Connection, app_id: i32, user_id: i32) -> SqlResult<ArtifactUsage> {
+    has_permission(con, app_id, user_id)?;
+    usage_of(con, app_id)
+}
+
+fn for_app(
+    con: &Connection,
+    app_id: i32,
+    user_id: i32,
+    query: &ArtifactQuery,
+) -> SqlResult<Vec<Artifact>> {
+    has_permission(con, app_id, user_id)?;
+    let limit = query.limit.unwrap_or(DEFAULT_LIMIT).clamp(1, MAX_LIMIT);
+    Ok(query_rows!(con => "
+        SELECT * FROM artifacts WHERE app_id = ? AND (?2 IS NULL OR job_id = ?2) AND id < ?
+        ORDER BY id DESC LIMIT ?",
+        (app_id, query.job_id, query.before_id.unwrap_or(i64::MAX), limit),
+        Artifact
+    ))
+}
+
+/// Artifact of the app, no permission checks.
+fn by_id(con: &Connection, app_id: i32, artifact_id: i64) -> SqlResult<Option<Artifact>> {
+    query_row!(con => "SELECT * FROM artifacts WHERE id = ? AND app_id = ?", (artifact_id, app_id), Artifact)
+        .optional()
+}
+
+fn get(
+    con: &Connection,
+    app_id: i32,
+    user_id: i32,
+    artifact_id: i64,
+) -> SqlResult<Option<Artifact>> {
+    has_permission(con, app_id, user_id)?;
+    by_id(con, app_id, artifact_id)
+}
+
+/**
+    Bytes the broker may still upload for the job, what is left of the quota.
+    `None` if the job of the app was not given to this broker.
+*/
+fn allowance(
+    con: &Connection,
+    app_id: i32,
+    user_id: i32,
+    broker_id: i32,
+    job_id: i64,
+) -> SqlResult<Option<u64>> {
+    has_permission(con, app_id, user_id)?;
+    let assigned = con
+        .query_row(
+            "SELECT id FROM jobs WHERE id = ? AND app_id = ? AND broker_id = ?",
+            (job_id, app_id, broker_id),
+            |_| Ok(()),
+        )
+        .optional()?;
+    if assigned.is_none() {
+        return Ok(None);
+    }
+    let usage = usage_of(con, app_id)?;
+    Ok(Some(usage.quota_bytes.saturating_sub(usage.used_bytes)))
+}
+
+/**
+    Records an upload, `None` if it does not fit into the quota anymore,
+    concurrent uploads may have used it up since `allowance`.
+*/
+fn create(
+    con: &mut Connection,
+    app_id: i32,
+    user_id: i32,
+    new_artifact: NewArtifact,
+) -> SqlResult<Option<Artifact>> {
+    let tx = con.transaction()?;
+    has_permission(&tx, app_id, user_id)?;
+    let usage = usage_of(&tx, app_id)?;
+    if usage.used_bytes + new_artifact.size > usage.quota_bytes {
+        return Ok(None);
+    }
+    let now = now();
+    let expires_at = now + usage.retention_days as u64 * DAY_SECS;
+    query_execute!(tx => "
+        INSERT INTO artifacts(app_id, job_id, broker_id, name, content_type, size, sha256, created_by, created_at, expires_at)
+        VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?)",
+        (
+            app_id,
+            new_artifact.job_id,
+            new_artifact.broker_id,
+            &new_artifact.name,
+            &new_artifact.content_type,
+            new_artifact.size,
+            &new_artifact.sha256,
+            user_id,
+            now,
+            expires_at
+        )
+    )?;
+    let artifact_id = tx.last_insert_rowid();
+    let artifact = by_id(&tx, app_id, artifact_id)?.unwrap();
+
+    let event = AppEvent::ArtifactStored {
+        artifact_id,
+        job_id: artifact.job_id,
+        name: artifact.name.clone(),
+        size: artifact.size,
+    };
+    events::record(&tx, app_id, &event)?;
+    let change = Change {
+        actor_id: Some(user_id),
+        app_id: Some(app_id),
+        action: "artifact.create",
+        target: format!("artifact:{artifact_id}"),
+        before: None,
+        after: snapshot(&artifact),
+    };
+    audit::record(&tx, change)?;
+    tx.commit()?;
+    Ok(Some(artifact))
+}
+
+/// Of `keys`, the ones no artifact refers to anymore.
+fn unused(con: &Connection, mut keys: Vec<String>) -> SqlResult<Vec<String>> {
+    keys.sort();
+    keys.dedup();
+    let mut unused = Vec::new();
+    for key in keys {
+        if is_unused(con, &key)? {
+            unused.push(key);
+        }
+    }
+    Ok(unused)
+}
+
+/// Whether content can be removed from the store.
+fn is_unused(con: &Connection, sha256: &str) -> SqlResult<bool> {
+    let used = con
+        .query_row(
+            "SELECT id FROM artifacts WHERE sha256 = ? LIMIT 1",
+            [sha256],
+            |_| Ok(()),
+        )
+        .optional()?;
+    Ok(used.is_none())
+}
+
+/// `None` if the artifact does not exist, otherwise the content keys to remove from the store.
+fn delete(
+    con: &mut Connection,
+    app_id: i32,
+    user_id: i32,
+    artifact_id: i64,
+) -> SqlResult<Option<Vec<String>>> {
+    let tx = con.transaction()?;
+    has_permission(&tx, app_id, user_id)?;
+    let Some(before) = by_id(&tx, app_id, artifact_id)? else {
+        return Ok(None);
+    };
+    share_links::delete_for_artifact(&tx, artifact_id)?;
+    query_execute!(tx => "DELETE FROM artifacts WHERE id = ?", [artifact_id])?;
+    let unused = unused(&tx, vec![before.sha256.clone()])?;
+
+    events::record(&tx, app_id, &AppEvent::ArtifactRemoved { artifact_id })?;
+    let change = Change {
+        actor_id: Some(user_id),
+        app_id: Some(app_id),
+        action: "artifact.delete",
+        target: format!("artifact:{artifact_id}"),
+        before: snapshot(&before),
+        after: None,
+    };
+    audit::record(&tx, change)?;
+    tx.commit()?;
+    Ok(Some(unused))
+}
+
+/// Stores the policy row, `None` values go back to the defaults.
+fn save_policy(
+    con: &Connection,
+    app_id: i32,
+    quota_bytes: Option<Option<u64>>,
+    retention_days: Option<Option<u32>>,
+) -> SqlResult<usize> {
+    let (current_quota, current_retention): (Option<u64>, Option<u32>) = con
+        .query_row(
+            "SELECT quota_bytes, retention_days FROM artifact_policies WHERE app_id = ?",
+            [app_id],
+            |row| Ok((row.get(0)?, row.get(1)?)),
+        )
+        .optional()?
+        .unwrap_or_default();
+    query_execute!(con => "
+        INSERT INTO artifact_policies(app_id, quota_bytes, retention_days, updated_at) VALUES (?1, ?2, ?3, ?4)
+        ON CONFLICT(app_id) DO UPDATE SET quota_bytes = ?2, retention_days = ?3, updated_at = ?4",
+        (
+            app_id,
+            quota_bytes.unwrap_or(current_quota),
+            retention_days.unwrap_or(current_retention),
+            now()
+        )
+    )
+}
+
+/// Existing artifacts of the app expire according to the new retention.
+fn set_retention(
+    con: &mut Connection,
+    app_id: i32,
+    user_id: i32,
+    days: Option<u32>,
+) -> SqlResult<ArtifactUsage> {
+    let tx = con.transaction()?;
+    has_permission(&tx, app_id, user_id)?;
+    let before = usage_of(&tx, app_id)?;
+    save_policy(&tx, app_id, None, Some(days))?;
+    let after = usage_of(&tx, app_id)?;
+    query_execute!(tx => "UPDATE artifacts SET expires_at = created_at + ? WHERE app_id = ?",
+        (after.retention_days as u64 * DAY_SECS, app_id))?;
+
+    let change = Change {
+        actor_id: Some(user_id),
+        app_id: Some(app_id),
+        action: "artifact.retention",
+        target: format!("app:{app_id}"),
+        before: snapshot(&before),
+        after: snapshot(&after),
+    };
+    audit::record(&tx, change)?;
+    tx.commit()?;
+    Ok(after)
+}
+
+/// For admins, `None` if the app does not exist. Artifacts above a lowered quota are kept.
+fn set_quota(
+    con: &mut Connection,
+    app_id: i32,
+    user_id: i32,
+    bytes: Option<u64>,
+) -> SqlResult<Option<ArtifactUsage>> {
+    let tx = con.transaction()?;
+    let exists = tx
+        .query_row(
+            "SELECT id FROM apps WHERE id = ? AND deleted_at IS NULL",
+            [app_id],
+            |_| Ok(()),
+        )
+        .optional()?;
+    if exists.is_none() {
+        return Ok(None);
+    }
+    let before = usage_of(&tx, app_id)?;
+    save_policy(&tx, app_id, Some(bytes), None)?;
+    let after = usage_of(&tx, app_id)?;
+
+    let change = Change {
+        actor_id: Some(user_id),
+        app_id: Some(app_id),
+        action: "artifact.quota",
+        target: format!("app:{app_id}"),
+        before: snapshot(&before),
+        after: snapshot(&after),
+    };
+    audit::record(&tx, change)?;
+    tx.commit()?;
+    Ok(Some(after))
+}
+
+/**
+    Removes artifacts expired at `at` and all artifacts of apps deleted before
+    `app_deleted_before`, so purging those apps leaves nothing in the store.
+    Returns the content keys to remove from the store.
+*/
+fn expire(con: &mut Connection, at: u64, app_deleted_before: u64) -> SqlResult<Vec<String>> {
+    let tx = con.transaction()?;
+    let expired = query_rows!(tx => "
+        SELECT artifacts.* FROM artifacts JOIN apps ON apps.id = artifacts.app_id
+        WHERE artifacts.expires_at <= ? OR (apps.deleted_at IS NOT NULL AND apps.deleted_at < ?)",
+        [at, app_deleted_before], Artifact
+    );
+    let mut keys = Vec::new();
+    for artifact in expired {
+        share_links::delete_for_artifact(&tx, artifact.id)?;
+        query_execute!(tx => "DELETE FROM artifacts WHERE id = ?", [artifact.id])?;
+        if artifact.expires_at <= at {
+            let event = AppEvent::ArtifactRemoved {
+                artifact_id: artifact.id,
+            };
+            events::record(&tx, artifact.app_id, &event)?;
+        }
+        keys.push(artifact.sha256);
+    }
+    let unused = unused(&tx, keys)?;
+    tx.commit()?;
+    Ok(unused)
+}
@@ -0,0 +1,297 @@
+use axum_utils::copy;
+use rusqlite::{Connection, Row};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use super::events::now;
+use super::{query_execute, query_rows, Con, SqlResult};
+
+tokio::task_local! {
+    /// Id of the http request being handled, set by `routes::request_id`.
+    pub static REQUEST_ID: String;
+}
+
+/**
+    Append-only log of every change made through the api or admin cli.
+    Entries are written in the same transaction as the change,
+    rows are never updated or deleted.
+*/
+pub struct AuditLog {
+    con: Con,
+}
+
+/// What changed, `before`/`after` are json snapshots of the target.
+pub struct Change<'a> {
+    pub actor_id: Option<i32>,
+    pub app_id: Option<i32>,
+    pub action: &'a str,
+    pub target: String,
+    pub before: Option<Value>,
+    pub after: Option<Value>,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct AuditEntry {
+    pub id: i64,
+    pub actor_id: Option<i32>,
+    pub app_id: Option<i32>,
+    pub action: String,
+    pub target: String,
+    pub before: Option<Value>,
+    pub after: Option<Value>,
+    pub request_id: Option<String>,
+    pub created_at: u64,
+}
+
+impl AuditEntry {
+    pub fn from_row(row: &Row) -> SqlResult<Self> {
+        let json = |column: &str| -> SqlResult<Option<Value>> {
+            let text: Option<String> = row.get(column)?;
+            Ok(text.and_then(|text| serde_json::from_str(&text).ok()))
+        };
+
+        Ok(Self {
+            id: row.get("id")?,
+            actor_id: row.get("actor_id")?,
+            app_id: row.get("app_id")?,
+            action: row.get("action")?,
+            target: row.get("target")?,
+            before: json("before")?,
+            after: json("after")?,
+            request_id: row.get("request_id")?,
+            created_at: row.get("created_at")?,
+        })
+    }
+}
+
+#[derive(Serialize, Deserialize, Default)]
+#[serde(rename_all = "camelCase")]
+pub struct AuditFilter {
+    pub app_id: Option<i32>,
+    pub actor_id: Option<i32>,
+    pub action: Option<String>,
+    pub since: Option<u64>,
+    pub until: Option<u64>,
+    /// Entries older than this id, for paging.
+    pub before_id: Option<i64>,
+    pub limit: Option<usize>,
+}
+
+const DEFAULT_LIMIT: usize = 100;
+const MAX_LIMIT: usize = 1000;
+
+impl AuditLog {
+    pub fn new(con: &Con) -> Self {
+        Self { con: con.clone() }
+    }
+
+    copy!(create_table() -> SqlResult<usize>);
+    copy!(for_app(app_id: i32, user_id: i32, filter: AuditFilter) -> SqlResult<Vec<AuditEntry>>);
+    copy!(query(filter: AuditFilter) -> SqlResult<Vec<AuditEntry>>);
+}
+
+fn create_table(con: &Connection) -> SqlResult<usize> {
+    con.execute(
+        "CREATE TABLE IF NOT EXISTS audit_log (
+            id INTEGER PRIMARY KEY AUTOINCREMENT,
+            actor_id INTEGER,
+            app_id INTEGER,
+            action TEXT,
+            target TEXT,
+            before TEXT,
+            after TEXT,
+            request_id TEXT,
+            created_at INTEGER
+        )",
+        [],
+    )?;
+    con.execute_batch(
+        "CREATE TRIGGER IF NOT EXISTS audit_log_no_update BEFORE UPDATE ON audit_log
+        BEGIN SELECT RAISE(ABORT, 'audit log is append-only'); END;
+        CREATE TRIGGER IF NOT EXISTS audit_log_no_delete BEFORE DELETE ON audit_log
+        BEGIN SELECT RAISE(ABORT, 'audit log is append-only'); END;",
+    )?;
+    Ok(0)
+}
+
+/// Entries of one app, only author of the app can read them.
+fn for_app(
+    con: &Connection,
+    app_id: i32,
+    user_id: i32,
+    filter: AuditFilter,
+) -> SqlResult<Vec<AuditEntry>> {
+    con.prepare_cached("SELECT * FROM apps WHERE id = ? AND author_id = ?")?
+        .query_row([app_id, user_id], |_| Ok(()))?;
+    query(
+        con,
+        AuditFilter {
+            app_id: Some(app_id),
+            ..filter
+        },
+    )
+}
+
+pub fn record(con: &Connection, change: Change) -> SqlResult<i64> {
+    let json = |value: Option<Value>| value.map(|value| value.to_string());
+    let request_id = REQUEST_ID.try_with(Clone::clone).ok();
+
+    query_execute!(con => "
+        INSERT INTO audit_log(actor_id, app_id, action, target, before, after, request_id, created_at)
+        VALUES (?, ?, ?, ?, ?, ?, ?, ?)",
+        (
+            change.actor_id,
+            change.app_id,
+            change.action,
+            change.target,
+            json(change.before),
+            json(change.after),
+            request_id,
+            now(),
+        )
+    )?;
+    Ok(con.last_insert_rowid())
+}
+
+/// Json snapshot of a row for `Change::before`/`Change::after`.
+pub fn snapshot<T: Serialize>(value: &T) -> Option<Value> {
+    serde_json::to_value(value).ok()
+}
+
+/// Entries of all apps, permission is checked by the caller.
+fn query(con: &Connection, filter: AuditFilter) -> SqlResult<Vec<AuditEntry>> {
+    let limit = filter.limit.unwrap_or(DEFAULT_LIMIT).min(MAX_LIMIT);
+    let entries = query_rows!(con => "
+        SELECT * FROM audit_log
+        WHERE (?1 IS NULL OR app_id = ?1)
+            AND (?2 IS NULL OR actor_id = ?2)
+            AND (?3 IS NULL OR action = ?3)
+            AND (?4 IS NULL OR created_at >= ?4)
+            AND (?5 IS NULL OR created_at <= ?5)
+            AND (?6 IS NULL OR id < ?6)
+        ORDER BY id DESC
+        LIMIT ?7",
+        (
+            filter.app_id,
+            filter.actor_id,
+            filter.action,
+            filter.since,
+            filter.until,
+            filter.before_id,
+            limit,
+        ),
+        AuditEntry
+    );
+    Ok(entries)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::testing;
+
+    fn change(app_id: i32, actor_id: i32, action: &str) -> Change<'_> {
+        Change {
+            actor_id: Some(actor_id),
+            app_id: Some(app_id),
+            action,
+            target: format!("app:{app_id}"),
+            before: None,
+            after: Some(serde_json::json!({ "title": "new" })),
+        }
+    }
+
+    fn actions(entries: &[AuditEntry]) -> Vec<&str> {
+        entries.iter().map(|entry| entry.action.as_str()).collect()
+    }
+
+    #[test]
+    fn entries_can_not_be_changed_or_removed() {
+        let con = Connection::open_in_memory().unwrap();
+        create_table(&con).unwrap();
+        record(&con, change(1, 1, "app.update")).unwrap();
+
+        let update = con.execute("UPDATE audit_log SET action = 'x'", []);
+        assert!(update.is_err());
+        assert!(con.execute("DELETE FROM audit_log", []).is_err());
+        let entries = query(&con, AuditFilter::default()).unwrap();
+        assert_eq!(actions(&entries), ["app.update"]);
+    }
+
+    #[tokio::test]
+    async fn request_id_of_the_request_is_recorded() {
+        let con = Connection::open_in_memory().unwrap();
+        create_table(&con).unwrap();
+        REQUEST_ID
+            .scope("req-1".to_string(), async {
+                record(&con, change(1, 1, "app.update")).unwrap();
+            })
+            .await;
+        record(&con, change(1, 1, "app.delete")).unwrap();
+
+        let entries = query(&con, AuditFilter::default()).unwrap();
+        assert_eq!(entries[0].request_id, None);
+        assert_eq!(entries[1].request_id.as_deref(), Some("req-1"));
+        assert_eq!(entries[1].after.as_ref().unwrap()["title"], "new");
+    }
+
+    #[test]
+    fn query_filters_newest_first_and_pages_by_id() {
+        let con = Connection::open_in_memory().unwrap();
+        create_table(&con).unwrap();
+        record(&con, change(1, 1, "app.create")).unwrap();
+        record(&con, change(2, 1, "app.create")).unwrap();
+        record(&con, change(1, 2, "operator.add")).unwrap();
+        record(&con, change(1, 1, "app.update")).unwrap();
+
+        let of_app = |filter: AuditFilter| {
+            let filter = AuditFilter {
+                app_id: Some(1),
+                ..filter
+            };
+            query(&con, filter).unwrap()
+        };
+        let all = of_app(AuditFilter::default());
+        assert_eq!(actions(&all), ["app.update", "operator.add", "app.create"]);
+
+        let by_actor = of_app(AuditFilter {
+            actor_id: Some(2),
+            ..Default::default()
+        });
+        assert_eq!(actions(&by_actor), ["operator.add"]);
+        let by_action = of_app(AuditFilter {
+            action: Some("app.create".to_string()),
+            ..Default::default()
+        });
+        assert_eq!(by_action[0].app_id, Some(1));
+
+        let page = of_app(AuditFilter {
+            limit: Some(1),
+            ..Default::default()
+        });
+        let next = of_app(AuditFilter {
+            before_id: Some(page[0].id),
+            limit: Some(1),
+            ..Default::default()
+        });
+        assert_eq!(actions(&next), ["operator.add"]);
+        let future = of_app(AuditFilter {
+            since: Some(now() + 60),
+            ..Default::default()
+        });
+        assert!(future.is_empty());
+    }
+
+    #[test]
+    fn only_the_author_reads_the_log_of_an_app() {
+        let db = testing::db();
+        let (author, other) = (testing::user(&db, "alice"), testing::user(&db, "bob"));
+        let app_id = testing::app(&db, author);
+
+        let entries = db.audit.for_app(app_id, author, AuditFilter::default());
+        assert!(entries.is_ok());
+        let denied = db.audit.for_app(app_id, other, AuditFilter::default());
+        assert!(matches!(denied, Err(rusqlite::Error::QueryReturnedNoRows)));
+    }
+}
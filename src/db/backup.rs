@@ -0,0 +1,147 @@
+use std::{
+    cmp::Reverse,
+    fs, io,
+    path::{Path, PathBuf},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use rusqlite::{backup::Progress, Connection, DatabaseName};
+use serde::{Deserialize, Serialize};
+
+use super::{Con, SqlResult};
+
+/**
+    Point-in-time copies of the live database, made with sqlite backup api,
+    so they are consistent even while the server keeps writing.
+    Only the newest `keep` snapshots are kept.
+*/
+pub struct Snapshots {
+    con: Con,
+    dir: PathBuf,
+    keep: usize,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Snapshot {
+    pub name: String,
+    pub created_at: u64,
+    pub size: u64,
+}
+
+const PREFIX: &str = "sqlite-";
+const EXTENSION: &str = ".db";
+
+#[derive(Debug)]
+pub enum SnapshotError {
+    NotFound,
+    Io(io::Error),
+    SqliteError(rusqlite::Error),
+}
+
+impl From<io::Error> for SnapshotError {
+    fn from(value: io::Error) -> Self {
+        SnapshotError::Io(value)
+    }
+}
+
+impl From<rusqlite::Error> for SnapshotError {
+    fn from(value: rusqlite::Error) -> Self {
+        SnapshotError::SqliteError(value)
+    }
+}
+
+impl Snapshots {
+    pub fn new(con: &Con, dir: impl Into<PathBuf>, keep: usize) -> Self {
+        Self {
+            con: con.clone(),
+            dir: dir.into(),
+            keep,
+        }
+    }
+
+    /// Takes a new snapshot and drops the ones past retention.
+    pub fn create(&self) -> Result<Snapshot, SnapshotError> {
+        fs::create_dir_all(&self.dir)?;
+        let created_at = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_millis() as u64;
+        let name = format!("{PREFIX}{created_at}{EXTENSION}");
+        let path = self.dir.join(&name);
+
+        {
+            let con = self.con.lock().unwrap();
+            backup(&con, &path)?;
+        }
+        self.prune()?;
+
+        Ok(Snapshot {
+            size: fs::metadata(&path)?.len(),
+            name,
+            created_at,
+        })
+    }
+
+    /// Newest first.
+    pub fn list(&self) -> Result<Vec<Snapshot>, SnapshotError> {
+        if !self.dir.exists() {
+            return Ok(vec![]);
+        }
+
+        let mut snapshots = vec![];
+        for entry in fs::read_dir(&self.dir)? {
+            let entry = entry?;
+            let name = entry.file_name().to_string_lossy().to_string();
+            if let Some(created_at) = parse_name(&name) {
+                snapshots.push(Snapshot {
+                    size: entry.metadata()?.len(),
+                    name,
+                    created_at,
+                });
+            }
+        }
+        snapshots.sort_by_key(|snapshot| Reverse(snapshot.created_at));
+        Ok(snapshots)
+    }
+
+    /// Path of existing snapshot, names that are not ours (`../sqlite.db`) are rejected.
+    pub fn path(&self, name: &str) -> Option<PathBuf> {
+        parse_name(name)?;
+        let path = self.dir.join(name);
+        path.exists().then_some(path)
+    }
+
+    fn prune(&self) -> Result<(), SnapshotError> {
+        for old in self.list()?.into_iter().skip(self.keep) {
+            fs::remove_file(self.dir.join(old.name))?;
+        }
+        Ok(())
+    }
+}
+
+fn parse_name(name: &str) -> Option<u64> {
+    name.strip_prefix(PREFIX)?
+        .strip_suffix(EXTENSION)?
+        .parse()
+        .ok()
+}
+
+/// Online copy of the live database into `path`.
+pub fn backup(con: &Connection, path: &Path) -> SqlResult<()> {
+    con.backup(DatabaseName::Main, path, None)
+}
+
+/**
+    Replaces live database with the contents of `path`.
+    Schema of the backup may be older, see `SqliteDb::restore_from`.
+*/
+pub fn restore(con: &mut Connection, path: &Path) -> Result<(), SnapshotError> {
+    if !path.exists() {
+        return Err(SnapshotError::NotFound);
+    }
+
+    con.restore(DatabaseName::Main, path, None::<fn(Progress)>)?;
+    con.flush_prepared_statement_cache();
+    Ok(())
+}
@@ -0,0 +1,622 @@
+use std::collections::BTreeMap;
+
+use axum_utils::{copy, copy_mut};
+use rusqlite::{Connection, OptionalExtension, Row};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+
+use super::apps::has_permission;
+use super::audit::{self, snapshot, Change};
+use super::events::{self, now, AppEvent};
+use super::{query_execute, query_row, query_rows, Con, SqlResult};
+use crate::crypto::{decrypt_secret, encrypt_secret};
+use crate::validation::ValidationErrors;
+
+/// Shown instead of secret values, they only ever leave the server towards brokers.
+pub const SECRET_MASK: &str = "********";
+const MAX_CONCURRENCY: u32 = 256;
+
+/**
+    Versioned settings documents for brokers. Every app has defaults,
+    every pool and broker can override them, brokers get all of them merged, see `effective`.
+    Saving always adds a new version, older ones stay for reference.
+*/
+pub struct BrokerConfigs {
+    con: Con,
+}
+
+/// Secret values are encrypted while stored and masked in every response.
+#[derive(Serialize, Deserialize, Clone, Default, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct BrokerSettings {
+    /// Renders a broker runs at the same time.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub concurrency: Option<u32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub output_formats: Option<Vec<String>>,
+    #[serde(default)]
+    pub env: BTreeMap<String, String>,
+    #[serde(default)]
+    pub secrets: BTreeMap<String, String>,
+}
+
+impl BrokerSettings {
+    fn masked(mut self) -> Self {
+        for value in self.secrets.values_mut() {
+            *value = SECRET_MASK.to_string();
+        }
+        self
+    }
+
+    /// `overrides` wins for every setting it has, maps are merged by key.
+    fn merge(mut self, overrides: BrokerSettings) -> Self {
+        self.concurrency = overrides.concurrency.or(self.concurrency);
+        self.output_formats = overrides.output_formats.or(self.output_formats);
+        self.env.extend(overrides.env);
+        self.secrets.extend(overrides.secrets);
+        self
+    }
+}
+
+/// Body of a save, a secret set to `null` keeps its current value.
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct NewBrokerSettings {
+    pub concurrency: Option<u32>,
+    pub output_formats: Option<Vec<String>>,
+    #[serde(default)]
+    pub env: BTreeMap<String, String>,
+    #[serde(default)]
+    pub secrets: BTreeMap<String, Option<String>>,
+}
+
+impl NewBrokerSettings {
+    /// Every problem is reported, names have to work as environment variables.
+    pub fn validate(self) -> Result<Self, ValidationErrors> {
+        let mut errors = ValidationErrors::default();
+        if self
+            .concurrency
+            .is_some_and(|concurrency| !(1..=MAX_CONCURRENCY).contains(&concurrency))
+        {
+            let message = format!("concurrency must be between 1 and {MAX_CONCURRENCY}");
+            errors.add("concurrency", "outOfRange", &message);
+        }
+        if let Some(formats) = &self.output_formats {
+            if formats.iter().any(|format| format.trim().is_empty()) {
+                errors.add(
+                    "outputFormats",
+                    "required",
+                    "output formats must not be empty",
+                );
+            }
+        }
+        for (field, names) in [
+            ("env", self.env.keys().collect::<Vec<_>>()),
+            ("secrets", self.secrets.keys().collect()),
+        ] {
+            if names.iter().any(|name| !is_variable_name(name)) {
+                let message = format!("{field} names must be letters, digits and underscores");
+                errors.add(field, "invalidName", &message);
+            }
+        }
+        if self.env.keys().any(|name| self.secrets.contains_key(name)) {
+            errors.add(
+                "secrets",
+                "duplicate",
+                "a name can't be both env and secret",
+            );
+        }
+        errors.into_result(self)
+    }
+}
+
+fn is_variable_name(name: &str) -> bool {
+    let mut chars = name.chars();
+    chars
+        .next()
+        .is_some_and(|c| c.is_ascii_alphabetic() || c == '_')
+        && chars.all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+/// Whose settings a document holds, the later ones override the earlier ones.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum ConfigScope {
+    App,
+    Pool(i32),
+    Broker(i32),
+}
+
+impl ConfigScope {
+    fn broker_id(self) -> Option<i32> {
+        match self {
+            ConfigScope::Broker(broker_id) => Some(broker_id),
+            _ => None,
+        }
+    }
+
+    fn pool_id(self) -> Option<i32> {
+        match self {
+            ConfigScope::Pool(pool_id) => Some(pool_id),
+            _ => None,
+        }
+    }
+
+    fn target(self, app_id: i32) -> String {
+        match self {
+            ConfigScope::App => format!("app:{app_id}"),
+            ConfigScope::Pool(pool_id) => format!("pool:{pool_id}"),
+            ConfigScope::Broker(broker_id) => format!("broker:{broker_id}"),
+        }
+    }
+}
+
+/// A saved version, `broker_id` and `pool_id` are `None` for the app defaults.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct ConfigVersion {
+    pub app_id: i32,
+    pub broker_id: Option<i32>,
+    #[serde(default)]
+    pub pool_id: Option<i32>,
+    pub version: i64,
+    pub settings: BrokerSettings,
+    pub created_by: Option<i32>,
+    pub created_at: u64,
+}
+
+impl ConfigVersion {
+    pub fn from_row(row: &Row) -> SqlResult<Self> {
+        let settings: String = row.get("settings")?;
+        let settings = serde_json::from_str(&settings).map_err(|e| {
+            rusqlite::Error::FromSqlConversionFailure(0, rusqlite::types::Type::Text, Box::new(e))
+        })?;
+        Ok(Self {
+            app_id: row.get("app_id")?,
+            broker_id: row.get("broker_id")?,
+            pool_id: row.get("pool_id")?,
+            version: row.get("version")?,
+            settings,
+            created_by: row.get("created_by")?,
+            created_at: row.get("created_at")?,
+        })
+    }
+
+    fn masked(self) -> Self {
+        Self {
+            settings: self.settings.masked(),
+            ..self
+        }
+    }
+}
+
+/// What a broker gets, secrets in plain text. Versions are 0 while nothing was saved.
+#[derive(Serialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct EffectiveConfig {
+    pub app_version: i64,
+    /// Pool the broker is in, see `broker_pools`.
+    pub pool_id: Option<i32>,
+    pub pool_version: i64,
+    pub broker_version: i64,
+    pub settings: BrokerSettings,
+}
+
+impl BrokerConfigs {
+    pub fn new(con: &Con) -> Self {
+        Self { con: con.clone() }
+    }
+
+    copy!(create_table() -> SqlResult<usize>);
+    copy!(get(app_id: i32, user_id: i32, scope: ConfigScope) -> SqlResult<Option<ConfigVersion>>);
+    copy!(versions(app_id: i32, user_id: i32, scope: ConfigScope) -> SqlResult<Vec<ConfigVersion>>);
+    copy_mut!(save(app_id: i32, user_id: i32, scope: ConfigScope, settings: NewBrokerSettings) -> SqlResult<Option<ConfigVersion>>);
+    copy!(effective(app_id: i32, broker_id: i32) -> SqlResult<EffectiveConfig>);
+}
+
+fn create_table(con: &Connection) -> SqlResult<usize> {
+    con.execute(
+        "CREATE TABLE IF NOT EXISTS broker_configs (
+            id INTEGER PRIMARY KEY,
+            app_id INTEGER,
+            broker_id INTEGER,
+            version INTEGER,
+            settings TEXT,
+            created_by INTEGER,
+            created_at INTEGER,
+            UNIQUE(app_id, broker_id, version),
+            FOREIGN KEY(app_id) REFERENCES apps(id),
+            FOREIGN KEY(broker_id) REFERENCES brokers(id)
+        )",
+        [],
+    )
+}
+
+/// Newest version, no permission checks, secrets stay encrypted.
+fn current(con: &Connection, app_id: i32, scope: ConfigScope) -> SqlResult<Option<ConfigVersion>> {
+    query_row!(con => "
+        SELECT * FROM broker_configs WHERE app_id = ? AND broker_id IS ? AND pool_id IS ?
+        ORDER BY version DESC LIMIT 1",
+        (app_id, scope.broker_id(), scope.pool_id()), ConfigVersion
+    )
+    .optional()
+}
+
+/// Broker or pool must belong to the app, the app itself is always fine.
+fn scope_exists(con: &Connection, app_id: i32, scope: ConfigScope) -> SqlResult<bool> {
+    let sql = match scope {
+        ConfigScope::App => return Ok(true),
+        ConfigScope::Pool(_) => "SELECT 1 FROM broker_pools WHERE id = ? AND app_id = ?",
+        ConfigScope::Broker(_) => {
+            "SELECT 1 FROM brokers WHERE id = ? AND app_id = ? AND deleted_at IS NULL"
+        }
+    };
+    let id = scope.broker_id().or(scope.pool_id());
+    con.prepare_cached(sql)?.exists((id, app_id))
+}
+
+/// `None` if nothing was saved yet.
+fn get(
+    con: &Connection,
+    app_id: i32,
+    user_id: i32,
+    scope: ConfigScope,
+) -> SqlResult<Option<ConfigVersion>> {
+    has_permission(con, app_id, user_id)?;
+    if !scope_exists(con, app_id, scope)? {
+        return Err(rusqlite::Error::QueryReturnedNoRows);
+    }
+    Ok(current(con, app_id, scope)?.map(ConfigVersion::masked))
+}
+
+fn versions(
+    con: &Connection,
+    app_id: i32,
+    user_id: i32,
+    scope: ConfigScope,
+) -> SqlResult<Vec<ConfigVersion>> {
+    has_permission(con, app_id, user_id)?;
+    if !scope_exists(con, app_id, scope)? {
+        return Err(rusqlite::Error::QueryReturnedNoRows);
+    }
+    let versions = query_rows!(con => "
+        SELECT * FROM broker_configs WHERE app_id = ? AND broker_id IS ? AND pool_id IS ?
+        ORDER BY version DESC",
+        (app_id, scope.broker_id(), scope.pool_id()), ConfigVersion
+    );
+    Ok(versions.into_iter().map(ConfigVersion::masked).collect())
+}
+
+/// Stores the next version, `None` if the broker or pool does not exist in the app.
+fn save(
+    con: &mut Connection,
+    app_id: i32,
+    user_id: i32,
+    scope: ConfigScope,
+    settings: NewBrokerSettings,
+) -> SqlResult<Option<ConfigVersion>> {
+    let tx = con.transaction()?;
+    has_permission(&tx, app_id, user_id)?;
+    if !scope_exists(&tx, app_id, scope)? {
+        return Ok(None);
+    }
+    let before = current(&tx, app_id, scope)?;
+    let kept = before
+        .as_ref()
+        .map(|before| before.settings.secrets.clone())
+        .unwrap_or_default();
+
+    let mut secrets = BTreeMap::new();
+    for (name, value) in settings.secrets {
+        let sealed = match value {
+            Some(value) => encrypt_secret(&value),
+            None => match kept.get(&name) {
+                Some(sealed) => sealed.clone(),
+                None => continue,
+            },
+        };
+        secrets.insert(name, sealed);
+    }
+    let stored = BrokerSettings {
+        concurrency: settings.concurrency,
+        output_formats: settings.output_formats,
+        env: settings.env,
+        secrets,
+    };
+    let version = before.as_ref().map_or(0, |before| before.version) + 1;
+    query_execute!(tx => "
+        INSERT INTO broker_configs(app_id, broker_id, pool_id, version, settings, created_by, created_at) VALUES (?, ?, ?, ?, ?, ?, ?)",
+        (app_id, scope.broker_id(), scope.pool_id(), version, serde_json::to_string(&stored).unwrap(), user_id, now())
+    )?;
+    let after = current(&tx, app_id, scope)?.unwrap().masked();
+
+    let event = AppEvent::BrokerConfigChanged {
+        broker_id: scope.broker_id(),
+        pool_id: scope.pool_id(),
+        version,
+    };
+    events::record(&tx, app_id, &event)?;
+    let change = Change {
+        actor_id: Some(user_id),
+        app_id: Some(app_id),
+        action: "brokerConfig.update",
+        target: scope.target(app_id),
+        before: before
+            .map(ConfigVersion::masked)
+            .as_ref()
+            .and_then(snapshot),
+        after: snapshot(&json!({ "version": version, "settings": after.settings })),
+    };
+    audit::record(&tx, change)?;
+    tx.commit()?;
+    Ok(Some(after))
+}
+
+/// Pool of the broker, no permission checks.
+fn pool_of(con: &Connection, app_id: i32, broker_id: i32) -> SqlResult<Option<i32>> {
+    con.prepare_cached("SELECT pool_id FROM brokers WHERE id = ? AND app_id = ?")?
+        .query_row([broker_id, app_id], |row| row.get(0))
+        .optional()
+        .map(Option::flatten)
+}
+
+/// Documents that apply to the broker, app defaults first.
+fn layers(con: &Connection, app_id: i32, broker_id: i32) -> SqlResult<[Option<ConfigVersion>; 3]> {
+    let pool = match pool_of(con, app_id, broker_id)? {
+        Some(pool_id) => current(con, app_id, ConfigScope::Pool(pool_id))?,
+        None => None,
+    };
+    Ok([
+        current(con, app_id, ConfigScope::App)?,
+        pool,
+        current(con, app_id, ConfigScope::Broker(broker_id))?,
+    ])
+}
+
+/**
+    App defaults with the pool and broker overrides on top, secrets decrypted.
+    Secrets that can't be decrypted, because the secrets key changed, are left out.
+    No permission checks, only meant for delivery to the broker.
+*/
+fn effective(con: &Connection, app_id: i32, broker_id: i32) -> SqlResult<EffectiveConfig> {
+    let pool_id = pool_of(con, app_id, broker_id)?;
+    let [defaults, pool, overrides] = layers(con, app_id, broker_id)?;
+    let version =
+        |config: &Option<ConfigVersion>| config.as_ref().map_or(0, |config| config.version);
+    let app_version = version(&defaults);
+    let pool_version = version(&pool);
+    let broker_version = version(&overrides);
+
+    let settings =
+        |config: Option<ConfigVersion>| config.map(|config| config.settings).unwrap_or_default();
+    let mut settings = settings(defaults)
+        .merge(settings(pool))
+        .merge(settings(overrides));
+    settings.secrets = settings
+        .secrets
+        .into_iter()
+        .filter_map(|(name, sealed)| Some((name, decrypt_secret(&sealed)?)))
+        .collect();
+    Ok(EffectiveConfig {
+        app_version,
+        pool_id,
+        pool_version,
+        broker_version,
+        settings,
+    })
+}
+
+/// Effective `concurrency` of a broker without decrypting anything, used by job dispatch.
+pub(super) fn concurrency(con: &Connection, app_id: i32, broker_id: i32) -> SqlResult<Option<u32>> {
+    let layers = layers(con, app_id, broker_id)?;
+    Ok(layers
+        .into_iter()
+        .rev()
+        .find_map(|config| config.and_then(|config| config.settings.concurrency)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::{broker_pools::NewBrokerPool, testing, SqliteDb};
+
+    fn settings(
+        concurrency: Option<u32>,
+        formats: Option<&[&str]>,
+        env: &[(&str, &str)],
+        secrets: &[(&str, Option<&str>)],
+    ) -> NewBrokerSettings {
+        let pairs = |pairs: &[(&str, &str)]| {
+            pairs
+                .iter()
+                .map(|(name, value)| (name.to_string(), value.to_string()))
+                .collect()
+        };
+        NewBrokerSettings {
+            concurrency,
+            output_formats: formats.map(|formats| formats.iter().map(|f| f.to_string()).collect()),
+            env: pairs(env),
+            secrets: secrets
+                .iter()
+                .map(|(name, value)| (name.to_string(), value.map(str::to_string)))
+                .collect(),
+        }
+    }
+
+    fn env(settings: &BrokerSettings) -> Vec<(&str, &str)> {
+        settings
+            .env
+            .iter()
+            .map(|(name, value)| (name.as_str(), value.as_str()))
+            .collect()
+    }
+
+    /// App with a pool and two brokers, only the first one is in the pool.
+    fn setup() -> (SqliteDb, i32, i32, i32, i32, i32) {
+        let db = testing::db();
+        let author = testing::user(&db, "alice");
+        let app_id = testing::app(&db, author);
+        let new_pool = NewBrokerPool {
+            name: "eu".to_string(),
+            description: String::new(),
+        };
+        let pool_id = db.broker_pools.create(app_id, author, new_pool).unwrap().id;
+        let (pooled, single) = (testing::broker(&db, app_id), testing::broker(&db, app_id));
+        db.broker_pools
+            .set_pool(app_id, author, pooled, Some(pool_id))
+            .unwrap();
+        (db, author, app_id, pool_id, pooled, single)
+    }
+
+    #[test]
+    fn overrides_win_and_maps_merge_by_key() {
+        let base = BrokerSettings {
+            concurrency: Some(2),
+            output_formats: Some(vec!["pdf".to_string()]),
+            env: [("A", "1"), ("B", "1")]
+                .into_iter()
+                .map(|(name, value)| (name.to_string(), value.to_string()))
+                .collect(),
+            secrets: BTreeMap::new(),
+        };
+        let overrides = BrokerSettings {
+            env: [("B".to_string(), "2".to_string())].into(),
+            ..Default::default()
+        };
+
+        let merged = base.merge(overrides);
+        assert_eq!(merged.concurrency, Some(2));
+        assert_eq!(merged.output_formats, Some(vec!["pdf".to_string()]));
+        assert_eq!(env(&merged), [("A", "1"), ("B", "2")]);
+
+        let overrides = BrokerSettings {
+            concurrency: Some(8),
+            output_formats: Some(Vec::new()),
+            ..Default::default()
+        };
+        let merged = merged.merge(overrides);
+        assert_eq!(merged.concurrency, Some(8));
+        assert_eq!(merged.output_formats, Some(Vec::new()));
+    }
+
+    #[test]
+    fn pool_settings_sit_between_app_and_broker() {
+        let (db, author, app_id, pool_id, pooled, single) = setup();
+        let save = |scope, settings| {
+            db.broker_configs
+                .save(app_id, author, scope, settings)
+                .unwrap()
+                .unwrap()
+        };
+        let defaults = settings(
+            Some(1),
+            Some(&["pdf"]),
+            &[("REGION", "any"), ("LOG", "info")],
+            &[("TOKEN", Some("app"))],
+        );
+        save(ConfigScope::App, defaults);
+        let pool = settings(
+            Some(4),
+            Some(&["pdf", "xlsx"]),
+            &[("REGION", "eu")],
+            &[("DB", Some("pool"))],
+        );
+        save(ConfigScope::Pool(pool_id), pool);
+        let broker = settings(Some(2), None, &[("LOG", "debug")], &[]);
+        save(ConfigScope::Broker(pooled), broker);
+
+        let config = db.broker_configs.effective(app_id, pooled).unwrap();
+        assert_eq!(
+            (
+                config.app_version,
+                config.pool_id,
+                config.pool_version,
+                config.broker_version
+            ),
+            (1, Some(pool_id), 1, 1)
+        );
+        assert_eq!(config.settings.concurrency, Some(2));
+        assert_eq!(
+            config.settings.output_formats,
+            Some(vec!["pdf".to_string(), "xlsx".to_string()])
+        );
+        assert_eq!(env(&config.settings), [("LOG", "debug"), ("REGION", "eu")]);
+        assert_eq!(config.settings.secrets["TOKEN"], "app");
+        assert_eq!(config.settings.secrets["DB"], "pool");
+
+        // brokers outside the pool only get the app defaults
+        let config = db.broker_configs.effective(app_id, single).unwrap();
+        assert_eq!((config.pool_id, config.pool_version), (None, 0));
+        assert_eq!(config.settings.concurrency, Some(1));
+        assert_eq!(env(&config.settings), [("LOG", "info"), ("REGION", "any")]);
+
+        let con = db.con.lock().unwrap();
+        assert_eq!(concurrency(&con, app_id, pooled).unwrap(), Some(2));
+        assert_eq!(concurrency(&con, app_id, single).unwrap(), Some(1));
+    }
+
+    #[test]
+    fn leaving_the_pool_drops_its_settings() {
+        let (db, author, app_id, pool_id, pooled, _) = setup();
+        let pool = settings(Some(4), None, &[("REGION", "eu")], &[]);
+        let scope = ConfigScope::Pool(pool_id);
+        db.broker_configs.save(app_id, author, scope, pool).unwrap();
+        assert_eq!(
+            db.broker_configs
+                .effective(app_id, pooled)
+                .unwrap()
+                .pool_version,
+            1
+        );
+
+        db.broker_pools
+            .set_pool(app_id, author, pooled, None)
+            .unwrap();
+        let config = db.broker_configs.effective(app_id, pooled).unwrap();
+        assert_eq!((config.pool_id, config.pool_version), (None, 0));
+        assert_eq!(config.settings.concurrency, None);
+        assert!(config.settings.env.is_empty());
+    }
+
+    #[test]
+    fn pool_versions_keep_unchanged_secrets_and_mask_them() {
+        let (db, author, app_id, pool_id, pooled, _) = setup();
+        let scope = ConfigScope::Pool(pool_id);
+        let first = settings(None, None, &[], &[("DB", Some("one")), ("OLD", Some("x"))]);
+        db.broker_configs
+            .save(app_id, author, scope, first)
+            .unwrap();
+        // `null` keeps the stored value, leaving a secret out removes it
+        let second = settings(None, None, &[], &[("DB", None), ("NEW", None)]);
+        let saved = db
+            .broker_configs
+            .save(app_id, author, scope, second)
+            .unwrap()
+            .unwrap();
+        assert_eq!(saved.version, 2);
+        assert_eq!(saved.pool_id, Some(pool_id));
+        assert_eq!(saved.settings.secrets.len(), 1);
+        assert_eq!(saved.settings.secrets["DB"], SECRET_MASK);
+
+        let config = db.broker_configs.effective(app_id, pooled).unwrap();
+        assert_eq!(config.pool_version, 2);
+        assert_eq!(config.settings.secrets.len(), 1);
+        assert_eq!(config.settings.secrets["DB"], "one");
+
+        let versions = db.broker_configs.versions(app_id, author, scope).unwrap();
+        assert_eq!(versions.len(), 2);
+        assert_eq!(versions[1].settings.secrets["OLD"], SECRET_MASK);
+    }
+
+    #[test]
+    fn pools_of_other_apps_are_not_configured() {
+        let (db, author, _, pool_id, _, _) = setup();
+        let other_app = testing::app(&db, author);
+        let scope = ConfigScope::Pool(pool_id);
+        let saved = db
+            .broker_configs
+            .save(other_app, author, scope, settings(Some(2), None, &[], &[]))
+            .unwrap();
+        assert!(saved.is_none());
+        assert!(db.broker_configs.get(other_app, author, scope).is_err());
+    }
+}
@@ -0,0 +1,450 @@
+use rusqlite::{Connection, Row};
+use serde::{Deserialize, Serialize};
+use tokio::sync::watch;
+
+use super::apps::has_permission;
+use super::events::now;
+use super::{query_execute, query_rows, Con, SqlResult};
+
+/// Logs older than this are pruned, see `BrokerLogs::prune`.
+pub const LOG_RETENTION_SECS: u64 = 7 * 24 * 60 * 60;
+/// Only the newest lines of every broker are kept.
+pub const LOG_LINES_KEPT: usize = 10_000;
+/// Longer messages are cut, a broker must not fill the database with one line.
+const MAX_MESSAGE_LENGTH: usize = 8 * 1024;
+const DEFAULT_LIMIT: usize = 100;
+const MAX_LIMIT: usize = 1000;
+
+/**
+    Log lines brokers send over their connection, so failed renders
+    can be looked into without access to the broker host.
+*/
+pub struct BrokerLogs {
+    con: Con,
+    changes: watch::Sender<()>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Debug)]
+#[serde(rename_all = "lowercase")]
+pub enum LogLevel {
+    Debug = 0,
+    Info = 1,
+    Warn = 2,
+    Error = 3,
+}
+
+impl From<usize> for LogLevel {
+    fn from(level: usize) -> Self {
+        match level {
+            0 => LogLevel::Debug,
+            1 => LogLevel::Info,
+            2 => LogLevel::Warn,
+            _ => LogLevel::Error,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct LogLine {
+    pub id: i64,
+    pub broker_id: i32,
+    pub level: LogLevel,
+    pub message: String,
+    pub created_at: u64,
+}
+
+impl LogLine {
+    pub fn from_row(row: &Row) -> SqlResult<Self> {
+        Ok(Self {
+            id: row.get("id")?,
+            broker_id: row.get("broker_id")?,
+            level: usize::into(row.get("level")?),
+            message: row.get("message")?,
+            created_at: row.get("created_at")?,
+        })
+    }
+}
+
+/// Line as sent by the broker, `timestamp` defaults to when it arrived.
+#[derive(Deserialize)]
+pub struct NewLogLine {
+    pub level: LogLevel,
+    pub message: String,
+    pub timestamp: Option<u64>,
+}
+
+/// Filters of the log query, newest lines come first.
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct LogQuery {
+    /// Lines of this level and above.
+    pub level: Option<LogLevel>,
+    pub since: Option<u64>,
+    pub until: Option<u64>,
+    /// For paging, lines older than this one.
+    pub before_id: Option<i64>,
+    pub limit: Option<usize>,
+}
+
+impl BrokerLogs {
+    pub fn new(con: &Con) -> Self {
+        Self {
+            con: con.clone(),
+            changes: watch::Sender::new(()),
+        }
+    }
+
+    pub fn create_table(&self) -> SqlResult<usize> {
+        let con = self.con.lock().unwrap();
+        con.execute(
+            "CREATE TABLE IF NOT EXISTS broker_logs (
+                id INTEGER PRIMARY KEY AUTOINCREMENT,
+                broker_id INTEGER,
+                level INTEGER,
+                message TEXT,
+                created_at INTEGER,
+                FOREIGN KEY(broker_id) REFERENCES brokers(id)
+            )",
+            [],
+        )?;
+        con.execute(
+            "CREATE INDEX IF NOT EXISTS broker_logs_broker ON broker_logs(broker_id, id)",
+            [],
+        )
+    }
+
+    /// Wakes up tails, called by `append`.
+    fn notify(&self) {
+        self.changes.send_replace(());
+    }
+
+    pub fn subscribe(&self) -> watch::Receiver<()> {
+        self.changes.subscribe()
+    }
+
+    /// Stores lines of a connected broker, permission is checked when broker connects.
+    pub fn append(&self, broker_id: i32, lines: &[NewLogLine]) -> SqlResult<usize> {
+        let result = {
+            let mut con = self.con.lock().unwrap();
+            append(&mut con, broker_id, lines)?
+        };
+        self.notify();
+        Ok(result)
+    }
+
+    pub fn for_broker(
+        &self,
+        app_id: i32,
+        user_id: i32,
+        broker_id: i32,
+        query: &LogQuery,
+    ) -> SqlResult<Vec<LogLine>> {
+        let con = self.con.lock().unwrap();
+        for_broker(&con, app_id, user_id, broker_id, query)
+    }
+
+    /// Lines after `last_id` in the order they were written, no permission checks.
+    pub fn since(&self, broker_id: i32, last_id: i64, level: LogLevel) -> SqlResult<Vec<LogLine>> {
+        let con = self.con.lock().unwrap();
+        Ok(query_rows!(con => "
+            SELECT * FROM broker_logs WHERE broker_id = ? AND id > ? AND level >= ?
+            ORDER BY id LIMIT ?",
+            (broker_id, last_id, level as usize, MAX_LIMIT), LogLine))
+    }
+
+    /// Id of the newest line, tails start from here.
+    pub fn last_id(&self) -> SqlResult<i64> {
+        let con = self.con.lock().unwrap();
+        con.query_row("SELECT COALESCE(MAX(id), 0) FROM broker_logs", [], |row| {
+            row.get(0)
+        })
+    }
+
+    /// Removes lines written before `older_than`.
+    pub fn prune(&self, older_than: u64) -> SqlResult<usize> {
+        let con = self.con.lock().unwrap();
+        query_execute!(con => "DELETE FROM broker_logs WHERE created_at < ?", [older_than])
+    }
+}
+
+fn append(con: &mut Connection, broker_id: i32, lines: &[NewLogLine]) -> SqlResult<usize> {
+    let tx = con.transaction()?;
+    let now = now();
+    for line in lines {
+        let message: String = line.message.chars().take(MAX_MESSAGE_LENGTH).collect();
+        // clocks of broker hosts can't be trusted to be in the past
+        let created_at = line.timestamp.filter(|at| *at <= now).unwrap_or(now);
+        query_execute!(tx => "INSERT INTO broker_logs(broker_id, level, message, created_at) VALUES (?, ?, ?, ?)",
+            (broker_id, line.level as usize, message, created_at))?;
+    }
+    query_execute!(tx => "
+        DELETE FROM broker_logs WHERE broker_id = ?1 AND id <= (
+            SELECT id FROM broker_logs WHERE broker_id = ?1 ORDER BY id DESC LIMIT 1 OFFSET ?2
+        )",
+        (broker_id, LOG_LINES_KEPT)
+    )?;
+    tx.commit()?;
+    Ok(lines.len())
+}
+
+fn for_broker(
+    con: &Connection,
+    app_id: i32,
+    user_id: i32,
+    broker_id: i32,
+    query: &LogQuery,
+) -> SqlResult<Vec<LogLine>> {
+    has_permission(con, app_id, user_id)?;
+    let level = query.level.unwrap_or(LogLevel::Debug) as usize;
+    let limit = query.limit.unwrap_or(DEFAULT_LIMIT).clamp(1, MAX_LIMIT);
+    Ok(query_rows!(con => "
+        SELECT broker_logs.* FROM broker_logs JOIN brokers ON brokers.id = broker_logs.broker_id
+        WHERE brokers.id = ? AND brokers.app_id = ? AND brokers.deleted_at IS NULL
+            AND level >= ? AND broker_logs.created_at >= ? AND broker_logs.created_at <= ?
+            AND broker_logs.id < ?
+        ORDER BY broker_logs.id DESC LIMIT ?",
+        (
+            broker_id,
+            app_id,
+            level,
+            query.since.unwrap_or(0),
+            query.until.unwrap_or(i64::MAX as u64),
+            query.before_id.unwrap_or(i64::MAX),
+            limit
+        ),
+        LogLine
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::{testing, SqliteDb};
+
+    fn line(level: LogLevel, message: &str, timestamp: Option<u64>) -> NewLogLine {
+        NewLogLine {
+            level,
+            message: message.to_string(),
+            timestamp,
+        }
+    }
+
+    fn query(level: Option<LogLevel>, before_id: Option<i64>, limit: Option<usize>) -> LogQuery {
+        LogQuery {
+            level,
+            since: None,
+            until: None,
+            before_id,
+            limit,
+        }
+    }
+
+    fn messages(lines: &[LogLine]) -> Vec<&str> {
+        lines.iter().map(|line| line.message.as_str()).collect()
+    }
+
+    fn setup() -> (SqliteDb, i32, i32, i32) {
+        let db = testing::db();
+        let author = testing::user(&db, "alice");
+        let app_id = testing::app(&db, author);
+        let broker_id = testing::broker(&db, app_id);
+        (db, author, app_id, broker_id)
+    }
+
+    #[test]
+    fn lines_are_queried_newest_first_by_level() {
+        let (db, author, app_id, broker_id) = setup();
+        let lines = [
+            line(LogLevel::Debug, "one", None),
+            line(LogLevel::Info, "two", None),
+            line(LogLevel::Warn, "three", None),
+            line(LogLevel::Error, "four", None),
+        ];
+        db.broker_logs.append(broker_id, &lines).unwrap();
+
+        let all = db
+            .broker_logs
+            .for_broker(app_id, author, broker_id, &query(None, None, None))
+            .unwrap();
+        assert_eq!(messages(&all), ["four", "three", "two", "one"]);
+
+        let query = query(Some(LogLevel::Warn), None, None);
+        let warnings = db
+            .broker_logs
+            .for_broker(app_id, author, broker_id, &query)
+            .unwrap();
+        assert_eq!(messages(&warnings), ["four", "three"]);
+    }
+
+    #[test]
+    fn lines_are_paged_before_an_id() {
+        let (db, author, app_id, broker_id) = setup();
+        let lines: Vec<_> = (0..5)
+            .map(|i| line(LogLevel::Info, &i.to_string(), None))
+            .collect();
+        db.broker_logs.append(broker_id, &lines).unwrap();
+
+        let page = db
+            .broker_logs
+            .for_broker(app_id, author, broker_id, &query(None, None, Some(2)))
+            .unwrap();
+        assert_eq!(messages(&page), ["4", "3"]);
+        let before_id = Some(page[1].id);
+        let page = db
+            .broker_logs
+            .for_broker(app_id, author, broker_id, &query(None, before_id, Some(2)))
+            .unwrap();
+        assert_eq!(messages(&page), ["2", "1"]);
+
+        // a limit of 0 still returns a line
+        let page = db
+            .broker_logs
+            .for_broker(app_id, author, broker_id, &query(None, None, Some(0)))
+            .unwrap();
+        assert_eq!(page.len(), 1);
+    }
+
+    #[test]
+    fn lines_are_filtered_by_time() {
+        let (db, author, app_id, broker_id) = setup();
+        let lines = [
+            line(LogLevel::Info, "old", Some(1_000)),
+            line(LogLevel::Info, "older", Some(500)),
+            line(LogLevel::Info, "future", Some(now() + 3600)),
+        ];
+        db.broker_logs.append(broker_id, &lines).unwrap();
+
+        // future timestamps are taken as arrival time
+        let query = LogQuery {
+            since: Some(now() - 60),
+            ..query(None, None, None)
+        };
+        let recent = db
+            .broker_logs
+            .for_broker(app_id, author, broker_id, &query)
+            .unwrap();
+        assert_eq!(messages(&recent), ["future"]);
+        assert!(recent[0].created_at <= now());
+
+        let query = LogQuery {
+            since: Some(600),
+            until: Some(1_000),
+            ..query
+        };
+        let window = db
+            .broker_logs
+            .for_broker(app_id, author, broker_id, &query)
+            .unwrap();
+        assert_eq!(messages(&window), ["old"]);
+    }
+
+    #[test]
+    fn lines_are_only_shown_to_members_of_the_app() {
+        let (db, author, app_id, broker_id) = setup();
+        let bob = testing::user(&db, "bob");
+        let other_app = testing::app(&db, author);
+        db.broker_logs
+            .append(broker_id, &[line(LogLevel::Info, "hello", None)])
+            .unwrap();
+
+        let query = query(None, None, None);
+        assert!(db
+            .broker_logs
+            .for_broker(app_id, bob, broker_id, &query)
+            .is_err());
+        let foreign = db
+            .broker_logs
+            .for_broker(other_app, author, broker_id, &query)
+            .unwrap();
+        assert!(foreign.is_empty());
+    }
+
+    #[test]
+    fn long_messages_are_cut() {
+        let (db, _, _, broker_id) = setup();
+        let message = "x".repeat(MAX_MESSAGE_LENGTH + 10);
+        db.broker_logs
+            .append(broker_id, &[line(LogLevel::Info, &message, None)])
+            .unwrap();
+
+        let lines = db.broker_logs.since(broker_id, 0, LogLevel::Debug).unwrap();
+        assert_eq!(lines[0].message.len(), MAX_MESSAGE_LENGTH);
+    }
+
+    #[test]
+    fn old_lines_are_pruned() {
+        let (db, _, _, broker_id) = setup();
+        let lines = [
+            line(
+                LogLevel::Info,
+                "expired",
+                Some(now() - LOG_RETENTION_SECS - 1),
+            ),
+            line(LogLevel::Info, "kept", None),
+        ];
+        db.broker_logs.append(broker_id, &lines).unwrap();
+
+        let pruned = db.broker_logs.prune(now() - LOG_RETENTION_SECS).unwrap();
+        assert_eq!(pruned, 1);
+        let lines = db.broker_logs.since(broker_id, 0, LogLevel::Debug).unwrap();
+        assert_eq!(messages(&lines), ["kept"]);
+    }
+
+    #[test]
+    fn only_the_newest_lines_of_a_broker_are_kept() {
+        let (db, _, app_id, broker_id) = setup();
+        let other_broker = testing::broker(&db, app_id);
+        db.broker_logs
+            .append(other_broker, &[line(LogLevel::Info, "other", None)])
+            .unwrap();
+        let lines: Vec<_> = (0..LOG_LINES_KEPT + 5)
+            .map(|i| line(LogLevel::Info, &i.to_string(), None))
+            .collect();
+        db.broker_logs.append(broker_id, &lines).unwrap();
+
+        let con = db.con.lock().unwrap();
+        let (count, oldest): (usize, String) = con
+            .query_row(
+                "SELECT COUNT(*), (SELECT message FROM broker_logs WHERE broker_id = ?1 ORDER BY id LIMIT 1)
+                FROM broker_logs WHERE broker_id = ?1",
+                [broker_id],
+                |row| Ok((row.get(0)?, row.get(1)?)),
+            )
+            .unwrap();
+        assert_eq!(count, LOG_LINES_KEPT);
+        assert_eq!(oldest, "5");
+        drop(con);
+        let other = db
+            .broker_logs
+            .since(other_broker, 0, LogLevel::Debug)
+            .unwrap();
+        assert_eq!(messages(&other), ["other"]);
+    }
+
+    #[test]
+    fn tails_read_lines_in_order_from_an_id() {
+        let (db, _, _, broker_id) = setup();
+        let start = db.broker_logs.last_id().unwrap();
+        let mut changes = db.broker_logs.subscribe();
+        changes.borrow_and_update();
+        let lines = [
+            line(LogLevel::Debug, "one", None),
+            line(LogLevel::Error, "two", None),
+        ];
+        db.broker_logs.append(broker_id, &lines).unwrap();
+
+        assert!(changes.has_changed().unwrap());
+        let tail = db
+            .broker_logs
+            .since(broker_id, start, LogLevel::Debug)
+            .unwrap();
+        assert_eq!(messages(&tail), ["one", "two"]);
+        let errors = db
+            .broker_logs
+            .since(broker_id, start, LogLevel::Error)
+            .unwrap();
+        assert_eq!(messages(&errors), ["two"]);
+        assert_eq!(db.broker_logs.last_id().unwrap(), tail[1].id);
+    }
+}
@@ -0,0 +1,347 @@
+use axum_utils::{copy, copy_mut};
+use rusqlite::{Connection, OptionalExtension, Row};
+use serde::{Deserialize, Serialize};
+
+use super::apps::has_permission;
+use super::audit::{self, snapshot, Change};
+use super::brokers::{self, change_stopped, count_versions, Broker, VersionCount};
+use super::events::{self, now, AppEvent};
+use super::{query_execute, query_row, query_rows, Con, SqlResult};
+
+/**
+    Named groups of brokers within an app. Pools have their own config
+    defaults, see `broker_configs::ConfigScope::Pool`, and can be started
+    and stopped as a whole. A broker is in one pool at most.
+*/
+pub struct BrokerPools {
+    con: Con,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct BrokerPool {
+    pub id: i32,
+    pub app_id: i32,
+    pub name: String,
+    pub description: String,
+    pub created_at: u64,
+    pub updated_at: u64,
+}
+
+impl BrokerPool {
+    pub fn from_row(row: &Row) -> SqlResult<Self> {
+        Ok(Self {
+            id: row.get("id")?,
+            app_id: row.get("app_id")?,
+            name: row.get("name")?,
+            description: row.get("description")?,
+            created_at: row.get("created_at")?,
+            updated_at: row.get("updated_at")?,
+        })
+    }
+}
+
+/// Pool with the state of its brokers, as shown in the listing.
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct PoolStatus {
+    #[serde(flatten)]
+    pub pool: BrokerPool,
+    pub brokers: usize,
+    /// Connected right now.
+    pub active: usize,
+    pub stopped: usize,
+    pub versions: Vec<VersionCount>,
+}
+
+#[derive(Deserialize)]
+pub struct NewBrokerPool {
+    pub name: String,
+    #[serde(default)]
+    pub description: String,
+}
+
+#[derive(Deserialize)]
+pub struct BrokerPoolUpdate {
+    pub name: Option<String>,
+    pub description: Option<String>,
+}
+
+impl BrokerPools {
+    pub fn new(con: &Con) -> Self {
+        Self { con: con.clone() }
+    }
+
+    copy!(create_table() -> SqlResult<usize>);
+    copy!(for_app(app_id: i32, user_id: i32) -> SqlResult<Vec<PoolStatus>>);
+    copy!(get(app_id: i32, user_id: i32, pool_id: i32) -> SqlResult<Option<PoolStatus>>);
+    copy_mut!(create(app_id: i32, user_id: i32, new_pool: NewBrokerPool) -> SqlResult<BrokerPool>);
+    copy_mut!(update(app_id: i32, user_id: i32, pool_id: i32, changes: BrokerPoolUpdate) -> SqlResult<Option<BrokerPool>>);
+    copy_mut!(delete(app_id: i32, user_id: i32, pool_id: i32) -> SqlResult<usize>);
+    copy!(brokers(app_id: i32, user_id: i32, pool_id: i32) -> SqlResult<Option<Vec<Broker>>>);
+    copy_mut!(set_pool(app_id: i32, user_id: i32, broker_id: i32, pool_id: Option<i32>) -> SqlResult<Option<Broker>>);
+    copy_mut!(set_stopped(app_id: i32, user_id: i32, pool_id: i32, stopped: bool) -> SqlResult<Option<Vec<Broker>>>);
+}
+
+fn create_table(con: &Connection) -> SqlResult<usize> {
+    con.execute(
+        "CREATE TABLE IF NOT EXISTS broker_pools (
+            id INTEGER PRIMARY KEY,
+            app_id INTEGER,
+            name TEXT,
+            description TEXT,
+            created_at INTEGER,
+            updated_at INTEGER,
+            UNIQUE(app_id, name),
+            FOREIGN KEY(app_id) REFERENCES apps(id)
+        )",
+        [],
+    )
+}
+
+/// Pool of the app, no permission checks.
+fn by_id(con: &Connection, app_id: i32, pool_id: i32) -> SqlResult<Option<BrokerPool>> {
+    query_row!(con => "SELECT * FROM broker_pools WHERE id = ? AND app_id = ?", [pool_id, app_id], BrokerPool)
+        .optional()
+}
+
+/// Whether `pool_id` is a pool of the app, no pool always is.
+pub(super) fn exists(con: &Connection, app_id: i32, pool_id: Option<i32>) -> SqlResult<bool> {
+    match pool_id {
+        Some(pool_id) => Ok(by_id(con, app_id, pool_id)?.is_some()),
+        None => Ok(true),
+    }
+}
+
+fn members(con: &Connection, pool_id: i32) -> SqlResult<Vec<Broker>> {
+    Ok(query_rows!(con => "SELECT * FROM brokers WHERE pool_id = ? AND deleted_at IS NULL ORDER BY id", [pool_id], Broker))
+}
+
+fn status(con: &Connection, pool: BrokerPool) -> SqlResult<PoolStatus> {
+    let policy = brokers::version_policy(con, pool.app_id)?;
+    let members = members(con, pool.id)?;
+    Ok(PoolStatus {
+        brokers: members.len(),
+        active: members.iter().filter(|broker| broker.active).count(),
+        stopped: members.iter().filter(|broker| broker.stopped).count(),
+        versions: count_versions(&members, &policy),
+        pool,
+    })
+}
+
+fn for_app(con: &Connection, app_id: i32, user_id: i32) -> SqlResult<Vec<PoolStatus>> {
+    has_permission(con, app_id, user_id)?;
+    let pools = query_rows!(con => "SELECT * FROM broker_pools WHERE app_id = ? ORDER BY name", [app_id], BrokerPool);
+    pools.into_iter().map(|pool| status(con, pool)).collect()
+}
+
+fn get(con: &Connection, app_id: i32, user_id: i32, pool_id: i32) -> SqlResult<Option<PoolStatus>> {
+    has_permission(con, app_id, user_id)?;
+    by_id(con, app_id, pool_id)?
+        .map(|pool| status(con, pool))
+        .transpose()
+}
+
+/// Names are unique within the app, a taken one is a constraint error.
+fn create(
+    con: &mut Connection,
+    app_id: i32,
+    user_id: i32,
+    new_pool: NewBrokerPool,
+) -> SqlResult<BrokerPool> {
+    let tx = con.transaction()?;
+    has_permission(&tx, app_id, user_id)?;
+    let now = now();
+    query_execute!(tx => "INSERT INTO broker_pools(app_id, name, description, created_at, updated_at) VALUES (?, ?, ?, ?, ?)",
+        (app_id, &new_pool.name, &new_pool.description, now, now))?;
+    let pool_id = tx.last_insert_rowid() as i32;
+    let pool = by_id(&tx, app_id, pool_id)?.unwrap();
+
+    let event = AppEvent::BrokerPoolAdded {
+        pool_id,
+        name: new_pool.name,
+    };
+    events::record(&tx, app_id, &event)?;
+    let change = Change {
+        actor_id: Some(user_id),
+        app_id: Some(app_id),
+        action: "brokerPool.create",
+        target: format!("pool:{pool_id}"),
+        before: None,
+        after: snapshot(&pool),
+    };
+    audit::record(&tx, change)?;
+    tx.commit()?;
+    Ok(pool)
+}
+
+/// `None` if the pool does not exist in the app.
+fn update(
+    con: &mut Connection,
+    app_id: i32,
+    user_id: i32,
+    pool_id: i32,
+    update: BrokerPoolUpdate,
+) -> SqlResult<Option<BrokerPool>> {
+    let tx = con.transaction()?;
+    has_permission(&tx, app_id, user_id)?;
+    let Some(before) = by_id(&tx, app_id, pool_id)? else {
+        return Ok(None);
+    };
+    query_execute!(tx => "
+        UPDATE broker_pools SET
+            name = COALESCE(?, name),
+            description = COALESCE(?, description),
+            updated_at = ?
+        WHERE id = ?",
+        (update.name, update.description, now(), pool_id)
+    )?;
+    let after = by_id(&tx, app_id, pool_id)?.unwrap();
+
+    if before.name != after.name || before.description != after.description {
+        let event = AppEvent::BrokerPoolUpdated {
+            pool_id,
+            name: after.name.clone(),
+        };
+        events::record(&tx, app_id, &event)?;
+    }
+    let change = Change {
+        actor_id: Some(user_id),
+        app_id: Some(app_id),
+        action: "brokerPool.update",
+        target: format!("pool:{pool_id}"),
+        before: snapshot(&before),
+        after: snapshot(&after),
+    };
+    audit::record(&tx, change)?;
+    tx.commit()?;
+    Ok(Some(after))
+}
+
+/**
+    Brokers of the pool stay in the app without a pool, the config of the pool is removed.
+    Jobs and schedules that targeted the pool may run on any broker of the app.
+*/
+fn delete(con: &mut Connection, app_id: i32, user_id: i32, pool_id: i32) -> SqlResult<usize> {
+    let tx = con.transaction()?;
+    has_permission(&tx, app_id, user_id)?;
+    let Some(before) = by_id(&tx, app_id, pool_id)? else {
+        return Ok(0);
+    };
+    for broker in members(&tx, pool_id)? {
+        let event = AppEvent::BrokerLeftPool {
+            broker_id: broker.id,
+            pool_id,
+        };
+        events::record(&tx, app_id, &event)?;
+    }
+    // soft deleted brokers too, they may be restored
+    query_execute!(tx => "UPDATE brokers SET pool_id = NULL WHERE pool_id = ?", [pool_id])?;
+    query_execute!(tx => "DELETE FROM broker_configs WHERE pool_id = ?", [pool_id])?;
+    query_execute!(tx => "UPDATE jobs SET pool_id = NULL WHERE pool_id = ?", [pool_id])?;
+    query_execute!(tx => "UPDATE schedules SET pool_id = NULL WHERE pool_id = ?", [pool_id])?;
+    let result = query_execute!(tx => "DELETE FROM broker_pools WHERE id = ?", [pool_id])?;
+
+    events::record(&tx, app_id, &AppEvent::BrokerPoolRemoved { pool_id })?;
+    let change = Change {
+        actor_id: Some(user_id),
+        app_id: Some(app_id),
+        action: "brokerPool.delete",
+        target: format!("pool:{pool_id}"),
+        before: snapshot(&before),
+        after: None,
+    };
+    audit::record(&tx, change)?;
+    tx.commit()?;
+    Ok(result)
+}
+
+/// `None` if the pool does not exist in the app.
+fn brokers(
+    con: &Connection,
+    app_id: i32,
+    user_id: i32,
+    pool_id: i32,
+) -> SqlResult<Option<Vec<Broker>>> {
+    has_permission(con, app_id, user_id)?;
+    if by_id(con, app_id, pool_id)?.is_none() {
+        return Ok(None);
+    }
+    members(con, pool_id).map(Some)
+}
+
+/**
+    Moves the broker into `pool_id`, out of the pool it was in before.
+    `None` removes it from its pool. Returns `None` if the broker or pool
+    does not exist in the app.
+*/
+fn set_pool(
+    con: &mut Connection,
+    app_id: i32,
+    user_id: i32,
+    broker_id: i32,
+    pool_id: Option<i32>,
+) -> SqlResult<Option<Broker>> {
+    let tx = con.transaction()?;
+    has_permission(&tx, app_id, user_id)?;
+    let Some(before) = brokers::get(&tx, app_id, broker_id)? else {
+        return Ok(None);
+    };
+    if let Some(pool_id) = pool_id {
+        if by_id(&tx, app_id, pool_id)?.is_none() {
+            return Ok(None);
+        }
+    }
+    if before.pool_id == pool_id {
+        return Ok(Some(before));
+    }
+    query_execute!(tx => "UPDATE brokers SET pool_id = ? WHERE id = ?", (pool_id, broker_id))?;
+    let after = brokers::get(&tx, app_id, broker_id)?.unwrap();
+
+    if let Some(pool_id) = before.pool_id {
+        events::record(
+            &tx,
+            app_id,
+            &AppEvent::BrokerLeftPool { broker_id, pool_id },
+        )?;
+    }
+    if let Some(pool_id) = pool_id {
+        events::record(
+            &tx,
+            app_id,
+            &AppEvent::BrokerJoinedPool { broker_id, pool_id },
+        )?;
+    }
+    let change = Change {
+        actor_id: Some(user_id),
+        app_id: Some(app_id),
+        action: "broker.pool",
+        target: format!("broker:{broker_id}"),
+        before: snapshot(&before),
+        after: snapshot(&after),
+    };
+    audit::record(&tx, change)?;
+    tx.commit()?;
+    Ok(Some(after))
+}
+
+/// Stops or starts every broker of the pool, each one is recorded like `brokers::set_stopped`.
+fn set_stopped(
+    con: &mut Connection,
+    app_id: i32,
+    user_id: i32,
+    pool_id: i32,
+    stopped: bool,
+) -> SqlResult<Option<Vec<Broker>>> {
+    let tx = con.transaction()?;
+    has_permission(&tx, app_id, user_id)?;
+    if by_id(&tx, app_id, pool_id)?.is_none() {
+        return Ok(None);
+    }
+    let brokers = members(&tx, pool_id)?
+        .into_iter()
+        .map(|broker| change_stopped(&tx, user_id, broker, stopped))
+        .collect::<SqlResult<Vec<_>>>()?;
+    tx.commit()?;
+    Ok(Some(brokers))
+}
@@ -1,10 +1,14 @@
-use axum_utils::{copy, copy_mut, impl_from_row};
-use rusqlite::{Connection, Row};
+use axum_utils::{copy, copy_mut};
+use rusqlite::{Connection, OptionalExtension, Row};
 use serde::{Deserialize, Serialize};
 
 use super::apps::has_permission;
+use super::audit::{self, snapshot, Change};
+use super::events::{self, now, AppEvent};
 
-use super::{query_execute, query_rows, Con, SqlResult};
+use super::{query_execute, query_row, query_rows, Con, SqlResult};
+use crate::validation::ValidationErrors;
+use crate::version::Version;
 
 pub struct Brokers {
     con: Con,
@@ -18,9 +22,25 @@ impl Brokers {
     copy!(create_table() -> SqlResult<usize>);
     copy_mut!(for_app(app_id: i32, user_id: i32) -> SqlResult<Vec<Broker>>);
     copy_mut!(create(app_id: i32, user_id: i32, new_broker: NewBroker) -> SqlResult<i32>);
-    copy_mut!(delete(app_id: i32, user_id: i32, app_user_id: i32) -> SqlResult<usize>);
+    copy_mut!(delete(app_id: i32, user_id: i32, broker_id: i32) -> SqlResult<usize>);
+    copy!(all() -> SqlResult<Vec<Broker>>);
+    copy_mut!(update(app_id: i32, user_id: i32, broker_id: i32, changes: BrokerUpdate) -> SqlResult<Option<Broker>>);
+    copy_mut!(set_stopped(app_id: i32, user_id: i32, broker_id: i32, stopped: bool) -> SqlResult<Option<Broker>>);
+    copy!(get(app_id: i32, broker_id: i32) -> SqlResult<Option<Broker>>);
+    copy_mut!(connect(app_id: i32, broker_id: i32) -> SqlResult<Option<u32>>);
+    copy_mut!(disconnect(app_id: i32, broker_id: i32, session: u32) -> SqlResult<usize>);
+    copy!(set_version(app_id: i32, broker_id: i32, version: &Version) -> SqlResult<usize>);
+    copy!(set_labels(app_id: i32, broker_id: i32, labels: &[String]) -> SqlResult<usize>);
+    copy_mut!(report_incompatible(app_id: i32, broker_id: i32, version: &str) -> SqlResult<i64>);
+    copy!(version_policy(app_id: i32) -> SqlResult<VersionPolicy>);
+    copy!(rollout(app_id: i32, user_id: i32) -> SqlResult<Rollout>);
+    copy!(heartbeat(app_id: i32, broker_id: i32, report: &Heartbeat) -> SqlResult<usize>);
+    copy_mut!(mark_stale(older_than: u64) -> SqlResult<usize>);
 }
 
+/// Active brokers not heard from for this long are marked inactive, see `mark_stale`.
+pub const STALE_AFTER_SECS: u64 = 90;
+
 fn create_table(con: &Connection) -> SqlResult<usize> {
     con.execute(
         "CREATE TABLE IF NOT EXISTS brokers (
@@ -47,25 +67,76 @@ pub struct Broker {
     pub version: String,
     pub active: bool,
     pub stopped: bool,
+    /// Start of the current connection, `None` while inactive.
+    #[serde(default)]
+    pub connected_at: Option<u64>,
+    /// Last heartbeat, or connect if the broker did not send one yet.
+    #[serde(default)]
+    pub last_seen_at: Option<u64>,
+    /// Seconds since `connected_at`.
+    #[serde(default, skip_deserializing)]
+    pub uptime: Option<u64>,
+    /// As reported by the broker, no unit is enforced.
+    #[serde(default)]
+    pub load: Option<f64>,
+    #[serde(default)]
+    pub hostname: Option<String>,
+    #[serde(default)]
+    pub platform: Option<String>,
+    /// Declared by the broker on connect, see `check_labels`.
+    #[serde(default)]
+    pub labels: Vec<String>,
+    /// See `broker_pools`, a broker is in one pool at most.
+    #[serde(default)]
+    pub pool_id: Option<i32>,
+    /// Bumped on every connect, only the latest connection may disconnect the broker.
+    #[serde(skip)]
+    pub session: u32,
+    pub created_at: u64,
+    pub updated_at: u64,
 }
 
-impl_from_row!(Broker {
-    id,
-    app_id,
-    name,
-    description,
-    version,
-    active,
-    stopped
-});
+impl Broker {
+    pub fn from_row(row: &Row) -> SqlResult<Self> {
+        let active: bool = row.get("active")?;
+        let connected_at: Option<u64> = row.get("connected_at")?;
+        Ok(Self {
+            id: row.get("id")?,
+            app_id: row.get("app_id")?,
+            name: row.get("name")?,
+            description: row.get("description")?,
+            version: row.get("version")?,
+            active,
+            stopped: row.get("stopped")?,
+            connected_at,
+            last_seen_at: row.get("last_seen_at")?,
+            uptime: connected_at
+                .filter(|_| active)
+                .map(|connected_at| now().saturating_sub(connected_at)),
+            load: row.get("load")?,
+            hostname: row.get("hostname")?,
+            platform: row.get("platform")?,
+            labels: split_labels(&row.get::<_, String>("labels")?),
+            pool_id: row.get("pool_id")?,
+            session: row.get("session")?,
+            created_at: row.get("created_at")?,
+            updated_at: row.get("updated_at")?,
+        })
+    }
+}
 
 fn for_app(con: &mut Connection, app_id: i32, user_id: i32) -> SqlResult<Vec<Broker>> {
     let tx = con.transaction()?;
     has_permission(&tx, app_id, user_id)?;
-    let users = query_rows!(tx => "SELECT * FROM brokers WHERE app_id = ?", [app_id], Broker);
+    let users = query_rows!(tx => "SELECT * FROM brokers WHERE app_id = ? AND deleted_at IS NULL", [app_id], Broker);
     Ok(users)
 }
 
+/// Brokers of every app, no permission checks.
+fn all(con: &Connection) -> SqlResult<Vec<Broker>> {
+    Ok(query_rows!(con => "SELECT * FROM brokers WHERE deleted_at IS NULL ORDER BY app_id, id", [], Broker))
+}
+
 fn create(
     con: &mut Connection,
     app_id: i32,
@@ -74,19 +145,429 @@ fn create(
 ) -> SqlResult<i32> {
     let tx = con.transaction()?;
     has_permission(&tx, app_id, user_id)?;
+    let name = new_broker.name.clone();
     let result = unchecked_create(&tx, app_id, new_broker)?;
+    let event = AppEvent::BrokerAdded {
+        broker_id: result,
+        name,
+    };
+    events::record(&tx, app_id, &event)?;
+    let change = Change {
+        actor_id: Some(user_id),
+        app_id: Some(app_id),
+        action: "broker.create",
+        target: format!("broker:{result}"),
+        before: None,
+        after: snapshot(&by_id(&tx, result)?),
+    };
+    audit::record(&tx, change)?;
+    tx.commit()?;
+    Ok(result)
+}
+
+fn delete(con: &mut Connection, app_id: i32, user_id: i32, broker_id: i32) -> SqlResult<usize> {
+    let tx = con.transaction()?;
+    has_permission(&tx, app_id, user_id)?;
+    let before = by_id(&tx, broker_id).ok();
+    let result = unchecked_delete(&tx, app_id, broker_id)?;
+    events::record(&tx, app_id, &AppEvent::BrokerRemoved { broker_id })?;
+    let change = Change {
+        actor_id: Some(user_id),
+        app_id: Some(app_id),
+        action: "broker.delete",
+        target: format!("broker:{broker_id}"),
+        before: before.as_ref().and_then(snapshot),
+        after: None,
+    };
+    audit::record(&tx, change)?;
     tx.commit()?;
     Ok(result)
 }
 
-fn delete(con: &mut Connection, app_id: i32, user_id: i32, app_user_id: i32) -> SqlResult<usize> {
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct BrokerUpdate {
+    pub name: Option<String>,
+    pub description: Option<String>,
+}
+
+/// `None` if the broker does not exist in the app.
+fn update(
+    con: &mut Connection,
+    app_id: i32,
+    user_id: i32,
+    broker_id: i32,
+    update: BrokerUpdate,
+) -> SqlResult<Option<Broker>> {
+    let tx = con.transaction()?;
+    has_permission(&tx, app_id, user_id)?;
+    let Some(before) = get(&tx, app_id, broker_id)? else {
+        return Ok(None);
+    };
+    query_execute!(tx => "
+        UPDATE brokers SET
+            name = COALESCE(?, name),
+            description = COALESCE(?, description)
+        WHERE id = ?",
+        (update.name, update.description, broker_id)
+    )?;
+    let after = by_id(&tx, broker_id)?;
+
+    if before.name != after.name || before.description != after.description {
+        let event = AppEvent::BrokerUpdated {
+            broker_id,
+            name: after.name.clone(),
+        };
+        events::record(&tx, app_id, &event)?;
+    }
+    let change = Change {
+        actor_id: Some(user_id),
+        app_id: Some(app_id),
+        action: "broker.update",
+        target: format!("broker:{broker_id}"),
+        before: snapshot(&before),
+        after: snapshot(&after),
+    };
+    audit::record(&tx, change)?;
+    tx.commit()?;
+    Ok(Some(after))
+}
+
+/**
+    Stopped brokers stay connected but must not take work, see `handlers::brokers::session`.
+    Nothing is recorded if the broker already is in that state.
+*/
+fn set_stopped(
+    con: &mut Connection,
+    app_id: i32,
+    user_id: i32,
+    broker_id: i32,
+    stopped: bool,
+) -> SqlResult<Option<Broker>> {
     let tx = con.transaction()?;
     has_permission(&tx, app_id, user_id)?;
-    let result = unchecked_delete(&tx, app_user_id)?;
+    let Some(before) = get(&tx, app_id, broker_id)? else {
+        return Ok(None);
+    };
+    let after = change_stopped(&tx, user_id, before, stopped)?;
+    tx.commit()?;
+    Ok(Some(after))
+}
+
+/// Stops or starts `before` and records it, nothing happens if it already is in that state.
+pub(super) fn change_stopped(
+    con: &Connection,
+    user_id: i32,
+    before: Broker,
+    stopped: bool,
+) -> SqlResult<Broker> {
+    if before.stopped == stopped {
+        return Ok(before);
+    }
+    let (app_id, broker_id) = (before.app_id, before.id);
+    query_execute!(con => "UPDATE brokers SET stopped = ? WHERE id = ?", (stopped, broker_id))?;
+    let after = by_id(con, broker_id)?;
+
+    let (event, action) = match stopped {
+        true => (AppEvent::BrokerStopped { broker_id }, "broker.stop"),
+        false => (AppEvent::BrokerStarted { broker_id }, "broker.start"),
+    };
+    events::record(con, app_id, &event)?;
+    let change = Change {
+        actor_id: Some(user_id),
+        app_id: Some(app_id),
+        action,
+        target: format!("broker:{broker_id}"),
+        before: snapshot(&before),
+        after: snapshot(&after),
+    };
+    audit::record(con, change)?;
+    Ok(after)
+}
+
+/// Broker of the app, no permission checks.
+pub(super) fn get(con: &Connection, app_id: i32, broker_id: i32) -> SqlResult<Option<Broker>> {
+    query_row!(con => "SELECT * FROM brokers WHERE id = ? AND app_id = ? AND deleted_at IS NULL", [broker_id, app_id], Broker).optional()
+}
+
+/**
+    Called by broker connection, permission is checked when broker connects.
+    Replaces any connection the broker already has, returns the new session
+    or `None` if the broker does not exist.
+*/
+fn connect(con: &mut Connection, app_id: i32, broker_id: i32) -> SqlResult<Option<u32>> {
+    let tx = con.transaction()?;
+    let connected_at = now();
+    let result = query_execute!(tx => "
+        UPDATE brokers SET active = 1, connected_at = ?1, last_seen_at = ?1, session = session + 1
+        WHERE id = ?2 AND app_id = ?3 AND deleted_at IS NULL",
+        (connected_at, broker_id, app_id)
+    )?;
+    if result == 0 {
+        return Ok(None);
+    }
+    events::record(&tx, app_id, &AppEvent::BrokerConnected { broker_id })?;
+    let session = tx.query_row(
+        "SELECT session FROM brokers WHERE id = ?",
+        [broker_id],
+        |row| row.get(0),
+    )?;
+    tx.commit()?;
+    Ok(Some(session))
+}
+
+/// Only ends `session`, a broker that reconnected since stays active.
+fn disconnect(con: &mut Connection, app_id: i32, broker_id: i32, session: u32) -> SqlResult<usize> {
+    let tx = con.transaction()?;
+    let result = query_execute!(tx => "
+        UPDATE brokers SET active = 0, connected_at = NULL
+        WHERE id = ? AND app_id = ? AND session = ? AND active = 1 AND deleted_at IS NULL",
+        (broker_id, app_id, session)
+    )?;
+    if result == 0 {
+        return Ok(result);
+    }
+    events::record(&tx, app_id, &AppEvent::BrokerDisconnected { broker_id })?;
+    tx.commit()?;
+    Ok(result)
+}
+
+/// Sent by a connected broker every few seconds, well within `STALE_AFTER_SECS`.
+#[derive(Deserialize)]
+pub struct Heartbeat {
+    pub load: Option<f64>,
+    pub hostname: Option<String>,
+    pub platform: Option<String>,
+}
+
+/// Only touches active brokers, a stale one has to reconnect.
+fn heartbeat(
+    con: &Connection,
+    app_id: i32,
+    broker_id: i32,
+    heartbeat: &Heartbeat,
+) -> SqlResult<usize> {
+    let load = heartbeat.load.filter(|load| load.is_finite() && *load >= 0.0);
+    let text = |value: &Option<String>| {
+        value
+            .as_deref()
+            .map(|value| value.chars().take(255).collect::<String>())
+    };
+    query_execute!(con => "
+        UPDATE brokers SET last_seen_at = ?, load = ?, hostname = COALESCE(?, hostname), platform = COALESCE(?, platform)
+        WHERE id = ? AND app_id = ? AND active = 1 AND deleted_at IS NULL",
+        (now(), load, text(&heartbeat.hostname), text(&heartbeat.platform), broker_id, app_id)
+    )
+}
+
+/**
+    Marks active brokers inactive that were last seen before `older_than`.
+    Their connection is dead without having been closed, brokers that are not
+    stopped also get a `brokerOffline` event to alert on. Returns how many were marked.
+*/
+fn mark_stale(con: &mut Connection, older_than: u64) -> SqlResult<usize> {
+    let tx = con.transaction()?;
+    let stale = query_rows!(tx => "
+        SELECT * FROM brokers
+        WHERE active = 1 AND COALESCE(last_seen_at, 0) < ? AND deleted_at IS NULL",
+        [older_than], Broker
+    );
+    for broker in &stale {
+        let broker_id = broker.id;
+        query_execute!(tx => "UPDATE brokers SET active = 0, connected_at = NULL WHERE id = ?", [broker_id])?;
+        events::record(&tx, broker.app_id, &AppEvent::BrokerDisconnected { broker_id })?;
+        if !broker.stopped {
+            let event = AppEvent::BrokerOffline {
+                broker_id,
+                last_seen_at: broker.last_seen_at,
+            };
+            events::record(&tx, broker.app_id, &event)?;
+        }
+    }
+    tx.commit()?;
+    Ok(stale.len())
+}
+
+/// Stores the version a connecting broker reported.
+fn set_version(
+    con: &Connection,
+    app_id: i32,
+    broker_id: i32,
+    version: &Version,
+) -> SqlResult<usize> {
+    query_execute!(con => "UPDATE brokers SET version = ?1 WHERE id = ?2 AND app_id = ?3 AND version != ?1",
+        (version.to_string(), broker_id, app_id))
+}
+
+/// Replaces the labels a connecting broker declared.
+fn set_labels(
+    con: &Connection,
+    app_id: i32,
+    broker_id: i32,
+    labels: &[String],
+) -> SqlResult<usize> {
+    query_execute!(con => "UPDATE brokers SET labels = ?1 WHERE id = ?2 AND app_id = ?3 AND labels != ?1",
+        (join_labels(labels), broker_id, app_id))
+}
+
+/// Most labels a broker can declare or a job can require.
+pub const MAX_LABELS: usize = 32;
+const MAX_LABEL_LENGTH: usize = 64;
+
+/**
+    Trims, lowercases and sorts labels, duplicates and empty ones are dropped.
+    Labels name what a broker can do, like `pdf` or `db:sales`,
+    jobs are only routed to brokers that have every label they require.
+*/
+pub fn normalize_labels<I: IntoIterator<Item = String>>(labels: I) -> Vec<String> {
+    let mut labels: Vec<String> = labels
+        .into_iter()
+        .map(|label| label.trim().to_lowercase())
+        .filter(|label| !label.is_empty())
+        .collect();
+    labels.sort();
+    labels.dedup();
+    labels
+}
+
+/// Expects normalized labels, letters, digits and `-_.:/` only.
+pub fn check_labels(field: &str, labels: &[String], errors: &mut ValidationErrors) {
+    if labels.len() > MAX_LABELS {
+        let message = format!("at most {MAX_LABELS} labels are allowed");
+        errors.add(field, "tooMany", &message);
+    }
+    let valid = |label: &String| {
+        label.len() <= MAX_LABEL_LENGTH
+            && label
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || "-_.:/".contains(c))
+    };
+    if !labels.iter().all(valid) {
+        let message = format!(
+            "labels must be at most {MAX_LABEL_LENGTH} letters, digits or -_.:/ characters"
+        );
+        errors.add(field, "invalidLabel", &message);
+    }
+}
+
+/// Labels are stored space separated.
+pub(super) fn split_labels(labels: &str) -> Vec<String> {
+    labels.split_whitespace().map(str::to_string).collect()
+}
+
+pub(super) fn join_labels(labels: &[String]) -> String {
+    labels.join(" ")
+}
+
+/// Records that a connected broker runs a version the app does not support.
+fn report_incompatible(
+    con: &mut Connection,
+    app_id: i32,
+    broker_id: i32,
+    version: &str,
+) -> SqlResult<i64> {
+    let tx = con.transaction()?;
+    let event = AppEvent::BrokerIncompatible {
+        broker_id,
+        version: version.to_string(),
+    };
+    let result = events::record(&tx, app_id, &event)?;
     tx.commit()?;
     Ok(result)
 }
 
+/// Broker versions an app works with, `min` is inclusive, `max` exclusive.
+#[derive(Serialize, Deserialize, Clone, Default, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct VersionPolicy {
+    pub min: Option<Version>,
+    pub max: Option<Version>,
+    /// Incompatible brokers are turned away instead of only warned.
+    pub reject: bool,
+}
+
+impl VersionPolicy {
+    /// Versions that don't parse are never compatible, unless there are no bounds.
+    pub fn allows(&self, version: &str) -> bool {
+        if self.min.is_none() && self.max.is_none() {
+            return true;
+        }
+        let Ok(version) = version.parse::<Version>() else {
+            return false;
+        };
+        self.min.as_ref().is_none_or(|min| version >= *min)
+            && self.max.as_ref().is_none_or(|max| version < *max)
+    }
+}
+
+/// Bounds that don't parse are ignored, they are validated when set.
+pub(super) fn version_policy(con: &Connection, app_id: i32) -> SqlResult<VersionPolicy> {
+    let mut stmt = con.prepare_cached(
+        "SELECT min_broker_version, max_broker_version, reject_incompatible_brokers FROM apps WHERE id = ?",
+    )?;
+    stmt.query_row([app_id], |row| {
+        let bound = |text: Option<String>| text.and_then(|text| text.parse().ok());
+        Ok(VersionPolicy {
+            min: bound(row.get(0)?),
+            max: bound(row.get(1)?),
+            reject: row.get(2)?,
+        })
+    })
+}
+
+/// How many brokers of an app run which version, newest version first.
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Rollout {
+    pub policy: VersionPolicy,
+    pub versions: Vec<VersionCount>,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct VersionCount {
+    pub version: String,
+    pub brokers: usize,
+    /// Connected right now.
+    pub active: usize,
+    pub compatible: bool,
+}
+
+fn rollout(con: &Connection, app_id: i32, user_id: i32) -> SqlResult<Rollout> {
+    has_permission(con, app_id, user_id)?;
+    let policy = version_policy(con, app_id)?;
+    let brokers = query_rows!(con => "SELECT * FROM brokers WHERE app_id = ? AND deleted_at IS NULL", [app_id], Broker);
+    let versions = count_versions(&brokers, &policy);
+    Ok(Rollout { policy, versions })
+}
+
+/// Brokers grouped by version, newest version first.
+pub(super) fn count_versions(brokers: &[Broker], policy: &VersionPolicy) -> Vec<VersionCount> {
+    let mut versions: Vec<VersionCount> = Vec::new();
+    for broker in brokers {
+        let count = match versions
+            .iter_mut()
+            .find(|count| count.version == broker.version)
+        {
+            Some(count) => count,
+            None => {
+                versions.push(VersionCount {
+                    version: broker.version.clone(),
+                    brokers: 0,
+                    active: 0,
+                    compatible: policy.allows(&broker.version),
+                });
+                versions.last_mut().unwrap()
+            }
+        };
+        count.brokers += 1;
+        count.active += usize::from(broker.active);
+    }
+    // versions that don't parse go last
+    versions.sort_by_key(|count| std::cmp::Reverse(count.version.parse::<Version>().ok()));
+    versions
+}
+
 #[derive(Serialize, Deserialize)]
 #[serde(rename_all = "camelCase")]
 pub struct NewBroker {
@@ -95,6 +576,10 @@ pub struct NewBroker {
     stopped: bool,
 }
 
+fn by_id(con: &Connection, broker_id: i32) -> SqlResult<Broker> {
+    query_row!(con => "SELECT * FROM brokers WHERE id = ?", [broker_id], Broker)
+}
+
 fn unchecked_create(con: &Connection, app_id: i32, new_broker: NewBroker) -> SqlResult<i32> {
     let NewBroker {
         name,
@@ -105,6 +590,293 @@ fn unchecked_create(con: &Connection, app_id: i32, new_broker: NewBroker) -> Sql
     Ok(con.last_insert_rowid() as i32)
 }
 
-fn unchecked_delete(con: &Connection, broker_id: i32) -> SqlResult<usize> {
-    query_execute!(con => "DELETE FROM brokers WHERE id = ?", [broker_id])
+fn unchecked_delete(con: &Connection, app_id: i32, broker_id: i32) -> SqlResult<usize> {
+    query_execute!(con => "UPDATE brokers SET deleted_at = ? WHERE id = ? AND app_id = ? AND deleted_at IS NULL", (events::now(), broker_id, app_id))
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::Value;
+
+    use super::*;
+    use crate::db::{testing, SqliteDb};
+
+    fn recorded(db: &SqliteDb, app_id: i32, kind: &str) -> Vec<Value> {
+        let events = db.events.since(app_id, 0).unwrap();
+        let Value::Array(events) = serde_json::to_value(events).unwrap() else {
+            unreachable!()
+        };
+        events
+            .into_iter()
+            .filter(|event| event["type"] == kind)
+            .collect()
+    }
+
+    fn disconnects(db: &SqliteDb, app_id: i32) -> usize {
+        recorded(db, app_id, "brokerDisconnected").len()
+    }
+
+    fn heartbeat(load: Option<f64>, hostname: Option<&str>) -> Heartbeat {
+        Heartbeat {
+            load,
+            hostname: hostname.map(str::to_string),
+            platform: None,
+        }
+    }
+
+    fn set_last_seen_at(db: &SqliteDb, broker_id: i32, last_seen_at: u64) {
+        let con = db.con.lock().unwrap();
+        con.execute(
+            "UPDATE brokers SET last_seen_at = ? WHERE id = ?",
+            (last_seen_at, broker_id),
+        )
+        .unwrap();
+    }
+
+    #[test]
+    fn a_replaced_connection_does_not_disconnect_the_broker() {
+        let db = testing::db();
+        let app_id = testing::app(&db, testing::user(&db, "alice"));
+        let broker_id = testing::broker(&db, app_id);
+
+        let first = db.brokers.connect(app_id, broker_id).unwrap().unwrap();
+        let second = db.brokers.connect(app_id, broker_id).unwrap().unwrap();
+        assert_ne!(first, second);
+        let broker = db.brokers.get(app_id, broker_id).unwrap().unwrap();
+        assert_eq!(broker.session, second);
+
+        assert_eq!(db.brokers.disconnect(app_id, broker_id, first).unwrap(), 0);
+        assert!(db.brokers.get(app_id, broker_id).unwrap().unwrap().active);
+        assert_eq!(disconnects(&db, app_id), 0);
+
+        assert_eq!(db.brokers.disconnect(app_id, broker_id, second).unwrap(), 1);
+        assert!(!db.brokers.get(app_id, broker_id).unwrap().unwrap().active);
+        assert_eq!(disconnects(&db, app_id), 1);
+    }
+
+    #[test]
+    fn a_stale_connection_is_disconnected_once() {
+        let db = testing::db();
+        let app_id = testing::app(&db, testing::user(&db, "alice"));
+        let broker_id = testing::broker(&db, app_id);
+
+        let session = db.brokers.connect(app_id, broker_id).unwrap().unwrap();
+        assert_eq!(db.brokers.mark_stale(now() + 1).unwrap(), 1);
+        let disconnected = db.brokers.disconnect(app_id, broker_id, session).unwrap();
+        assert_eq!(disconnected, 0);
+        assert_eq!(disconnects(&db, app_id), 1);
+    }
+
+    #[test]
+    fn deleted_or_foreign_brokers_do_not_connect() {
+        let db = testing::db();
+        let author = testing::user(&db, "alice");
+        let (app_id, other_app) = (testing::app(&db, author), testing::app(&db, author));
+        let broker_id = testing::broker(&db, app_id);
+
+        assert_eq!(db.brokers.connect(other_app, broker_id).unwrap(), None);
+        db.brokers.delete(app_id, author, broker_id).unwrap();
+        assert_eq!(db.brokers.connect(app_id, broker_id).unwrap(), None);
+    }
+
+    #[test]
+    fn heartbeats_only_touch_connected_brokers() {
+        let db = testing::db();
+        let app_id = testing::app(&db, testing::user(&db, "alice"));
+        let broker_id = testing::broker(&db, app_id);
+        let beat = heartbeat(Some(0.5), Some("runner-1"));
+
+        assert_eq!(db.brokers.heartbeat(app_id, broker_id, &beat).unwrap(), 0);
+        db.brokers.connect(app_id, broker_id).unwrap();
+        set_last_seen_at(&db, broker_id, 1);
+        assert_eq!(db.brokers.heartbeat(app_id, broker_id, &beat).unwrap(), 1);
+        let broker = db.brokers.get(app_id, broker_id).unwrap().unwrap();
+        assert_eq!(broker.load, Some(0.5));
+        assert_eq!(broker.hostname.as_deref(), Some("runner-1"));
+        assert!(broker.last_seen_at.unwrap() >= now() - 1);
+
+        // missing details are kept, unusable loads are dropped
+        for load in [f64::NAN, f64::INFINITY, -1.0] {
+            let beat = heartbeat(Some(load), None);
+            db.brokers.heartbeat(app_id, broker_id, &beat).unwrap();
+            let broker = db.brokers.get(app_id, broker_id).unwrap().unwrap();
+            assert_eq!(broker.load, None);
+            assert_eq!(broker.hostname.as_deref(), Some("runner-1"));
+        }
+
+        let long = "h".repeat(300);
+        let beat = heartbeat(None, Some(&long));
+        db.brokers.heartbeat(app_id, broker_id, &beat).unwrap();
+        let broker = db.brokers.get(app_id, broker_id).unwrap().unwrap();
+        assert_eq!(broker.hostname.unwrap().len(), 255);
+    }
+
+    #[test]
+    fn brokers_not_heard_from_are_marked_stale() {
+        let db = testing::db();
+        let app_id = testing::app(&db, testing::user(&db, "alice"));
+        let (fresh, stale) = (testing::broker(&db, app_id), testing::broker(&db, app_id));
+        db.brokers.connect(app_id, fresh).unwrap();
+        db.brokers.connect(app_id, stale).unwrap();
+        let last_seen_at = now() - STALE_AFTER_SECS - 10;
+        set_last_seen_at(&db, stale, last_seen_at);
+
+        let older_than = now() - STALE_AFTER_SECS;
+        assert_eq!(db.brokers.mark_stale(older_than).unwrap(), 1);
+        assert!(db.brokers.get(app_id, fresh).unwrap().unwrap().active);
+        let broker = db.brokers.get(app_id, stale).unwrap().unwrap();
+        assert!(!broker.active);
+        assert_eq!(broker.connected_at, None);
+        assert_eq!(broker.last_seen_at, Some(last_seen_at));
+
+        let offline = recorded(&db, app_id, "brokerOffline");
+        assert_eq!(offline.len(), 1);
+        assert_eq!(offline[0]["brokerId"], stale);
+        assert_eq!(offline[0]["lastSeenAt"], last_seen_at);
+        assert_eq!(disconnects(&db, app_id), 1);
+
+        // a stale broker has to reconnect, heartbeats don't revive it
+        let beat = heartbeat(None, None);
+        assert_eq!(db.brokers.heartbeat(app_id, stale, &beat).unwrap(), 0);
+        assert_eq!(db.brokers.mark_stale(older_than).unwrap(), 0);
+    }
+
+    #[test]
+    fn stopped_brokers_go_stale_without_going_offline() {
+        let db = testing::db();
+        let author = testing::user(&db, "alice");
+        let app_id = testing::app(&db, author);
+        let broker_id = testing::broker(&db, app_id);
+        db.brokers.connect(app_id, broker_id).unwrap();
+        db.brokers
+            .set_stopped(app_id, author, broker_id, true)
+            .unwrap();
+        set_last_seen_at(&db, broker_id, 1);
+
+        assert_eq!(db.brokers.mark_stale(now() - STALE_AFTER_SECS).unwrap(), 1);
+        assert_eq!(disconnects(&db, app_id), 1);
+        assert!(recorded(&db, app_id, "brokerOffline").is_empty());
+    }
+
+    fn policy(min: Option<&str>, max: Option<&str>) -> VersionPolicy {
+        VersionPolicy {
+            min: min.map(|min| min.parse().unwrap()),
+            max: max.map(|max| max.parse().unwrap()),
+            reject: false,
+        }
+    }
+
+    #[test]
+    fn policy_includes_min_and_excludes_max() {
+        let bounded = policy(Some("1.2.0"), Some("2.0.0"));
+        assert!(bounded.allows("1.2.0"));
+        assert!(bounded.allows("v1.9.9"));
+        assert!(!bounded.allows("1.2.0-rc.1"));
+        assert!(!bounded.allows("1.1.9"));
+        // pre-releases of the max come before it
+        assert!(bounded.allows("2.0.0-alpha"));
+        assert!(!bounded.allows("2.0.0"));
+        assert!(!bounded.allows("unknown"));
+
+        assert!(policy(Some("1.0.0"), None).allows("99.0.0"));
+        assert!(policy(None, Some("1.0.0")).allows("0.9.0"));
+        // without bounds nothing is checked
+        assert!(VersionPolicy::default().allows("unknown"));
+    }
+
+    #[test]
+    fn rollout_counts_brokers_by_version() {
+        let db = testing::db();
+        let author = testing::user(&db, "alice");
+        let app_id = testing::app(&db, author);
+        {
+            let con = db.con.lock().unwrap();
+            con.execute(
+                "UPDATE apps SET min_broker_version = '1.0.0', max_broker_version = 'junk' WHERE id = ?",
+                [app_id],
+            )
+            .unwrap();
+        }
+        for (i, version) in ["1.0.0", "0.9.0", "1.10.0", "1.0.0"].iter().enumerate() {
+            let broker_id = testing::broker(&db, app_id);
+            db.brokers
+                .set_version(app_id, broker_id, &version.parse().unwrap())
+                .unwrap();
+            if i == 0 {
+                db.brokers.connect(app_id, broker_id).unwrap();
+            }
+        }
+        let unparsed = testing::broker(&db, app_id);
+        {
+            let con = db.con.lock().unwrap();
+            con.execute(
+                "UPDATE brokers SET version = 'dev' WHERE id = ?",
+                [unparsed],
+            )
+            .unwrap();
+        }
+
+        let rollout = db.brokers.rollout(app_id, author).unwrap();
+        // unparsable bounds are ignored
+        assert!(rollout.policy.max.is_none());
+        let counts: Vec<_> = rollout
+            .versions
+            .iter()
+            .map(|count| {
+                let VersionCount {
+                    version,
+                    brokers,
+                    active,
+                    compatible,
+                } = count;
+                (version.as_str(), *brokers, *active, *compatible)
+            })
+            .collect();
+        assert_eq!(
+            counts,
+            [
+                ("1.10.0", 1, 0, true),
+                ("1.0.0", 2, 1, true),
+                ("0.9.0", 1, 0, false),
+                ("dev", 1, 0, false),
+            ]
+        );
+
+        let bob = testing::user(&db, "bob");
+        assert!(db.brokers.rollout(app_id, bob).is_err());
+    }
+
+    fn labels(labels: &[&str]) -> Vec<String> {
+        normalize_labels(labels.iter().map(|label| label.to_string()))
+    }
+
+    fn label_errors(labels: &[String]) -> Vec<String> {
+        let mut errors = ValidationErrors::default();
+        check_labels("labels", labels, &mut errors);
+        errors.errors.into_iter().map(|error| error.code).collect()
+    }
+
+    #[test]
+    fn labels_are_trimmed_lowercased_sorted_and_unique() {
+        assert_eq!(
+            labels(&[" PDF ", "db:Sales", "pdf", "", "  "]),
+            ["db:sales", "pdf"]
+        );
+        assert_eq!(split_labels(&join_labels(&labels(&["b", "a"]))), ["a", "b"]);
+        assert!(split_labels("").is_empty());
+    }
+
+    #[test]
+    fn labels_are_checked_after_normalizing() {
+        assert!(label_errors(&labels(&["db:sales", "os/linux", "gpu_2", "v1.2-x"])).is_empty());
+        assert_eq!(label_errors(&labels(&["two words"])), ["invalidLabel"]);
+        assert_eq!(label_errors(&labels(&["ünicode"])), ["invalidLabel"]);
+        let long = "x".repeat(MAX_LABEL_LENGTH);
+        assert!(label_errors(std::slice::from_ref(&long)).is_empty());
+        assert_eq!(label_errors(&[long + "x"]), ["invalidLabel"]);
+
+        let many: Vec<String> = (0..=MAX_LABELS).map(|i| format!("l{i}")).collect();
+        assert_eq!(label_errors(&many), ["tooMany"]);
+        assert!(label_errors(&many[..MAX_LABELS]).is_empty());
+    }
 }
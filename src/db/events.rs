@@ -0,0 +1,398 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use rusqlite::{Connection, Row};
+use serde::{Deserialize, Serialize};
+use tokio::sync::watch;
+
+use super::{apps::AppStatus, jobs::JobStatus, query_execute, webhooks, Con, SqlResult};
+
+/**
+    Everything that happens to an app, in the order it happened.
+    Events are written in the same transaction as the change they describe,
+    so ids can be used to resume a stream after reconnect.
+*/
+pub struct Events {
+    con: Con,
+    changes: watch::Sender<()>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(tag = "type", rename_all = "camelCase")]
+pub enum AppEvent {
+    #[serde(rename_all = "camelCase")]
+    AppUpdated { title: String, version: String },
+    #[serde(rename_all = "camelCase")]
+    StatusChanged { status: AppStatus },
+    #[serde(rename_all = "camelCase")]
+    OperatorAdded { operator_id: i32, user_id: i32 },
+    #[serde(rename_all = "camelCase")]
+    OperatorRemoved { operator_id: i32 },
+    #[serde(rename_all = "camelCase")]
+    AppUserAdded { app_user_id: i32, user_id: i32 },
+    #[serde(rename_all = "camelCase")]
+    AppUserRemoved { app_user_id: i32 },
+    #[serde(rename_all = "camelCase")]
+    BrokerAdded { broker_id: i32, name: String },
+    #[serde(rename_all = "camelCase")]
+    BrokerRemoved { broker_id: i32 },
+    #[serde(rename_all = "camelCase")]
+    BrokerUpdated { broker_id: i32, name: String },
+    /// `broker_id` and `pool_id` are `None` for the defaults of the app.
+    #[serde(rename_all = "camelCase")]
+    BrokerConfigChanged {
+        broker_id: Option<i32>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        pool_id: Option<i32>,
+        version: i64,
+    },
+    #[serde(rename_all = "camelCase")]
+    BrokerStarted { broker_id: i32 },
+    #[serde(rename_all = "camelCase")]
+    BrokerStopped { broker_id: i32 },
+    #[serde(rename_all = "camelCase")]
+    BrokerConnected { broker_id: i32 },
+    #[serde(rename_all = "camelCase")]
+    BrokerDisconnected { broker_id: i32 },
+    /// Broker runs a version outside the range of the app, see `brokers::VersionPolicy`.
+    #[serde(rename_all = "camelCase")]
+    BrokerIncompatible { broker_id: i32, version: String },
+    /// Broker that should be running stopped sending heartbeats.
+    #[serde(rename_all = "camelCase")]
+    BrokerOffline {
+        broker_id: i32,
+        last_seen_at: Option<u64>,
+    },
+    #[serde(rename_all = "camelCase")]
+    BrokerPoolAdded { pool_id: i32, name: String },
+    #[serde(rename_all = "camelCase")]
+    BrokerPoolUpdated { pool_id: i32, name: String },
+    #[serde(rename_all = "camelCase")]
+    BrokerPoolRemoved { pool_id: i32 },
+    #[serde(rename_all = "camelCase")]
+    BrokerJoinedPool { broker_id: i32, pool_id: i32 },
+    #[serde(rename_all = "camelCase")]
+    BrokerLeftPool { broker_id: i32, pool_id: i32 },
+    /// New job, or one whose broker went away.
+    #[serde(rename_all = "camelCase")]
+    JobQueued { job_id: i64, template: String },
+    #[serde(rename_all = "camelCase")]
+    JobAssigned { job_id: i64, broker_id: i32 },
+    /// No active broker in the pool of the job has the labels it requires.
+    #[serde(rename_all = "camelCase")]
+    JobNoEligibleBroker { job_id: i64, labels: Vec<String> },
+    #[serde(rename_all = "camelCase")]
+    JobFinished { job_id: i64, status: JobStatus },
+    #[serde(rename_all = "camelCase")]
+    ScheduleAdded { schedule_id: i32 },
+    #[serde(rename_all = "camelCase")]
+    ScheduleUpdated { schedule_id: i32 },
+    #[serde(rename_all = "camelCase")]
+    ScheduleRemoved { schedule_id: i32 },
+    #[serde(rename_all = "camelCase")]
+    SchedulePaused { schedule_id: i32 },
+    #[serde(rename_all = "camelCase")]
+    ScheduleResumed { schedule_id: i32 },
+    #[serde(rename_all = "camelCase")]
+    ScheduleTriggered {
+        schedule_id: i32,
+        job_id: i64,
+        scheduled_for: u64,
+    },
+    /// Run missed while the server was down, skipped because of `schedules::MissedRuns::Skip`.
+    #[serde(rename_all = "camelCase")]
+    ScheduleRunSkipped {
+        schedule_id: i32,
+        scheduled_for: u64,
+    },
+    #[serde(rename_all = "camelCase")]
+    ArtifactStored {
+        artifact_id: i64,
+        job_id: i64,
+        name: String,
+        size: u64,
+    },
+    /// Deleted, or expired by the retention of the app.
+    #[serde(rename_all = "camelCase")]
+    ArtifactRemoved { artifact_id: i64 },
+    #[serde(rename_all = "camelCase")]
+    ShareLinkCreated { share_id: i64, artifact_id: i64 },
+    #[serde(rename_all = "camelCase")]
+    ShareLinkRevoked { share_id: i64 },
+}
+
+impl AppEvent {
+    /// Name used in the `type` field, also stored separately for filtering.
+    pub fn kind(&self) -> &'static str {
+        match self {
+            AppEvent::AppUpdated { .. } => "appUpdated",
+            AppEvent::StatusChanged { .. } => "statusChanged",
+            AppEvent::OperatorAdded { .. } => "operatorAdded",
+            AppEvent::OperatorRemoved { .. } => "operatorRemoved",
+            AppEvent::AppUserAdded { .. } => "appUserAdded",
+            AppEvent::AppUserRemoved { .. } => "appUserRemoved",
+            AppEvent::BrokerAdded { .. } => "brokerAdded",
+            AppEvent::BrokerRemoved { .. } => "brokerRemoved",
+            AppEvent::BrokerUpdated { .. } => "brokerUpdated",
+            AppEvent::BrokerConfigChanged { .. } => "brokerConfigChanged",
+            AppEvent::BrokerStarted { .. } => "brokerStarted",
+            AppEvent::BrokerStopped { .. } => "brokerStopped",
+            AppEvent::BrokerConnected { .. } => "brokerConnected",
+            AppEvent::BrokerDisconnected { .. } => "brokerDisconnected",
+            AppEvent::BrokerIncompatible { .. } => "brokerIncompatible",
+            AppEvent::BrokerOffline { .. } => "brokerOffline",
+            AppEvent::BrokerPoolAdded { .. } => "brokerPoolAdded",
+            AppEvent::BrokerPoolUpdated { .. } => "brokerPoolUpdated",
+            AppEvent::BrokerPoolRemoved { .. } => "brokerPoolRemoved",
+            AppEvent::BrokerJoinedPool { .. } => "brokerJoinedPool",
+            AppEvent::BrokerLeftPool { .. } => "brokerLeftPool",
+            AppEvent::JobQueued { .. } => "jobQueued",
+            AppEvent::JobAssigned { .. } => "jobAssigned",
+            AppEvent::JobNoEligibleBroker { .. } => "jobNoEligibleBroker",
+            AppEvent::JobFinished { .. } => "jobFinished",
+            AppEvent::ScheduleAdded { .. } => "scheduleAdded",
+            AppEvent::ScheduleUpdated { .. } => "scheduleUpdated",
+            AppEvent::ScheduleRemoved { .. } => "scheduleRemoved",
+            AppEvent::SchedulePaused { .. } => "schedulePaused",
+            AppEvent::ScheduleResumed { .. } => "scheduleResumed",
+            AppEvent::ScheduleTriggered { .. } => "scheduleTriggered",
+            AppEvent::ScheduleRunSkipped { .. } => "scheduleRunSkipped",
+            AppEvent::ArtifactStored { .. } => "artifactStored",
+            AppEvent::ArtifactRemoved { .. } => "artifactRemoved",
+            AppEvent::ShareLinkCreated { .. } => "shareLinkCreated",
+            AppEvent::ShareLinkRevoked { .. } => "shareLinkRevoked",
+        }
+    }
+}
+
+/// Every `AppEvent::kind`, what webhooks can subscribe to. New events have to be added here too.
+pub const KINDS: &[&str] = &[
+    "appUpdated",
+    "statusChanged",
+    "operatorAdded",
+    "operatorRemoved",
+    "appUserAdded",
+    "appUserRemoved",
+    "brokerAdded",
+    "brokerRemoved",
+    "brokerUpdated",
+    "brokerConfigChanged",
+    "brokerStarted",
+    "brokerStopped",
+    "brokerConnected",
+    "brokerDisconnected",
+    "brokerIncompatible",
+    "brokerOffline",
+    "brokerPoolAdded",
+    "brokerPoolUpdated",
+    "brokerPoolRemoved",
+    "brokerJoinedPool",
+    "brokerLeftPool",
+    "jobQueued",
+    "jobAssigned",
+    "jobNoEligibleBroker",
+    "jobFinished",
+    "scheduleAdded",
+    "scheduleUpdated",
+    "scheduleRemoved",
+    "schedulePaused",
+    "scheduleResumed",
+    "scheduleTriggered",
+    "scheduleRunSkipped",
+    "artifactStored",
+    "artifactRemoved",
+    "shareLinkCreated",
+    "shareLinkRevoked",
+];
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct StoredEvent {
+    pub id: i64,
+    pub app_id: i32,
+    pub created_at: u64,
+    #[serde(flatten)]
+    pub event: AppEvent,
+}
+
+impl StoredEvent {
+    pub fn from_row(row: &Row) -> SqlResult<Self> {
+        let payload: String = row.get("payload")?;
+        let event = serde_json::from_str(&payload).map_err(|e| {
+            rusqlite::Error::FromSqlConversionFailure(0, rusqlite::types::Type::Text, Box::new(e))
+        })?;
+
+        Ok(Self {
+            id: row.get("id")?,
+            app_id: row.get("app_id")?,
+            created_at: row.get("created_at")?,
+            event,
+        })
+    }
+}
+
+/// How many events a stream reads from the table at once.
+const BATCH: i64 = 100;
+
+impl Events {
+    pub fn new(con: &Con) -> Self {
+        Self {
+            con: con.clone(),
+            changes: watch::Sender::new(()),
+        }
+    }
+
+    pub fn create_table(&self) -> SqlResult<usize> {
+        let con = self.con.lock().unwrap();
+        con.execute(
+            "CREATE TABLE IF NOT EXISTS app_events (
+                id INTEGER PRIMARY KEY AUTOINCREMENT,
+                app_id INTEGER,
+                kind TEXT,
+                payload TEXT,
+                created_at INTEGER
+            )",
+            [],
+        )
+    }
+
+    /// Wakes up streams, call after the transaction with new events is committed.
+    pub fn notify(&self) {
+        self.changes.send_replace(());
+    }
+
+    pub fn subscribe(&self) -> watch::Receiver<()> {
+        self.changes.subscribe()
+    }
+
+    pub fn since(&self, app_id: i32, last_id: i64) -> SqlResult<Vec<StoredEvent>> {
+        let con = self.con.lock().unwrap();
+        let mut stmt = con.prepare_cached(
+            "SELECT * FROM app_events WHERE app_id = ? AND id > ? ORDER BY id LIMIT ?",
+        )?;
+        let events = stmt
+            .query_map((app_id, last_id, BATCH), StoredEvent::from_row)?
+            .collect::<Result<_, _>>()?;
+        Ok(events)
+    }
+
+    /// Id of the newest event, streams without last-event-id start from here.
+    pub fn last_id(&self) -> SqlResult<i64> {
+        let con = self.con.lock().unwrap();
+        con.query_row("SELECT COALESCE(MAX(id), 0) FROM app_events", [], |row| {
+            row.get(0)
+        })
+    }
+}
+
+/**
+    Stores event, meant to be called inside the transaction that made the change.
+    Webhook deliveries for the event are queued in the same transaction.
+*/
+pub fn record(con: &Connection, app_id: i32, event: &AppEvent) -> SqlResult<i64> {
+    let created_at = now();
+    let payload = serde_json::to_string(event).unwrap();
+    query_execute!(con => "INSERT INTO app_events(app_id, kind, payload, created_at) VALUES (?, ?, ?, ?)",
+        (app_id, event.kind(), payload, created_at))?;
+    let id = con.last_insert_rowid();
+
+    let stored = StoredEvent {
+        id,
+        app_id,
+        created_at,
+        event: event.clone(),
+    };
+    webhooks::enqueue(con, app_id, id, event.kind(), &serde_json::to_string(&stored).unwrap())?;
+    Ok(id)
+}
+
+pub fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_secs()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::{testing, SqliteDb};
+
+    fn record_all(db: &SqliteDb, app_id: i32, count: i32) {
+        for broker_id in 0..count {
+            testing::event(db, app_id, &AppEvent::BrokerStarted { broker_id });
+        }
+    }
+
+    #[test]
+    fn kinds_are_unique_and_match_the_events() {
+        let mut kinds = KINDS.to_vec();
+        kinds.sort();
+        kinds.dedup();
+        assert_eq!(kinds.len(), KINDS.len());
+        let events = [
+            AppEvent::BrokerStarted { broker_id: 1 },
+            AppEvent::JobAssigned {
+                job_id: 1,
+                broker_id: 1,
+            },
+            AppEvent::ShareLinkRevoked { share_id: 1 },
+        ];
+        for event in events {
+            assert!(KINDS.contains(&event.kind()));
+        }
+    }
+
+    fn ids(events: &[StoredEvent]) -> Vec<i64> {
+        events.iter().map(|event| event.id).collect()
+    }
+
+    #[test]
+    fn since_returns_events_of_the_app_in_order_after_the_id() {
+        let db = testing::db();
+        let user_id = testing::user(&db, "alice");
+        let (app, other_app) = (testing::app(&db, user_id), testing::app(&db, user_id));
+        record_all(&db, app, 3);
+        record_all(&db, other_app, 2);
+        record_all(&db, app, 1);
+
+        let events = db.events.since(app, 0).unwrap();
+        assert_eq!(ids(&events), [1, 2, 3, 6]);
+        assert!(events.iter().all(|event| event.app_id == app));
+
+        assert_eq!(ids(&db.events.since(app, 2).unwrap()), [3, 6]);
+        assert!(db.events.since(app, 6).unwrap().is_empty());
+        assert_eq!(db.events.last_id().unwrap(), 6);
+    }
+
+    #[test]
+    fn since_reads_in_batches() {
+        let db = testing::db();
+        let user_id = testing::user(&db, "alice");
+        let app = testing::app(&db, user_id);
+        record_all(&db, app, BATCH as i32 + 5);
+
+        let first = db.events.since(app, 0).unwrap();
+        assert_eq!(first.len(), BATCH as usize);
+        let rest = db.events.since(app, first.last().unwrap().id).unwrap();
+        assert_eq!(rest.len(), 5);
+    }
+
+    #[test]
+    fn stored_events_serialize_flat_with_kind_as_type() {
+        let db = testing::db();
+        let user_id = testing::user(&db, "alice");
+        let app = testing::app(&db, user_id);
+        let event = AppEvent::JobAssigned {
+            job_id: 7,
+            broker_id: 3,
+        };
+        testing::event(&db, app, &event);
+
+        let stored = &db.events.since(app, 0).unwrap()[0];
+        let json = serde_json::to_value(stored).unwrap();
+        assert_eq!(json["type"], event.kind());
+        assert_eq!(json["jobId"], 7);
+        assert_eq!(json["brokerId"], 3);
+        assert_eq!(json["appId"], app);
+    }
+}
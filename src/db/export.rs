@@ -0,0 +1,304 @@
+use axum_utils::impl_from_row;
+use rusqlite::{Connection, Row};
+use serde::{Deserialize, Serialize};
+
+use super::two_factor::{RecoveryCodeRecord, TotpRecord};
+use super::{brokers::Broker, query_execute, query_rows, SqlResult};
+use crate::crypto::{encrypt_secret, is_sealed, random_token};
+
+/**
+    Backend independent dump of the whole database.
+    Rows keep their ids, so references between tables survive the round trip.
+    Soft deleted rows are not exported.
+*/
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Export {
+    pub schema_version: usize,
+    pub users: Vec<UserRecord>,
+    pub apps: Vec<AppRecord>,
+    pub operators: Vec<MembershipRecord>,
+    pub app_users: Vec<MembershipRecord>,
+    pub brokers: Vec<Broker>,
+    /// Missing in exports made before 2FA existed.
+    #[serde(default)]
+    pub totp: Vec<TotpRecord>,
+    #[serde(default)]
+    pub recovery_codes: Vec<RecoveryCodeRecord>,
+}
+
+impl Export {
+    /**
+        Same data without password hashes, TOTP secrets and recovery codes, for dumps that
+        leave the server. Imported users need a password reset and have to enrol 2FA again.
+    */
+    pub fn without_credentials(self) -> Self {
+        Self {
+            users: self
+                .users
+                .into_iter()
+                .map(|user| UserRecord {
+                    password: None,
+                    ..user
+                })
+                .collect(),
+            totp: Vec::new(),
+            recovery_codes: Vec::new(),
+            ..self
+        }
+    }
+}
+
+/**
+    Row of `users`. The only type that serializes password hashes,
+    restoring a backup would log everyone out otherwise.
+    Exports made before the fields were camel case still import.
+*/
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct UserRecord {
+    pub id: i32,
+    pub name: String,
+    pub username: String,
+    /// `None` in exports without credentials, see `Export::without_credentials`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub password: Option<String>,
+    pub admin: bool,
+    pub email: Option<String>,
+    #[serde(default, alias = "avatar_url")]
+    pub avatar_url: Option<String>,
+    #[serde(default)]
+    pub bio: Option<String>,
+    #[serde(default = "searchable_default")]
+    pub searchable: bool,
+    #[serde(alias = "created_at")]
+    pub created_at: u64,
+    #[serde(alias = "updated_at")]
+    pub updated_at: u64,
+}
+
+fn searchable_default() -> bool {
+    true
+}
+
+impl_from_row!(UserRecord {
+    id,
+    name,
+    username,
+    password,
+    admin,
+    email,
+    avatar_url,
+    bio,
+    searchable,
+    created_at,
+    updated_at
+});
+
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct AppRecord {
+    pub id: i32,
+    pub author_id: i32,
+    pub title: String,
+    pub description: String,
+    pub weblink: String,
+    pub version: String,
+    pub public: bool,
+    pub status: usize,
+    #[serde(default)]
+    pub require_two_factor: bool,
+    #[serde(default)]
+    pub min_broker_version: Option<String>,
+    #[serde(default)]
+    pub max_broker_version: Option<String>,
+    #[serde(default)]
+    pub reject_incompatible_brokers: bool,
+    pub created_at: u64,
+    pub updated_at: u64,
+}
+
+impl_from_row!(AppRecord {
+    id,
+    author_id,
+    title,
+    description,
+    weblink,
+    version,
+    public,
+    status,
+    require_two_factor,
+    min_broker_version,
+    max_broker_version,
+    reject_incompatible_brokers,
+    created_at,
+    updated_at
+});
+
+/// Row of `operators` or `app_users`.
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct MembershipRecord {
+    pub id: i32,
+    pub app_id: i32,
+    pub user_id: i32,
+    pub created_at: u64,
+    pub updated_at: u64,
+}
+
+impl_from_row!(MembershipRecord {
+    id,
+    app_id,
+    user_id,
+    created_at,
+    updated_at
+});
+
+pub fn export(con: &Connection) -> SqlResult<Export> {
+    Ok(Export {
+        schema_version: super::migrations::version(con)?,
+        users: query_rows!(con => "SELECT * FROM users WHERE deleted_at IS NULL ORDER BY id", [], UserRecord),
+        apps: query_rows!(con => "SELECT * FROM apps WHERE deleted_at IS NULL ORDER BY id", [], AppRecord),
+        operators: query_rows!(con => "SELECT * FROM operators WHERE deleted_at IS NULL ORDER BY id", [], MembershipRecord),
+        app_users: query_rows!(con => "SELECT * FROM app_users WHERE deleted_at IS NULL ORDER BY id", [], MembershipRecord),
+        brokers: query_rows!(con => "SELECT * FROM brokers WHERE deleted_at IS NULL ORDER BY id", [], Broker),
+        totp: query_rows!(con => "
+            SELECT user_totp.* FROM user_totp JOIN users ON users.id = user_totp.user_id
+            WHERE users.deleted_at IS NULL ORDER BY user_id", [], TotpRecord),
+        recovery_codes: query_rows!(con => "
+            SELECT recovery_codes.* FROM recovery_codes JOIN users ON users.id = recovery_codes.user_id
+            WHERE users.deleted_at IS NULL ORDER BY recovery_codes.id", [], RecoveryCodeRecord),
+    })
+}
+
+pub fn import(con: &mut Connection, data: Export) -> SqlResult<()> {
+    let tx = con.transaction()?;
+
+    for user in data.users {
+        // not a bcrypt hash, so no password matches until it is reset
+        let password = user.password.unwrap_or_else(|| random_token(32));
+        query_execute!(tx => "INSERT INTO users(id, name, username, password, admin, email, avatar_url, bio, searchable, created_at, updated_at) VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?)",
+            (user.id, user.name, user.username, password, user.admin, user.email, user.avatar_url, user.bio, user.searchable, user.created_at, user.updated_at))?;
+    }
+    for app in data.apps {
+        query_execute!(tx => "INSERT INTO apps(id, author_id, title, description, weblink, version, public, status, require_two_factor, min_broker_version, max_broker_version, reject_incompatible_brokers, created_at, updated_at) VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?)",
+            (app.id, app.author_id, app.title, app.description, app.weblink, app.version, app.public, app.status, app.require_two_factor, app.min_broker_version, app.max_broker_version, app.reject_incompatible_brokers, app.created_at, app.updated_at))?;
+    }
+    for operator in data.operators {
+        query_execute!(tx => "INSERT INTO operators(id, app_id, user_id, created_at, updated_at) VALUES (?, ?, ?, ?, ?)",
+            (operator.id, operator.app_id, operator.user_id, operator.created_at, operator.updated_at))?;
+    }
+    for app_user in data.app_users {
+        query_execute!(tx => "INSERT INTO app_users(id, app_id, user_id, created_at, updated_at) VALUES (?, ?, ?, ?, ?)",
+            (app_user.id, app_user.app_id, app_user.user_id, app_user.created_at, app_user.updated_at))?;
+    }
+    for broker in data.brokers {
+        query_execute!(tx => "INSERT INTO brokers(id, app_id, name, description, version, active, stopped, created_at, updated_at) VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?)",
+            (broker.id, broker.app_id, broker.name, broker.description, broker.version, broker.active, broker.stopped, broker.created_at, broker.updated_at))?;
+    }
+    // sealed secrets only open with the same secrets key, dumps of older versions have them in plain text
+    for totp in data.totp {
+        let secret = match is_sealed(&totp.secret) {
+            true => totp.secret,
+            false => encrypt_secret(&totp.secret),
+        };
+        query_execute!(tx => "INSERT INTO user_totp(user_id, secret, enabled, last_step, created_at) VALUES (?, ?, ?, ?, ?)",
+            (totp.user_id, secret, totp.enabled, totp.last_step, totp.created_at))?;
+    }
+    for code in data.recovery_codes {
+        query_execute!(tx => "INSERT INTO recovery_codes(id, user_id, code_hash, used_at) VALUES (?, ?, ?, ?)",
+            (code.id, code.user_id, code.code_hash, code.used_at))?;
+    }
+
+    tx.commit()
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::{json, Value};
+
+    use super::*;
+    use crate::crypto::{decrypt_secret, totp_secret};
+    use crate::db::{testing, SqliteDb};
+
+    /// Alice owns an app with Bob as operator and has 2FA, Carol is deleted.
+    fn populated() -> (SqliteDb, i32) {
+        let db = testing::db();
+        let (alice, bob) = (testing::user(&db, "alice"), testing::user(&db, "bob"));
+        let carol = testing::user(&db, "carol");
+        let app_id = testing::app(&db, alice);
+        db.operators.create(app_id, alice, bob).unwrap();
+        db.users.set_password("alice", "Tr1cky-Enough").unwrap();
+        db.two_factor.enroll(alice, &totp_secret()).unwrap();
+        {
+            let con = db.con.lock().unwrap();
+            con.execute("UPDATE user_totp SET enabled = 1", []).unwrap();
+            con.execute(
+                "INSERT INTO recovery_codes(user_id, code_hash) VALUES (?, 'hash')",
+                [alice],
+            )
+            .unwrap();
+            con.execute("UPDATE users SET deleted_at = 1 WHERE id = ?", [carol])
+                .unwrap();
+        }
+        (db, alice)
+    }
+
+    fn to_value(export: &Export) -> Value {
+        serde_json::to_value(export).unwrap()
+    }
+
+    #[test]
+    fn import_restores_what_was_exported() {
+        let (db, alice) = populated();
+        let exported = db.export().unwrap();
+        let usernames: Vec<&str> = exported.users.iter().map(|u| u.username.as_str()).collect();
+        assert_eq!(usernames, ["alice", "bob"]);
+
+        let copy = testing::db();
+        copy.import(db.export().unwrap()).unwrap();
+        assert_eq!(to_value(&copy.export().unwrap()), to_value(&exported));
+        assert!(copy.users.verify_password(alice, "Tr1cky-Enough").unwrap());
+        assert!(copy.two_factor.is_enabled(alice).unwrap());
+        assert!(copy.import(db.export().unwrap()).is_err());
+    }
+
+    #[test]
+    fn export_without_credentials_imports_locked_accounts() {
+        let (db, alice) = populated();
+        let exported = db.export().unwrap().without_credentials();
+        let value = to_value(&exported);
+        assert!(value["users"][0].get("password").is_none());
+        assert_eq!(value["totp"], json!([]));
+        assert_eq!(value["recoveryCodes"], json!([]));
+
+        let copy = testing::db();
+        copy.import(exported).unwrap();
+        assert!(!copy.users.verify_password(alice, "Tr1cky-Enough").unwrap());
+        assert!(!copy.two_factor.is_enabled(alice).unwrap());
+        assert_eq!(copy.operators.for_app(1, alice).unwrap().len(), 1);
+    }
+
+    #[test]
+    fn old_exports_still_import() {
+        let secret = totp_secret();
+        let old = json!({
+            "schemaVersion": 1,
+            "users": [{
+                "id": 1, "name": "Alice", "username": "alice", "password": "", "admin": false,
+                "email": null, "created_at": 1, "updated_at": 2
+            }],
+            "apps": [], "operators": [], "appUsers": [], "brokers": [],
+            "totp": [{ "userId": 1, "secret": secret, "enabled": true, "lastStep": 0, "createdAt": 1 }]
+        });
+        let db = testing::db();
+        db.import(serde_json::from_value(old).unwrap()).unwrap();
+
+        let exported = db.export().unwrap();
+        assert!(exported.users[0].searchable);
+        assert_eq!(exported.users[0].updated_at, 2);
+        let sealed = &exported.totp[0].secret;
+        assert!(is_sealed(sealed));
+        assert_eq!(decrypt_secret(sealed), Some(secret));
+    }
+}
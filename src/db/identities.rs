@@ -0,0 +1,208 @@
+use axum_utils::{copy, copy_mut, impl_from_row};
+use rusqlite::{Connection, Error, ErrorCode, OptionalExtension, Row};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+
+use super::audit::{self, Change};
+use super::events::now;
+use super::users::{insert_user, NewUser, RegistrationError};
+use super::{query_execute, query_row, query_rows, Con, SqlResult};
+
+/**
+    Accounts at single sign-on providers, linked to local users.
+    An identity is the `sub` claim of its issuer and belongs to exactly one user.
+*/
+pub struct Identities {
+    con: Con,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ExternalIdentity {
+    pub id: i32,
+    pub user_id: i32,
+    pub issuer: String,
+    pub subject: String,
+    pub email: Option<String>,
+    pub created_at: u64,
+    pub last_login_at: Option<u64>,
+}
+
+impl_from_row!(ExternalIdentity {
+    id,
+    user_id,
+    issuer,
+    subject,
+    email,
+    created_at,
+    last_login_at
+});
+
+/// Local user an identity is linked to.
+pub enum IdentityOwner {
+    User(i32),
+    /// Linked to a deleted account, the link goes away when the account is purged.
+    Deleted,
+}
+
+impl Identities {
+    pub fn new(con: &Con) -> Self {
+        Self { con: con.clone() }
+    }
+
+    copy!(create_table() -> SqlResult<usize>);
+    copy!(user_for(issuer: &str, subject: &str) -> SqlResult<Option<IdentityOwner>>);
+    copy!(for_user(user_id: i32) -> SqlResult<Vec<ExternalIdentity>>);
+    copy_mut!(link(user_id: i32, issuer: &str, subject: &str, email: Option<&str>) -> SqlResult<usize>);
+    copy_mut!(unlink(user_id: i32, identity_id: i32) -> SqlResult<usize>);
+    copy_mut!(provision(user: &NewUser, issuer: &str, subject: &str, email: Option<&str>) -> Result<i32, RegistrationError>);
+    copy_mut!(logged_in(user_id: i32, issuer: &str, subject: &str, admin: Option<bool>) -> SqlResult<usize>);
+}
+
+fn create_table(con: &Connection) -> SqlResult<usize> {
+    con.execute(
+        "CREATE TABLE IF NOT EXISTS external_identities (
+            id INTEGER PRIMARY KEY,
+            user_id INTEGER,
+            issuer TEXT,
+            subject TEXT,
+            email TEXT,
+            created_at INTEGER,
+            last_login_at INTEGER,
+            UNIQUE(issuer, subject),
+            FOREIGN KEY(user_id) REFERENCES users(id)
+        )",
+        [],
+    )
+}
+
+fn user_for(con: &Connection, issuer: &str, subject: &str) -> SqlResult<Option<IdentityOwner>> {
+    let mut stmt = con.prepare_cached(
+        "SELECT users.id, users.deleted_at IS NOT NULL FROM external_identities
+        JOIN users ON users.id = external_identities.user_id
+        WHERE issuer = ? AND subject = ?",
+    )?;
+    stmt.query_row([issuer, subject], |row| {
+        Ok(match row.get(1)? {
+            true => IdentityOwner::Deleted,
+            false => IdentityOwner::User(row.get(0)?),
+        })
+    })
+    .optional()
+}
+
+fn for_user(con: &Connection, user_id: i32) -> SqlResult<Vec<ExternalIdentity>> {
+    Ok(
+        query_rows!(con => "SELECT * FROM external_identities WHERE user_id = ? ORDER BY id", [user_id], ExternalIdentity),
+    )
+}
+
+/// Returns 0 if the identity already belongs to a user.
+fn link(
+    con: &mut Connection,
+    user_id: i32,
+    issuer: &str,
+    subject: &str,
+    email: Option<&str>,
+) -> SqlResult<usize> {
+    let tx = con.transaction()?;
+    let result = unchecked_link(&tx, user_id, issuer, subject, email);
+    let result = match result {
+        Err(Error::SqliteFailure(e, _)) if e.code == ErrorCode::ConstraintViolation => {
+            return Ok(0)
+        }
+        result => result?,
+    };
+    let change = Change {
+        actor_id: Some(user_id),
+        app_id: None,
+        action: "user.identityLink",
+        target: format!("user:{user_id}"),
+        before: None,
+        after: Some(json!({ "issuer": issuer, "subject": subject })),
+    };
+    audit::record(&tx, change)?;
+    tx.commit()?;
+    Ok(result)
+}
+
+fn unlink(con: &mut Connection, user_id: i32, identity_id: i32) -> SqlResult<usize> {
+    let tx = con.transaction()?;
+    let before = query_row!(tx => "SELECT * FROM external_identities WHERE id = ? AND user_id = ?", [identity_id, user_id], ExternalIdentity).optional()?;
+    let result = query_execute!(tx => "DELETE FROM external_identities WHERE id = ? AND user_id = ?", [identity_id, user_id])?;
+    if let Some(before) = before {
+        let change = Change {
+            actor_id: Some(user_id),
+            app_id: None,
+            action: "user.identityUnlink",
+            target: format!("user:{user_id}"),
+            before: Some(json!({ "issuer": before.issuer, "subject": before.subject })),
+            after: None,
+        };
+        audit::record(&tx, change)?;
+    }
+    tx.commit()?;
+    Ok(result)
+}
+
+/// Creates a user for an identity seen for the first time, both or neither.
+fn provision(
+    con: &mut Connection,
+    user: &NewUser,
+    issuer: &str,
+    subject: &str,
+    email: Option<&str>,
+) -> Result<i32, RegistrationError> {
+    let tx = con.transaction()?;
+    let user_id = insert_user(&tx, user)?;
+    unchecked_link(&tx, user_id, issuer, subject, email)?;
+    tx.commit()?;
+    Ok(user_id)
+}
+
+/**
+    Remembers the login. `admin` comes from the provider groups, when set
+    the admin flag of the user follows it.
+*/
+fn logged_in(
+    con: &mut Connection,
+    user_id: i32,
+    issuer: &str,
+    subject: &str,
+    admin: Option<bool>,
+) -> SqlResult<usize> {
+    let tx = con.transaction()?;
+    let result = query_execute!(tx => "
+        UPDATE external_identities SET last_login_at = ? WHERE issuer = ? AND subject = ?",
+        (now(), issuer, subject)
+    )?;
+    if let Some(admin) = admin {
+        let changed = query_execute!(tx => "UPDATE users SET admin = ? WHERE id = ? AND admin != ?", (admin, user_id, admin))?;
+        if changed == 1 {
+            let change = Change {
+                actor_id: None,
+                app_id: None,
+                action: "user.admin",
+                target: format!("user:{user_id}"),
+                before: Some(json!({ "admin": !admin })),
+                after: Some(json!({ "admin": admin, "source": issuer })),
+            };
+            audit::record(&tx, change)?;
+        }
+    }
+    tx.commit()?;
+    Ok(result)
+}
+
+fn unchecked_link(
+    con: &Connection,
+    user_id: i32,
+    issuer: &str,
+    subject: &str,
+    email: Option<&str>,
+) -> SqlResult<usize> {
+    query_execute!(con => "
+        INSERT INTO external_identities(user_id, issuer, subject, email, created_at) VALUES (?, ?, ?, ?, ?)",
+        (user_id, issuer, subject, email, now())
+    )
+}
@@ -0,0 +1,764 @@
+use std::collections::{hash_map::Entry, HashMap};
+
+use axum_utils::{copy, copy_mut};
+use rusqlite::{Connection, OptionalExtension, Row};
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+
+use super::apps::has_permission;
+use super::audit::{self, snapshot, Change};
+use super::brokers::{self, check_labels, join_labels, normalize_labels, split_labels, Broker};
+use super::events::{self, now, AppEvent};
+use super::{broker_configs, broker_pools};
+use super::{query_execute, query_row, query_rows, Con, SqlResult};
+use crate::validation::ValidationErrors;
+
+const MAX_TEMPLATE_LENGTH: usize = 255;
+/// Longer errors reported by brokers are cut.
+const MAX_ERROR_LENGTH: usize = 1000;
+const DEFAULT_LIMIT: usize = 50;
+const MAX_LIMIT: usize = 500;
+
+/**
+    Report runs of an app. New jobs wait in `queued` until `dispatch`
+    hands them to an active broker that has every label the job requires,
+    the connected broker is told through its websocket and reports back.
+*/
+pub struct Jobs {
+    con: Con,
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Debug)]
+#[serde(rename_all = "camelCase")]
+pub enum JobStatus {
+    Queued,
+    /// No active broker qualifies for the job, it stays until one connects.
+    NoEligibleBroker,
+    Assigned,
+    Running,
+    Completed,
+    Failed,
+    Cancelled,
+}
+
+impl JobStatus {
+    const ALL: [JobStatus; 7] = [
+        JobStatus::Queued,
+        JobStatus::NoEligibleBroker,
+        JobStatus::Assigned,
+        JobStatus::Running,
+        JobStatus::Completed,
+        JobStatus::Failed,
+        JobStatus::Cancelled,
+    ];
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            JobStatus::Queued => "queued",
+            JobStatus::NoEligibleBroker => "noEligibleBroker",
+            JobStatus::Assigned => "assigned",
+            JobStatus::Running => "running",
+            JobStatus::Completed => "completed",
+            JobStatus::Failed => "failed",
+            JobStatus::Cancelled => "cancelled",
+        }
+    }
+
+    pub(super) fn parse(status: &str) -> Option<JobStatus> {
+        JobStatus::ALL
+            .into_iter()
+            .find(|known| known.as_str() == status)
+    }
+
+    pub fn is_finished(self) -> bool {
+        matches!(
+            self,
+            JobStatus::Completed | JobStatus::Failed | JobStatus::Cancelled
+        )
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct Job {
+    pub id: i64,
+    pub app_id: i32,
+    pub template: String,
+    pub parameters: Value,
+    /// Broker labels the job requires, see `brokers::check_labels`.
+    pub labels: Vec<String>,
+    /// Only brokers of this pool may run the job.
+    pub pool_id: Option<i32>,
+    /// Schedule the job was queued by, see `schedules::run_due`.
+    pub schedule_id: Option<i32>,
+    pub status: JobStatus,
+    /// Broker the job is or was assigned to.
+    pub broker_id: Option<i32>,
+    /// Reported by the broker when the job failed.
+    pub error: Option<String>,
+    pub created_by: Option<i32>,
+    pub created_at: u64,
+    pub assigned_at: Option<u64>,
+    pub finished_at: Option<u64>,
+}
+
+impl Job {
+    pub fn from_row(row: &Row) -> SqlResult<Self> {
+        let conversion = |e: Box<dyn std::error::Error + Send + Sync>| {
+            rusqlite::Error::FromSqlConversionFailure(0, rusqlite::types::Type::Text, e)
+        };
+        let parameters: String = row.get("parameters")?;
+        let status: String = row.get("status")?;
+        Ok(Self {
+            id: row.get("id")?,
+            app_id: row.get("app_id")?,
+            template: row.get("template")?,
+            parameters: serde_json::from_str(&parameters).map_err(|e| conversion(Box::new(e)))?,
+            labels: split_labels(&row.get::<_, String>("labels")?),
+            pool_id: row.get("pool_id")?,
+            schedule_id: row.get("schedule_id")?,
+            status: JobStatus::parse(&status)
+                .ok_or_else(|| conversion(format!("unknown job status '{status}'").into()))?,
+            broker_id: row.get("broker_id")?,
+            error: row.get("error")?,
+            created_by: row.get("created_by")?,
+            created_at: row.get("created_at")?,
+            assigned_at: row.get("assigned_at")?,
+            finished_at: row.get("finished_at")?,
+        })
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct NewJob {
+    pub template: String,
+    #[serde(default)]
+    pub parameters: Map<String, Value>,
+    #[serde(default)]
+    pub labels: Vec<String>,
+    pub pool_id: Option<i32>,
+}
+
+impl NewJob {
+    /// Labels are normalized, every problem is reported.
+    pub fn validate(mut self) -> Result<Self, ValidationErrors> {
+        let mut errors = ValidationErrors::default();
+        self.check(&mut errors);
+        errors.into_result(self)
+    }
+
+    /// Same as `validate`, for bodies that contain a job.
+    pub fn check(&mut self, errors: &mut ValidationErrors) {
+        self.template = self.template.trim().to_string();
+        if self.template.is_empty() {
+            errors.add("template", "required", "template is required");
+        } else if self.template.chars().count() > MAX_TEMPLATE_LENGTH {
+            let message = format!("template must be at most {MAX_TEMPLATE_LENGTH} characters");
+            errors.add("template", "tooLong", &message);
+        }
+        self.labels = normalize_labels(std::mem::take(&mut self.labels));
+        check_labels("labels", &self.labels, errors);
+    }
+}
+
+/// Filters of the job listing, newest jobs come first.
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct JobQuery {
+    pub status: Option<JobStatus>,
+    /// For paging, jobs older than this one.
+    pub before_id: Option<i64>,
+    pub limit: Option<usize>,
+}
+
+/// Sent by the broker running the job, only `running`, `completed` and `failed` are taken.
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct JobReport {
+    pub job_id: i64,
+    pub status: JobStatus,
+    pub error: Option<String>,
+}
+
+impl Jobs {
+    pub fn new(con: &Con) -> Self {
+        Self { con: con.clone() }
+    }
+
+    copy!(create_table() -> SqlResult<usize>);
+    copy_mut!(create(app_id: i32, user_id: i32, new_job: NewJob) -> SqlResult<Option<Job>>);
+    copy!(for_app(app_id: i32, user_id: i32, query: &JobQuery) -> SqlResult<Vec<Job>>);
+    copy!(get(app_id: i32, user_id: i32, job_id: i64) -> SqlResult<Option<Job>>);
+    copy_mut!(cancel(app_id: i32, user_id: i32, job_id: i64) -> SqlResult<Option<Job>>);
+    copy!(by_id(job_id: i64) -> SqlResult<Option<Job>>);
+    copy!(for_broker(broker_id: i32) -> SqlResult<Vec<Job>>);
+    copy_mut!(report(app_id: i32, broker_id: i32, job_report: &JobReport) -> SqlResult<usize>);
+    copy_mut!(dispatch() -> SqlResult<usize>);
+}
+
+fn create_table(con: &Connection) -> SqlResult<usize> {
+    con.execute(
+        "CREATE TABLE IF NOT EXISTS jobs (
+            id INTEGER PRIMARY KEY AUTOINCREMENT,
+            app_id INTEGER,
+            template TEXT,
+            parameters TEXT,
+            labels TEXT,
+            status TEXT,
+            broker_id INTEGER,
+            error TEXT,
+            created_by INTEGER,
+            created_at INTEGER,
+            assigned_at INTEGER,
+            finished_at INTEGER,
+            FOREIGN KEY(app_id) REFERENCES apps(id),
+            FOREIGN KEY(broker_id) REFERENCES brokers(id)
+        )",
+        [],
+    )?;
+    con.execute(
+        "CREATE INDEX IF NOT EXISTS jobs_status ON jobs(status, id)",
+        [],
+    )
+}
+
+/// Queues the job without permission checks, `pool_id` has to be a pool of the app.
+pub(super) fn insert(
+    con: &Connection,
+    app_id: i32,
+    created_by: Option<i32>,
+    schedule_id: Option<i32>,
+    new_job: &NewJob,
+) -> SqlResult<Job> {
+    let parameters = Value::Object(new_job.parameters.clone()).to_string();
+    query_execute!(con => "
+        INSERT INTO jobs(app_id, template, parameters, labels, pool_id, schedule_id, status, created_by, created_at) VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?)",
+        (
+            app_id,
+            &new_job.template,
+            parameters,
+            join_labels(&new_job.labels),
+            new_job.pool_id,
+            schedule_id,
+            JobStatus::Queued.as_str(),
+            created_by,
+            now()
+        )
+    )?;
+    let job_id = con.last_insert_rowid();
+
+    let event = AppEvent::JobQueued {
+        job_id,
+        template: new_job.template.clone(),
+    };
+    events::record(con, app_id, &event)?;
+    Ok(by_id(con, job_id)?.unwrap())
+}
+
+/// `None` if the job targets a pool the app does not have.
+fn create(
+    con: &mut Connection,
+    app_id: i32,
+    user_id: i32,
+    new_job: NewJob,
+) -> SqlResult<Option<Job>> {
+    let tx = con.transaction()?;
+    has_permission(&tx, app_id, user_id)?;
+    if !broker_pools::exists(&tx, app_id, new_job.pool_id)? {
+        return Ok(None);
+    }
+    let job = insert(&tx, app_id, Some(user_id), None, &new_job)?;
+    let job_id = job.id;
+
+    let change = Change {
+        actor_id: Some(user_id),
+        app_id: Some(app_id),
+        action: "job.create",
+        target: format!("job:{job_id}"),
+        before: None,
+        after: snapshot(&job),
+    };
+    audit::record(&tx, change)?;
+    tx.commit()?;
+    Ok(Some(job))
+}
+
+fn for_app(con: &Connection, app_id: i32, user_id: i32, query: &JobQuery) -> SqlResult<Vec<Job>> {
+    has_permission(con, app_id, user_id)?;
+    let limit = query.limit.unwrap_or(DEFAULT_LIMIT).clamp(1, MAX_LIMIT);
+    Ok(query_rows!(con => "
+        SELECT * FROM jobs WHERE app_id = ? AND (?2 IS NULL OR status = ?2) AND id < ?
+        ORDER BY id DESC LIMIT ?",
+        (
+            app_id,
+            query.status.map(JobStatus::as_str),
+            query.before_id.unwrap_or(i64::MAX),
+            limit
+        ),
+        Job
+    ))
+}
+
+fn get(con: &Connection, app_id: i32, user_id: i32, job_id: i64) -> SqlResult<Option<Job>> {
+    has_permission(con, app_id, user_id)?;
+    Ok(by_id(con, job_id)?.filter(|job| job.app_id == app_id))
+}
+
+/// Job by id, no permission checks.
+fn by_id(con: &Connection, job_id: i64) -> SqlResult<Option<Job>> {
+    query_row!(con => "SELECT * FROM jobs WHERE id = ?", [job_id], Job).optional()
+}
+
+/// Nothing is recorded if the job already finished, a broker running it is told to stop.
+fn cancel(con: &mut Connection, app_id: i32, user_id: i32, job_id: i64) -> SqlResult<Option<Job>> {
+    let tx = con.transaction()?;
+    has_permission(&tx, app_id, user_id)?;
+    let Some(before) = by_id(&tx, job_id)?.filter(|job| job.app_id == app_id) else {
+        return Ok(None);
+    };
+    if before.status.is_finished() {
+        return Ok(Some(before));
+    }
+    query_execute!(tx => "UPDATE jobs SET status = ?, finished_at = ? WHERE id = ?",
+        (JobStatus::Cancelled.as_str(), now(), job_id))?;
+    let after = by_id(&tx, job_id)?.unwrap();
+
+    let event = AppEvent::JobFinished {
+        job_id,
+        status: JobStatus::Cancelled,
+    };
+    events::record(&tx, app_id, &event)?;
+    let change = Change {
+        actor_id: Some(user_id),
+        app_id: Some(app_id),
+        action: "job.cancel",
+        target: format!("job:{job_id}"),
+        before: snapshot(&before),
+        after: snapshot(&after),
+    };
+    audit::record(&tx, change)?;
+    tx.commit()?;
+    Ok(Some(after))
+}
+
+/// Jobs assigned to or running on the broker, oldest first.
+fn for_broker(con: &Connection, broker_id: i32) -> SqlResult<Vec<Job>> {
+    Ok(query_rows!(con => "
+        SELECT * FROM jobs WHERE broker_id = ? AND status IN ('assigned', 'running') ORDER BY id",
+        [broker_id], Job))
+}
+
+/// Progress of a job the broker was given, reports about other jobs are ignored.
+fn report(
+    con: &mut Connection,
+    app_id: i32,
+    broker_id: i32,
+    report: &JobReport,
+) -> SqlResult<usize> {
+    let tx = con.transaction()?;
+    let error = report
+        .error
+        .as_deref()
+        .map(|error| error.chars().take(MAX_ERROR_LENGTH).collect::<String>());
+    let finished_at = report.status.is_finished().then(now);
+    let result = match report.status {
+        JobStatus::Running | JobStatus::Completed | JobStatus::Failed => query_execute!(tx => "
+                UPDATE jobs SET status = ?, error = ?, finished_at = ?
+                WHERE id = ? AND app_id = ? AND broker_id = ? AND status IN ('assigned', 'running')",
+            (report.status.as_str(), error, finished_at, report.job_id, app_id, broker_id)
+        )?,
+        _ => 0,
+    };
+    if result > 0 && finished_at.is_some() {
+        let event = AppEvent::JobFinished {
+            job_id: report.job_id,
+            status: report.status,
+        };
+        events::record(&tx, app_id, &event)?;
+    }
+    tx.commit()?;
+    Ok(result)
+}
+
+/// Broker that can take jobs right now, with the jobs it already has.
+struct Candidate {
+    broker: Broker,
+    jobs: usize,
+    capacity: usize,
+}
+
+impl Candidate {
+    /// Broker is in the pool of the job, if it has one, and has all its labels.
+    fn accepts(&self, job: &Job) -> bool {
+        job.pool_id
+            .is_none_or(|pool_id| self.broker.pool_id == Some(pool_id))
+            && job
+                .labels
+                .iter()
+                .all(|label| self.broker.labels.contains(label))
+    }
+}
+
+/// Active, started brokers of the app whose version the app accepts.
+fn candidates(con: &Connection, app_id: i32) -> SqlResult<Vec<Candidate>> {
+    let policy = brokers::version_policy(con, app_id)?;
+    let online = query_rows!(con => "
+        SELECT * FROM brokers WHERE app_id = ? AND active = 1 AND stopped = 0 AND deleted_at IS NULL",
+        [app_id], Broker
+    );
+    let mut candidates = Vec::new();
+    for broker in online {
+        if !policy.allows(&broker.version) {
+            continue;
+        }
+        let jobs = con.query_row(
+            "SELECT COUNT(*) FROM jobs WHERE broker_id = ? AND status IN ('assigned', 'running')",
+            [broker.id],
+            |row| row.get(0),
+        )?;
+        let capacity = broker_configs::concurrency(con, app_id, broker.id)?.unwrap_or(1) as usize;
+        candidates.push(Candidate {
+            broker,
+            jobs,
+            capacity,
+        });
+    }
+    Ok(candidates)
+}
+
+/**
+    Routes waiting jobs, oldest first, returns how many jobs changed.
+    Jobs of brokers that went offline or were deleted are queued again.
+    A job goes to the broker with the fewest unfinished jobs among those in its pool,
+    having all its labels and room below their `concurrency`, lowest reported load breaks ties.
+    Jobs no broker qualifies for are marked `noEligibleBroker`,
+    jobs whose brokers are all busy stay `queued`.
+*/
+fn dispatch(con: &mut Connection) -> SqlResult<usize> {
+    let tx = con.transaction()?;
+    let mut changed = 0;
+
+    let orphaned = query_rows!(tx => "
+        SELECT jobs.* FROM jobs LEFT JOIN brokers ON brokers.id = jobs.broker_id
+        WHERE jobs.status IN ('assigned', 'running')
+            AND (brokers.id IS NULL OR brokers.active = 0 OR brokers.deleted_at IS NOT NULL)",
+        [], Job
+    );
+    for job in orphaned {
+        query_execute!(tx => "UPDATE jobs SET status = ?, broker_id = NULL, assigned_at = NULL WHERE id = ?",
+            (JobStatus::Queued.as_str(), job.id))?;
+        let event = AppEvent::JobQueued {
+            job_id: job.id,
+            template: job.template,
+        };
+        events::record(&tx, job.app_id, &event)?;
+        changed += 1;
+    }
+
+    let waiting = query_rows!(tx => "
+        SELECT jobs.* FROM jobs JOIN apps ON apps.id = jobs.app_id
+        WHERE jobs.status IN ('queued', 'noEligibleBroker') AND apps.deleted_at IS NULL
+        ORDER BY jobs.id",
+        [], Job
+    );
+    let mut apps: HashMap<i32, Vec<Candidate>> = HashMap::new();
+    for job in waiting {
+        let candidates = match apps.entry(job.app_id) {
+            Entry::Occupied(entry) => entry.into_mut(),
+            Entry::Vacant(entry) => entry.insert(candidates(&tx, job.app_id)?),
+        };
+        let mut eligible = candidates
+            .iter_mut()
+            .filter(|candidate| candidate.accepts(&job))
+            .peekable();
+
+        if eligible.peek().is_none() {
+            if job.status != JobStatus::NoEligibleBroker {
+                query_execute!(tx => "UPDATE jobs SET status = ? WHERE id = ?",
+                    (JobStatus::NoEligibleBroker.as_str(), job.id))?;
+                let event = AppEvent::JobNoEligibleBroker {
+                    job_id: job.id,
+                    labels: job.labels,
+                };
+                events::record(&tx, job.app_id, &event)?;
+                changed += 1;
+            }
+            continue;
+        }
+        let chosen = eligible
+            .filter(|candidate| candidate.jobs < candidate.capacity)
+            .min_by(|a, b| {
+                a.jobs
+                    .cmp(&b.jobs)
+                    .then(
+                        a.broker
+                            .load
+                            .unwrap_or(0.0)
+                            .total_cmp(&b.broker.load.unwrap_or(0.0)),
+                    )
+                    .then(a.broker.id.cmp(&b.broker.id))
+            });
+        let Some(chosen) = chosen else {
+            if job.status != JobStatus::Queued {
+                query_execute!(tx => "UPDATE jobs SET status = ? WHERE id = ?",
+                    (JobStatus::Queued.as_str(), job.id))?;
+                changed += 1;
+            }
+            continue;
+        };
+
+        chosen.jobs += 1;
+        let broker_id = chosen.broker.id;
+        query_execute!(tx => "UPDATE jobs SET status = ?, broker_id = ?, assigned_at = ? WHERE id = ?",
+            (JobStatus::Assigned.as_str(), broker_id, now(), job.id))?;
+        let event = AppEvent::JobAssigned {
+            job_id: job.id,
+            broker_id,
+        };
+        events::record(&tx, job.app_id, &event)?;
+        changed += 1;
+    }
+    tx.commit()?;
+    Ok(changed)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+
+    use serde_json::json;
+
+    use super::*;
+    use crate::db::{
+        broker_configs::{ConfigScope, NewBrokerSettings},
+        brokers::Heartbeat,
+        testing, SqliteDb,
+    };
+
+    fn candidate(labels: &[&str], pool_id: Option<i32>) -> Candidate {
+        let broker = json!({
+            "id": 1, "appId": 1, "name": "b", "description": "", "version": "1.0.0",
+            "active": true, "stopped": false, "labels": labels, "poolId": pool_id,
+            "createdAt": 0, "updatedAt": 0,
+        });
+        Candidate {
+            broker: serde_json::from_value(broker).unwrap(),
+            jobs: 0,
+            capacity: 1,
+        }
+    }
+
+    fn job(labels: &[&str], pool_id: Option<i32>) -> Job {
+        let job = json!({
+            "id": 1, "appId": 1, "template": "t", "parameters": {}, "labels": labels,
+            "poolId": pool_id, "scheduleId": null, "status": "queued", "brokerId": null,
+            "error": null, "createdBy": null, "createdAt": 0, "assignedAt": null, "finishedAt": null,
+        });
+        serde_json::from_value(job).unwrap()
+    }
+
+    fn new_job(labels: &[&str]) -> NewJob {
+        NewJob {
+            template: "monthly".to_string(),
+            parameters: Map::new(),
+            labels: labels.iter().map(|label| label.to_string()).collect(),
+            pool_id: None,
+        }
+    }
+
+    /// Connected broker with `labels`.
+    fn online(db: &SqliteDb, app_id: i32, labels: &[&str]) -> i32 {
+        let broker_id = testing::broker(db, app_id);
+        let labels: Vec<String> = labels.iter().map(|label| label.to_string()).collect();
+        db.brokers.set_labels(app_id, broker_id, &labels).unwrap();
+        db.brokers.connect(app_id, broker_id).unwrap();
+        broker_id
+    }
+
+    fn queue(db: &SqliteDb, app_id: i32, author: i32, labels: &[&str]) -> i64 {
+        db.jobs
+            .create(app_id, author, new_job(labels))
+            .unwrap()
+            .unwrap()
+            .id
+    }
+
+    fn job_of(db: &SqliteDb, job_id: i64) -> (JobStatus, Option<i32>) {
+        let job = db.jobs.by_id(job_id).unwrap().unwrap();
+        (job.status, job.broker_id)
+    }
+
+    fn setup() -> (SqliteDb, i32, i32) {
+        let db = testing::db();
+        let author = testing::user(&db, "alice");
+        let app_id = testing::app(&db, author);
+        (db, author, app_id)
+    }
+
+    #[test]
+    fn candidates_need_every_label_and_the_pool() {
+        let broker = candidate(&["db:sales", "pdf"], None);
+        assert!(broker.accepts(&job(&[], None)));
+        assert!(broker.accepts(&job(&["pdf"], None)));
+        assert!(broker.accepts(&job(&["db:sales", "pdf"], None)));
+        assert!(!broker.accepts(&job(&["pdf", "xlsx"], None)));
+        assert!(!broker.accepts(&job(&[], Some(1))));
+
+        let pooled = candidate(&["pdf"], Some(1));
+        assert!(pooled.accepts(&job(&[], None)));
+        assert!(pooled.accepts(&job(&["pdf"], Some(1))));
+        assert!(!pooled.accepts(&job(&["pdf"], Some(2))));
+    }
+
+    #[test]
+    fn new_jobs_have_normalized_labels() {
+        let job = new_job(&[" PDF", "db:Sales", "pdf", ""])
+            .validate()
+            .ok()
+            .unwrap();
+        assert_eq!(job.labels, ["db:sales", "pdf"]);
+
+        let mut invalid = new_job(&["pdf files"]);
+        invalid.template = "  ".to_string();
+        let errors = invalid.validate().err().unwrap().errors;
+        let codes: Vec<_> = errors
+            .iter()
+            .map(|error| (error.field.as_str(), error.code.as_str()))
+            .collect();
+        assert_eq!(
+            codes,
+            [("template", "required"), ("labels", "invalidLabel")]
+        );
+    }
+
+    #[test]
+    fn jobs_go_to_brokers_with_their_labels() {
+        let (db, author, app_id) = setup();
+        let plain = online(&db, app_id, &[]);
+        let pdf = online(&db, app_id, &["pdf"]);
+        let any = queue(&db, app_id, author, &[]);
+        let needs_pdf = queue(&db, app_id, author, &["pdf"]);
+        let needs_xlsx = queue(&db, app_id, author, &["xlsx"]);
+
+        db.jobs.dispatch().unwrap();
+        assert_eq!(job_of(&db, any), (JobStatus::Assigned, Some(plain)));
+        assert_eq!(job_of(&db, needs_pdf), (JobStatus::Assigned, Some(pdf)));
+        assert_eq!(job_of(&db, needs_xlsx), (JobStatus::NoEligibleBroker, None));
+
+        // nothing changed, nothing is recorded again
+        assert_eq!(db.jobs.dispatch().unwrap(), 0);
+
+        let xlsx = online(&db, app_id, &["xlsx"]);
+        assert_eq!(db.jobs.dispatch().unwrap(), 1);
+        assert_eq!(job_of(&db, needs_xlsx), (JobStatus::Assigned, Some(xlsx)));
+    }
+
+    #[test]
+    fn jobs_wait_for_room_below_concurrency() {
+        let (db, author, app_id) = setup();
+        let broker_id = online(&db, app_id, &[]);
+        let settings = NewBrokerSettings {
+            concurrency: Some(2),
+            output_formats: None,
+            env: BTreeMap::new(),
+            secrets: BTreeMap::new(),
+        };
+        let scope = ConfigScope::Broker(broker_id);
+        db.broker_configs
+            .save(app_id, author, scope, settings)
+            .unwrap();
+        let jobs: Vec<i64> = (0..3).map(|_| queue(&db, app_id, author, &[])).collect();
+
+        db.jobs.dispatch().unwrap();
+        assert_eq!(job_of(&db, jobs[0]), (JobStatus::Assigned, Some(broker_id)));
+        assert_eq!(job_of(&db, jobs[1]), (JobStatus::Assigned, Some(broker_id)));
+        assert_eq!(job_of(&db, jobs[2]), (JobStatus::Queued, None));
+
+        let report = JobReport {
+            job_id: jobs[0],
+            status: JobStatus::Completed,
+            error: None,
+        };
+        db.jobs.report(app_id, broker_id, &report).unwrap();
+        db.jobs.dispatch().unwrap();
+        assert_eq!(job_of(&db, jobs[2]), (JobStatus::Assigned, Some(broker_id)));
+    }
+
+    #[test]
+    fn least_busy_then_least_loaded_broker_is_chosen() {
+        let (db, author, app_id) = setup();
+        let busy = online(&db, app_id, &[]);
+        let loaded = online(&db, app_id, &[]);
+        let idle = online(&db, app_id, &[]);
+        let heartbeat = |broker_id, load| {
+            let heartbeat = Heartbeat {
+                load: Some(load),
+                hostname: None,
+                platform: None,
+            };
+            db.brokers.heartbeat(app_id, broker_id, &heartbeat).unwrap();
+        };
+        heartbeat(busy, 0.0);
+        heartbeat(loaded, 0.9);
+        heartbeat(idle, 0.1);
+
+        let first = queue(&db, app_id, author, &[]);
+        db.jobs.dispatch().unwrap();
+        assert_eq!(job_of(&db, first), (JobStatus::Assigned, Some(busy)));
+
+        let second = queue(&db, app_id, author, &[]);
+        db.jobs.dispatch().unwrap();
+        assert_eq!(job_of(&db, second), (JobStatus::Assigned, Some(idle)));
+    }
+
+    #[test]
+    fn stopped_and_incompatible_brokers_get_no_jobs() {
+        let (db, author, app_id) = setup();
+        let stopped = online(&db, app_id, &[]);
+        db.brokers
+            .set_stopped(app_id, author, stopped, true)
+            .unwrap();
+        let outdated = online(&db, app_id, &[]);
+        {
+            let con = db.con.lock().unwrap();
+            con.execute(
+                "UPDATE apps SET min_broker_version = '2.0.0' WHERE id = ?",
+                [app_id],
+            )
+            .unwrap();
+        }
+        let job_id = queue(&db, app_id, author, &[]);
+
+        db.jobs.dispatch().unwrap();
+        assert_eq!(job_of(&db, job_id), (JobStatus::NoEligibleBroker, None));
+        let current = online(&db, app_id, &[]);
+        db.brokers
+            .set_version(app_id, current, &"2.1.0".parse().unwrap())
+            .unwrap();
+        db.jobs.dispatch().unwrap();
+        assert_ne!(job_of(&db, job_id).1, Some(outdated));
+        assert_eq!(job_of(&db, job_id), (JobStatus::Assigned, Some(current)));
+    }
+
+    #[test]
+    fn jobs_of_disconnected_brokers_are_queued_again() {
+        let (db, author, app_id) = setup();
+        let first = online(&db, app_id, &[]);
+        let job_id = queue(&db, app_id, author, &[]);
+        db.jobs.dispatch().unwrap();
+        assert_eq!(job_of(&db, job_id), (JobStatus::Assigned, Some(first)));
+
+        // a replaced connection leaves the job where it is
+        let replaced = db.brokers.get(app_id, first).unwrap().unwrap().session;
+        db.brokers.connect(app_id, first).unwrap();
+        db.brokers.disconnect(app_id, first, replaced).unwrap();
+        assert_eq!(db.jobs.dispatch().unwrap(), 0);
+
+        let session = db.brokers.get(app_id, first).unwrap().unwrap().session;
+        db.brokers.disconnect(app_id, first, session).unwrap();
+        let second = online(&db, app_id, &[]);
+        db.jobs.dispatch().unwrap();
+        assert_eq!(job_of(&db, job_id), (JobStatus::Assigned, Some(second)));
+    }
+}
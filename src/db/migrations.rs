@@ -0,0 +1,88 @@
+use rusqlite::Connection;
+
+use super::SqlResult;
+
+macro_rules! now {
+    () => {
+        "CAST(strftime('%s', 'now') AS INTEGER)"
+    };
+}
+
+/**
+    Adds `created_at`, `updated_at` and `deleted_at` to a table.
+    Triggers fill the timestamps, so inserts and updates don't have to.
+*/
+macro_rules! timestamps {
+    ($table:literal) => {
+        concat!(
+            "ALTER TABLE ", $table, " ADD COLUMN created_at INTEGER;",
+            "ALTER TABLE ", $table, " ADD COLUMN updated_at INTEGER;",
+            "ALTER TABLE ", $table, " ADD COLUMN deleted_at INTEGER;",
+            "UPDATE ", $table, " SET created_at = ", now!(), ", updated_at = ", now!(), ";",
+            "CREATE TRIGGER ", $table, "_created AFTER INSERT ON ", $table,
+            " WHEN NEW.created_at IS NULL BEGIN",
+            " UPDATE ", $table, " SET created_at = ", now!(), ", updated_at = ", now!(), " WHERE id = NEW.id;",
+            " END;",
+            "CREATE TRIGGER ", $table, "_updated AFTER UPDATE ON ", $table,
+            " WHEN NEW.updated_at IS OLD.updated_at BEGIN",
+            " UPDATE ", $table, " SET updated_at = ", now!(), " WHERE id = NEW.id;",
+            " END;",
+        )
+    };
+}
+
+/**
+    Schema changes applied on top of the `create_table` statements.
+    Index + 1 is the schema version stored in `PRAGMA user_version`,
+    so new migrations must only ever be appended.
+*/
+const MIGRATIONS: &[&str] = &[
+    "ALTER TABLE users ADD COLUMN admin INTEGER NOT NULL DEFAULT 0",
+    concat!(
+        timestamps!("users"),
+        timestamps!("apps"),
+        timestamps!("operators"),
+        timestamps!("app_users"),
+        timestamps!("brokers"),
+    ),
+    "ALTER TABLE apps ADD COLUMN require_two_factor INTEGER NOT NULL DEFAULT 0",
+    "ALTER TABLE users ADD COLUMN email TEXT;
+    ALTER TABLE users ADD COLUMN session_epoch INTEGER NOT NULL DEFAULT 0;",
+    "ALTER TABLE users ADD COLUMN avatar_url TEXT;
+    ALTER TABLE users ADD COLUMN bio TEXT;",
+    "ALTER TABLE users ADD COLUMN searchable INTEGER NOT NULL DEFAULT 1",
+    "ALTER TABLE brokers ADD COLUMN connected_at INTEGER;
+    ALTER TABLE brokers ADD COLUMN last_seen_at INTEGER;
+    ALTER TABLE brokers ADD COLUMN load REAL;
+    ALTER TABLE brokers ADD COLUMN hostname TEXT;
+    ALTER TABLE brokers ADD COLUMN platform TEXT;",
+    "ALTER TABLE apps ADD COLUMN min_broker_version TEXT;
+    ALTER TABLE apps ADD COLUMN max_broker_version TEXT;
+    ALTER TABLE apps ADD COLUMN reject_incompatible_brokers INTEGER NOT NULL DEFAULT 0;",
+    "ALTER TABLE brokers ADD COLUMN labels TEXT NOT NULL DEFAULT ''",
+    "ALTER TABLE brokers ADD COLUMN pool_id INTEGER REFERENCES broker_pools(id);
+    ALTER TABLE broker_configs ADD COLUMN pool_id INTEGER REFERENCES broker_pools(id);",
+    "ALTER TABLE jobs ADD COLUMN pool_id INTEGER REFERENCES broker_pools(id);
+    ALTER TABLE jobs ADD COLUMN schedule_id INTEGER REFERENCES schedules(id);",
+    "ALTER TABLE brokers ADD COLUMN session INTEGER NOT NULL DEFAULT 0",
+];
+
+pub fn version(con: &Connection) -> SqlResult<usize> {
+    con.query_row("PRAGMA user_version", [], |row| row.get(0))
+}
+
+pub fn latest() -> usize {
+    MIGRATIONS.len()
+}
+
+/// Applies pending migrations, returns how many were applied.
+pub fn migrate(con: &mut Connection) -> SqlResult<usize> {
+    let current = version(con)?;
+    let tx = con.transaction()?;
+    for (i, sql) in MIGRATIONS.iter().enumerate().skip(current) {
+        tx.execute_batch(sql)?;
+        tx.pragma_update(None, "user_version", i + 1)?;
+    }
+    tx.commit()?;
+    Ok(latest().saturating_sub(current))
+}
@@ -1,21 +1,57 @@
 use std::{
     ops::Deref,
+    path::Path,
     sync::{Arc, Mutex},
 };
 
+use access_tokens::AccessTokens;
 use app_users::AppUsers;
 use apps::Apps;
+use artifacts::Artifacts;
+use audit::AuditLog;
+use backup::{SnapshotError, Snapshots};
+use broker_configs::BrokerConfigs;
+use broker_logs::BrokerLogs;
+use broker_pools::BrokerPools;
 use brokers::Brokers;
+use events::Events;
+use export::Export;
+use identities::Identities;
+use jobs::Jobs;
 use operators::Operators;
+use password_resets::PasswordResets;
+use schedules::Schedules;
+use share_links::ShareLinks;
+use trash::Trash;
+use two_factor::TwoFactor;
 use rusqlite::Connection;
 use users::Users;
+use webhooks::Webhooks;
 
+pub mod access_tokens;
 pub mod app_users;
 pub mod apps;
+pub mod artifacts;
+pub mod audit;
+pub mod backup;
+pub mod broker_configs;
+pub mod broker_logs;
+pub mod broker_pools;
 pub mod brokers;
+pub mod events;
+pub mod export;
+pub mod identities;
+pub mod jobs;
+pub mod migrations;
 pub mod operators;
+pub mod password_resets;
+pub mod schedules;
+pub mod share_links;
 pub mod table;
+pub mod trash;
+pub mod two_factor;
 pub mod users;
+pub mod webhooks;
 
 pub type SqlResult<T> = Result<T, rusqlite::Error>;
 
@@ -47,9 +83,28 @@ pub struct SqliteDb {
     pub apps: Apps,
     pub app_users: AppUsers,
     pub operators: Operators,
+    pub password_resets: PasswordResets,
     pub brokers: Brokers,
+    pub broker_logs: BrokerLogs,
+    pub broker_configs: BrokerConfigs,
+    pub broker_pools: BrokerPools,
+    pub jobs: Jobs,
+    pub schedules: Schedules,
+    pub artifacts: Artifacts,
+    pub share_links: ShareLinks,
+    pub events: Events,
+    pub webhooks: Webhooks,
+    pub audit: AuditLog,
+    pub trash: Trash,
+    pub two_factor: TwoFactor,
+    pub access_tokens: AccessTokens,
+    pub identities: Identities,
+    pub snapshots: Snapshots,
 }
 
+pub const SNAPSHOT_DIR: &str = "backups";
+pub const SNAPSHOTS_KEPT: usize = 14;
+
 impl SqliteDb {
     pub fn new(path: String) -> Result<Self, rusqlite::Error> {
         let con = Con::new(Connection::open(path)
@@ -60,8 +115,24 @@ impl SqliteDb {
             users: Users::new(&con),
             apps: Apps::new(&con),
             operators: Operators::new(&con),
+            password_resets: PasswordResets::new(&con),
             app_users: AppUsers::new(&con),
             brokers: Brokers::new(&con),
+            broker_logs: BrokerLogs::new(&con),
+            broker_configs: BrokerConfigs::new(&con),
+            broker_pools: BrokerPools::new(&con),
+            jobs: Jobs::new(&con),
+            schedules: Schedules::new(&con),
+            artifacts: Artifacts::new(&con),
+            share_links: ShareLinks::new(&con),
+            events: Events::new(&con),
+            webhooks: Webhooks::new(&con),
+            audit: AuditLog::new(&con),
+            trash: Trash::new(&con),
+            two_factor: TwoFactor::new(&con),
+            access_tokens: AccessTokens::new(&con),
+            identities: Identities::new(&con),
+            snapshots: Snapshots::new(&con, SNAPSHOT_DIR, SNAPSHOTS_KEPT),
             con,
         };
 
@@ -77,7 +148,69 @@ impl SqliteDb {
         self.apps.create_table()?;
         self.operators.create_table()?;
         self.app_users.create_table()?;
-        self.brokers.create_table()
+        self.broker_pools.create_table()?;
+        self.brokers.create_table()?;
+        self.broker_logs.create_table()?;
+        self.broker_configs.create_table()?;
+        self.jobs.create_table()?;
+        self.schedules.create_table()?;
+        self.artifacts.create_table()?;
+        self.share_links.create_table()?;
+        self.events.create_table()?;
+        self.webhooks.create_table()?;
+        self.audit.create_table()?;
+        self.two_factor.create_table()?;
+        self.password_resets.create_table()?;
+        self.access_tokens.create_table()?;
+        self.identities.create_table()?;
+        self.migrate()
+    }
+
+    /// Applies pending schema migrations, returns how many were applied.
+    pub fn migrate(&self) -> SqlResult<usize> {
+        let mut con = self.con.lock().unwrap();
+        migrations::migrate(&mut con)
+    }
+
+    pub fn schema_version(&self) -> SqlResult<usize> {
+        let con = self.con.lock().unwrap();
+        migrations::version(&con)
+    }
+
+    pub fn export(&self) -> SqlResult<Export> {
+        let con = self.con.lock().unwrap();
+        export::export(&con)
+    }
+
+    /// Online copy of the database into `path`.
+    pub fn backup_to(&self, path: &Path) -> SqlResult<()> {
+        let con = self.con.lock().unwrap();
+        backup::backup(&con, path)
+    }
+
+    /**
+        Replaces the whole database with the one stored in `path`.
+        Backups from older versions lack tables added since, so it goes through `init` like on startup.
+    */
+    pub fn restore_from(&self, path: &Path) -> Result<(), SnapshotError> {
+        {
+            let mut con = self.con.lock().unwrap();
+            backup::restore(&mut con, path)?;
+        }
+        self.init()?;
+        Ok(())
+    }
+
+    /// Restores a snapshot of the backups directory, see `restore_from`.
+    pub fn restore_snapshot(&self, name: &str) -> Result<(), SnapshotError> {
+        let path = self.snapshots.path(name).ok_or(SnapshotError::NotFound)?;
+        self.restore_from(&path)
+    }
+
+    /// Loads exported data, existing rows with the same ids are a constraint error.
+    pub fn import(&self, data: Export) -> SqlResult<()> {
+        let mut con = self.con.lock().unwrap();
+        export::import(&mut con, data)
     }
 }
 
@@ -110,3 +243,58 @@ macro_rules! query_execute {
 }
 
 pub(crate) use query_execute;
+
+/// In-memory database with the full schema, and rows tests build on.
+#[cfg(test)]
+pub mod testing {
+    use super::{
+        events::{self, AppEvent},
+        SqliteDb,
+    };
+
+    pub fn db() -> SqliteDb {
+        let db = SqliteDb::new(":memory:".to_string()).unwrap();
+        db.init().unwrap();
+        db
+    }
+
+    /// Inserted directly with an unusable password, bcrypt is too slow for tests.
+    pub fn user(db: &SqliteDb, username: &str) -> i32 {
+        let con = db.con.lock().unwrap();
+        con.execute(
+            "INSERT INTO users(name, username, password) VALUES (?, ?, '')",
+            [username, username],
+        )
+        .unwrap();
+        con.last_insert_rowid() as i32
+    }
+
+    pub fn app(db: &SqliteDb, author_id: i32) -> i32 {
+        let con = db.con.lock().unwrap();
+        con.execute(
+            "INSERT INTO apps(author_id, title, description, weblink, version, public, status)
+            VALUES (?, 'app', '', '', '1.0.0', 0, 0)",
+            [author_id],
+        )
+        .unwrap();
+        con.last_insert_rowid() as i32
+    }
+
+    /// Disconnected and started, without an event.
+    pub fn broker(db: &SqliteDb, app_id: i32) -> i32 {
+        let con = db.con.lock().unwrap();
+        con.execute(
+            "INSERT INTO brokers(app_id, name, description, version, active, stopped)
+            VALUES (?, 'broker', '', '1.0.0', 0, 0)",
+            [app_id],
+        )
+        .unwrap();
+        con.last_insert_rowid() as i32
+    }
+
+    /// Recorded outside of any change, streams still have to be notified.
+    pub fn event(db: &SqliteDb, app_id: i32, event: &AppEvent) -> i64 {
+        let con = db.con.lock().unwrap();
+        events::record(&con, app_id, event).unwrap()
+    }
+}
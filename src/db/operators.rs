@@ -1,7 +1,9 @@
-use axum_utils::{copy, impl_from_row};
+use axum_utils::{copy, copy_mut, impl_from_row};
 use serde::{Deserialize, Serialize};
 
 use crate::db::apps::has_permission;
+use crate::db::audit::{self, snapshot, Change};
+use crate::db::events::{self, AppEvent};
 
 use super::{users::user_exists, Con, SqlResult};
 use rusqlite::{Connection, Row};
@@ -16,8 +18,8 @@ impl Operators {
     }
 
     copy!(create_table() -> SqlResult<usize>);
-    copy!(create(app_id: i32, owner_id: i32, new_operator_id: i32) -> SqlResult<Operator>);
-    copy!(delete(app_id: i32, user_id: i32, operator_id: i32) -> SqlResult<usize>);
+    copy_mut!(create(app_id: i32, owner_id: i32, new_operator_id: i32) -> SqlResult<Operator>);
+    copy_mut!(delete(app_id: i32, user_id: i32, operator_id: i32) -> SqlResult<usize>);
     copy!(for_app(app_id: i32, user_id: i32) -> SqlResult<Vec<Operator>>);
 }
 
@@ -37,46 +39,88 @@ fn create_table(con: &Connection) -> SqlResult<usize> {
 
 // TODO: custom error type (sql error / permission denied / app not found)
 fn create(
-    con: &Connection,
+    con: &mut Connection,
     app_id: i32,
     owner_id: i32,
     new_operator_id: i32,
 ) -> SqlResult<Operator> {
-    has_permission(con, app_id, owner_id).map_err(|e| {
+    let tx = con.transaction()?;
+    has_permission(&tx, app_id, owner_id).map_err(|e| {
         println!(
             "no permission for user {owner_id} to add operator {new_operator_id} to app {app_id}"
         );
         e
     })?;
-    user_exists(con, new_operator_id).inspect_err(|_| println!("user doesnt exist"))?;
+    user_exists(&tx, new_operator_id).inspect_err(|_| println!("user doesnt exist"))?;
+    // soft deleted membership would violate UNIQUE(app_id, user_id)
+    tx.prepare_cached(
+        "DELETE FROM operators WHERE app_id = ? AND user_id = ? AND deleted_at IS NOT NULL",
+    )?
+    .execute([app_id, new_operator_id])?;
 
-    let mut stmt = con.prepare_cached("INSERT INTO operators (app_id, user_id) VALUES(?,?)")?;
-    stmt.execute([app_id, new_operator_id])?;
-    let inserted_id = con.last_insert_rowid();
+    tx.prepare_cached("INSERT INTO operators (app_id, user_id) VALUES(?,?)")?
+        .execute([app_id, new_operator_id])?;
+    let inserted_id = tx.last_insert_rowid();
 
-    con.query_row(
-        "SELECT * FROM operators 
-        JOIN users ON users.id = user_id
-        WHERE operators.id = ?",
-        [inserted_id],
-        Operator::from_row,
-    )
+    let operator = by_id(&tx, inserted_id as i32)?;
+    let event = AppEvent::OperatorAdded {
+        operator_id: operator.id,
+        user_id: new_operator_id,
+    };
+    events::record(&tx, app_id, &event)?;
+    let change = Change {
+        actor_id: Some(owner_id),
+        app_id: Some(app_id),
+        action: "operator.create",
+        target: format!("operator:{}", operator.id),
+        before: None,
+        after: snapshot(&operator),
+    };
+    audit::record(&tx, change)?;
+    tx.commit()?;
+    Ok(operator)
 }
 
 // TODO: custom error type
 // maybe app id is not needed
-fn delete(con: &Connection, app_id: i32, user_id: i32, operator_id: i32) -> SqlResult<usize> {
-    let mut stmt = con.prepare_cached(
+fn delete(con: &mut Connection, app_id: i32, user_id: i32, operator_id: i32) -> SqlResult<usize> {
+    let tx = con.transaction()?;
+    tx.prepare_cached(
         "
     SELECT * FROM apps 
     JOIN operators ON operators.app_id = apps.id 
     JOIN users ON operators.user_id = users.id 
-    WHERE operators.id = ? AND apps.author_id = ? AND apps.id = ?",
-    )?;
-    stmt.query_row([operator_id, user_id, app_id], |_| Ok(()))?;
+    WHERE operators.id = ? AND apps.author_id = ? AND apps.id = ?
+        AND operators.deleted_at IS NULL AND apps.deleted_at IS NULL",
+    )?
+    .query_row([operator_id, user_id, app_id], |_| Ok(()))?;
+    let before = by_id(&tx, operator_id)?;
 
-    let mut stmt = con.prepare_cached("DELETE FROM operators WHERE id = ?")?;
-    stmt.execute([operator_id])
+    let result = tx
+        .prepare_cached("UPDATE operators SET deleted_at = ? WHERE id = ?")?
+        .execute((events::now(), operator_id))?;
+    events::record(&tx, app_id, &AppEvent::OperatorRemoved { operator_id })?;
+    let change = Change {
+        actor_id: Some(user_id),
+        app_id: Some(app_id),
+        action: "operator.delete",
+        target: format!("operator:{operator_id}"),
+        before: snapshot(&before),
+        after: None,
+    };
+    audit::record(&tx, change)?;
+    tx.commit()?;
+    Ok(result)
+}
+
+fn by_id(con: &Connection, operator_id: i32) -> SqlResult<Operator> {
+    con.query_row(
+        "SELECT * FROM operators 
+        JOIN users ON users.id = user_id
+        WHERE operators.id = ?",
+        [operator_id],
+        Operator::from_row,
+    )
 }
 
 fn for_app(con: &Connection, app_id: i32, user_id: i32) -> SqlResult<Vec<Operator>> {
@@ -91,7 +135,7 @@ fn for_app(con: &Connection, app_id: i32, user_id: i32) -> SqlResult<Vec<Operato
     SELECT operators.id as id, * FROM operators 
     JOIN users ON user_id = users.id 
     JOIN apps ON operators.app_id = apps.id
-    WHERE app_id = ? AND apps.author_id = ?",
+    WHERE app_id = ? AND apps.author_id = ? AND operators.deleted_at IS NULL",
     )?;
     let x = stmt
         .query_map([app_id, user_id], Operator::from_row)?
@@ -107,6 +151,8 @@ pub struct Operator {
     user_id: i32,
     name: String,
     username: String,
+    created_at: u64,
+    updated_at: u64,
 }
 
 impl_from_row!(Operator {
@@ -114,5 +160,7 @@ impl_from_row!(Operator {
     app_id,
     user_id,
     name,
-    username
+    username,
+    created_at,
+    updated_at
 });
@@ -0,0 +1,167 @@
+use axum_utils::{copy, copy_mut};
+use rusqlite::{Connection, OptionalExtension};
+
+use super::audit::{self, Change};
+use super::events::now;
+use super::users::{set_password_hash, PasswordHash};
+use super::{query_execute, Con, SqlResult};
+use crate::crypto::sha256_hex;
+
+/// Reset tokens expire after this long.
+pub const RESET_TTL_SECS: u64 = 60 * 60;
+
+/**
+    Single use tokens for setting a new password without knowing the old one.
+    Only sha256 of the token is stored, a new token replaces the unused ones of the user.
+*/
+pub struct PasswordResets {
+    con: Con,
+}
+
+impl PasswordResets {
+    pub fn new(con: &Con) -> Self {
+        Self { con: con.clone() }
+    }
+
+    copy!(create_table() -> SqlResult<usize>);
+    copy_mut!(create(user_id: i32, token: &str, created_by: Option<i32>) -> SqlResult<usize>);
+    copy!(user_for(token: &str) -> SqlResult<Option<i32>>);
+
+    /// Sets the password and uses up the token, `None` if the token is not valid anymore.
+    pub fn complete(&self, token: &str, password: &str) -> SqlResult<Option<i32>> {
+        let hash = PasswordHash::new(password);
+        let mut con = self.con.lock().unwrap();
+        complete(&mut con, token, &hash)
+    }
+}
+
+fn create_table(con: &Connection) -> SqlResult<usize> {
+    con.execute(
+        "CREATE TABLE IF NOT EXISTS password_resets (
+            id INTEGER PRIMARY KEY,
+            user_id INTEGER,
+            token_hash TEXT UNIQUE,
+            created_by INTEGER,
+            created_at INTEGER,
+            expires_at INTEGER,
+            used_at INTEGER,
+            FOREIGN KEY(user_id) REFERENCES users(id)
+        )",
+        [],
+    )
+}
+
+/// `created_by` is the admin that started the reset, `None` if the user asked for it.
+fn create(
+    con: &mut Connection,
+    user_id: i32,
+    token: &str,
+    created_by: Option<i32>,
+) -> SqlResult<usize> {
+    let tx = con.transaction()?;
+    let now = now();
+    query_execute!(tx => "DELETE FROM password_resets WHERE user_id = ? AND used_at IS NULL", [user_id])?;
+    let result = query_execute!(tx => "
+        INSERT INTO password_resets(user_id, token_hash, created_by, created_at, expires_at) VALUES (?, ?, ?, ?, ?)",
+        (user_id, sha256_hex(token), created_by, now, now + RESET_TTL_SECS)
+    )?;
+    let change = Change {
+        actor_id: created_by.or(Some(user_id)),
+        app_id: None,
+        action: "user.passwordResetRequest",
+        target: format!("user:{user_id}"),
+        before: None,
+        after: None,
+    };
+    audit::record(&tx, change)?;
+    tx.commit()?;
+    Ok(result)
+}
+
+/// User a valid token belongs to, for validating the new password before `complete`.
+fn user_for(con: &Connection, token: &str) -> SqlResult<Option<i32>> {
+    let mut stmt = con.prepare_cached(
+        "SELECT user_id FROM password_resets WHERE token_hash = ? AND used_at IS NULL AND expires_at > ?",
+    )?;
+    stmt.query_row((sha256_hex(token), now()), |row| row.get(0))
+        .optional()
+}
+
+fn complete(con: &mut Connection, token: &str, hash: &PasswordHash) -> SqlResult<Option<i32>> {
+    let tx = con.transaction()?;
+    let Some(user_id) = user_for(&tx, token)? else {
+        return Ok(None);
+    };
+
+    query_execute!(tx => "UPDATE password_resets SET used_at = ? WHERE token_hash = ?", (now(), sha256_hex(token)))?;
+    set_password_hash(&tx, user_id, hash)?;
+    let change = Change {
+        actor_id: Some(user_id),
+        app_id: None,
+        action: "user.passwordReset",
+        target: format!("user:{user_id}"),
+        before: None,
+        after: None,
+    };
+    audit::record(&tx, change)?;
+    tx.commit()?;
+    Ok(Some(user_id))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::testing;
+
+    #[test]
+    fn token_sets_the_password_once() {
+        let db = testing::db();
+        let user_id = testing::user(&db, "alice");
+        let epoch = db.users.session_epoch(user_id).unwrap();
+        db.password_resets.create(user_id, "token-1", None).unwrap();
+
+        assert_eq!(
+            db.password_resets.user_for("token-1").unwrap(),
+            Some(user_id)
+        );
+        assert_eq!(db.password_resets.user_for("token-2").unwrap(), None);
+        let completed = db.password_resets.complete("token-1", "Tr1cky-Enough");
+        assert_eq!(completed.unwrap(), Some(user_id));
+        assert!(db.users.verify_password(user_id, "Tr1cky-Enough").unwrap());
+        assert_eq!(db.users.session_epoch(user_id).unwrap(), epoch + 1);
+
+        let again = db.password_resets.complete("token-1", "An0ther-Secret");
+        assert_eq!(again.unwrap(), None);
+        assert!(db.users.verify_password(user_id, "Tr1cky-Enough").unwrap());
+    }
+
+    #[test]
+    fn new_token_replaces_unused_ones_and_tokens_expire() {
+        let db = testing::db();
+        let (user_id, admin_id) = (testing::user(&db, "alice"), testing::user(&db, "root"));
+        db.password_resets.create(user_id, "token-1", None).unwrap();
+        db.password_resets
+            .create(user_id, "token-2", Some(admin_id))
+            .unwrap();
+        assert_eq!(db.password_resets.user_for("token-1").unwrap(), None);
+        assert_eq!(
+            db.password_resets.user_for("token-2").unwrap(),
+            Some(user_id)
+        );
+
+        {
+            let con = db.con.lock().unwrap();
+            con.execute("UPDATE password_resets SET expires_at = ?", [now() - 1])
+                .unwrap();
+            let stored: String = con
+                .query_row("SELECT token_hash FROM password_resets", [], |row| {
+                    row.get(0)
+                })
+                .unwrap();
+            assert_eq!(stored, sha256_hex("token-2"));
+        }
+        assert_eq!(db.password_resets.user_for("token-2").unwrap(), None);
+        let expired = db.password_resets.complete("token-2", "Tr1cky-Enough");
+        assert_eq!(expired.unwrap(), None);
+    }
+}
@@ -0,0 +1,572 @@
+use std::collections::VecDeque;
+
+use axum_utils::{copy, copy_mut};
+use jiff::tz::TimeZone;
+use rusqlite::{Connection, Error, OptionalExtension, Row};
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+
+use super::apps::has_permission;
+use super::audit::{self, snapshot, Change};
+use super::brokers::{join_labels, split_labels};
+use super::events::{self, now, AppEvent};
+use super::jobs::{self, JobStatus, NewJob};
+use super::{broker_pools, query_execute, query_row, query_rows, Con, SqlResult};
+use crate::cron::Cron;
+use crate::validation::ValidationErrors;
+
+/// Runs this late are missed, the scheduler checks far more often.
+pub const MISSED_AFTER_SECS: u64 = 5 * 60;
+/// Only the latest missed runs are caught up or recorded as skipped.
+const MAX_MISSED_RUNS: usize = 24;
+/// Older runs of a schedule are dropped from its history.
+const RUNS_KEPT: usize = 1000;
+const DEFAULT_LIMIT: usize = 50;
+const MAX_LIMIT: usize = 500;
+
+/**
+    Jobs an app queues on its own, whenever a cron expression matches
+    in the timezone of the schedule. `run_due` is called by the scheduler
+    task of the server, every run it makes or skips is kept in the history.
+*/
+pub struct Schedules {
+    con: Con,
+}
+
+/// What happens to runs that were due while the server was down.
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Default, Debug)]
+#[serde(rename_all = "camelCase")]
+pub enum MissedRuns {
+    #[default]
+    Skip,
+    /// Queue a job for each of them, at most `MAX_MISSED_RUNS`.
+    CatchUp,
+}
+
+impl MissedRuns {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            MissedRuns::Skip => "skip",
+            MissedRuns::CatchUp => "catchUp",
+        }
+    }
+
+    fn parse(policy: &str) -> Option<MissedRuns> {
+        [MissedRuns::Skip, MissedRuns::CatchUp]
+            .into_iter()
+            .find(|known| known.as_str() == policy)
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct Schedule {
+    pub id: i32,
+    pub app_id: i32,
+    pub cron: String,
+    /// IANA name like `Europe/Berlin`.
+    pub timezone: String,
+    pub template: String,
+    pub parameters: Map<String, Value>,
+    pub labels: Vec<String>,
+    /// Jobs only run on brokers of this pool.
+    pub pool_id: Option<i32>,
+    pub missed_runs: MissedRuns,
+    pub paused: bool,
+    /// `None` while paused, or when the expression never matches again.
+    pub next_run_at: Option<u64>,
+    /// Time the last job was queued for, skipped runs don't count.
+    pub last_run_at: Option<u64>,
+    pub created_by: Option<i32>,
+    pub created_at: u64,
+    pub updated_at: u64,
+}
+
+impl Schedule {
+    pub fn from_row(row: &Row) -> SqlResult<Self> {
+        let conversion = |e: Box<dyn std::error::Error + Send + Sync>| {
+            Error::FromSqlConversionFailure(0, rusqlite::types::Type::Text, e)
+        };
+        let parameters: String = row.get("parameters")?;
+        let missed_runs: String = row.get("missed_runs")?;
+        Ok(Self {
+            id: row.get("id")?,
+            app_id: row.get("app_id")?,
+            cron: row.get("cron")?,
+            timezone: row.get("timezone")?,
+            template: row.get("template")?,
+            parameters: serde_json::from_str(&parameters).map_err(|e| conversion(Box::new(e)))?,
+            labels: split_labels(&row.get::<_, String>("labels")?),
+            pool_id: row.get("pool_id")?,
+            missed_runs: MissedRuns::parse(&missed_runs).ok_or_else(|| {
+                conversion(format!("unknown missed runs policy '{missed_runs}'").into())
+            })?,
+            paused: row.get("paused")?,
+            next_run_at: row.get("next_run_at")?,
+            last_run_at: row.get("last_run_at")?,
+            created_by: row.get("created_by")?,
+            created_at: row.get("created_at")?,
+            updated_at: row.get("updated_at")?,
+        })
+    }
+
+    /// `None` if the expression or timezone stopped being valid, or it never matches again.
+    fn next_after(&self, after: u64) -> Option<u64> {
+        let cron: Cron = self.cron.parse().ok()?;
+        cron.next_after(after, &TimeZone::get(&self.timezone).ok()?)
+    }
+
+    fn job(&self) -> NewJob {
+        NewJob {
+            template: self.template.clone(),
+            parameters: self.parameters.clone(),
+            labels: self.labels.clone(),
+            pool_id: self.pool_id,
+        }
+    }
+}
+
+/// Run the scheduler made, or skipped, with the state of its job.
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ScheduleRun {
+    pub id: i64,
+    pub schedule_id: i32,
+    /// Time the cron expression matched.
+    pub scheduled_for: u64,
+    pub skipped: bool,
+    pub job_id: Option<i64>,
+    /// `None` for skipped runs and purged jobs.
+    pub job_status: Option<JobStatus>,
+    pub created_at: u64,
+}
+
+impl ScheduleRun {
+    pub fn from_row(row: &Row) -> SqlResult<Self> {
+        let job_status: Option<String> = row.get("job_status")?;
+        Ok(Self {
+            id: row.get("id")?,
+            schedule_id: row.get("schedule_id")?,
+            scheduled_for: row.get("scheduled_for")?,
+            skipped: row.get("skipped")?,
+            job_id: row.get("job_id")?,
+            job_status: job_status.as_deref().and_then(JobStatus::parse),
+            created_at: row.get("created_at")?,
+        })
+    }
+}
+
+fn utc() -> String {
+    "UTC".to_string()
+}
+
+/// Schedule with the job it queues, also used to replace one.
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct NewSchedule {
+    pub cron: String,
+    #[serde(default = "utc")]
+    pub timezone: String,
+    #[serde(default)]
+    pub missed_runs: MissedRuns,
+    #[serde(flatten)]
+    pub job: NewJob,
+}
+
+impl NewSchedule {
+    /// The expression is normalized, see `Cron`, every problem is reported.
+    pub fn validate(mut self) -> Result<Self, ValidationErrors> {
+        let mut errors = ValidationErrors::default();
+        self.job.check(&mut errors);
+        let cron = match self.cron.parse::<Cron>() {
+            Ok(cron) => Some(cron),
+            Err(e) => {
+                errors.add("cron", "invalidCron", &e.to_string());
+                None
+            }
+        };
+        self.timezone = self.timezone.trim().to_string();
+        let timezone = match TimeZone::get(&self.timezone) {
+            Ok(timezone) => Some(timezone),
+            Err(_) => {
+                let message = format!("'{}' is not a known timezone", self.timezone);
+                errors.add("timezone", "unknownTimezone", &message);
+                None
+            }
+        };
+        if let (Some(cron), Some(timezone)) = (cron, timezone) {
+            if cron.next_after(now(), &timezone).is_none() {
+                errors.add("cron", "neverRuns", "cron expression never matches");
+            }
+            self.cron = cron.to_string();
+        }
+        errors.into_result(self)
+    }
+}
+
+/// Filters of the run history, newest runs come first.
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct RunQuery {
+    /// For paging, runs older than this one.
+    pub before_id: Option<i64>,
+    pub limit: Option<usize>,
+}
+
+pub enum ScheduleError {
+    /// `poolId` is not a pool of the app.
+    UnknownPool,
+    SqliteError(Error),
+}
+
+impl From<Error> for ScheduleError {
+    fn from(value: Error) -> Self {
+        ScheduleError::SqliteError(value)
+    }
+}
+
+impl Schedules {
+    pub fn new(con: &Con) -> Self {
+        Self { con: con.clone() }
+    }
+
+    copy!(create_table() -> SqlResult<usize>);
+    copy!(for_app(app_id: i32, user_id: i32) -> SqlResult<Vec<Schedule>>);
+    copy!(get(app_id: i32, user_id: i32, schedule_id: i32) -> SqlResult<Option<Schedule>>);
+    copy_mut!(create(app_id: i32, user_id: i32, new_schedule: NewSchedule) -> Result<Schedule, ScheduleError>);
+    copy_mut!(update(app_id: i32, user_id: i32, schedule_id: i32, changes: NewSchedule) -> Result<Option<Schedule>, ScheduleError>);
+    copy_mut!(delete(app_id: i32, user_id: i32, schedule_id: i32) -> SqlResult<usize>);
+    copy_mut!(set_paused(app_id: i32, user_id: i32, schedule_id: i32, paused: bool) -> SqlResult<Option<Schedule>>);
+    copy!(runs(app_id: i32, user_id: i32, schedule_id: i32, query: &RunQuery) -> SqlResult<Option<Vec<ScheduleRun>>>);
+    copy_mut!(run_due(at: u64) -> SqlResult<usize>);
+}
+
+fn create_table(con: &Connection) -> SqlResult<usize> {
+    con.execute(
+        "CREATE TABLE IF NOT EXISTS schedules (
+            id INTEGER PRIMARY KEY,
+            app_id INTEGER,
+            cron TEXT,
+            timezone TEXT,
+            template TEXT,
+            parameters TEXT,
+            labels TEXT,
+            pool_id INTEGER,
+            missed_runs TEXT,
+            paused INTEGER NOT NULL DEFAULT 0,
+            next_run_at INTEGER,
+            last_run_at INTEGER,
+            created_by INTEGER,
+            created_at INTEGER,
+            updated_at INTEGER,
+            FOREIGN KEY(app_id) REFERENCES apps(id),
+            FOREIGN KEY(pool_id) REFERENCES broker_pools(id)
+        )",
+        [],
+    )?;
+    con.execute(
+        "CREATE TABLE IF NOT EXISTS schedule_runs (
+            id INTEGER PRIMARY KEY AUTOINCREMENT,
+            schedule_id INTEGER,
+            scheduled_for INTEGER,
+            skipped INTEGER NOT NULL DEFAULT 0,
+            job_id INTEGER,
+            created_at INTEGER,
+            FOREIGN KEY(schedule_id) REFERENCES schedules(id),
+            FOREIGN KEY(job_id) REFERENCES jobs(id)
+        )",
+        [],
+    )?;
+    con.execute(
+        "CREATE INDEX IF NOT EXISTS schedule_runs_schedule ON schedule_runs(schedule_id, id)",
+        [],
+    )
+}
+
+/// Schedule of the app, no permission checks.
+fn by_id(con: &Connection, app_id: i32, schedule_id: i32) -> SqlResult<Option<Schedule>> {
+    query_row!(con => "SELECT * FROM schedules WHERE id = ? AND app_id = ?", [schedule_id, app_id], Schedule)
+        .optional()
+}
+
+fn for_app(con: &Connection, app_id: i32, user_id: i32) -> SqlResult<Vec<Schedule>> {
+    has_permission(con, app_id, user_id)?;
+    Ok(query_rows!(con => "SELECT * FROM schedules WHERE app_id = ? ORDER BY id", [app_id], Schedule))
+}
+
+fn get(
+    con: &Connection,
+    app_id: i32,
+    user_id: i32,
+    schedule_id: i32,
+) -> SqlResult<Option<Schedule>> {
+    has_permission(con, app_id, user_id)?;
+    by_id(con, app_id, schedule_id)
+}
+
+/// `new_schedule` has to be validated, runs start from now.
+fn create(
+    con: &mut Connection,
+    app_id: i32,
+    user_id: i32,
+    new_schedule: NewSchedule,
+) -> Result<Schedule, ScheduleError> {
+    let tx = con.transaction()?;
+    has_permission(&tx, app_id, user_id)?;
+    if !broker_pools::exists(&tx, app_id, new_schedule.job.pool_id)? {
+        return Err(ScheduleError::UnknownPool);
+    }
+    let now = now();
+    let job = &new_schedule.job;
+    query_execute!(tx => "
+        INSERT INTO schedules(app_id, cron, timezone, template, parameters, labels, pool_id, missed_runs, created_by, created_at, updated_at)
+        VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?)",
+        (
+            app_id,
+            &new_schedule.cron,
+            &new_schedule.timezone,
+            &job.template,
+            Value::Object(job.parameters.clone()).to_string(),
+            join_labels(&job.labels),
+            job.pool_id,
+            new_schedule.missed_runs.as_str(),
+            user_id,
+            now,
+            now
+        )
+    )?;
+    let schedule_id = tx.last_insert_rowid() as i32;
+    let next_run_at = by_id(&tx, app_id, schedule_id)?.unwrap().next_after(now);
+    query_execute!(tx => "UPDATE schedules SET next_run_at = ? WHERE id = ?", (next_run_at, schedule_id))?;
+    let schedule = by_id(&tx, app_id, schedule_id)?.unwrap();
+
+    events::record(&tx, app_id, &AppEvent::ScheduleAdded { schedule_id })?;
+    let change = Change {
+        actor_id: Some(user_id),
+        app_id: Some(app_id),
+        action: "schedule.create",
+        target: format!("schedule:{schedule_id}"),
+        before: None,
+        after: snapshot(&schedule),
+    };
+    audit::record(&tx, change)?;
+    tx.commit()?;
+    Ok(schedule)
+}
+
+/**
+    Replaces everything but the paused state, `None` if the schedule does not exist.
+    The next run is computed again from now, unless paused.
+*/
+fn update(
+    con: &mut Connection,
+    app_id: i32,
+    user_id: i32,
+    schedule_id: i32,
+    update: NewSchedule,
+) -> Result<Option<Schedule>, ScheduleError> {
+    let tx = con.transaction()?;
+    has_permission(&tx, app_id, user_id)?;
+    let Some(before) = by_id(&tx, app_id, schedule_id)? else {
+        return Ok(None);
+    };
+    if !broker_pools::exists(&tx, app_id, update.job.pool_id)? {
+        return Err(ScheduleError::UnknownPool);
+    }
+    let now = now();
+    let job = &update.job;
+    query_execute!(tx => "
+        UPDATE schedules SET
+            cron = ?, timezone = ?, template = ?, parameters = ?, labels = ?, pool_id = ?, missed_runs = ?, updated_at = ?
+        WHERE id = ?",
+        (
+            &update.cron,
+            &update.timezone,
+            &job.template,
+            Value::Object(job.parameters.clone()).to_string(),
+            join_labels(&job.labels),
+            job.pool_id,
+            update.missed_runs.as_str(),
+            now,
+            schedule_id
+        )
+    )?;
+    let updated = by_id(&tx, app_id, schedule_id)?.unwrap();
+    let next_run_at = (!updated.paused).then(|| updated.next_after(now)).flatten();
+    query_execute!(tx => "UPDATE schedules SET next_run_at = ? WHERE id = ?", (next_run_at, schedule_id))?;
+    let after = by_id(&tx, app_id, schedule_id)?.unwrap();
+
+    events::record(&tx, app_id, &AppEvent::ScheduleUpdated { schedule_id })?;
+    let change = Change {
+        actor_id: Some(user_id),
+        app_id: Some(app_id),
+        action: "schedule.update",
+        target: format!("schedule:{schedule_id}"),
+        before: snapshot(&before),
+        after: snapshot(&after),
+    };
+    audit::record(&tx, change)?;
+    tx.commit()?;
+    Ok(Some(after))
+}
+
+/// History goes with the schedule, its jobs stay.
+fn delete(con: &mut Connection, app_id: i32, user_id: i32, schedule_id: i32) -> SqlResult<usize> {
+    let tx = con.transaction()?;
+    has_permission(&tx, app_id, user_id)?;
+    let Some(before) = by_id(&tx, app_id, schedule_id)? else {
+        return Ok(0);
+    };
+    query_execute!(tx => "DELETE FROM schedule_runs WHERE schedule_id = ?", [schedule_id])?;
+    query_execute!(tx => "UPDATE jobs SET schedule_id = NULL WHERE schedule_id = ?", [schedule_id])?;
+    let result = query_execute!(tx => "DELETE FROM schedules WHERE id = ?", [schedule_id])?;
+
+    events::record(&tx, app_id, &AppEvent::ScheduleRemoved { schedule_id })?;
+    let change = Change {
+        actor_id: Some(user_id),
+        app_id: Some(app_id),
+        action: "schedule.delete",
+        target: format!("schedule:{schedule_id}"),
+        before: snapshot(&before),
+        after: None,
+    };
+    audit::record(&tx, change)?;
+    tx.commit()?;
+    Ok(result)
+}
+
+/**
+    Runs that would have been due while paused are not missed,
+    a resumed schedule continues with the next match after now.
+*/
+fn set_paused(
+    con: &mut Connection,
+    app_id: i32,
+    user_id: i32,
+    schedule_id: i32,
+    paused: bool,
+) -> SqlResult<Option<Schedule>> {
+    let tx = con.transaction()?;
+    has_permission(&tx, app_id, user_id)?;
+    let Some(before) = by_id(&tx, app_id, schedule_id)? else {
+        return Ok(None);
+    };
+    if before.paused == paused {
+        return Ok(Some(before));
+    }
+    let next_run_at = (!paused).then(|| before.next_after(now())).flatten();
+    query_execute!(tx => "UPDATE schedules SET paused = ?, next_run_at = ?, updated_at = ? WHERE id = ?",
+        (paused, next_run_at, now(), schedule_id))?;
+    let after = by_id(&tx, app_id, schedule_id)?.unwrap();
+
+    let (event, action) = match paused {
+        true => (AppEvent::SchedulePaused { schedule_id }, "schedule.pause"),
+        false => (AppEvent::ScheduleResumed { schedule_id }, "schedule.resume"),
+    };
+    events::record(&tx, app_id, &event)?;
+    let change = Change {
+        actor_id: Some(user_id),
+        app_id: Some(app_id),
+        action,
+        target: format!("schedule:{schedule_id}"),
+        before: snapshot(&before),
+        after: snapshot(&after),
+    };
+    audit::record(&tx, change)?;
+    tx.commit()?;
+    Ok(Some(after))
+}
+
+/// `None` if the schedule does not exist in the app.
+fn runs(
+    con: &Connection,
+    app_id: i32,
+    user_id: i32,
+    schedule_id: i32,
+    query: &RunQuery,
+) -> SqlResult<Option<Vec<ScheduleRun>>> {
+    has_permission(con, app_id, user_id)?;
+    if by_id(con, app_id, schedule_id)?.is_none() {
+        return Ok(None);
+    }
+    let limit = query.limit.unwrap_or(DEFAULT_LIMIT).clamp(1, MAX_LIMIT);
+    Ok(Some(query_rows!(con => "
+        SELECT schedule_runs.*, jobs.status AS job_status FROM schedule_runs
+        LEFT JOIN jobs ON jobs.id = schedule_runs.job_id
+        WHERE schedule_runs.schedule_id = ? AND schedule_runs.id < ?
+        ORDER BY schedule_runs.id DESC LIMIT ?",
+        (schedule_id, query.before_id.unwrap_or(i64::MAX), limit),
+        ScheduleRun
+    )))
+}
+
+/**
+    Queues a job for every run of active schedules due at `at`, returns how many runs were made or skipped.
+    Runs more than `MISSED_AFTER_SECS` late were missed while the server was down,
+    they are queued or skipped as the `MissedRuns` policy of the schedule says.
+    Only the latest `MAX_MISSED_RUNS` of them are looked at, older ones leave no trace.
+*/
+fn run_due(con: &mut Connection, at: u64) -> SqlResult<usize> {
+    let tx = con.transaction()?;
+    let due = query_rows!(tx => "
+        SELECT schedules.* FROM schedules JOIN apps ON apps.id = schedules.app_id
+        WHERE schedules.paused = 0 AND schedules.next_run_at <= ? AND apps.deleted_at IS NULL
+        ORDER BY schedules.next_run_at",
+        [at], Schedule
+    );
+    let mut changed = 0;
+    for schedule in due {
+        let schedule_id = schedule.id;
+        let mut times = VecDeque::new();
+        let mut next_run_at = schedule.next_run_at;
+        while let Some(time) = next_run_at.filter(|time| *time <= at) {
+            if times.len() == MAX_MISSED_RUNS {
+                times.pop_front();
+            }
+            times.push_back(time);
+            next_run_at = schedule.next_after(time);
+        }
+
+        let mut last_run_at = schedule.last_run_at;
+        for scheduled_for in times {
+            let missed = scheduled_for + MISSED_AFTER_SECS < at;
+            if missed && schedule.missed_runs == MissedRuns::Skip {
+                query_execute!(tx => "INSERT INTO schedule_runs(schedule_id, scheduled_for, skipped, created_at) VALUES (?, ?, 1, ?)",
+                    (schedule_id, scheduled_for, at))?;
+                let event = AppEvent::ScheduleRunSkipped {
+                    schedule_id,
+                    scheduled_for,
+                };
+                events::record(&tx, schedule.app_id, &event)?;
+            } else {
+                let job = jobs::insert(
+                    &tx,
+                    schedule.app_id,
+                    None,
+                    Some(schedule_id),
+                    &schedule.job(),
+                )?;
+                query_execute!(tx => "INSERT INTO schedule_runs(schedule_id, scheduled_for, job_id, created_at) VALUES (?, ?, ?, ?)",
+                    (schedule_id, scheduled_for, job.id, at))?;
+                let event = AppEvent::ScheduleTriggered {
+                    schedule_id,
+                    job_id: job.id,
+                    scheduled_for,
+                };
+                events::record(&tx, schedule.app_id, &event)?;
+                last_run_at = Some(scheduled_for);
+            }
+            changed += 1;
+        }
+
+        query_execute!(tx => "UPDATE schedules SET next_run_at = ?, last_run_at = ? WHERE id = ?",
+            (next_run_at, last_run_at, schedule_id))?;
+        query_execute!(tx => "
+            DELETE FROM schedule_runs WHERE schedule_id = ?1 AND id <= (
+                SELECT id FROM schedule_runs WHERE schedule_id = ?1 ORDER BY id DESC LIMIT 1 OFFSET ?2
+            )",
+            (schedule_id, RUNS_KEPT))?;
+    }
+    tx.commit()?;
+    Ok(changed)
+}
@@ -0,0 +1,546 @@
+use axum_utils::{copy, copy_mut};
+use rusqlite::{Connection, Error, OptionalExtension, Row};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+
+use super::apps::has_permission;
+use super::artifacts::Artifact;
+use super::audit::{self, snapshot, Change};
+use super::events::{self, now, AppEvent};
+use super::{query_execute, query_row, query_rows, Con, SqlResult};
+use crate::crypto::{sign, verify_signature};
+
+/// Longest a link may stay valid, in seconds.
+pub const MAX_LIFETIME_SECS: u64 = 90 * 24 * 60 * 60;
+
+/**
+    Links to an artifact for people without an account.
+    The token carries the link id and expiry signed with HMAC, see `token`,
+    so forged or altered links are rejected before the database is asked.
+    Links can have a password, only its bcrypt hash is stored.
+*/
+pub struct ShareLinks {
+    con: Con,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct ShareLink {
+    pub id: i64,
+    pub app_id: i32,
+    pub artifact_id: i64,
+    /// Whether a password is needed to download.
+    pub protected: bool,
+    pub expires_at: u64,
+    pub downloads: u64,
+    pub last_download_at: Option<u64>,
+    pub revoked_at: Option<u64>,
+    pub created_by: Option<i32>,
+    pub created_at: u64,
+}
+
+impl ShareLink {
+    pub fn from_row(row: &Row) -> SqlResult<Self> {
+        Ok(Self {
+            id: row.get("id")?,
+            app_id: row.get("app_id")?,
+            artifact_id: row.get("artifact_id")?,
+            protected: row.get::<_, Option<String>>("password_hash")?.is_some(),
+            expires_at: row.get("expires_at")?,
+            downloads: row.get("downloads")?,
+            last_download_at: row.get("last_download_at")?,
+            revoked_at: row.get("revoked_at")?,
+            created_by: row.get("created_by")?,
+            created_at: row.get("created_at")?,
+        })
+    }
+
+    /// What goes into the url, only handed out on creation.
+    pub fn token(&self) -> String {
+        let signed = signed_part(self.id, self.expires_at);
+        format!("{signed}.{}", sign(&signed))
+    }
+}
+
+fn signed_part(share_id: i64, expires_at: u64) -> String {
+    format!("{share_id}.{expires_at}")
+}
+
+/// Link id and expiry of a token, `None` if it was not made by `ShareLink::token`.
+pub fn verify_token(token: &str) -> Option<(i64, u64)> {
+    let (signed, signature) = token.rsplit_once('.')?;
+    if !verify_signature(signed, signature) {
+        return None;
+    }
+    let (share_id, expires_at) = signed.split_once('.')?;
+    Some((share_id.parse().ok()?, expires_at.parse().ok()?))
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct NewShareLink {
+    /// Unix time, at most `MAX_LIFETIME_SECS` ahead.
+    pub expires_at: u64,
+    #[serde(default)]
+    pub password: Option<String>,
+}
+
+pub enum ShareLinkError {
+    /// Link would be valid after the artifact expires.
+    OutlivesArtifact,
+    SqliteError(Error),
+}
+
+impl From<Error> for ShareLinkError {
+    fn from(e: Error) -> Self {
+        ShareLinkError::SqliteError(e)
+    }
+}
+
+/// Outcome of using a link.
+pub enum ShareAccess {
+    Granted(Artifact),
+    /// No password was sent for a protected link.
+    PasswordRequired,
+    WrongPassword,
+    /// Unknown, revoked or expired, also when the artifact is gone.
+    Gone,
+}
+
+impl ShareLinks {
+    pub fn new(con: &Con) -> Self {
+        Self { con: con.clone() }
+    }
+
+    copy!(create_table() -> SqlResult<usize>);
+
+    /// `None` if the app has no such artifact. bcrypt runs before the connection is taken.
+    pub fn create(
+        &self,
+        app_id: i32,
+        user_id: i32,
+        artifact_id: i64,
+        new_link: &NewShareLink,
+    ) -> Result<Option<ShareLink>, ShareLinkError> {
+        let password_hash = new_link
+            .password
+            .as_ref()
+            .map(|password| bcrypt::hash(password, 10).unwrap());
+        let mut con = self.con.lock().unwrap();
+        create(
+            &mut con,
+            app_id,
+            user_id,
+            artifact_id,
+            new_link.expires_at,
+            password_hash.as_deref(),
+        )
+    }
+
+    copy!(for_artifact(app_id: i32, user_id: i32, artifact_id: i64) -> SqlResult<Option<Vec<ShareLink>>>);
+    copy_mut!(revoke(app_id: i32, user_id: i32, artifact_id: i64, share_id: i64) -> SqlResult<Option<ShareLink>>);
+
+    /**
+        Checks a link from a verified token, bcrypt runs without holding the connection.
+        Only requests with `count_download` count as a download, so resuming one with a
+        `Range` doesn't count it twice. Every access is audited without an actor,
+        wrong passwords and uncounted ones included.
+    */
+    pub fn access(
+        &self,
+        share_id: i64,
+        expires_at: u64,
+        password: Option<&str>,
+        count_download: bool,
+    ) -> SqlResult<ShareAccess> {
+        let link = {
+            let con = self.con.lock().unwrap();
+            usable(&con, share_id, expires_at)?
+        };
+        let Some(link) = link else {
+            return Ok(ShareAccess::Gone);
+        };
+        let denied = match (&link.password_hash, password) {
+            (None, _) => false,
+            (Some(_), None) => return Ok(ShareAccess::PasswordRequired),
+            (Some(hash), Some(password)) => !bcrypt::verify(password, hash).unwrap_or(false),
+        };
+
+        let mut con = self.con.lock().unwrap();
+        let counted = count_download && !denied;
+        if !record_access(&mut con, share_id, expires_at, denied, counted)? {
+            return Ok(ShareAccess::Gone);
+        }
+        Ok(match denied {
+            true => ShareAccess::WrongPassword,
+            false => ShareAccess::Granted(link.artifact),
+        })
+    }
+}
+
+fn create_table(con: &Connection) -> SqlResult<usize> {
+    con.execute(
+        "CREATE TABLE IF NOT EXISTS share_links (
+            id INTEGER PRIMARY KEY AUTOINCREMENT,
+            app_id INTEGER,
+            artifact_id INTEGER,
+            password_hash TEXT,
+            expires_at INTEGER,
+            downloads INTEGER DEFAULT 0,
+            last_download_at INTEGER,
+            revoked_at INTEGER,
+            created_by INTEGER,
+            created_at INTEGER,
+            FOREIGN KEY(app_id) REFERENCES apps(id),
+            FOREIGN KEY(artifact_id) REFERENCES artifacts(id)
+        )",
+        [],
+    )?;
+    con.execute(
+        "CREATE INDEX IF NOT EXISTS share_links_artifact_id ON share_links(artifact_id)",
+        [],
+    )
+}
+
+fn by_id(con: &Connection, share_id: i64) -> SqlResult<Option<ShareLink>> {
+    query_row!(con => "SELECT * FROM share_links WHERE id = ?", [share_id], ShareLink).optional()
+}
+
+fn artifact(con: &Connection, app_id: i32, artifact_id: i64) -> SqlResult<Option<Artifact>> {
+    query_row!(con => "SELECT * FROM artifacts WHERE id = ? AND app_id = ?", (artifact_id, app_id), Artifact)
+        .optional()
+}
+
+/// `None` if the app has no such artifact.
+fn create(
+    con: &mut Connection,
+    app_id: i32,
+    user_id: i32,
+    artifact_id: i64,
+    expires_at: u64,
+    password_hash: Option<&str>,
+) -> Result<Option<ShareLink>, ShareLinkError> {
+    let tx = con.transaction()?;
+    has_permission(&tx, app_id, user_id)?;
+    let Some(artifact) = artifact(&tx, app_id, artifact_id)? else {
+        return Ok(None);
+    };
+    if expires_at > artifact.expires_at {
+        return Err(ShareLinkError::OutlivesArtifact);
+    }
+    query_execute!(tx => "
+        INSERT INTO share_links(app_id, artifact_id, password_hash, expires_at, created_by, created_at)
+        VALUES (?, ?, ?, ?, ?, ?)",
+        (app_id, artifact_id, password_hash, expires_at, user_id, now())
+    )?;
+    let share_id = tx.last_insert_rowid();
+    let link = by_id(&tx, share_id)?.unwrap();
+
+    let event = AppEvent::ShareLinkCreated {
+        share_id,
+        artifact_id,
+    };
+    events::record(&tx, app_id, &event)?;
+    let change = Change {
+        actor_id: Some(user_id),
+        app_id: Some(app_id),
+        action: "shareLink.create",
+        target: format!("shareLink:{share_id}"),
+        before: None,
+        after: snapshot(&link),
+    };
+    audit::record(&tx, change)?;
+    tx.commit()?;
+    Ok(Some(link))
+}
+
+/// Revoked and expired links included, `None` if the app has no such artifact.
+fn for_artifact(
+    con: &Connection,
+    app_id: i32,
+    user_id: i32,
+    artifact_id: i64,
+) -> SqlResult<Option<Vec<ShareLink>>> {
+    has_permission(con, app_id, user_id)?;
+    if artifact(con, app_id, artifact_id)?.is_none() {
+        return Ok(None);
+    }
+    Ok(Some(query_rows!(con => "
+        SELECT * FROM share_links WHERE artifact_id = ? ORDER BY id DESC",
+        [artifact_id], ShareLink)))
+}
+
+/// Link keeps its counters, revoking twice is not an error.
+fn revoke(
+    con: &mut Connection,
+    app_id: i32,
+    user_id: i32,
+    artifact_id: i64,
+    share_id: i64,
+) -> SqlResult<Option<ShareLink>> {
+    let tx = con.transaction()?;
+    has_permission(&tx, app_id, user_id)?;
+    let Some(before) = by_id(&tx, share_id)? else {
+        return Ok(None);
+    };
+    if before.app_id != app_id || before.artifact_id != artifact_id {
+        return Ok(None);
+    }
+    if before.revoked_at.is_some() {
+        return Ok(Some(before));
+    }
+    query_execute!(tx => "UPDATE share_links SET revoked_at = ? WHERE id = ?", (now(), share_id))?;
+    let after = by_id(&tx, share_id)?.unwrap();
+
+    events::record(&tx, app_id, &AppEvent::ShareLinkRevoked { share_id })?;
+    let change = Change {
+        actor_id: Some(user_id),
+        app_id: Some(app_id),
+        action: "shareLink.revoke",
+        target: format!("shareLink:{share_id}"),
+        before: snapshot(&before),
+        after: snapshot(&after),
+    };
+    audit::record(&tx, change)?;
+    tx.commit()?;
+    Ok(Some(after))
+}
+
+/// Link that can still be used, looked up for `ShareLinks::access`.
+struct UsableLink {
+    app_id: i32,
+    artifact: Artifact,
+    password_hash: Option<String>,
+}
+
+/// `None` if the link is unknown, revoked or expired, or its artifact or app is gone.
+fn usable(con: &Connection, share_id: i64, expires_at: u64) -> SqlResult<Option<UsableLink>> {
+    let link: Option<(i32, i64, Option<String>)> = con
+        .query_row(
+            "SELECT share_links.app_id, artifact_id, password_hash FROM share_links
+            JOIN apps ON apps.id = share_links.app_id
+            WHERE share_links.id = ? AND share_links.expires_at = ? AND share_links.expires_at > ?
+                AND revoked_at IS NULL AND apps.deleted_at IS NULL",
+            (share_id, expires_at, now()),
+            |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)),
+        )
+        .optional()?;
+    let Some((app_id, artifact_id, password_hash)) = link else {
+        return Ok(None);
+    };
+    let Some(artifact) = artifact(con, app_id, artifact_id)? else {
+        return Ok(None);
+    };
+    Ok(Some(UsableLink {
+        app_id,
+        artifact,
+        password_hash,
+    }))
+}
+
+/**
+    Audits a download or a wrong password and counts the download if `counted`,
+    `false` if the link stopped working while the password was checked.
+*/
+fn record_access(
+    con: &mut Connection,
+    share_id: i64,
+    expires_at: u64,
+    denied: bool,
+    counted: bool,
+) -> SqlResult<bool> {
+    let tx = con.transaction()?;
+    let Some(link) = usable(&tx, share_id, expires_at)? else {
+        return Ok(false);
+    };
+    if counted {
+        query_execute!(tx => "
+            UPDATE share_links SET downloads = downloads + 1, last_download_at = ? WHERE id = ?",
+            (now(), share_id)
+        )?;
+    }
+    let change = Change {
+        actor_id: None,
+        app_id: Some(link.app_id),
+        action: if denied {
+            "shareLink.denied"
+        } else {
+            "shareLink.download"
+        },
+        target: format!("shareLink:{share_id}"),
+        before: None,
+        after: Some(match denied {
+            true => json!({ "artifactId": link.artifact.id }),
+            false => json!({ "artifactId": link.artifact.id, "counted": counted }),
+        }),
+    };
+    audit::record(&tx, change)?;
+    tx.commit()?;
+    Ok(true)
+}
+
+/// Links of artifacts about to be removed, the caller's transaction removes the artifacts.
+pub(super) fn delete_for_artifact(con: &Connection, artifact_id: i64) -> SqlResult<usize> {
+    query_execute!(con => "DELETE FROM share_links WHERE artifact_id = ?", [artifact_id])
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::Value;
+
+    use super::*;
+    use crate::db::{audit::AuditFilter, jobs::NewJob, testing, SqliteDb};
+
+    /// App with an artifact kept for a day, returns app, author and artifact.
+    fn setup() -> (SqliteDb, i32, i32, i64) {
+        let db = testing::db();
+        let author = testing::user(&db, "alice");
+        let app_id = testing::app(&db, author);
+        let new_job = NewJob {
+            template: "monthly".to_string(),
+            parameters: Default::default(),
+            labels: Vec::new(),
+            pool_id: None,
+        };
+        let job_id = db.jobs.create(app_id, author, new_job).unwrap().unwrap().id;
+        let con = db.con.lock().unwrap();
+        con.execute(
+            "INSERT INTO artifacts(app_id, job_id, name, content_type, size, sha256, created_by, created_at, expires_at)
+            VALUES (?, ?, 'report.pdf', 'application/pdf', 10, 'abc', ?, ?, ?)",
+            (app_id, job_id, author, now(), now() + 24 * 60 * 60),
+        )
+        .unwrap();
+        let artifact_id = con.last_insert_rowid();
+        drop(con);
+        (db, app_id, author, artifact_id)
+    }
+
+    /// Cheaper bcrypt than `ShareLinks::create`, tests don't need the cost.
+    fn link(
+        db: &SqliteDb,
+        app_id: i32,
+        author: i32,
+        artifact_id: i64,
+        password: Option<&str>,
+    ) -> ShareLink {
+        let hash = password.map(|password| bcrypt::hash(password, 4).unwrap());
+        let mut con = db.con.lock().unwrap();
+        let expires_at = now() + 3600;
+        create(
+            &mut con,
+            app_id,
+            author,
+            artifact_id,
+            expires_at,
+            hash.as_deref(),
+        )
+        .ok()
+        .flatten()
+        .unwrap()
+    }
+
+    fn access(
+        db: &SqliteDb,
+        link: &ShareLink,
+        password: Option<&str>,
+        count_download: bool,
+    ) -> &'static str {
+        let access = db
+            .share_links
+            .access(link.id, link.expires_at, password, count_download)
+            .unwrap();
+        match access {
+            ShareAccess::Granted(_) => "granted",
+            ShareAccess::PasswordRequired => "passwordRequired",
+            ShareAccess::WrongPassword => "wrongPassword",
+            ShareAccess::Gone => "gone",
+        }
+    }
+
+    fn downloads(db: &SqliteDb, link: &ShareLink) -> u64 {
+        let con = db.con.lock().unwrap();
+        by_id(&con, link.id).unwrap().unwrap().downloads
+    }
+
+    /// Audited accesses of the app's links, they have no actor, oldest first.
+    fn audited(db: &SqliteDb, app_id: i32) -> Vec<(String, Option<Value>)> {
+        let filter = AuditFilter {
+            app_id: Some(app_id),
+            ..Default::default()
+        };
+        let mut entries = db.audit.query(filter).unwrap();
+        entries.retain(|entry| entry.actor_id.is_none());
+        entries.reverse();
+        entries
+            .into_iter()
+            .map(|entry| (entry.action, entry.after))
+            .collect()
+    }
+
+    #[test]
+    fn every_access_is_audited_and_only_full_downloads_count() {
+        let (db, app_id, author, artifact_id) = setup();
+        let link = link(&db, app_id, author, artifact_id, None);
+
+        assert_eq!(access(&db, &link, None, true), "granted");
+        assert_eq!(access(&db, &link, None, false), "granted");
+        assert_eq!(downloads(&db, &link), 1);
+        let counted = |counted| json!({ "artifactId": artifact_id, "counted": counted });
+        assert_eq!(
+            audited(&db, app_id),
+            [
+                ("shareLink.download".to_string(), Some(counted(true))),
+                ("shareLink.download".to_string(), Some(counted(false))),
+            ]
+        );
+    }
+
+    #[test]
+    fn wrong_passwords_are_audited_and_never_counted() {
+        let (db, app_id, author, artifact_id) = setup();
+        let link = link(&db, app_id, author, artifact_id, Some("s3cret"));
+
+        assert_eq!(access(&db, &link, None, true), "passwordRequired");
+        assert!(audited(&db, app_id).is_empty());
+        assert_eq!(access(&db, &link, Some("guess"), true), "wrongPassword");
+        assert_eq!(access(&db, &link, Some("guess"), false), "wrongPassword");
+        assert_eq!(downloads(&db, &link), 0);
+        assert_eq!(access(&db, &link, Some("s3cret"), false), "granted");
+        assert_eq!(downloads(&db, &link), 0);
+
+        let actions: Vec<String> = audited(&db, app_id)
+            .into_iter()
+            .map(|(action, _)| action)
+            .collect();
+        assert_eq!(
+            actions,
+            ["shareLink.denied", "shareLink.denied", "shareLink.download"]
+        );
+    }
+
+    #[test]
+    fn revoked_and_altered_links_are_gone() {
+        let (db, app_id, author, artifact_id) = setup();
+        let link = link(&db, app_id, author, artifact_id, None);
+        assert_eq!(
+            verify_token(&link.token()),
+            Some((link.id, link.expires_at))
+        );
+        let altered = link.token().replacen(&link.id.to_string(), "999", 1);
+        assert_eq!(verify_token(&altered), None);
+
+        // the expiry is part of the token, a different one finds nothing
+        let later = ShareLink {
+            expires_at: link.expires_at + 1,
+            ..link.clone()
+        };
+        assert_eq!(access(&db, &later, None, true), "gone");
+
+        db.share_links
+            .revoke(app_id, author, artifact_id, link.id)
+            .unwrap();
+        assert_eq!(access(&db, &link, None, true), "gone");
+        assert!(audited(&db, app_id).is_empty());
+        assert_eq!(downloads(&db, &link), 0);
+    }
+}
@@ -0,0 +1,384 @@
+use rusqlite::{Connection, Error, OptionalExtension};
+
+use super::audit::{self, Change};
+use super::events::{self, now, AppEvent};
+use super::{query_execute, Con, SqlResult};
+
+/// Soft deleted rows can be restored by the app author for this long.
+pub const RESTORE_GRACE_SECS: u64 = 14 * 24 * 60 * 60;
+/// Soft deleted rows older than this are removed for good.
+pub const PURGE_AFTER_SECS: u64 = 30 * 24 * 60 * 60;
+
+/**
+    Soft deleted rows: restore within grace period and purge past retention.
+*/
+pub struct Trash {
+    con: Con,
+}
+
+#[derive(Clone, Copy)]
+pub enum Trashed {
+    App,
+    Operator,
+    AppUser,
+    Broker,
+}
+
+impl Trashed {
+    fn table(self) -> &'static str {
+        match self {
+            Trashed::App => "apps",
+            Trashed::Operator => "operators",
+            Trashed::AppUser => "app_users",
+            Trashed::Broker => "brokers",
+        }
+    }
+
+    fn name(self) -> &'static str {
+        match self {
+            Trashed::App => "app",
+            Trashed::Operator => "operator",
+            Trashed::AppUser => "appUser",
+            Trashed::Broker => "broker",
+        }
+    }
+}
+
+pub enum RestoreError {
+    /// The row belongs to a user who is deleted, purge would fail on it.
+    UserDeleted,
+    SqliteError(Error),
+}
+
+impl From<Error> for RestoreError {
+    fn from(e: Error) -> Self {
+        RestoreError::SqliteError(e)
+    }
+}
+
+impl Trash {
+    pub fn new(con: &Con) -> Self {
+        Self { con: con.clone() }
+    }
+
+    /// Returns restored row count, 0 if nothing was deleted recently enough.
+    pub fn restore(
+        &self,
+        app_id: i32,
+        user_id: i32,
+        kind: Trashed,
+        id: i32,
+    ) -> Result<usize, RestoreError> {
+        let mut con = self.con.lock().unwrap();
+        restore(&mut con, app_id, user_id, kind, id)
+    }
+
+    /// Removes rows soft deleted before `older_than`.
+    pub fn purge(&self, older_than: u64) -> SqlResult<usize> {
+        let mut con = self.con.lock().unwrap();
+        purge(&mut con, older_than)
+    }
+}
+
+fn restore(
+    con: &mut Connection,
+    app_id: i32,
+    user_id: i32,
+    kind: Trashed,
+    id: i32,
+) -> Result<usize, RestoreError> {
+    let tx = con.transaction()?;
+    let deleted_after = now().saturating_sub(RESTORE_GRACE_SECS);
+
+    let result = match kind {
+        Trashed::App => {
+            let deleted_at: Option<u64> = tx
+                .query_row(
+                    "SELECT deleted_at FROM apps WHERE id = ? AND author_id = ? AND deleted_at >= ?",
+                    (app_id, user_id, deleted_after),
+                    |row| row.get(0),
+                )
+                .optional()?;
+            let Some(deleted_at) = deleted_at else {
+                return Ok(0);
+            };
+
+            // members who are deleted themselves stay in the trash and are purged with their user
+            for child in ["operators", "app_users"] {
+                let sql = format!(
+                    "UPDATE {child} SET deleted_at = NULL WHERE app_id = ? AND deleted_at = ?
+                        AND user_id NOT IN (SELECT id FROM users WHERE deleted_at IS NOT NULL)"
+                );
+                tx.execute(&sql, (app_id, deleted_at))?;
+            }
+            query_execute!(tx => "
+                UPDATE brokers SET deleted_at = NULL WHERE app_id = ? AND deleted_at = ?",
+                (app_id, deleted_at)
+            )?;
+            query_execute!(tx => "UPDATE apps SET deleted_at = NULL WHERE id = ?", [app_id])?
+        }
+        _ => {
+            tx.query_row(
+                "SELECT id FROM apps WHERE id = ? AND author_id = ? AND deleted_at IS NULL",
+                [app_id, user_id],
+                |_| Ok(()),
+            )?;
+            if let Trashed::Operator | Trashed::AppUser = kind {
+                let sql = format!(
+                    "SELECT users.deleted_at IS NOT NULL FROM {0}
+                    JOIN users ON users.id = {0}.user_id
+                    WHERE {0}.id = ? AND {0}.app_id = ? AND {0}.deleted_at >= ?",
+                    kind.table()
+                );
+                let user_deleted: Option<bool> = tx
+                    .query_row(&sql, (id, app_id, deleted_after), |row| row.get(0))
+                    .optional()?;
+                if user_deleted == Some(true) {
+                    return Err(RestoreError::UserDeleted);
+                }
+            }
+            let sql = format!(
+                "UPDATE {} SET deleted_at = NULL WHERE id = ? AND app_id = ? AND deleted_at >= ?",
+                kind.table()
+            );
+            let restored = tx.execute(&sql, (id, app_id, deleted_after))?;
+            if restored > 0 {
+                events::record(&tx, app_id, &restored_event(&tx, kind, id)?)?;
+            }
+            restored
+        }
+    };
+
+    if result > 0 {
+        let change = Change {
+            actor_id: Some(user_id),
+            app_id: Some(app_id),
+            action: &format!("{}.restore", kind.name()),
+            target: format!("{}:{id}", kind.name()),
+            before: None,
+            after: None,
+        };
+        audit::record(&tx, change)?;
+    }
+    tx.commit()?;
+    Ok(result)
+}
+
+/// Same event as creating the row, streams and webhooks saw it removed.
+fn restored_event(con: &Connection, kind: Trashed, id: i32) -> SqlResult<AppEvent> {
+    let sql = format!("SELECT * FROM {} WHERE id = ?", kind.table());
+    con.query_row(&sql, [id], |row| {
+        Ok(match kind {
+            Trashed::Operator => AppEvent::OperatorAdded {
+                operator_id: id,
+                user_id: row.get("user_id")?,
+            },
+            Trashed::AppUser => AppEvent::AppUserAdded {
+                app_user_id: id,
+                user_id: row.get("user_id")?,
+            },
+            Trashed::Broker => AppEvent::BrokerAdded {
+                broker_id: id,
+                name: row.get("name")?,
+            },
+            // deleting an app records no events, neither does restoring it
+            Trashed::App => unreachable!("apps are restored with their members"),
+        })
+    })
+}
+
+fn purge(con: &mut Connection, older_than: u64) -> SqlResult<usize> {
+    let tx = con.transaction()?;
+    // webhooks, app restricted access tokens, broker pools, configs, logs, jobs,
+    // schedules, artifacts and share links are not soft deleted, they go away with their app or broker
+    query_execute!(tx => "
+        DELETE FROM webhook_deliveries WHERE webhook_id IN (
+            SELECT webhooks.id FROM webhooks JOIN apps ON apps.id = webhooks.app_id
+            WHERE apps.deleted_at IS NOT NULL AND apps.deleted_at < ?
+        )", [older_than])?;
+    query_execute!(tx => "
+        DELETE FROM webhooks WHERE app_id IN (
+            SELECT id FROM apps WHERE deleted_at IS NOT NULL AND deleted_at < ?
+        )", [older_than])?;
+    query_execute!(tx => "
+        DELETE FROM access_tokens WHERE app_id IN (
+            SELECT id FROM apps WHERE deleted_at IS NOT NULL AND deleted_at < ?
+        )", [older_than])?;
+
+    query_execute!(tx => "
+        DELETE FROM broker_configs WHERE app_id IN (
+            SELECT id FROM apps WHERE deleted_at IS NOT NULL AND deleted_at < ?
+        ) OR broker_id IN (
+            SELECT id FROM brokers WHERE deleted_at IS NOT NULL AND deleted_at < ?
+        )", [older_than, older_than])?;
+    query_execute!(tx => "
+        DELETE FROM broker_logs WHERE broker_id IN (
+            SELECT id FROM brokers WHERE deleted_at IS NOT NULL AND deleted_at < ?
+        )", [older_than])?;
+    query_execute!(tx => "
+        DELETE FROM schedule_runs WHERE schedule_id IN (
+            SELECT schedules.id FROM schedules JOIN apps ON apps.id = schedules.app_id
+            WHERE apps.deleted_at IS NOT NULL AND apps.deleted_at < ?
+        )", [older_than])?;
+    // normally expired by `artifacts::expire` first, which also removes their contents
+    query_execute!(tx => "
+        DELETE FROM share_links WHERE app_id IN (
+            SELECT id FROM apps WHERE deleted_at IS NOT NULL AND deleted_at < ?
+        )", [older_than])?;
+    query_execute!(tx => "
+        DELETE FROM artifacts WHERE app_id IN (
+            SELECT id FROM apps WHERE deleted_at IS NOT NULL AND deleted_at < ?
+        )", [older_than])?;
+    query_execute!(tx => "
+        DELETE FROM artifact_policies WHERE app_id IN (
+            SELECT id FROM apps WHERE deleted_at IS NOT NULL AND deleted_at < ?
+        )", [older_than])?;
+    query_execute!(tx => "
+        DELETE FROM jobs WHERE app_id IN (
+            SELECT id FROM apps WHERE deleted_at IS NOT NULL AND deleted_at < ?
+        )", [older_than])?;
+    query_execute!(tx => "
+        DELETE FROM schedules WHERE app_id IN (
+            SELECT id FROM apps WHERE deleted_at IS NOT NULL AND deleted_at < ?
+        )", [older_than])?;
+    // finished jobs of other brokers stay in the history of the app
+    query_execute!(tx => "
+        UPDATE jobs SET broker_id = NULL WHERE broker_id IN (
+            SELECT id FROM brokers WHERE deleted_at IS NOT NULL AND deleted_at < ?
+        )", [older_than])?;
+    query_execute!(tx => "
+        UPDATE artifacts SET broker_id = NULL WHERE broker_id IN (
+            SELECT id FROM brokers WHERE deleted_at IS NOT NULL AND deleted_at < ?
+        )", [older_than])?;
+
+    for table in [
+        "recovery_codes",
+        "user_totp",
+        "password_resets",
+        "access_tokens",
+        "external_identities",
+    ] {
+        let sql = format!("
+            DELETE FROM {table} WHERE user_id IN (
+                SELECT id FROM users WHERE deleted_at IS NOT NULL AND deleted_at < ?
+            )");
+        tx.execute(&sql, [older_than])?;
+    }
+
+    query_execute!(tx => "
+        UPDATE brokers SET pool_id = NULL WHERE app_id IN (
+            SELECT id FROM apps WHERE deleted_at IS NOT NULL AND deleted_at < ?
+        )", [older_than])?;
+    query_execute!(tx => "
+        DELETE FROM broker_pools WHERE app_id IN (
+            SELECT id FROM apps WHERE deleted_at IS NOT NULL AND deleted_at < ?
+        )", [older_than])?;
+
+    let mut purged = 0;
+    // children first, apps reference users and everything else references apps
+    for table in ["operators", "app_users", "brokers", "apps", "users"] {
+        let sql = format!("DELETE FROM {table} WHERE deleted_at IS NOT NULL AND deleted_at < ?");
+        purged += tx.execute(&sql, [older_than])?;
+    }
+    tx.commit()?;
+    Ok(purged)
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::{json, Value};
+
+    use super::*;
+    use crate::db::{brokers::NewBroker, testing, SqliteDb};
+
+    fn restored(db: &SqliteDb, app_id: i32, kind: Trashed, id: i32) -> usize {
+        let author_id = 1;
+        match db.trash.restore(app_id, author_id, kind, id) {
+            Ok(restored) => restored,
+            Err(_) => panic!("restore failed"),
+        }
+    }
+
+    fn add_operator(db: &SqliteDb, app_id: i32, user_id: i32) -> i32 {
+        let operator = db.operators.create(app_id, 1, user_id).unwrap();
+        let id = serde_json::to_value(operator).unwrap()["id"].as_i64();
+        id.unwrap() as i32
+    }
+
+    fn last_event(db: &SqliteDb, app_id: i32) -> Value {
+        let events = db.events.since(app_id, 0).unwrap();
+        serde_json::to_value(events.last().unwrap()).unwrap()
+    }
+
+    fn set_deleted_at(db: &SqliteDb, table: &str, id: i32, deleted_at: u64) {
+        let sql = format!("UPDATE {table} SET deleted_at = ? WHERE id = ?");
+        let con = db.con.lock().unwrap();
+        con.execute(&sql, (deleted_at, id)).unwrap();
+    }
+
+    #[test]
+    fn restoring_members_and_brokers_records_them_as_added() {
+        let db = testing::db();
+        let (author, bob) = (testing::user(&db, "alice"), testing::user(&db, "bob"));
+        let app_id = testing::app(&db, author);
+
+        let operator_id = add_operator(&db, app_id, bob);
+        db.operators.delete(app_id, author, operator_id).unwrap();
+        assert_eq!(last_event(&db, app_id)["type"], "operatorRemoved");
+        assert_eq!(restored(&db, app_id, Trashed::Operator, operator_id), 1);
+        let event = last_event(&db, app_id);
+        assert_eq!(event["type"], "operatorAdded");
+        assert_eq!(event["operatorId"], operator_id);
+        assert_eq!(event["userId"], bob);
+
+        let new_broker = json!({ "name": "b1", "description": "", "stopped": false });
+        let new_broker: NewBroker = serde_json::from_value(new_broker).unwrap();
+        let broker_id = db.brokers.create(app_id, author, new_broker).unwrap();
+        db.brokers.delete(app_id, author, broker_id).unwrap();
+        assert_eq!(restored(&db, app_id, Trashed::Broker, broker_id), 1);
+        let event = last_event(&db, app_id);
+        assert_eq!(event["type"], "brokerAdded");
+        assert_eq!(event["name"], "b1");
+
+        // nothing left to restore, nothing recorded
+        let events = db.events.since(app_id, 0).unwrap().len();
+        assert_eq!(restored(&db, app_id, Trashed::Broker, broker_id), 0);
+        assert_eq!(db.events.since(app_id, 0).unwrap().len(), events);
+    }
+
+    #[test]
+    fn rows_past_the_grace_period_stay_deleted() {
+        let db = testing::db();
+        let (author, bob) = (testing::user(&db, "alice"), testing::user(&db, "bob"));
+        let app_id = testing::app(&db, author);
+        let operator_id = add_operator(&db, app_id, bob);
+        let deleted_at = now() - RESTORE_GRACE_SECS - 1;
+        set_deleted_at(&db, "operators", operator_id, deleted_at);
+
+        assert_eq!(restored(&db, app_id, Trashed::Operator, operator_id), 0);
+    }
+
+    #[test]
+    fn members_of_deleted_users_are_not_restored() {
+        let db = testing::db();
+        let author = testing::user(&db, "alice");
+        let (bob, carol) = (testing::user(&db, "bob"), testing::user(&db, "carol"));
+        let app_id = testing::app(&db, author);
+        let bobs = add_operator(&db, app_id, bob);
+        let carols = add_operator(&db, app_id, carol);
+        db.operators.delete(app_id, author, bobs).unwrap();
+        set_deleted_at(&db, "users", bob, now());
+
+        let result = db.trash.restore(app_id, author, Trashed::Operator, bobs);
+        assert!(matches!(result, Err(RestoreError::UserDeleted)));
+
+        db.apps.delete(app_id).unwrap();
+        assert_eq!(restored(&db, app_id, Trashed::App, app_id), 1);
+        let operators = db.operators.for_app(app_id, author).unwrap();
+        let ids: Vec<Value> = operators
+            .iter()
+            .map(|operator| serde_json::to_value(operator).unwrap()["id"].clone())
+            .collect();
+        assert_eq!(ids, [json!(carols)]);
+    }
+}
@@ -0,0 +1,348 @@
+use axum_utils::{copy, copy_mut, impl_from_row};
+use rusqlite::{Connection, OptionalExtension, Row};
+use serde::{Deserialize, Serialize};
+
+use super::audit::{self, Change};
+use super::events::now;
+use super::{query_execute, query_row, Con, SqlResult};
+use crate::crypto::{decrypt_secret, encrypt_secret, is_sealed, sha256_hex, totp, TOTP_PERIOD};
+
+/**
+    TOTP second factor of users.
+    Secret is stored sealed with `encrypt_secret` on enrolment but only used
+    for login after it is confirmed with a valid code. Recovery codes are stored as sha256 and can be used once.
+*/
+pub struct TwoFactor {
+    con: Con,
+}
+
+/// Codes of the previous and next time step are accepted too, for clock drift.
+const ALLOWED_DRIFT: u64 = 1;
+
+impl TwoFactor {
+    pub fn new(con: &Con) -> Self {
+        Self { con: con.clone() }
+    }
+
+    copy!(create_table() -> SqlResult<usize>);
+    copy!(is_enabled(user_id: i32) -> SqlResult<bool>);
+    copy_mut!(enroll(user_id: i32, secret: &str) -> SqlResult<usize>);
+    copy_mut!(confirm(user_id: i32, code: &str, recovery_codes: &[String]) -> SqlResult<bool>);
+    copy_mut!(verify(user_id: i32, code: &str) -> SqlResult<bool>);
+    copy_mut!(replace_recovery_codes(user_id: i32, recovery_codes: &[String]) -> SqlResult<usize>);
+    copy_mut!(disable(user_id: i32) -> SqlResult<usize>);
+}
+
+fn create_table(con: &Connection) -> SqlResult<usize> {
+    con.execute(
+        "CREATE TABLE IF NOT EXISTS user_totp (
+            user_id INTEGER PRIMARY KEY,
+            secret TEXT,
+            enabled INTEGER,
+            last_step INTEGER,
+            created_at INTEGER,
+            FOREIGN KEY(user_id) REFERENCES users(id)
+        )",
+        [],
+    )?;
+    con.execute(
+        "CREATE TABLE IF NOT EXISTS recovery_codes (
+            id INTEGER PRIMARY KEY,
+            user_id INTEGER,
+            code_hash TEXT,
+            used_at INTEGER,
+            FOREIGN KEY(user_id) REFERENCES users(id)
+        )",
+        [],
+    )?;
+    seal_plain_secrets(con)
+}
+
+/// Secrets enrolled before they were sealed are still in plain text.
+fn seal_plain_secrets(con: &Connection) -> SqlResult<usize> {
+    let secrets: Vec<(i32, String)> = con
+        .prepare("SELECT user_id, secret FROM user_totp")?
+        .query_map([], |row| Ok((row.get(0)?, row.get(1)?)))?
+        .collect::<SqlResult<_>>()?;
+    let mut sealed = 0;
+    for (user_id, secret) in secrets.iter().filter(|(_, secret)| !is_sealed(secret)) {
+        sealed += query_execute!(con => "UPDATE user_totp SET secret = ? WHERE user_id = ?",
+            (encrypt_secret(secret), user_id))?;
+    }
+    Ok(sealed)
+}
+
+/// Row of `user_totp`, also used by export, `secret` is sealed.
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct TotpRecord {
+    pub user_id: i32,
+    pub secret: String,
+    pub enabled: bool,
+    pub last_step: u64,
+    pub created_at: u64,
+}
+
+impl_from_row!(TotpRecord {
+    user_id,
+    secret,
+    enabled,
+    last_step,
+    created_at
+});
+
+/// Row of `recovery_codes`, also used by export.
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct RecoveryCodeRecord {
+    pub id: i32,
+    pub user_id: i32,
+    pub code_hash: String,
+    pub used_at: Option<u64>,
+}
+
+impl_from_row!(RecoveryCodeRecord {
+    id,
+    user_id,
+    code_hash,
+    used_at
+});
+
+pub fn is_enabled(con: &Connection, user_id: i32) -> SqlResult<bool> {
+    let mut stmt = con.prepare_cached("SELECT enabled FROM user_totp WHERE user_id = ?")?;
+    stmt.query_row([user_id], |row| row.get(0))
+        .optional()
+        .map(|enabled| enabled.unwrap_or(false))
+}
+
+/// Stores new pending secret, returns 0 if 2FA is already enabled.
+fn enroll(con: &mut Connection, user_id: i32, secret: &str) -> SqlResult<usize> {
+    let tx = con.transaction()?;
+    if is_enabled(&tx, user_id)? {
+        return Ok(0);
+    }
+    let result = query_execute!(tx => "
+        INSERT OR REPLACE INTO user_totp(user_id, secret, enabled, last_step, created_at) VALUES (?, ?, 0, 0, ?)",
+        (user_id, encrypt_secret(secret), now())
+    )?;
+    tx.commit()?;
+    Ok(result)
+}
+
+/// Enables 2FA if `code` matches the pending secret, stores `recovery_codes` hashed.
+fn confirm(
+    con: &mut Connection,
+    user_id: i32,
+    code: &str,
+    recovery_codes: &[String],
+) -> SqlResult<bool> {
+    let tx = con.transaction()?;
+    let pending = query_row!(tx => "SELECT * FROM user_totp WHERE user_id = ? AND enabled = 0", [user_id], TotpRecord).optional()?;
+    let Some(step) = pending.and_then(|pending| matching_step(&pending, code)) else {
+        return Ok(false);
+    };
+
+    query_execute!(tx => "UPDATE user_totp SET enabled = 1, last_step = ? WHERE user_id = ?", (step, user_id))?;
+    store_recovery_codes(&tx, user_id, recovery_codes)?;
+    let change = Change {
+        actor_id: Some(user_id),
+        app_id: None,
+        action: "user.twoFactorEnable",
+        target: format!("user:{user_id}"),
+        before: None,
+        after: None,
+    };
+    audit::record(&tx, change)?;
+    tx.commit()?;
+    Ok(true)
+}
+
+/**
+    Checks a TOTP code or an unused recovery code of a user with 2FA enabled.
+    Every code works only once, used time steps and recovery codes are remembered.
+*/
+fn verify(con: &mut Connection, user_id: i32, code: &str) -> SqlResult<bool> {
+    let tx = con.transaction()?;
+    let enabled = query_row!(tx => "SELECT * FROM user_totp WHERE user_id = ? AND enabled = 1", [user_id], TotpRecord).optional()?;
+    let Some(enabled) = enabled else {
+        return Ok(false);
+    };
+
+    if let Some(step) = matching_step(&enabled, code) {
+        query_execute!(tx => "UPDATE user_totp SET last_step = ? WHERE user_id = ?", (step, user_id))?;
+        tx.commit()?;
+        return Ok(true);
+    }
+
+    let used = query_execute!(tx => "
+        UPDATE recovery_codes SET used_at = ? WHERE user_id = ? AND code_hash = ? AND used_at IS NULL",
+        (now(), user_id, sha256_hex(&normalize_recovery_code(code)))
+    )?;
+    if used == 1 {
+        let change = Change {
+            actor_id: Some(user_id),
+            app_id: None,
+            action: "user.recoveryCodeUse",
+            target: format!("user:{user_id}"),
+            before: None,
+            after: None,
+        };
+        audit::record(&tx, change)?;
+    }
+    tx.commit()?;
+    Ok(used == 1)
+}
+
+fn replace_recovery_codes(
+    con: &mut Connection,
+    user_id: i32,
+    recovery_codes: &[String],
+) -> SqlResult<usize> {
+    let tx = con.transaction()?;
+    let result = store_recovery_codes(&tx, user_id, recovery_codes)?;
+    let change = Change {
+        actor_id: Some(user_id),
+        app_id: None,
+        action: "user.recoveryCodesReplace",
+        target: format!("user:{user_id}"),
+        before: None,
+        after: None,
+    };
+    audit::record(&tx, change)?;
+    tx.commit()?;
+    Ok(result)
+}
+
+fn disable(con: &mut Connection, user_id: i32) -> SqlResult<usize> {
+    let tx = con.transaction()?;
+    query_execute!(tx => "DELETE FROM recovery_codes WHERE user_id = ?", [user_id])?;
+    let result = query_execute!(tx => "DELETE FROM user_totp WHERE user_id = ?", [user_id])?;
+    if result == 1 {
+        let change = Change {
+            actor_id: Some(user_id),
+            app_id: None,
+            action: "user.twoFactorDisable",
+            target: format!("user:{user_id}"),
+            before: None,
+            after: None,
+        };
+        audit::record(&tx, change)?;
+    }
+    tx.commit()?;
+    Ok(result)
+}
+
+/// Time step `code` belongs to, if it is within drift and newer than the last used one.
+fn matching_step(record: &TotpRecord, code: &str) -> Option<u64> {
+    let code = code.trim();
+    let secret = decrypt_secret(&record.secret)?;
+    let current = now() / TOTP_PERIOD;
+    (current.saturating_sub(ALLOWED_DRIFT)..=current + ALLOWED_DRIFT)
+        .filter(|step| *step > record.last_step)
+        .find(|step| totp(&secret, *step).as_deref() == Some(code))
+}
+
+/// Recovery codes are shown grouped with `-`, users may type them either way.
+fn normalize_recovery_code(code: &str) -> String {
+    code.chars()
+        .filter(|c| c.is_ascii_alphanumeric())
+        .map(|c| c.to_ascii_lowercase())
+        .collect()
+}
+
+/// Replaces all recovery codes of the user.
+fn store_recovery_codes(
+    con: &Connection,
+    user_id: i32,
+    recovery_codes: &[String],
+) -> SqlResult<usize> {
+    query_execute!(con => "DELETE FROM recovery_codes WHERE user_id = ?", [user_id])?;
+    for code in recovery_codes {
+        query_execute!(con => "INSERT INTO recovery_codes(user_id, code_hash) VALUES (?, ?)",
+            (user_id, sha256_hex(&normalize_recovery_code(code))))?;
+    }
+    Ok(recovery_codes.len())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::crypto::totp_secret;
+    use crate::db::testing;
+
+    fn code(secret: &str, step: u64) -> String {
+        totp(secret, step).unwrap()
+    }
+
+    #[test]
+    fn codes_work_once_and_only_forward() {
+        let db = testing::db();
+        let user_id = testing::user(&db, "alice");
+        let secret = totp_secret();
+        let current = now() / TOTP_PERIOD;
+        db.two_factor.enroll(user_id, &secret).unwrap();
+        assert!(!db
+            .two_factor
+            .verify(user_id, &code(&secret, current))
+            .unwrap());
+
+        let recovery = ["3f9a1-c07d2".to_string()];
+        let wrong = db.two_factor.confirm(user_id, "abcdef", &recovery);
+        assert!(!wrong.unwrap());
+        let previous = code(&secret, current - 1);
+        assert!(db
+            .two_factor
+            .confirm(user_id, &previous, &recovery)
+            .unwrap());
+        assert!(db.two_factor.is_enabled(user_id).unwrap());
+
+        assert!(!db.two_factor.verify(user_id, &previous).unwrap());
+        let now_code = code(&secret, current);
+        assert!(db.two_factor.verify(user_id, &now_code).unwrap());
+        assert!(!db.two_factor.verify(user_id, &now_code).unwrap());
+        let too_far = code(&secret, current + ALLOWED_DRIFT + 1);
+        assert!(!db.two_factor.verify(user_id, &too_far).unwrap());
+    }
+
+    #[test]
+    fn recovery_codes_are_single_use_and_replaceable() {
+        let db = testing::db();
+        let user_id = testing::user(&db, "alice");
+        let secret = totp_secret();
+        db.two_factor.enroll(user_id, &secret).unwrap();
+        let recovery = ["3f9a1-c07d2".to_string(), "8e4b0-51a6f".to_string()];
+        let first = code(&secret, now() / TOTP_PERIOD);
+        assert!(db.two_factor.confirm(user_id, &first, &recovery).unwrap());
+
+        assert!(db.two_factor.verify(user_id, " 3F9A1C07D2 ").unwrap());
+        assert!(!db.two_factor.verify(user_id, "3f9a1-c07d2").unwrap());
+
+        db.two_factor
+            .replace_recovery_codes(user_id, &["aaaaa-bbbbb".to_string()])
+            .unwrap();
+        assert!(!db.two_factor.verify(user_id, "8e4b0-51a6f").unwrap());
+        assert!(db.two_factor.verify(user_id, "aaaaa-bbbbb").unwrap());
+
+        db.two_factor.disable(user_id).unwrap();
+        assert!(!db.two_factor.is_enabled(user_id).unwrap());
+    }
+
+    #[test]
+    fn secret_is_stored_sealed() {
+        let db = testing::db();
+        let user_id = testing::user(&db, "alice");
+        let secret = totp_secret();
+        db.two_factor.enroll(user_id, &secret).unwrap();
+
+        let con = db.con.lock().unwrap();
+        let stored: String = con
+            .query_row(
+                "SELECT secret FROM user_totp WHERE user_id = ?",
+                [user_id],
+                |row| row.get(0),
+            )
+            .unwrap();
+        assert!(is_sealed(&stored));
+        assert_eq!(decrypt_secret(&stored).as_deref(), Some(secret.as_str()));
+    }
+}
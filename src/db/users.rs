@@ -1,44 +1,147 @@
-use std::io;
+use std::{fmt, sync::OnceLock};
 
-use axum_utils::impl_from_row;
-use rusqlite::{Connection, Error, ErrorCode, Row};
+use axum_utils::{copy, copy_mut, impl_from_row};
+use rusqlite::types::{FromSql, FromSqlResult, ToSql, ToSqlOutput, ValueRef};
+use rusqlite::{Connection, Error, ErrorCode, OptionalExtension, Row};
 use serde::{Deserialize, Serialize};
+use serde_json::json;
 
-use super::{Con, SqlResult};
+use super::apps::{unchecked_delete, unchecked_transfer};
+use super::audit::{self, snapshot, Change};
+use super::events::{self, now, AppEvent};
+use super::{query_execute, query_row, Con, SqlResult};
+use crate::crypto::random_token;
+use crate::validation::{Policy, ValidationErrors};
 
 pub struct Users {
     con: Con,
 }
 
-#[derive(Serialize, Deserialize)]
+/// Not serializable on purpose, responses use `Profile` or `UserView`.
 pub struct User {
     pub id: i32,
     pub name: String,
     pub username: String,
-    pub password: String,
+    pub password: PasswordHash,
+    pub admin: bool,
+    pub email: Option<String>,
+    pub avatar_url: Option<String>,
+    pub bio: Option<String>,
+    pub created_at: u64,
+    pub updated_at: u64,
 }
 
 impl_from_row!(User {
     id,
     name,
     username,
-    password
+    password,
+    admin,
+    email,
+    avatar_url,
+    bio,
+    created_at,
+    updated_at
 });
 
+/**
+    bcrypt hash of a password. Implements neither `Serialize` nor a readable `Debug`,
+    so a type holding it can't end up in a response or a log line by accident.
+*/
+pub struct PasswordHash(String);
+
+impl PasswordHash {
+    /// Slow on purpose, callers keep it outside the connection lock and off the async executor.
+    pub fn new(password: &str) -> Self {
+        PasswordHash(bcrypt::hash(password, 10).unwrap())
+    }
+
+    pub fn verify(&self, password: &str) -> bool {
+        bcrypt::verify(password, &self.0).unwrap_or(false)
+    }
+}
+
+impl fmt::Debug for PasswordHash {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("PasswordHash(..)")
+    }
+}
+
+impl FromSql for PasswordHash {
+    fn column_result(value: ValueRef<'_>) -> FromSqlResult<Self> {
+        String::column_result(value).map(PasswordHash)
+    }
+}
+
+impl ToSql for PasswordHash {
+    fn to_sql(&self) -> rusqlite::Result<ToSqlOutput<'_>> {
+        self.0.to_sql()
+    }
+}
+
 pub struct NewUser {
     name: String,
     username: String,
-    password: String,
+    password: PasswordHash,
+    email: Option<String>,
 }
 
 impl NewUser {
-    pub fn new(name: String, username: String, password: String) -> io::Result<NewUser> {
-        //TODO: check for validity
+    /// Checks all fields against the current `Policy`, every problem is reported.
+    /// Hashes the password, so it blocks like `PasswordHash::new`.
+    pub fn new(
+        name: String,
+        username: String,
+        password: String,
+        email: Option<String>,
+    ) -> Result<NewUser, ValidationErrors> {
+        let policy = Policy::current();
+        let mut errors = ValidationErrors::default();
+        policy.check_name(&name, &mut errors);
+        policy.check_username(&username, &mut errors);
+        policy.check_password(&password, &username, &mut errors);
+        if let Some(email) = &email {
+            policy.check_email(email, &mut errors);
+        }
+
+        if !errors.is_empty() {
+            return Err(errors);
+        }
 
         Ok(Self {
-            name,
+            name: name.trim().to_string(),
             username,
-            password,
+            password: PasswordHash::new(&password),
+            email: email.map(|email| email.trim().to_string()),
+        })
+    }
+
+    /**
+        User that logs in through single sign-on. Password is random and unknown to anyone,
+        a password reset sets a usable one.
+    */
+    pub fn external(
+        name: String,
+        username: String,
+        email: Option<String>,
+    ) -> Result<NewUser, ValidationErrors> {
+        let policy = Policy::current();
+        let mut errors = ValidationErrors::default();
+        policy.check_name(&name, &mut errors);
+        policy.check_username(&username, &mut errors);
+        if let Some(email) = &email {
+            policy.check_email(email, &mut errors);
+        }
+
+        if !errors.is_empty() {
+            return Err(errors);
+        }
+
+        Ok(Self {
+            name: name.trim().to_string(),
+            username,
+            password: PasswordHash::new(&random_token(32)),
+            email: email.map(|email| email.trim().to_string()),
         })
     }
 }
@@ -62,25 +165,16 @@ impl Users {
         )
     }
 
-    pub fn insert(&self, user: NewUser) -> Result<i32, Error> {
-        let hash = bcrypt::hash(user.password, 10).unwrap();
-        let con = self.con.lock().unwrap();
-
-        let mut stmt =
-            con.prepare_cached("INSERT INTO users(name, username, password) VALUES(?,?,?)")?;
-        stmt.execute((user.name, user.username, hash))?;
-        Ok(con.last_insert_rowid() as i32)
-    }
-
     pub fn find_user_by_name(&self, username: &str) -> Result<User, Error> {
         let con = self.con.lock().unwrap();
 
-        let mut stmt = con.prepare_cached("SELECT * FROM users WHERE username = ?")?;
+        let mut stmt =
+            con.prepare_cached("SELECT * FROM users WHERE username = ? AND deleted_at IS NULL")?;
 
         stmt.query_row([username], User::from_row)
     }
 
-    pub fn register_user(&self, user: &NewUser) -> Result<(), RegistrationError> {
+    pub fn register_user(&self, user: &NewUser) -> Result<i32, RegistrationError> {
         register_user(self, user)
     }
 
@@ -88,10 +182,43 @@ impl Users {
         find_user(self, username, password)
     }
 
-    pub fn search(&self, query: &str) -> Result<Vec<UserView>, Error> {
+    pub fn search(&self, query: &str, limit: usize) -> Result<Vec<UserView>, Error> {
         let con = self.con.lock().unwrap();
-        search(&con, query)
+        search(&con, query, limit)
+    }
+
+    copy!(lookup(username: &str) -> SqlResult<Option<UserView>>);
+    pub fn set_password(&self, username: &str, password: &str) -> SqlResult<usize> {
+        let hash = PasswordHash::new(password);
+        let mut con = self.con.lock().unwrap();
+        set_password(&mut con, username, &hash)
+    }
+
+    copy_mut!(set_admin(username: &str, admin: bool) -> SqlResult<usize>);
+    copy!(is_admin(user_id: i32) -> SqlResult<bool>);
+
+    /// For confirming sensitive changes of a logged in user.
+    pub fn verify_password(&self, user_id: i32, password: &str) -> SqlResult<bool> {
+        let hash = {
+            let con = self.con.lock().unwrap();
+            password_hash(&con, user_id)?
+        };
+        Ok(hash.is_some_and(|hash| hash.verify(password)))
     }
+
+    copy!(by_id(user_id: i32) -> SqlResult<User>);
+    copy!(session_epoch(user_id: i32) -> SqlResult<u32>);
+
+    /// Sets new password and revokes all sessions, returns the new session epoch.
+    pub fn change_password(&self, user_id: i32, password: &str) -> SqlResult<u32> {
+        let hash = PasswordHash::new(password);
+        let mut con = self.con.lock().unwrap();
+        change_password(&mut con, user_id, &hash)
+    }
+
+    copy!(profile(user_id: i32) -> SqlResult<Profile>);
+    copy_mut!(update_profile(user_id: i32, changes: &ProfileUpdate) -> Result<Profile, RegistrationError>);
+    copy_mut!(delete_account(user_id: i32, owned_apps: OwnedApps) -> Result<Vec<i32>, DeleteAccountError>);
 }
 
 pub enum LoginError {
@@ -106,25 +233,33 @@ impl From<Error> for LoginError {
     }
 }
 
+/**
+    Unknown usernames are checked against a dummy hash,
+    so they take as long as a wrong password and can't be told apart by timing.
+*/
 pub fn find_user(users: &Users, username: &str, password: &str) -> Result<User, LoginError> {
-    let con = users.con.lock().unwrap();
-
-    let mut stmt = con.prepare_cached("SELECT * FROM users WHERE username = ?")?;
-
-    let user = stmt.query_row([username], User::from_row).map(|user| {
-        bcrypt::verify(password, &user.password)
-            .unwrap()
-            .then_some(user)
-    });
+    let user = {
+        let con = users.con.lock().unwrap();
+        let mut stmt =
+            con.prepare_cached("SELECT * FROM users WHERE username = ? AND deleted_at IS NULL")?;
+        stmt.query_row([username], User::from_row).optional()?
+    };
 
     match user {
-        Ok(Some(user)) => Ok(user),
-        Ok(None) => Err(LoginError::WrongPassword),
-        Err(Error::QueryReturnedNoRows) => Err(LoginError::UserNotFound),
-        Err(e) => Err(LoginError::SqliteError(e)),
+        Some(user) if user.password.verify(password) => Ok(user),
+        Some(_) => Err(LoginError::WrongPassword),
+        None => {
+            let _ = bcrypt::verify(password, dummy_hash());
+            Err(LoginError::UserNotFound)
+        }
     }
 }
 
+fn dummy_hash() -> &'static str {
+    static HASH: OnceLock<String> = OnceLock::new();
+    HASH.get_or_init(|| bcrypt::hash("not a real password", 10).unwrap())
+}
+
 pub enum RegistrationError {
     UserAlreadyExists,
     SqliteError(Error),
@@ -136,21 +271,39 @@ impl From<Error> for RegistrationError {
     }
 }
 
-fn register_user(users: &Users, user: &NewUser) -> Result<(), RegistrationError> {
-    let sql = "INSERT INTO users(name, username, password) VALUES (?, ?, ?)";
-
-    let con = users.con.lock().unwrap();
-    let mut stmt = con.prepare_cached(sql)?;
-    let result = stmt.execute([&user.name, &user.username, &user.password]);
+/// Uniqueness is left to the `UNIQUE` constraint, so concurrent registrations can't both succeed.
+fn register_user(users: &Users, user: &NewUser) -> Result<i32, RegistrationError> {
+    let mut con = users.con.lock().unwrap();
+    let tx = con.transaction()?;
+    let user_id = insert_user(&tx, user)?;
+    tx.commit()?;
+    Ok(user_id)
+}
 
+/// Meant to be called inside a transaction, also records the audit entry.
+pub fn insert_user(con: &Connection, user: &NewUser) -> Result<i32, RegistrationError> {
+    let result = con
+        .prepare_cached("INSERT INTO users(name, username, password, email) VALUES (?, ?, ?, ?)")?
+        .execute((&user.name, &user.username, &user.password, &user.email));
     match result {
-        Ok(_) => Ok(()),
-        Err(Error::SqliteFailure(e, o)) => match e.code {
-            ErrorCode::ConstraintViolation => Err(RegistrationError::UserAlreadyExists),
-            _ => Err(Error::SqliteFailure(e, o))?,
-        },
+        Ok(_) => {}
+        Err(Error::SqliteFailure(e, _)) if e.code == ErrorCode::ConstraintViolation => {
+            return Err(RegistrationError::UserAlreadyExists)
+        }
         Err(e) => Err(e)?,
     }
+
+    let user_id = con.last_insert_rowid() as i32;
+    let change = Change {
+        actor_id: Some(user_id),
+        app_id: None,
+        action: "user.create",
+        target: format!("user:{user_id}"),
+        before: None,
+        after: Some(json!({ "name": user.name, "username": user.username })),
+    };
+    audit::record(con, change)?;
+    Ok(user_id)
 }
 
 #[derive(Serialize, Deserialize)]
@@ -162,17 +315,466 @@ pub struct UserView {
 
 impl_from_row!(UserView { id, name, username });
 
-fn search(con: &Connection, query: &str) -> Result<Vec<UserView>, rusqlite::Error> {
-    let mut stmt =
-        con.prepare_cached("SELECT * FROM users WHERE users.username LIKE '%' || ? || '%'")?;
+/// Users whose username or name contains `query`, only those that allow being found.
+fn search(con: &Connection, query: &str, limit: usize) -> Result<Vec<UserView>, rusqlite::Error> {
+    // `_` is common in usernames, it and `%` must match literally
+    let pattern = query
+        .replace('\\', "\\\\")
+        .replace('%', "\\%")
+        .replace('_', "\\_");
+    let mut stmt = con.prepare_cached(
+        "SELECT * FROM users
+        WHERE (username LIKE '%' || ?1 || '%' ESCAPE '\\' OR name LIKE '%' || ?1 || '%' ESCAPE '\\')
+            AND searchable = 1 AND deleted_at IS NULL
+        ORDER BY username LIMIT ?2",
+    )?;
     let users = stmt
-        .query_map([query], UserView::from_row)?
+        .query_map((pattern, limit), UserView::from_row)?
         .collect::<Result<_, _>>()
         .unwrap();
     Ok(users)
 }
 
+/// Exact username, found even if the user opted out of search, the caller already knows it.
+fn lookup(con: &Connection, username: &str) -> SqlResult<Option<UserView>> {
+    query_row!(con => "SELECT * FROM users WHERE username = ? AND deleted_at IS NULL", [username], UserView).optional()
+}
+
 pub fn user_exists(con: &Connection, user_id: i32) -> SqlResult<()> {
-    let mut stmt = con.prepare_cached("SELECT * FROM users WHERE id = ?")?;
+    let mut stmt = con.prepare_cached("SELECT * FROM users WHERE id = ? AND deleted_at IS NULL")?;
     stmt.query_row([user_id], |_| Ok(()))
 }
+
+/**
+    Password reset by admin, credentials never go to the audit log.
+    Existing sessions of the user stop working.
+*/
+fn set_password(con: &mut Connection, username: &str, hash: &PasswordHash) -> SqlResult<usize> {
+    let tx = con.transaction()?;
+    let result = query_execute!(tx => "
+        UPDATE users SET password = ?, session_epoch = session_epoch + 1
+        WHERE username = ? AND deleted_at IS NULL", (hash, username))?;
+    if result == 1 {
+        let change = Change {
+            actor_id: None,
+            app_id: None,
+            action: "user.passwordReset",
+            target: format!("user:{username}"),
+            before: None,
+            after: None,
+        };
+        audit::record(&tx, change)?;
+    }
+    tx.commit()?;
+    Ok(result)
+}
+
+fn set_admin(con: &mut Connection, username: &str, admin: bool) -> SqlResult<usize> {
+    let tx = con.transaction()?;
+    let result = query_execute!(tx => "UPDATE users SET admin = ? WHERE username = ? AND deleted_at IS NULL", (admin, username))?;
+    if result == 1 {
+        let change = Change {
+            actor_id: None,
+            app_id: None,
+            action: "user.admin",
+            target: format!("user:{username}"),
+            before: Some(json!({ "admin": !admin })),
+            after: Some(json!({ "admin": admin })),
+        };
+        audit::record(&tx, change)?;
+    }
+    tx.commit()?;
+    Ok(result)
+}
+
+pub fn is_admin(con: &Connection, user_id: i32) -> SqlResult<bool> {
+    let mut stmt = con.prepare_cached("SELECT admin FROM users WHERE id = ? AND deleted_at IS NULL")?;
+    stmt.query_row([user_id], |row| row.get(0))
+        .optional()
+        .map(|admin| admin.unwrap_or(false))
+}
+
+pub fn by_id(con: &Connection, user_id: i32) -> SqlResult<User> {
+    let mut stmt = con.prepare_cached("SELECT * FROM users WHERE id = ? AND deleted_at IS NULL")?;
+    stmt.query_row([user_id], User::from_row)
+}
+
+fn password_hash(con: &Connection, user_id: i32) -> SqlResult<Option<PasswordHash>> {
+    let mut stmt =
+        con.prepare_cached("SELECT password FROM users WHERE id = ? AND deleted_at IS NULL")?;
+    stmt.query_row([user_id], |row| row.get(0)).optional()
+}
+
+/// Tokens carry the epoch they were issued in, bumping it revokes all of them.
+pub fn session_epoch(con: &Connection, user_id: i32) -> SqlResult<u32> {
+    let mut stmt =
+        con.prepare_cached("SELECT session_epoch FROM users WHERE id = ? AND deleted_at IS NULL")?;
+    stmt.query_row([user_id], |row| row.get(0))
+}
+
+fn change_password(con: &mut Connection, user_id: i32, hash: &PasswordHash) -> SqlResult<u32> {
+    let tx = con.transaction()?;
+    set_password_hash(&tx, user_id, hash)?;
+    let change = Change {
+        actor_id: Some(user_id),
+        app_id: None,
+        action: "user.passwordChange",
+        target: format!("user:{user_id}"),
+        before: None,
+        after: None,
+    };
+    audit::record(&tx, change)?;
+    let epoch = session_epoch(&tx, user_id)?;
+    tx.commit()?;
+    Ok(epoch)
+}
+
+/// Meant to be called inside a transaction that also records why the password changed.
+pub fn set_password_hash(con: &Connection, user_id: i32, hash: &PasswordHash) -> SqlResult<usize> {
+    query_execute!(con => "
+        UPDATE users SET password = ?, session_epoch = session_epoch + 1
+        WHERE id = ? AND deleted_at IS NULL", (hash, user_id))
+}
+
+/// What the owner of an account sees about themselves, never contains the password hash.
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Profile {
+    pub id: i32,
+    pub name: String,
+    pub username: String,
+    pub email: Option<String>,
+    pub avatar_url: Option<String>,
+    pub bio: Option<String>,
+    /// Others find the user through search, exact username lookup works regardless.
+    pub searchable: bool,
+    pub admin: bool,
+    pub created_at: u64,
+    pub updated_at: u64,
+}
+
+impl_from_row!(Profile {
+    id,
+    name,
+    username,
+    email,
+    avatar_url,
+    bio,
+    searchable,
+    admin,
+    created_at,
+    updated_at
+});
+
+/// Fields missing from the request stay as they are, empty `email`, `avatarUrl` or `bio` clears it.
+#[derive(Serialize, Deserialize, Default)]
+#[serde(rename_all = "camelCase")]
+pub struct ProfileUpdate {
+    pub name: Option<String>,
+    pub username: Option<String>,
+    pub email: Option<String>,
+    pub avatar_url: Option<String>,
+    pub bio: Option<String>,
+    pub searchable: Option<bool>,
+}
+
+impl ProfileUpdate {
+    /// Checks the present fields against the current `Policy`, every problem is reported.
+    pub fn validate(self) -> Result<Self, ValidationErrors> {
+        let policy = Policy::current();
+        let mut errors = ValidationErrors::default();
+        if let Some(name) = &self.name {
+            policy.check_name(name, &mut errors);
+        }
+        if let Some(username) = &self.username {
+            policy.check_username(username, &mut errors);
+        }
+        if let Some(email) = self
+            .email
+            .as_deref()
+            .filter(|email| !email.trim().is_empty())
+        {
+            policy.check_email(email, &mut errors);
+        }
+        if let Some(avatar_url) = self.avatar_url.as_deref().filter(|url| !url.is_empty()) {
+            policy.check_avatar_url(avatar_url, &mut errors);
+        }
+        if let Some(bio) = &self.bio {
+            policy.check_bio(bio, &mut errors);
+        }
+
+        let trim = |value: Option<String>| value.map(|value| value.trim().to_string());
+        errors.into_result(Self {
+            name: trim(self.name),
+            username: self.username,
+            email: trim(self.email),
+            avatar_url: trim(self.avatar_url),
+            bio: trim(self.bio),
+            searchable: self.searchable,
+        })
+    }
+}
+
+pub fn profile(con: &Connection, user_id: i32) -> SqlResult<Profile> {
+    query_row!(con => "SELECT * FROM users WHERE id = ? AND deleted_at IS NULL", [user_id], Profile)
+}
+
+/// A username that is already taken is `UserAlreadyExists`, same as on registration.
+fn update_profile(
+    con: &mut Connection,
+    user_id: i32,
+    changes: &ProfileUpdate,
+) -> Result<Profile, RegistrationError> {
+    let tx = con.transaction()?;
+    let before = profile(&tx, user_id)?;
+    let result = tx.execute(
+        "UPDATE users SET
+            name = COALESCE(?1, name),
+            username = COALESCE(?2, username),
+            email = CASE WHEN ?3 IS NULL THEN email ELSE NULLIF(?3, '') END,
+            avatar_url = CASE WHEN ?4 IS NULL THEN avatar_url ELSE NULLIF(?4, '') END,
+            bio = CASE WHEN ?5 IS NULL THEN bio ELSE NULLIF(?5, '') END,
+            searchable = COALESCE(?6, searchable)
+        WHERE id = ?7 AND deleted_at IS NULL",
+        (
+            &changes.name,
+            &changes.username,
+            &changes.email,
+            &changes.avatar_url,
+            &changes.bio,
+            changes.searchable,
+            user_id,
+        ),
+    );
+    match result {
+        Ok(_) => {}
+        Err(Error::SqliteFailure(e, _)) if e.code == ErrorCode::ConstraintViolation => {
+            return Err(RegistrationError::UserAlreadyExists)
+        }
+        Err(e) => Err(e)?,
+    }
+
+    let after = profile(&tx, user_id)?;
+    let change = Change {
+        actor_id: Some(user_id),
+        app_id: None,
+        action: "user.update",
+        target: format!("user:{user_id}"),
+        before: snapshot(&before),
+        after: snapshot(&after),
+    };
+    audit::record(&tx, change)?;
+    tx.commit()?;
+    Ok(after)
+}
+
+/// How account deletion deals with apps the user is the author of.
+#[derive(Clone, Copy)]
+pub enum OwnedApps {
+    /// Only delete the account if the user owns no apps.
+    Keep,
+    Delete,
+    TransferTo(i32),
+}
+
+pub enum DeleteAccountError {
+    /// `TransferTo` the user being deleted or to a user who doesn't exist anymore.
+    InvalidNewAuthor,
+    SqliteError(Error),
+}
+
+impl From<Error> for DeleteAccountError {
+    fn from(value: Error) -> Self {
+        DeleteAccountError::SqliteError(value)
+    }
+}
+
+/**
+    Soft deletes the user with their operator and app user memberships.
+    With `OwnedApps::Keep` nothing happens while the user still owns apps,
+    their ids are returned instead. Sessions end because deleted users have no session epoch.
+*/
+fn delete_account(
+    con: &mut Connection,
+    user_id: i32,
+    owned_apps: OwnedApps,
+) -> Result<Vec<i32>, DeleteAccountError> {
+    let tx = con.transaction()?;
+    let owned = tx
+        .prepare_cached("SELECT id FROM apps WHERE author_id = ? AND deleted_at IS NULL")?
+        .query_map([user_id], |row| row.get(0))?
+        .collect::<SqlResult<Vec<i32>>>()?;
+    if !owned.is_empty() && matches!(owned_apps, OwnedApps::Keep) {
+        return Ok(owned);
+    }
+    if let OwnedApps::TransferTo(new_author_id) = owned_apps {
+        if new_author_id == user_id {
+            return Err(DeleteAccountError::InvalidNewAuthor);
+        }
+        match user_exists(&tx, new_author_id) {
+            Err(Error::QueryReturnedNoRows) => return Err(DeleteAccountError::InvalidNewAuthor),
+            result => result?,
+        }
+    }
+    for app_id in owned {
+        match owned_apps {
+            OwnedApps::Keep => 0,
+            OwnedApps::Delete => unchecked_delete(&tx, app_id, Some(user_id))?,
+            OwnedApps::TransferTo(new_author_id) => {
+                unchecked_transfer(&tx, app_id, new_author_id, Some(user_id))?
+            }
+        };
+    }
+
+    let now = now();
+    let operators = tx
+        .prepare_cached(
+            "SELECT id, app_id FROM operators WHERE user_id = ? AND deleted_at IS NULL",
+        )?
+        .query_map([user_id], |row| Ok((row.get(0)?, row.get(1)?)))?
+        .collect::<SqlResult<Vec<(i32, i32)>>>()?;
+    for (operator_id, app_id) in operators {
+        query_execute!(tx => "UPDATE operators SET deleted_at = ? WHERE id = ?", (now, operator_id))?;
+        events::record(&tx, app_id, &AppEvent::OperatorRemoved { operator_id })?;
+    }
+    let app_users = tx
+        .prepare_cached(
+            "SELECT id, app_id FROM app_users WHERE user_id = ? AND deleted_at IS NULL",
+        )?
+        .query_map([user_id], |row| Ok((row.get(0)?, row.get(1)?)))?
+        .collect::<SqlResult<Vec<(i32, i32)>>>()?;
+    for (app_user_id, app_id) in app_users {
+        query_execute!(tx => "UPDATE app_users SET deleted_at = ? WHERE id = ?", (now, app_user_id))?;
+        events::record(&tx, app_id, &AppEvent::AppUserRemoved { app_user_id })?;
+    }
+
+    let before = profile(&tx, user_id)?;
+    query_execute!(tx => "UPDATE users SET deleted_at = ? WHERE id = ?", (now, user_id))?;
+    let change = Change {
+        actor_id: Some(user_id),
+        app_id: None,
+        action: "user.delete",
+        target: format!("user:{user_id}"),
+        before: snapshot(&before),
+        after: None,
+    };
+    audit::record(&tx, change)?;
+    tx.commit()?;
+    Ok(Vec::new())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::testing;
+
+    fn new_user(username: &str, password: &str) -> NewUser {
+        let name = username.to_string();
+        NewUser::new(name, username.to_string(), password.to_string(), None).unwrap()
+    }
+
+    #[test]
+    fn registered_user_logs_in_with_the_password_only() {
+        let db = testing::db();
+        let user = new_user("alice", "Tr1cky-Enough");
+        let user_id = db.users.register_user(&user).ok().unwrap();
+
+        let user = db.users.find_user("alice", "Tr1cky-Enough").ok().unwrap();
+        assert_eq!(user.id, user_id);
+        let wrong = db.users.find_user("alice", "Tr1cky-Enough!");
+        assert!(matches!(wrong, Err(LoginError::WrongPassword)));
+        let unknown = db.users.find_user("bob", "Tr1cky-Enough");
+        assert!(matches!(unknown, Err(LoginError::UserNotFound)));
+
+        let again = db.users.register_user(&new_user("alice", "An0ther-Secret"));
+        assert!(matches!(again, Err(RegistrationError::UserAlreadyExists)));
+    }
+
+    #[test]
+    fn new_user_is_validated_before_hashing() {
+        let errors = NewUser::new("".into(), "Alice".into(), "short".into(), None)
+            .err()
+            .unwrap();
+        let fields: Vec<&str> = errors.errors.iter().map(|e| e.field.as_str()).collect();
+        assert_eq!(fields, ["name", "username", "password", "password"]);
+    }
+
+    #[test]
+    fn changing_the_password_revokes_sessions() {
+        let db = testing::db();
+        let user = new_user("alice", "Tr1cky-Enough");
+        let user_id = db.users.register_user(&user).ok().unwrap();
+        let epoch = db.users.session_epoch(user_id).unwrap();
+
+        let new_epoch = db.users.change_password(user_id, "An0ther-Secret").unwrap();
+        assert_eq!(new_epoch, epoch + 1);
+        assert!(db.users.verify_password(user_id, "An0ther-Secret").unwrap());
+        assert!(!db.users.verify_password(user_id, "Tr1cky-Enough").unwrap());
+
+        assert_eq!(db.users.set_password("alice", "Adm1n-Chosen").unwrap(), 1);
+        assert_eq!(db.users.session_epoch(user_id).unwrap(), epoch + 2);
+        assert!(db.users.verify_password(user_id, "Adm1n-Chosen").unwrap());
+        assert_eq!(db.users.set_password("bob", "Adm1n-Chosen").unwrap(), 0);
+    }
+
+    fn author_of(db: &crate::db::SqliteDb, app_id: i32) -> (i32, Option<u64>) {
+        let con = db.con.lock().unwrap();
+        let sql = "SELECT author_id, deleted_at FROM apps WHERE id = ?";
+        con.query_row(sql, [app_id], |row| Ok((row.get(0)?, row.get(1)?)))
+            .unwrap()
+    }
+
+    #[test]
+    fn owned_apps_must_be_dealt_with_before_deleting() {
+        let db = testing::db();
+        let (alice, bob) = (testing::user(&db, "alice"), testing::user(&db, "bob"));
+        let app_id = testing::app(&db, alice);
+
+        let kept = db
+            .users
+            .delete_account(alice, OwnedApps::Keep)
+            .ok()
+            .unwrap();
+        assert_eq!(kept, [app_id]);
+        assert!(db.users.profile(alice).is_ok());
+        let to_self = db.users.delete_account(alice, OwnedApps::TransferTo(alice));
+        assert!(matches!(to_self, Err(DeleteAccountError::InvalidNewAuthor)));
+        let to_nobody = db.users.delete_account(alice, OwnedApps::TransferTo(99));
+        assert!(matches!(
+            to_nobody,
+            Err(DeleteAccountError::InvalidNewAuthor)
+        ));
+
+        let transferred = db.users.delete_account(alice, OwnedApps::TransferTo(bob));
+        assert!(transferred.ok().unwrap().is_empty());
+        assert_eq!(author_of(&db, app_id), (bob, None));
+        assert!(db.users.profile(alice).is_err());
+        assert!(db.users.lookup("alice").unwrap().is_none());
+
+        let deleted = db.users.delete_account(bob, OwnedApps::Delete);
+        assert!(deleted.ok().unwrap().is_empty());
+        assert!(author_of(&db, app_id).1.is_some());
+    }
+
+    #[test]
+    fn memberships_end_with_events() {
+        let db = testing::db();
+        let (alice, bob) = (testing::user(&db, "alice"), testing::user(&db, "bob"));
+        let app_id = testing::app(&db, alice);
+        db.operators.create(app_id, alice, bob).unwrap();
+        db.app_users.create(app_id, alice, bob).unwrap();
+
+        let deleted = db.users.delete_account(bob, OwnedApps::Keep);
+        assert!(deleted.ok().unwrap().is_empty());
+        assert!(db.operators.for_app(app_id, alice).unwrap().is_empty());
+        assert!(db.app_users.for_app(app_id, alice).unwrap().is_empty());
+        let kinds: Vec<&str> = db
+            .events
+            .since(app_id, 0)
+            .unwrap()
+            .iter()
+            .map(|e| e.event.kind())
+            .collect();
+        assert!(kinds.ends_with(&["operatorRemoved", "appUserRemoved"]));
+    }
+}
@@ -0,0 +1,418 @@
+use axum_utils::{copy, copy_mut, impl_from_row};
+use rusqlite::{Connection, OptionalExtension, Row};
+use serde::{Deserialize, Serialize};
+
+use super::apps::has_permission;
+use super::audit::{self, snapshot, Change};
+use super::events::now;
+use super::{query_execute, query_row, query_rows, Con, SqlResult};
+use crate::crypto::{decrypt_secret, encrypt_secret, is_sealed};
+
+/**
+    Per app subscriptions to app events.
+    Every recorded event is put into `webhook_deliveries` outbox
+    for each matching subscription, in the same transaction as the event.
+    Secrets are stored sealed with `encrypt_secret`, they are needed to sign deliveries.
+*/
+pub struct Webhooks {
+    con: Con,
+}
+
+impl Webhooks {
+    pub fn new(con: &Con) -> Self {
+        Self { con: con.clone() }
+    }
+
+    copy!(create_table() -> SqlResult<usize>);
+    copy_mut!(for_app(app_id: i32, user_id: i32) -> SqlResult<Vec<Webhook>>);
+    copy_mut!(create(app_id: i32, user_id: i32, new_webhook: NewWebhook, secret: String) -> SqlResult<Webhook>);
+    copy_mut!(delete(app_id: i32, user_id: i32, webhook_id: i32) -> SqlResult<usize>);
+    copy_mut!(deliveries(app_id: i32, user_id: i32, webhook_id: i32) -> SqlResult<Vec<Delivery>>);
+    copy_mut!(redeliver(app_id: i32, user_id: i32, webhook_id: i32, delivery_id: i32) -> SqlResult<usize>);
+    copy!(due(limit: usize) -> SqlResult<Vec<PendingDelivery>>);
+    copy!(delivered(delivery_id: i32, status_code: u16) -> SqlResult<usize>);
+    copy!(failed(delivery_id: i32, status_code: Option<u16>, error: &str) -> SqlResult<usize>);
+}
+
+/// After this many attempts delivery is given up and marked `failed`.
+pub const MAX_ATTEMPTS: i32 = 8;
+/// Delay before the first retry, doubled after every failed attempt.
+pub const RETRY_BASE_SECS: u64 = 30;
+
+fn create_table(con: &Connection) -> SqlResult<usize> {
+    con.execute(
+        "CREATE TABLE IF NOT EXISTS webhooks (
+            id INTEGER PRIMARY KEY,
+            app_id INTEGER,
+            url TEXT,
+            secret TEXT,
+            events TEXT,
+            active INTEGER,
+            created_at INTEGER,
+            FOREIGN KEY(app_id) REFERENCES apps(id)
+        )",
+        [],
+    )?;
+    con.execute(
+        "CREATE TABLE IF NOT EXISTS webhook_deliveries (
+            id INTEGER PRIMARY KEY,
+            webhook_id INTEGER,
+            event_id INTEGER,
+            kind TEXT,
+            payload TEXT,
+            status TEXT,
+            attempts INTEGER,
+            next_attempt_at INTEGER,
+            last_status_code INTEGER,
+            last_error TEXT,
+            created_at INTEGER,
+            delivered_at INTEGER
+        )",
+        [],
+    )?;
+    seal_plain_secrets(con)
+}
+
+/// Webhooks created before secrets were sealed still have them in plain text.
+fn seal_plain_secrets(con: &Connection) -> SqlResult<usize> {
+    let secrets: Vec<(i32, String)> = con
+        .prepare("SELECT id, secret FROM webhooks WHERE secret IS NOT NULL")?
+        .query_map([], |row| Ok((row.get(0)?, row.get(1)?)))?
+        .collect::<SqlResult<_>>()?;
+    let mut sealed = 0;
+    for (webhook_id, secret) in secrets.iter().filter(|(_, secret)| !is_sealed(secret)) {
+        sealed += query_execute!(con => "UPDATE webhooks SET secret = ? WHERE id = ?",
+            (encrypt_secret(secret), webhook_id))?;
+    }
+    Ok(sealed)
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct NewWebhook {
+    pub url: String,
+    /// Event types to deliver, empty means all.
+    #[serde(default)]
+    pub events: Vec<String>,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Webhook {
+    pub id: i32,
+    pub app_id: i32,
+    pub url: String,
+    pub events: Vec<String>,
+    pub active: bool,
+    pub created_at: u64,
+    /// Only returned once, when webhook is created.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub secret: Option<String>,
+}
+
+impl Webhook {
+    pub fn from_row(row: &Row) -> SqlResult<Self> {
+        let events: String = row.get("events")?;
+        Ok(Self {
+            id: row.get("id")?,
+            app_id: row.get("app_id")?,
+            url: row.get("url")?,
+            events: split_events(&events),
+            active: row.get("active")?,
+            created_at: row.get("created_at")?,
+            secret: None,
+        })
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Delivery {
+    pub id: i32,
+    pub webhook_id: i32,
+    pub event_id: i64,
+    pub kind: String,
+    pub status: String,
+    pub attempts: i32,
+    pub next_attempt_at: Option<u64>,
+    pub last_status_code: Option<u16>,
+    pub last_error: Option<String>,
+    pub created_at: u64,
+    pub delivered_at: Option<u64>,
+}
+
+impl_from_row!(Delivery {
+    id,
+    webhook_id,
+    event_id,
+    kind,
+    status,
+    attempts,
+    next_attempt_at,
+    last_status_code,
+    last_error,
+    created_at,
+    delivered_at
+});
+
+/// Delivery joined with what is needed to send it.
+pub struct PendingDelivery {
+    pub id: i32,
+    pub kind: String,
+    pub payload: String,
+    pub attempts: i32,
+    pub url: String,
+    pub secret: String,
+}
+
+impl_from_row!(PendingDelivery {
+    id,
+    kind,
+    payload,
+    attempts,
+    url,
+    secret
+});
+
+/// Events are stored as `,a,b,` so a single event type can be matched with `instr`.
+fn join_events(events: &[String]) -> String {
+    match events.is_empty() {
+        true => String::new(),
+        false => format!(",{},", events.join(",")),
+    }
+}
+
+fn split_events(events: &str) -> Vec<String> {
+    events
+        .split(',')
+        .filter(|event| !event.is_empty())
+        .map(str::to_string)
+        .collect()
+}
+
+fn for_app(con: &mut Connection, app_id: i32, user_id: i32) -> SqlResult<Vec<Webhook>> {
+    let tx = con.transaction()?;
+    has_permission(&tx, app_id, user_id)?;
+    let webhooks = query_rows!(tx => "SELECT * FROM webhooks WHERE app_id = ?", [app_id], Webhook);
+    Ok(webhooks)
+}
+
+fn create(
+    con: &mut Connection,
+    app_id: i32,
+    user_id: i32,
+    new_webhook: NewWebhook,
+    secret: String,
+) -> SqlResult<Webhook> {
+    let tx = con.transaction()?;
+    has_permission(&tx, app_id, user_id)?;
+    query_execute!(tx => "INSERT INTO webhooks(app_id, url, secret, events, active, created_at) VALUES (?, ?, ?, ?, 1, ?)",
+        (app_id, &new_webhook.url, encrypt_secret(&secret), join_events(&new_webhook.events), now()))?;
+    let id = tx.last_insert_rowid();
+    let mut webhook = query_row!(tx => "SELECT * FROM webhooks WHERE id = ?", [id], Webhook)?;
+    let change = Change {
+        actor_id: Some(user_id),
+        app_id: Some(app_id),
+        action: "webhook.create",
+        target: format!("webhook:{id}"),
+        before: None,
+        after: snapshot(&webhook),
+    };
+    audit::record(&tx, change)?;
+    tx.commit()?;
+
+    webhook.secret = Some(secret);
+    Ok(webhook)
+}
+
+fn delete(con: &mut Connection, app_id: i32, user_id: i32, webhook_id: i32) -> SqlResult<usize> {
+    let tx = con.transaction()?;
+    has_permission(&tx, app_id, user_id)?;
+    let before = query_row!(tx => "SELECT * FROM webhooks WHERE id = ? AND app_id = ?", [webhook_id, app_id], Webhook).optional()?;
+    query_execute!(tx => "DELETE FROM webhook_deliveries WHERE webhook_id IN (SELECT id FROM webhooks WHERE id = ? AND app_id = ?)", [webhook_id, app_id])?;
+    let result = query_execute!(tx => "DELETE FROM webhooks WHERE id = ? AND app_id = ?", [webhook_id, app_id])?;
+    if let Some(before) = before {
+        let change = Change {
+            actor_id: Some(user_id),
+            app_id: Some(app_id),
+            action: "webhook.delete",
+            target: format!("webhook:{webhook_id}"),
+            before: snapshot(&before),
+            after: None,
+        };
+        audit::record(&tx, change)?;
+    }
+    tx.commit()?;
+    Ok(result)
+}
+
+fn deliveries(
+    con: &mut Connection,
+    app_id: i32,
+    user_id: i32,
+    webhook_id: i32,
+) -> SqlResult<Vec<Delivery>> {
+    let tx = con.transaction()?;
+    has_permission(&tx, app_id, user_id)?;
+    let deliveries = query_rows!(tx => "
+        SELECT webhook_deliveries.* FROM webhook_deliveries
+        JOIN webhooks ON webhooks.id = webhook_deliveries.webhook_id
+        WHERE webhooks.id = ? AND webhooks.app_id = ?
+        ORDER BY webhook_deliveries.id DESC
+        LIMIT 100",
+        [webhook_id, app_id],
+        Delivery
+    );
+    Ok(deliveries)
+}
+
+/// Puts delivery back into the outbox, no matter if it was delivered or failed before.
+fn redeliver(
+    con: &mut Connection,
+    app_id: i32,
+    user_id: i32,
+    webhook_id: i32,
+    delivery_id: i32,
+) -> SqlResult<usize> {
+    let tx = con.transaction()?;
+    has_permission(&tx, app_id, user_id)?;
+    let result = query_execute!(tx => "
+        UPDATE webhook_deliveries SET status = 'pending', attempts = 0, next_attempt_at = ?
+        WHERE id = ? AND webhook_id IN (SELECT id FROM webhooks WHERE id = ? AND app_id = ?)",
+        (now(), delivery_id, webhook_id, app_id)
+    )?;
+    if result == 1 {
+        let change = Change {
+            actor_id: Some(user_id),
+            app_id: Some(app_id),
+            action: "webhook.redeliver",
+            target: format!("webhookDelivery:{delivery_id}"),
+            before: None,
+            after: None,
+        };
+        audit::record(&tx, change)?;
+    }
+    tx.commit()?;
+    Ok(result)
+}
+
+/// Adds event to the outbox of every matching webhook of the app.
+pub fn enqueue(
+    con: &Connection,
+    app_id: i32,
+    event_id: i64,
+    kind: &str,
+    payload: &str,
+) -> SqlResult<usize> {
+    let now = now();
+    query_execute!(con => "
+        INSERT INTO webhook_deliveries(webhook_id, event_id, kind, payload, status, attempts, next_attempt_at, created_at)
+        SELECT id, ?, ?, ?, 'pending', 0, ?, ? FROM webhooks
+        WHERE app_id = ? AND active = 1 AND (events = '' OR instr(events, ',' || ? || ',') > 0)",
+        (event_id, kind, payload, now, now, app_id, kind)
+    )
+}
+
+/// Deliveries whose secret can't be unsealed anymore, after `SECRETS_KEY` changed, fail.
+fn due(con: &Connection, limit: usize) -> SqlResult<Vec<PendingDelivery>> {
+    let pending = query_rows!(con => "
+        SELECT webhook_deliveries.id, kind, payload, attempts, url, secret FROM webhook_deliveries
+        JOIN webhooks ON webhooks.id = webhook_deliveries.webhook_id
+        WHERE status = 'pending' AND next_attempt_at <= ?
+        ORDER BY next_attempt_at
+        LIMIT ?",
+        (now(), limit),
+        PendingDelivery
+    );
+    let mut due = Vec::new();
+    for mut delivery in pending {
+        match decrypt_secret(&delivery.secret) {
+            Some(secret) => {
+                delivery.secret = secret;
+                due.push(delivery);
+            }
+            None => {
+                failed(con, delivery.id, None, "webhook secret can't be decrypted")?;
+            }
+        }
+    }
+    Ok(due)
+}
+
+fn delivered(con: &Connection, delivery_id: i32, status_code: u16) -> SqlResult<usize> {
+    query_execute!(con => "
+        UPDATE webhook_deliveries
+        SET status = 'delivered', attempts = attempts + 1, last_status_code = ?, last_error = NULL, next_attempt_at = NULL, delivered_at = ?
+        WHERE id = ?",
+        (status_code, now(), delivery_id)
+    )
+}
+
+/// Schedules next attempt with exponential backoff, or gives up after `MAX_ATTEMPTS`.
+fn failed(
+    con: &Connection,
+    delivery_id: i32,
+    status_code: Option<u16>,
+    error: &str,
+) -> SqlResult<usize> {
+    let attempts: i32 = con
+        .query_row(
+            "SELECT attempts FROM webhook_deliveries WHERE id = ?",
+            [delivery_id],
+            |row| row.get(0),
+        )
+        .optional()?
+        .unwrap_or(0)
+        + 1;
+
+    let (status, next_attempt_at) = match attempts >= MAX_ATTEMPTS {
+        true => ("failed", None),
+        false => ("pending", Some(now() + (RETRY_BASE_SECS << (attempts - 1)))),
+    };
+
+    query_execute!(con => "
+        UPDATE webhook_deliveries
+        SET status = ?, attempts = ?, last_status_code = ?, last_error = ?, next_attempt_at = ?
+        WHERE id = ?",
+        (status, attempts, status_code, error, next_attempt_at, delivery_id)
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn delivery(con: &Connection) -> (String, Option<u64>, i32) {
+        con.query_row(
+            "SELECT status, next_attempt_at, attempts FROM webhook_deliveries WHERE id = 1",
+            [],
+            |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)),
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn failed_backs_off_exponentially_then_gives_up() {
+        let con = Connection::open_in_memory().unwrap();
+        create_table(&con).unwrap();
+        con.execute(
+            "INSERT INTO webhook_deliveries(id, webhook_id, status, attempts) VALUES (1, 1, 'pending', 0)",
+            [],
+        )
+        .unwrap();
+
+        for attempt in 1..MAX_ATTEMPTS {
+            let before = now();
+            failed(&con, 1, Some(500), "server error").unwrap();
+            let (status, next_attempt_at, attempts) = delivery(&con);
+            let delay = RETRY_BASE_SECS << (attempt - 1);
+            assert_eq!(status, "pending");
+            assert_eq!(attempts, attempt);
+            let next_attempt_at = next_attempt_at.unwrap();
+            assert!(next_attempt_at >= before + delay && next_attempt_at <= now() + delay);
+        }
+
+        failed(&con, 1, None, "timeout").unwrap();
+        assert_eq!(delivery(&con), ("failed".to_string(), None, MAX_ATTEMPTS));
+    }
+}
@@ -0,0 +1,76 @@
+use axum::{
+    extract::{Path, State},
+    http::StatusCode,
+    response::IntoResponse,
+    Json,
+};
+use axum_utils::{unwrap_json, Claim};
+use serde_json::json;
+
+use crate::{
+    crypto::random_token,
+    db::{
+        access_tokens::{NewAccessToken, ACCESS_TOKEN_PREFIX},
+        events::now,
+        Db,
+    },
+    validation::{Policy, ValidationErrors},
+};
+
+use super::tokens::AppClaim;
+
+pub async fn all(State(db): State<Db>, Claim(claim): AppClaim) -> impl IntoResponse {
+    match db.access_tokens.for_user(claim.user_id) {
+        Ok(tokens) => unwrap_json(&tokens).into_response(),
+        Err(e) => {
+            println!("handlers::access_tokens::all - {e:?}");
+            StatusCode::INTERNAL_SERVER_ERROR.into_response()
+        }
+    }
+}
+
+/// Response contains the token, it is not shown again.
+pub async fn create(
+    State(db): State<Db>,
+    Claim(claim): AppClaim,
+    Json(body): Json<NewAccessToken>,
+) -> impl IntoResponse {
+    let mut errors = ValidationErrors::default();
+    Policy::current().check_name(&body.name, &mut errors);
+    if body.scopes.is_empty() {
+        errors.add("scopes", "required", "at least one scope is needed");
+    }
+    if body
+        .expires_at
+        .is_some_and(|expires_at| expires_at <= now())
+    {
+        errors.add("expiresAt", "inPast", "expiresAt must be in the future");
+    }
+    if !errors.is_empty() {
+        return (StatusCode::UNPROCESSABLE_ENTITY, unwrap_json(&errors)).into_response();
+    }
+
+    let token = format!("{ACCESS_TOKEN_PREFIX}{}", random_token(32));
+    match db.access_tokens.create(claim.user_id, &body, &token) {
+        Ok(id) => unwrap_json(&json!({ "id": id, "token": token })).into_response(),
+        Err(e) => {
+            println!("handlers::access_tokens::create - {e:?}");
+            StatusCode::BAD_REQUEST.into_response()
+        }
+    }
+}
+
+pub async fn revoke(
+    State(db): State<Db>,
+    Claim(claim): AppClaim,
+    Path(token_id): Path<i32>,
+) -> impl IntoResponse {
+    match db.access_tokens.revoke(claim.user_id, token_id) {
+        Ok(0) => StatusCode::NOT_FOUND,
+        Ok(_) => StatusCode::NO_CONTENT,
+        Err(e) => {
+            println!("handlers::access_tokens::revoke - {e:?}");
+            StatusCode::INTERNAL_SERVER_ERROR
+        }
+    }
+}
@@ -0,0 +1,110 @@
+use axum::{
+    extract::{Path, State},
+    http::StatusCode,
+    response::IntoResponse,
+    Json,
+};
+use axum_utils::{unwrap_json, Claim};
+
+use crate::db::{backup::SnapshotError, export::Export, Db};
+
+use super::tokens::AppClaim;
+
+pub fn require_admin(db: &Db, user_id: i32) -> Result<(), StatusCode> {
+    match db.users.is_admin(user_id) {
+        Ok(true) => Ok(()),
+        Ok(false) => Err(StatusCode::FORBIDDEN),
+        Err(e) => {
+            println!("500 ERROR while checking admin rights of user {user_id}: {e}");
+            Err(StatusCode::INTERNAL_SERVER_ERROR)
+        }
+    }
+}
+
+pub async fn backups(State(db): State<Db>, Claim(claim): AppClaim) -> impl IntoResponse {
+    if let Err(status) = require_admin(&db, claim.user_id) {
+        return status.into_response();
+    }
+
+    match db.snapshots.list() {
+        Ok(snapshots) => unwrap_json(&snapshots).into_response(),
+        Err(e) => {
+            println!("handlers::admin::backups - {e:?}");
+            StatusCode::INTERNAL_SERVER_ERROR.into_response()
+        }
+    }
+}
+
+pub async fn backup(State(db): State<Db>, Claim(claim): AppClaim) -> impl IntoResponse {
+    if let Err(status) = require_admin(&db, claim.user_id) {
+        return status.into_response();
+    }
+
+    let result = tokio::task::spawn_blocking(move || db.snapshots.create())
+        .await
+        .unwrap();
+
+    match result {
+        Ok(snapshot) => unwrap_json(&snapshot).into_response(),
+        Err(e) => {
+            println!("handlers::admin::backup - {e:?}");
+            StatusCode::INTERNAL_SERVER_ERROR.into_response()
+        }
+    }
+}
+
+pub async fn restore(
+    State(db): State<Db>,
+    Claim(claim): AppClaim,
+    Path(name): Path<String>,
+) -> impl IntoResponse {
+    if let Err(status) = require_admin(&db, claim.user_id) {
+        return status;
+    }
+
+    let result = tokio::task::spawn_blocking(move || db.restore_snapshot(&name))
+        .await
+        .unwrap();
+
+    match result {
+        Ok(_) => StatusCode::OK,
+        Err(SnapshotError::NotFound) => StatusCode::NOT_FOUND,
+        Err(e) => {
+            println!("handlers::admin::restore - {e:?}");
+            StatusCode::INTERNAL_SERVER_ERROR
+        }
+    }
+}
+
+/// Dump without credentials, only the `export` cli command includes them.
+pub async fn export(State(db): State<Db>, Claim(claim): AppClaim) -> impl IntoResponse {
+    if let Err(status) = require_admin(&db, claim.user_id) {
+        return status.into_response();
+    }
+
+    match db.export() {
+        Ok(data) => unwrap_json(&data.without_credentials()).into_response(),
+        Err(e) => {
+            println!("handlers::admin::export - {e:?}");
+            StatusCode::INTERNAL_SERVER_ERROR.into_response()
+        }
+    }
+}
+
+pub async fn import(
+    State(db): State<Db>,
+    Claim(claim): AppClaim,
+    Json(data): Json<Export>,
+) -> impl IntoResponse {
+    if let Err(status) = require_admin(&db, claim.user_id) {
+        return status;
+    }
+
+    match db.import(data) {
+        Ok(_) => StatusCode::OK,
+        Err(e) => {
+            println!("handlers::admin::import - {e:?}");
+            StatusCode::CONFLICT
+        }
+    }
+}
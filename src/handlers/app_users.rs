@@ -42,7 +42,10 @@ pub async fn create(
     let result = db.app_users.create(app_id, claim.user_id, body.user_id);
 
     match result {
-        Ok(_) => StatusCode::OK,
+        Ok(_) => {
+            db.events.notify();
+            StatusCode::OK
+        }
         Err(e) => {
             println!("handlers::app_users::create - {e:?}");
             StatusCode::BAD_REQUEST
@@ -58,7 +61,10 @@ pub async fn delete(
     let result = db.app_users.delete(app_id, claim.user_id, app_user_id);
 
     match result {
-        Ok(_) => StatusCode::OK,
+        Ok(_) => {
+            db.events.notify();
+            StatusCode::OK
+        }
         Err(_) => StatusCode::BAD_REQUEST,
     }
 }
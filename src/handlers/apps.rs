@@ -7,9 +7,13 @@ use axum::{
 use axum_utils::{unwrap_json, Claim};
 use serde::{Deserialize, Serialize};
 
-use crate::db::{
-    apps::{AppStatus, NewApp},
-    Db,
+use crate::{
+    db::{
+        apps::{AppStatus, AppUpdate, NewApp},
+        Db,
+    },
+    validation::ValidationErrors,
+    version::Version,
 };
 
 use super::tokens::AppClaim;
@@ -53,6 +57,12 @@ impl NewAppRequest {
             version,
             public,
             status,
+            require_two_factor: false,
+            min_broker_version: None,
+            max_broker_version: None,
+            reject_incompatible_brokers: false,
+            created_at: None,
+            updated_at: None,
         }
     }
 }
@@ -62,6 +72,10 @@ pub async fn new_app(
     Claim(claim): AppClaim,
     Json(new_app): Json<NewAppRequest>,
 ) -> impl IntoResponse {
+    let errors = check_versions(Some(&new_app.version), None, None);
+    if !errors.is_empty() {
+        return (StatusCode::UNPROCESSABLE_ENTITY, unwrap_json(&errors));
+    }
     match db.apps.insert(new_app.with_author(claim.user_id)) {
         Ok(apps) => (StatusCode::OK, serde_json::to_string(&apps).unwrap()),
         Err(_) => (StatusCode::INTERNAL_SERVER_ERROR, String::new()),
@@ -103,3 +117,75 @@ pub async fn by_id(
         }
     }
 }
+
+pub async fn update(
+    State(db): State<Db>,
+    Claim(claim): AppClaim,
+    Path(id): Path<i32>,
+    Json(update): Json<AppUpdate>,
+) -> impl IntoResponse {
+    let errors = check_versions(
+        update.version.as_deref(),
+        update.min_broker_version.as_deref(),
+        update.max_broker_version.as_deref(),
+    );
+    if !errors.is_empty() {
+        return (StatusCode::UNPROCESSABLE_ENTITY, unwrap_json(&errors)).into_response();
+    }
+
+    // owner must not require what they don't have themselves
+    if update.require_two_factor == Some(true) {
+        match db.two_factor.is_enabled(claim.user_id) {
+            Ok(true) => {}
+            Ok(false) => return (StatusCode::CONFLICT).into_response(),
+            Err(sql) => {
+                println!(
+                    "sql error happend while checking 2fa of user '{}'\n {sql:?}",
+                    claim.user_id
+                );
+                return (StatusCode::INTERNAL_SERVER_ERROR).into_response();
+            }
+        }
+    }
+
+    match db.apps.update(id, claim.user_id, update) {
+        Ok(Some(app)) => {
+            db.events.notify();
+            (StatusCode::OK, unwrap_json(&app)).into_response()
+        }
+        Ok(None) => (StatusCode::NOT_FOUND).into_response(),
+        Err(sql) => {
+            println!("sql error happend while updating app with id '{id}'\n {sql:?}");
+
+            (StatusCode::INTERNAL_SERVER_ERROR).into_response()
+        }
+    }
+}
+
+/// Versions must be semantic versions, empty broker bounds are fine as they remove the bound.
+fn check_versions(version: Option<&str>, min: Option<&str>, max: Option<&str>) -> ValidationErrors {
+    let mut errors = ValidationErrors::default();
+    let mut parse = |field: &str, text: &str| match text.parse::<Version>() {
+        Ok(version) => Some(version),
+        Err(e) => {
+            errors.add(field, "invalidVersion", &e.to_string());
+            None
+        }
+    };
+    if let Some(version) = version {
+        parse("version", version);
+    }
+    let min = min
+        .filter(|min| !min.is_empty())
+        .and_then(|min| parse("min_broker_version", min));
+    let max = max
+        .filter(|max| !max.is_empty())
+        .and_then(|max| parse("max_broker_version", max));
+    if let (Some(min), Some(max)) = (min, max) {
+        if min >= max {
+            let message = "max_broker_version must be above min_broker_version";
+            errors.add("max_broker_version", "emptyRange", message);
+        }
+    }
+    errors
+}
@@ -0,0 +1,455 @@
+use axum::{
+    body::Body,
+    extract::{Path, Query, State},
+    http::{header, HeaderMap, HeaderValue, StatusCode},
+    response::{IntoResponse, Response},
+    Json,
+};
+use axum_utils::{unwrap_json, Claim};
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+use tokio::{fs, io::AsyncWriteExt};
+use tokio_stream::StreamExt;
+
+use crate::{
+    crypto::random_token,
+    db::{
+        artifacts::{
+            check_name, verify_upload_token, Artifact, ArtifactQuery, NewArtifact,
+            MAX_RETENTION_DAYS,
+        },
+        Db,
+    },
+    storage::{self, lock_key, store},
+    validation::ValidationErrors,
+};
+
+use super::{admin::require_admin, tokens::AppClaim};
+
+/// Carries the upload token the broker got with the job, see `artifacts::upload_token`.
+const UPLOAD_TOKEN_HEADER: &str = "x-upload-token";
+
+#[derive(Deserialize)]
+pub struct UploadQuery {
+    pub name: String,
+}
+
+/// `None` goes back to the server default.
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct RetentionChange {
+    pub retention_days: Option<u32>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct QuotaChange {
+    pub quota_bytes: Option<u64>,
+}
+
+pub async fn all(
+    State(db): State<Db>,
+    Path(app_id): Path<i32>,
+    Claim(claim): AppClaim,
+    Query(query): Query<ArtifactQuery>,
+) -> impl IntoResponse {
+    match db.artifacts.for_app(app_id, claim.user_id, &query) {
+        Ok(artifacts) => unwrap_json(&artifacts).into_response(),
+        Err(rusqlite::Error::QueryReturnedNoRows) => StatusCode::NOT_FOUND.into_response(),
+        Err(e) => {
+            println!("handlers::artifacts::all - {e:?}");
+            StatusCode::INTERNAL_SERVER_ERROR.into_response()
+        }
+    }
+}
+
+pub async fn get(
+    State(db): State<Db>,
+    Claim(claim): AppClaim,
+    Path((app_id, artifact_id)): Path<(i32, i64)>,
+) -> impl IntoResponse {
+    match db.artifacts.get(app_id, claim.user_id, artifact_id) {
+        Ok(Some(artifact)) => unwrap_json(&artifact).into_response(),
+        Ok(None) | Err(rusqlite::Error::QueryReturnedNoRows) => {
+            StatusCode::NOT_FOUND.into_response()
+        }
+        Err(e) => {
+            println!("handlers::artifacts::get - {e:?}");
+            StatusCode::INTERNAL_SERVER_ERROR.into_response()
+        }
+    }
+}
+
+/**
+    Streams the body of a broker into the store, `name` is the file name of the artifact.
+    Only the broker the job was given to can upload for it, with the upload token
+    it got along with the job. `413` once the upload does not fit into the quota of the app.
+*/
+pub async fn upload(
+    State(db): State<Db>,
+    Claim(claim): AppClaim,
+    Path((app_id, broker_id, job_id)): Path<(i32, i32, i64)>,
+    Query(query): Query<UploadQuery>,
+    headers: HeaderMap,
+    body: Body,
+) -> Response {
+    let mut errors = ValidationErrors::default();
+    check_name(&query.name, &mut errors);
+    if !errors.is_empty() {
+        return (StatusCode::UNPROCESSABLE_ENTITY, unwrap_json(&errors)).into_response();
+    }
+    let token = headers
+        .get(UPLOAD_TOKEN_HEADER)
+        .and_then(|value| value.to_str().ok())
+        .unwrap_or_default();
+    if !verify_upload_token(app_id, broker_id, job_id, token) {
+        return StatusCode::FORBIDDEN.into_response();
+    }
+    let remaining = match db
+        .artifacts
+        .allowance(app_id, claim.user_id, broker_id, job_id)
+    {
+        Ok(Some(remaining)) => remaining,
+        Ok(None) | Err(rusqlite::Error::QueryReturnedNoRows) => {
+            return StatusCode::NOT_FOUND.into_response()
+        }
+        Err(e) => {
+            println!("handlers::artifacts::upload - {e:?}");
+            return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+        }
+    };
+    let announced = headers
+        .get(header::CONTENT_LENGTH)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.parse::<u64>().ok());
+    if announced.is_some_and(|length| length > remaining) {
+        return StatusCode::PAYLOAD_TOO_LARGE.into_response();
+    }
+    let content_type = headers
+        .get(header::CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .unwrap_or("application/octet-stream")
+        .to_string();
+
+    let staged = storage::staging_dir().join(random_token(16));
+    let (size, sha256) = match stage(body, &staged, remaining).await {
+        Ok(Some(staged)) => staged,
+        Ok(None) => {
+            let _ = fs::remove_file(&staged).await;
+            return StatusCode::PAYLOAD_TOO_LARGE.into_response();
+        }
+        Err(e) => {
+            let _ = fs::remove_file(&staged).await;
+            println!("handlers::artifacts::upload - {e}");
+            return StatusCode::BAD_REQUEST.into_response();
+        }
+    };
+
+    // the row comes first, so removing another artifact with the same content keeps it,
+    // the lock keeps a removal that already found the content unused from finishing in between
+    let key_lock = lock_key(&sha256).await;
+    let new_artifact = NewArtifact {
+        job_id,
+        broker_id,
+        name: query.name,
+        content_type,
+        size,
+        sha256,
+    };
+    let artifact = match db.artifacts.create(app_id, claim.user_id, new_artifact) {
+        Ok(Some(artifact)) => artifact,
+        Ok(None) => {
+            let _ = fs::remove_file(&staged).await;
+            return StatusCode::PAYLOAD_TOO_LARGE.into_response();
+        }
+        Err(e) => {
+            let _ = fs::remove_file(&staged).await;
+            println!("handlers::artifacts::upload - {e:?}");
+            return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+        }
+    };
+    let stored = store().put(&artifact.sha256, &staged, artifact.size).await;
+    drop(key_lock);
+    if let Err(e) = stored {
+        println!("handlers::artifacts::upload - {e}");
+        let _ = fs::remove_file(&staged).await;
+        if let Ok(Some(keys)) = db.artifacts.delete(app_id, claim.user_id, artifact.id) {
+            remove_contents(&db, keys).await;
+        }
+        db.events.notify();
+        return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+    }
+    db.events.notify();
+    (StatusCode::CREATED, unwrap_json(&artifact)).into_response()
+}
+
+/// Writes the body to `path` while hashing it, `None` once it gets larger than `limit`.
+async fn stage(
+    body: Body,
+    path: &std::path::Path,
+    limit: u64,
+) -> Result<Option<(u64, String)>, String> {
+    fs::create_dir_all(storage::staging_dir())
+        .await
+        .map_err(|e| e.to_string())?;
+    let mut file = fs::File::create(path).await.map_err(|e| e.to_string())?;
+    let mut hasher = Sha256::new();
+    let mut size = 0u64;
+    let mut stream = body.into_data_stream();
+    while let Some(chunk) = stream.next().await {
+        let chunk = chunk.map_err(|e| e.to_string())?;
+        size += chunk.len() as u64;
+        if size > limit {
+            return Ok(None);
+        }
+        hasher.update(&chunk);
+        file.write_all(&chunk).await.map_err(|e| e.to_string())?;
+    }
+    file.flush().await.map_err(|e| e.to_string())?;
+    Ok(Some((size, hex::encode(hasher.finalize()))))
+}
+
+/**
+    Content of the artifact, a single range of it for a `Range` header
+    like `bytes=0-1023`, `bytes=1024-` or `bytes=-512`.
+*/
+pub async fn download(
+    State(db): State<Db>,
+    Claim(claim): AppClaim,
+    Path((app_id, artifact_id)): Path<(i32, i64)>,
+    headers: HeaderMap,
+) -> Response {
+    let artifact = match db.artifacts.get(app_id, claim.user_id, artifact_id) {
+        Ok(Some(artifact)) => artifact,
+        Ok(None) | Err(rusqlite::Error::QueryReturnedNoRows) => {
+            return StatusCode::NOT_FOUND.into_response()
+        }
+        Err(e) => {
+            println!("handlers::artifacts::download - {e:?}");
+            return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+        }
+    };
+    content(&artifact, &headers).await
+}
+
+/// Response with the content of the artifact, honoring a `Range` header.
+pub(super) async fn content(artifact: &Artifact, headers: &HeaderMap) -> Response {
+    let range = match headers
+        .get(header::RANGE)
+        .and_then(|value| value.to_str().ok())
+    {
+        Some(range) => match parse_range(range, artifact.size) {
+            Some(range) => Some(range),
+            None => {
+                let unsatisfied = format!("bytes */{}", artifact.size);
+                return (
+                    StatusCode::RANGE_NOT_SATISFIABLE,
+                    [(header::CONTENT_RANGE, unsatisfied)],
+                )
+                    .into_response();
+            }
+        },
+        None => None,
+    };
+    // an empty artifact has no bytes to ask the store for
+    let body = match artifact.size {
+        0 => Ok(Body::empty()),
+        _ => store().get(&artifact.sha256, range).await,
+    };
+    let body = match body {
+        Ok(body) => body,
+        Err(e) => {
+            println!("handlers::artifacts::content - {e}");
+            return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+        }
+    };
+
+    let mut response = Response::new(body);
+    let content_type = HeaderValue::from_str(&artifact.content_type)
+        .unwrap_or(HeaderValue::from_static("application/octet-stream"));
+    let disposition = format!(
+        "attachment; filename*=UTF-8''{}",
+        storage::uri_encode(&artifact.name)
+    );
+    let headers = response.headers_mut();
+    headers.insert(header::CONTENT_TYPE, content_type);
+    headers.insert(header::ACCEPT_RANGES, HeaderValue::from_static("bytes"));
+    if let Ok(etag) = HeaderValue::from_str(&format!("\"{}\"", artifact.sha256)) {
+        headers.insert(header::ETAG, etag);
+    }
+    if let Ok(disposition) = HeaderValue::from_str(&disposition) {
+        headers.insert(header::CONTENT_DISPOSITION, disposition);
+    }
+    match range {
+        Some((start, end)) => {
+            let content_range = format!("bytes {start}-{end}/{}", artifact.size);
+            headers.insert(header::CONTENT_RANGE, content_range.parse().unwrap());
+            headers.insert(header::CONTENT_LENGTH, (end + 1 - start).into());
+            *response.status_mut() = StatusCode::PARTIAL_CONTENT;
+        }
+        None => {
+            headers.insert(header::CONTENT_LENGTH, artifact.size.into());
+        }
+    }
+    response
+}
+
+/// Inclusive byte range of a single range `Range` header, `None` if it can't be served.
+fn parse_range(range: &str, size: u64) -> Option<(u64, u64)> {
+    let (start, end) = range.trim().strip_prefix("bytes=")?.split_once('-')?;
+    let (start, end) = match (start.trim(), end.trim()) {
+        ("", suffix) => {
+            let suffix: u64 = suffix.parse().ok()?;
+            (size.saturating_sub(suffix), size.checked_sub(1)?)
+        }
+        (start, "") => (start.parse().ok()?, size.checked_sub(1)?),
+        (start, end) => {
+            let end: u64 = end.parse().ok()?;
+            (start.parse().ok()?, end.min(size.checked_sub(1)?))
+        }
+    };
+    (start <= end && start < size).then_some((start, end))
+}
+
+/// Contents of the artifact stay in the store while other artifacts share them.
+pub async fn delete(
+    State(db): State<Db>,
+    Claim(claim): AppClaim,
+    Path((app_id, artifact_id)): Path<(i32, i64)>,
+) -> impl IntoResponse {
+    match db.artifacts.delete(app_id, claim.user_id, artifact_id) {
+        Ok(Some(keys)) => {
+            db.events.notify();
+            remove_contents(&db, keys).await;
+            StatusCode::OK
+        }
+        Ok(None) | Err(rusqlite::Error::QueryReturnedNoRows) => StatusCode::NOT_FOUND,
+        Err(e) => {
+            println!("handlers::artifacts::delete - {e:?}");
+            StatusCode::INTERNAL_SERVER_ERROR
+        }
+    }
+}
+
+/**
+    Removes contents no artifact refers to anymore from the store.
+    Checked again under the lock of the key, an upload of the same content may have come in.
+*/
+pub async fn remove_contents(db: &Db, keys: Vec<String>) {
+    for key in keys {
+        let _key_lock = lock_key(&key).await;
+        if !matches!(db.artifacts.is_unused(&key), Ok(true)) {
+            continue;
+        }
+        if let Err(e) = store().delete(&key).await {
+            println!("removing artifact content {key} failed: {e}");
+        }
+    }
+}
+
+pub async fn usage(
+    State(db): State<Db>,
+    Path(app_id): Path<i32>,
+    Claim(claim): AppClaim,
+) -> impl IntoResponse {
+    match db.artifacts.usage(app_id, claim.user_id) {
+        Ok(usage) => unwrap_json(&usage).into_response(),
+        Err(rusqlite::Error::QueryReturnedNoRows) => StatusCode::NOT_FOUND.into_response(),
+        Err(e) => {
+            println!("handlers::artifacts::usage - {e:?}");
+            StatusCode::INTERNAL_SERVER_ERROR.into_response()
+        }
+    }
+}
+
+/// Applies to existing artifacts too, shorter retention expires them on the next purge.
+pub async fn set_retention(
+    State(db): State<Db>,
+    Path(app_id): Path<i32>,
+    Claim(claim): AppClaim,
+    Json(change): Json<RetentionChange>,
+) -> Response {
+    if change
+        .retention_days
+        .is_some_and(|days| !(1..=MAX_RETENTION_DAYS).contains(&days))
+    {
+        let mut errors = ValidationErrors::default();
+        let message = format!("retentionDays must be between 1 and {MAX_RETENTION_DAYS}");
+        errors.add("retentionDays", "outOfRange", &message);
+        return (StatusCode::UNPROCESSABLE_ENTITY, unwrap_json(&errors)).into_response();
+    }
+    match db
+        .artifacts
+        .set_retention(app_id, claim.user_id, change.retention_days)
+    {
+        Ok(usage) => unwrap_json(&usage).into_response(),
+        Err(rusqlite::Error::QueryReturnedNoRows) => StatusCode::NOT_FOUND.into_response(),
+        Err(e) => {
+            println!("handlers::artifacts::set_retention - {e:?}");
+            StatusCode::INTERNAL_SERVER_ERROR.into_response()
+        }
+    }
+}
+
+/// For admins, artifacts above a lowered quota are kept but no new ones fit.
+pub async fn set_quota(
+    State(db): State<Db>,
+    Claim(claim): AppClaim,
+    Path(app_id): Path<i32>,
+    Json(change): Json<QuotaChange>,
+) -> Response {
+    if let Err(status) = require_admin(&db, claim.user_id) {
+        return status.into_response();
+    }
+    match db
+        .artifacts
+        .set_quota(app_id, claim.user_id, change.quota_bytes)
+    {
+        Ok(Some(usage)) => unwrap_json(&usage).into_response(),
+        Ok(None) => StatusCode::NOT_FOUND.into_response(),
+        Err(e) => {
+            println!("handlers::artifacts::set_quota - {e:?}");
+            StatusCode::INTERNAL_SERVER_ERROR.into_response()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ranges_are_clamped_to_the_content() {
+        assert_eq!(parse_range("bytes=0-99", 1000), Some((0, 99)));
+        assert_eq!(parse_range(" bytes=10-10 ", 1000), Some((10, 10)));
+        assert_eq!(parse_range("bytes=500-", 1000), Some((500, 999)));
+        assert_eq!(parse_range("bytes=900-5000", 1000), Some((900, 999)));
+        assert_eq!(parse_range("bytes=0-", 1), Some((0, 0)));
+    }
+
+    #[test]
+    fn suffix_ranges_count_from_the_end() {
+        assert_eq!(parse_range("bytes=-100", 1000), Some((900, 999)));
+        assert_eq!(parse_range("bytes=-5000", 1000), Some((0, 999)));
+        assert_eq!(parse_range("bytes=-0", 1000), None);
+    }
+
+    #[test]
+    fn unsatisfiable_or_unsupported_ranges_are_refused() {
+        for range in [
+            "bytes=1000-",
+            "bytes=1000-1001",
+            "bytes=5-4",
+            "bytes=-",
+            "bytes=a-b",
+            "bytes=0-1,5-6",
+            "items=0-1",
+            "0-1",
+        ] {
+            assert_eq!(parse_range(range, 1000), None, "{range}");
+        }
+        // nothing to serve of an empty artifact
+        assert_eq!(parse_range("bytes=0-", 0), None);
+        assert_eq!(parse_range("bytes=-1", 0), None);
+    }
+}
@@ -0,0 +1,46 @@
+use axum::{
+    extract::{Path, Query, State},
+    http::StatusCode,
+    response::IntoResponse,
+};
+use axum_utils::{unwrap_json, Claim};
+
+use crate::db::{audit::AuditFilter, Db};
+
+use super::{admin::require_admin, tokens::AppClaim};
+
+/// Audit log of the app, visible to its author.
+pub async fn for_app(
+    State(db): State<Db>,
+    Claim(claim): AppClaim,
+    Path(app_id): Path<i32>,
+    Query(filter): Query<AuditFilter>,
+) -> impl IntoResponse {
+    match db.audit.for_app(app_id, claim.user_id, filter) {
+        Ok(entries) => unwrap_json(&entries).into_response(),
+        Err(rusqlite::Error::QueryReturnedNoRows) => StatusCode::NOT_FOUND.into_response(),
+        Err(e) => {
+            println!("handlers::audit::for_app - {e:?}");
+            StatusCode::INTERNAL_SERVER_ERROR.into_response()
+        }
+    }
+}
+
+/// Audit log of the whole server, admins only.
+pub async fn all(
+    State(db): State<Db>,
+    Claim(claim): AppClaim,
+    Query(filter): Query<AuditFilter>,
+) -> impl IntoResponse {
+    if let Err(status) = require_admin(&db, claim.user_id) {
+        return status.into_response();
+    }
+
+    match db.audit.query(filter) {
+        Ok(entries) => unwrap_json(&entries).into_response(),
+        Err(e) => {
+            println!("handlers::audit::all - {e:?}");
+            StatusCode::INTERNAL_SERVER_ERROR.into_response()
+        }
+    }
+}
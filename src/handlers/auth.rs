@@ -1,10 +1,18 @@
-use axum::{extract::State, http::StatusCode, response::IntoResponse, Json};
-use axum_utils::VerifiebleClaim;
+use axum::{
+    extract::State,
+    http::StatusCode,
+    response::{IntoResponse, Response},
+    Json,
+};
+use axum_utils::{unwrap_json, VerifiebleClaim};
 use serde::{Deserialize, Serialize};
+use serde_json::json;
 
-use crate::db::{self, users::NewUser, Db};
+use crate::db::{self, events::now, users::NewUser, Db};
+use crate::ratelimit::{too_many_requests, ClientIp, LoginThrottle};
+use crate::validation::ValidationErrors;
 
-use super::tokens::UserClaim;
+use super::tokens::{TwoFactorChallenge, UserClaim};
 
 macro_rules! handle_request {
     [$name:ident($db:ident $($others:ident: $other_ty:ty),*, $body:ident : $body_type:ty) $body_block:block] => {
@@ -17,29 +25,35 @@ pub struct RegisterRequest {
     name: String,
     username: String,
     password: String,
+    /// Needed for password reset.
+    #[serde(default)]
+    email: Option<String>,
 }
 
 handle_request![register(db, req: RegisterRequest) {
-    let username = req.username;
-
-    let user = db.users.find_user_by_name(&username);
-    if user.is_ok() {
-        return (StatusCode::NOT_FOUND, "sorry".to_string());
-    }
-
-
-    let result = NewUser::new(req.name, username.clone(), req.password)
-        .map(|user| db.users.insert(user));
+    use db::users::RegistrationError::*;
+    let registered = tokio::task::spawn_blocking(move || {
+        NewUser::new(req.name, req.username, req.password, req.email)
+            .map(|user| db.users.register_user(&user))
+    })
+    .await
+    .unwrap();
+    let registered = match registered {
+        Ok(registered) => registered,
+        Err(errors) => return (StatusCode::UNPROCESSABLE_ENTITY, unwrap_json(&errors)),
+    };
 
-    match result {
-        // Insert successful
-        Ok(Ok(user_id)) => (StatusCode::OK, UserClaim { user_id }.sign()),
-        // Insert failed
-        Ok(Err(_)) => {
-            println!("Error while handling '/register', insert failed");
-            (StatusCode::NOT_FOUND, "".to_string()) },
-        // Validation failed
-        Err(_) => (StatusCode::NOT_FOUND, "".to_string())
+    match registered {
+        Ok(user_id) => (StatusCode::OK, UserClaim::for_user(user_id).sign()),
+        Err(UserAlreadyExists) => {
+            let mut errors = ValidationErrors::default();
+            errors.add("username", "taken", "username is already taken");
+            (StatusCode::CONFLICT, unwrap_json(&errors))
+        }
+        Err(SqliteError(e)) => {
+            println!("500 ERROR while executing 'register_user': Sqlite reported error: {e}");
+            (StatusCode::INTERNAL_SERVER_ERROR, ERROR.to_string())
+        }
     }
 }];
 
@@ -49,27 +63,107 @@ pub struct LoginRequest {
     password: String,
 }
 
-handle_request![login(db, req: LoginRequest) {
+/// Challenge returned by `login` for users with 2FA is valid this long.
+const CHALLENGE_TTL_SECS: u64 = 5 * 60;
+
+/**
+    Unknown user and wrong password get the same response, so usernames can't be probed.
+    Users with 2FA get `202` with a challenge for `login_two_factor` instead of the token.
+*/
+pub async fn login(
+    State(db): State<Db>,
+    ClientIp(ip): ClientIp,
+    Json(req): Json<LoginRequest>,
+) -> Response {
     use db::users::LoginError::*;
-    let username = req.username;
-    let user = db.users.find_user(&username, &req.password);
-
-    match user {
-        Ok(user) => {
-            (StatusCode::OK, UserClaim { user_id: user.id }.sign())
-        },
-        Err(UserNotFound) => {
-            println!("registering user, but username already exists");
-            (StatusCode::NOT_FOUND, ERROR.to_string())
-        },
-        Err(WrongPassword) => {
-            (StatusCode::FORBIDDEN, ERROR.to_string())
+    let throttle = LoginThrottle::current();
+    if let Err(retry_after) = throttle.check(ip, &req.username) {
+        return too_many_requests(retry_after);
+    }
+
+    let username = req.username.clone();
+    let user = tokio::task::spawn_blocking({
+        let db = db.clone();
+        move || db.users.find_user(&req.username, &req.password)
+    })
+    .await
+    .unwrap();
+
+    let user = match user {
+        Ok(user) => user,
+        Err(UserNotFound | WrongPassword) => {
+            throttle.failed(ip, &username);
+            return (StatusCode::UNAUTHORIZED, ERROR).into_response();
         }
         Err(SqliteError(e)) => {
             println!("500 ERROR while executing 'find_user': Sqlite reported error: {e}");
-            (StatusCode::INTERNAL_SERVER_ERROR, ERROR.to_string())
+            return (StatusCode::INTERNAL_SERVER_ERROR, ERROR).into_response();
+        }
+    };
+
+    match db.two_factor.is_enabled(user.id) {
+        Ok(false) => {
+            throttle.succeeded(&username);
+            (StatusCode::OK, UserClaim::for_user(user.id).sign()).into_response()
+        }
+        Ok(true) => two_factor_required(user.id, user.username),
+        Err(e) => {
+            println!(
+                "500 ERROR while executing 'two_factor::is_enabled': Sqlite reported error: {e}"
+            );
+            (StatusCode::INTERNAL_SERVER_ERROR, ERROR).into_response()
         }
     }
-}];
+}
+
+/// `202` with a challenge for `login_two_factor`, after the first factor of a user with 2FA.
+pub fn two_factor_required(user_id: i32, username: String) -> Response {
+    let challenge = TwoFactorChallenge {
+        pending_user_id: user_id,
+        username,
+        expires_at: now() + CHALLENGE_TTL_SECS,
+    };
+    let body = json!({ "status": "twoFactorRequired", "challenge": challenge.sign() });
+    (StatusCode::ACCEPTED, unwrap_json(&body)).into_response()
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct TwoFactorLoginRequest {
+    challenge: String,
+    code: String,
+}
+
+/// Second step of login for users with 2FA, `code` may also be a recovery code.
+pub async fn login_two_factor(
+    State(db): State<Db>,
+    ClientIp(ip): ClientIp,
+    Json(req): Json<TwoFactorLoginRequest>,
+) -> Response {
+    let challenge = match TwoFactorChallenge::check(&req.challenge) {
+        Ok(challenge) if challenge.expires_at > now() => challenge,
+        _ => return (StatusCode::UNAUTHORIZED, ERROR).into_response(),
+    };
+
+    let throttle = LoginThrottle::current();
+    if let Err(retry_after) = throttle.check(ip, &challenge.username) {
+        return too_many_requests(retry_after);
+    }
+
+    match db.two_factor.verify(challenge.pending_user_id, &req.code) {
+        Ok(true) => {
+            throttle.succeeded(&challenge.username);
+            let user_id = challenge.pending_user_id;
+            (StatusCode::OK, UserClaim::for_user(user_id).sign()).into_response()
+        }
+        Ok(false) => {
+            throttle.failed(ip, &challenge.username);
+            (StatusCode::UNAUTHORIZED, ERROR).into_response()
+        }
+        Err(e) => {
+            println!("500 ERROR while executing 'two_factor::verify': Sqlite reported error: {e}");
+            (StatusCode::INTERNAL_SERVER_ERROR, ERROR).into_response()
+        }
+    }
+}
 
 pub const ERROR: &str = "{ \"status\": \"error\" }";
@@ -0,0 +1,264 @@
+use axum::{
+    extract::{Path, State},
+    http::StatusCode,
+    response::{IntoResponse, Response},
+    Json,
+};
+use axum_utils::{unwrap_json, Claim};
+use rusqlite::ErrorCode;
+
+use crate::{
+    db::{
+        broker_configs::{ConfigScope, NewBrokerSettings},
+        broker_pools::{BrokerPoolUpdate, NewBrokerPool},
+        Db,
+    },
+    validation::{Policy, ValidationErrors},
+};
+
+use super::{brokers, tokens::AppClaim};
+
+/// Pools of the app with how many of their brokers are active, stopped and on which version.
+pub async fn all(
+    State(db): State<Db>,
+    Path(app_id): Path<i32>,
+    Claim(claim): AppClaim,
+) -> impl IntoResponse {
+    match db.broker_pools.for_app(app_id, claim.user_id) {
+        Ok(pools) => unwrap_json(&pools).into_response(),
+        Err(rusqlite::Error::QueryReturnedNoRows) => StatusCode::NOT_FOUND.into_response(),
+        Err(e) => {
+            println!("handlers::broker_pools::all - {e:?}");
+            StatusCode::INTERNAL_SERVER_ERROR.into_response()
+        }
+    }
+}
+
+pub async fn get(
+    State(db): State<Db>,
+    Claim(claim): AppClaim,
+    Path((app_id, pool_id)): Path<(i32, i32)>,
+) -> impl IntoResponse {
+    match db.broker_pools.get(app_id, claim.user_id, pool_id) {
+        Ok(Some(pool)) => unwrap_json(&pool).into_response(),
+        Ok(None) | Err(rusqlite::Error::QueryReturnedNoRows) => {
+            StatusCode::NOT_FOUND.into_response()
+        }
+        Err(e) => {
+            println!("handlers::broker_pools::get - {e:?}");
+            StatusCode::INTERNAL_SERVER_ERROR.into_response()
+        }
+    }
+}
+
+/// `422` response if the name is not allowed.
+fn invalid_name(name: &str) -> Option<Response> {
+    let mut errors = ValidationErrors::default();
+    Policy::current().check_name(name, &mut errors);
+    (!errors.is_empty())
+        .then(|| (StatusCode::UNPROCESSABLE_ENTITY, unwrap_json(&errors)).into_response())
+}
+
+/// `409` if the app already has a pool with that name.
+fn name_taken() -> Response {
+    let mut errors = ValidationErrors::default();
+    errors.add("name", "taken", "the app already has a pool with this name");
+    (StatusCode::CONFLICT, unwrap_json(&errors)).into_response()
+}
+
+pub async fn create(
+    State(db): State<Db>,
+    Path(app_id): Path<i32>,
+    Claim(claim): AppClaim,
+    Json(body): Json<NewBrokerPool>,
+) -> Response {
+    if let Some(response) = invalid_name(&body.name) {
+        return response;
+    }
+    match db.broker_pools.create(app_id, claim.user_id, body) {
+        Ok(pool) => {
+            db.events.notify();
+            (StatusCode::CREATED, unwrap_json(&pool)).into_response()
+        }
+        Err(rusqlite::Error::QueryReturnedNoRows) => StatusCode::NOT_FOUND.into_response(),
+        Err(rusqlite::Error::SqliteFailure(e, _)) if e.code == ErrorCode::ConstraintViolation => {
+            name_taken()
+        }
+        Err(e) => {
+            println!("handlers::broker_pools::create - {e:?}");
+            StatusCode::INTERNAL_SERVER_ERROR.into_response()
+        }
+    }
+}
+
+pub async fn update(
+    State(db): State<Db>,
+    Claim(claim): AppClaim,
+    Path((app_id, pool_id)): Path<(i32, i32)>,
+    Json(body): Json<BrokerPoolUpdate>,
+) -> Response {
+    if let Some(name) = &body.name {
+        if let Some(response) = invalid_name(name) {
+            return response;
+        }
+    }
+    match db.broker_pools.update(app_id, claim.user_id, pool_id, body) {
+        Ok(Some(pool)) => {
+            db.events.notify();
+            unwrap_json(&pool).into_response()
+        }
+        Ok(None) | Err(rusqlite::Error::QueryReturnedNoRows) => {
+            StatusCode::NOT_FOUND.into_response()
+        }
+        Err(rusqlite::Error::SqliteFailure(e, _)) if e.code == ErrorCode::ConstraintViolation => {
+            name_taken()
+        }
+        Err(e) => {
+            println!("handlers::broker_pools::update - {e:?}");
+            StatusCode::INTERNAL_SERVER_ERROR.into_response()
+        }
+    }
+}
+
+/// Brokers of the pool are kept, they are just not in a pool anymore.
+pub async fn delete(
+    State(db): State<Db>,
+    Claim(claim): AppClaim,
+    Path((app_id, pool_id)): Path<(i32, i32)>,
+) -> impl IntoResponse {
+    match db.broker_pools.delete(app_id, claim.user_id, pool_id) {
+        Ok(0) | Err(rusqlite::Error::QueryReturnedNoRows) => StatusCode::NOT_FOUND,
+        Ok(_) => {
+            db.events.notify();
+            StatusCode::OK
+        }
+        Err(e) => {
+            println!("handlers::broker_pools::delete - {e:?}");
+            StatusCode::INTERNAL_SERVER_ERROR
+        }
+    }
+}
+
+pub async fn brokers(
+    State(db): State<Db>,
+    Claim(claim): AppClaim,
+    Path((app_id, pool_id)): Path<(i32, i32)>,
+) -> impl IntoResponse {
+    match db.broker_pools.brokers(app_id, claim.user_id, pool_id) {
+        Ok(Some(brokers)) => unwrap_json(&brokers).into_response(),
+        Ok(None) | Err(rusqlite::Error::QueryReturnedNoRows) => {
+            StatusCode::NOT_FOUND.into_response()
+        }
+        Err(e) => {
+            println!("handlers::broker_pools::brokers - {e:?}");
+            StatusCode::INTERNAL_SERVER_ERROR.into_response()
+        }
+    }
+}
+
+/// Moves the broker into the pool, out of any pool it was in before.
+pub async fn add_broker(
+    State(db): State<Db>,
+    Claim(claim): AppClaim,
+    Path((app_id, pool_id, broker_id)): Path<(i32, i32, i32)>,
+) -> Response {
+    set_pool(db, claim.user_id, app_id, broker_id, Some(pool_id))
+}
+
+/// `404` if the broker is not in this pool.
+pub async fn remove_broker(
+    State(db): State<Db>,
+    Claim(claim): AppClaim,
+    Path((app_id, pool_id, broker_id)): Path<(i32, i32, i32)>,
+) -> Response {
+    match db.brokers.get(app_id, broker_id) {
+        Ok(Some(broker)) if broker.pool_id == Some(pool_id) => {}
+        Ok(_) => return StatusCode::NOT_FOUND.into_response(),
+        Err(e) => {
+            println!("handlers::broker_pools::remove_broker - {e:?}");
+            return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+        }
+    }
+    set_pool(db, claim.user_id, app_id, broker_id, None)
+}
+
+fn set_pool(db: Db, user_id: i32, app_id: i32, broker_id: i32, pool_id: Option<i32>) -> Response {
+    match db
+        .broker_pools
+        .set_pool(app_id, user_id, broker_id, pool_id)
+    {
+        Ok(Some(broker)) => {
+            db.events.notify();
+            unwrap_json(&broker).into_response()
+        }
+        Ok(None) | Err(rusqlite::Error::QueryReturnedNoRows) => {
+            StatusCode::NOT_FOUND.into_response()
+        }
+        Err(e) => {
+            println!("handlers::broker_pools::set_pool - {e:?}");
+            StatusCode::INTERNAL_SERVER_ERROR.into_response()
+        }
+    }
+}
+
+pub async fn start(
+    State(db): State<Db>,
+    Claim(claim): AppClaim,
+    Path((app_id, pool_id)): Path<(i32, i32)>,
+) -> Response {
+    set_stopped(db, claim.user_id, app_id, pool_id, false)
+}
+
+pub async fn stop(
+    State(db): State<Db>,
+    Claim(claim): AppClaim,
+    Path((app_id, pool_id)): Path<(i32, i32)>,
+) -> Response {
+    set_stopped(db, claim.user_id, app_id, pool_id, true)
+}
+
+/// Responds with the brokers of the pool, connected ones are told like for a single broker.
+fn set_stopped(db: Db, user_id: i32, app_id: i32, pool_id: i32, stopped: bool) -> Response {
+    match db
+        .broker_pools
+        .set_stopped(app_id, user_id, pool_id, stopped)
+    {
+        Ok(Some(brokers)) => {
+            db.events.notify();
+            unwrap_json(&brokers).into_response()
+        }
+        Ok(None) | Err(rusqlite::Error::QueryReturnedNoRows) => {
+            StatusCode::NOT_FOUND.into_response()
+        }
+        Err(e) => {
+            println!("handlers::broker_pools::set_stopped - {e:?}");
+            StatusCode::INTERNAL_SERVER_ERROR.into_response()
+        }
+    }
+}
+
+/// Defaults of the pool, they override the app defaults for brokers in the pool.
+pub async fn config(
+    State(db): State<Db>,
+    Claim(claim): AppClaim,
+    Path((app_id, pool_id)): Path<(i32, i32)>,
+) -> Response {
+    brokers::config(db, claim.user_id, app_id, ConfigScope::Pool(pool_id))
+}
+
+pub async fn config_versions(
+    State(db): State<Db>,
+    Claim(claim): AppClaim,
+    Path((app_id, pool_id)): Path<(i32, i32)>,
+) -> Response {
+    brokers::config_versions(db, claim.user_id, app_id, ConfigScope::Pool(pool_id))
+}
+
+pub async fn save_config(
+    State(db): State<Db>,
+    Claim(claim): AppClaim,
+    Path((app_id, pool_id)): Path<(i32, i32)>,
+    Json(body): Json<NewBrokerSettings>,
+) -> Response {
+    brokers::save_config(db, claim.user_id, app_id, ConfigScope::Pool(pool_id), body)
+}
@@ -1,13 +1,30 @@
+use std::collections::HashSet;
+
 use axum::{
-    extract::{Path, State},
+    extract::{
+        ws::{Message, WebSocket},
+        Path, Query, State, WebSocketUpgrade,
+    },
     http::StatusCode,
-    response::IntoResponse,
+    response::{IntoResponse, Response},
     Json,
 };
 use axum_utils::{unwrap_json, Claim};
 use serde::{Deserialize, Serialize};
+use serde_json::json;
 
-use crate::db::{brokers::NewBroker, Db};
+use crate::{
+    db::{
+        artifacts::upload_token,
+        broker_configs::{ConfigScope, NewBrokerSettings},
+        broker_logs::{LogLevel, LogQuery, NewLogLine},
+        brokers::{check_labels, normalize_labels, BrokerUpdate, Heartbeat, NewBroker},
+        jobs::{JobReport, JobStatus},
+        Db,
+    },
+    validation::{Policy, ValidationErrors},
+    version::Version,
+};
 
 use super::tokens::AppClaim;
 
@@ -21,7 +38,7 @@ pub async fn all(
     match users {
         Ok(users) => unwrap_json(&users).into_response(),
         Err(e) => {
-            println!("handlers::app_users::all - {e:?}");
+            println!("handlers::brokers::all - {e:?}");
             StatusCode::BAD_REQUEST.into_response()
         }
     }
@@ -42,9 +59,12 @@ pub async fn create(
     let result = db.brokers.create(app_id, claim.user_id, body);
 
     match result {
-        Ok(_) => StatusCode::OK,
+        Ok(_) => {
+            db.events.notify();
+            StatusCode::OK
+        }
         Err(e) => {
-            println!("handlers::app_users::create - {e:?}");
+            println!("handlers::brokers::create - {e:?}");
             StatusCode::BAD_REQUEST
         }
     }
@@ -58,7 +78,580 @@ pub async fn delete(
     let result = db.brokers.delete(app_id, claim.user_id, broker_id);
 
     match result {
-        Ok(_) => StatusCode::OK,
+        Ok(_) => {
+            db.events.notify();
+            StatusCode::OK
+        }
         Err(_) => StatusCode::BAD_REQUEST,
     }
 }
+
+pub async fn update(
+    State(db): State<Db>,
+    Claim(claim): AppClaim,
+    Path((app_id, broker_id)): Path<(i32, i32)>,
+    Json(body): Json<BrokerUpdate>,
+) -> impl IntoResponse {
+    if let Some(name) = &body.name {
+        let mut errors = ValidationErrors::default();
+        Policy::current().check_name(name, &mut errors);
+        if !errors.is_empty() {
+            return (StatusCode::UNPROCESSABLE_ENTITY, unwrap_json(&errors)).into_response();
+        }
+    }
+
+    match db.brokers.update(app_id, claim.user_id, broker_id, body) {
+        Ok(Some(broker)) => {
+            db.events.notify();
+            unwrap_json(&broker).into_response()
+        }
+        Ok(None) | Err(rusqlite::Error::QueryReturnedNoRows) => {
+            StatusCode::NOT_FOUND.into_response()
+        }
+        Err(e) => {
+            println!("handlers::brokers::update - {e:?}");
+            StatusCode::INTERNAL_SERVER_ERROR.into_response()
+        }
+    }
+}
+
+pub async fn start(
+    State(db): State<Db>,
+    Claim(claim): AppClaim,
+    Path((app_id, broker_id)): Path<(i32, i32)>,
+) -> impl IntoResponse {
+    set_stopped(db, claim.user_id, app_id, broker_id, false)
+}
+
+pub async fn stop(
+    State(db): State<Db>,
+    Claim(claim): AppClaim,
+    Path((app_id, broker_id)): Path<(i32, i32)>,
+) -> impl IntoResponse {
+    set_stopped(db, claim.user_id, app_id, broker_id, true)
+}
+
+/// A connected broker is told through its websocket, see `session`.
+fn set_stopped(db: Db, user_id: i32, app_id: i32, broker_id: i32, stopped: bool) -> Response {
+    match db.brokers.set_stopped(app_id, user_id, broker_id, stopped) {
+        Ok(Some(broker)) => {
+            db.events.notify();
+            unwrap_json(&broker).into_response()
+        }
+        Ok(None) | Err(rusqlite::Error::QueryReturnedNoRows) => {
+            StatusCode::NOT_FOUND.into_response()
+        }
+        Err(e) => {
+            println!("handlers::brokers::set_stopped - {e:?}");
+            StatusCode::INTERNAL_SERVER_ERROR.into_response()
+        }
+    }
+}
+
+/// Defaults of the app, `null` if none were saved yet. Secrets are masked.
+pub async fn app_config(
+    State(db): State<Db>,
+    Claim(claim): AppClaim,
+    Path(app_id): Path<i32>,
+) -> Response {
+    config(db, claim.user_id, app_id, ConfigScope::App)
+}
+
+/// Overrides of the broker only, the broker gets them merged with the app and pool defaults.
+pub async fn broker_config(
+    State(db): State<Db>,
+    Claim(claim): AppClaim,
+    Path((app_id, broker_id)): Path<(i32, i32)>,
+) -> Response {
+    config(db, claim.user_id, app_id, ConfigScope::Broker(broker_id))
+}
+
+pub(super) fn config(db: Db, user_id: i32, app_id: i32, scope: ConfigScope) -> Response {
+    match db.broker_configs.get(app_id, user_id, scope) {
+        Ok(config) => unwrap_json(&config).into_response(),
+        Err(rusqlite::Error::QueryReturnedNoRows) => StatusCode::NOT_FOUND.into_response(),
+        Err(e) => {
+            println!("handlers::brokers::config - {e:?}");
+            StatusCode::INTERNAL_SERVER_ERROR.into_response()
+        }
+    }
+}
+
+pub async fn app_config_versions(
+    State(db): State<Db>,
+    Claim(claim): AppClaim,
+    Path(app_id): Path<i32>,
+) -> Response {
+    config_versions(db, claim.user_id, app_id, ConfigScope::App)
+}
+
+pub async fn broker_config_versions(
+    State(db): State<Db>,
+    Claim(claim): AppClaim,
+    Path((app_id, broker_id)): Path<(i32, i32)>,
+) -> Response {
+    config_versions(db, claim.user_id, app_id, ConfigScope::Broker(broker_id))
+}
+
+pub(super) fn config_versions(db: Db, user_id: i32, app_id: i32, scope: ConfigScope) -> Response {
+    match db.broker_configs.versions(app_id, user_id, scope) {
+        Ok(versions) => unwrap_json(&versions).into_response(),
+        Err(rusqlite::Error::QueryReturnedNoRows) => StatusCode::NOT_FOUND.into_response(),
+        Err(e) => {
+            println!("handlers::brokers::config_versions - {e:?}");
+            StatusCode::INTERNAL_SERVER_ERROR.into_response()
+        }
+    }
+}
+
+pub async fn save_app_config(
+    State(db): State<Db>,
+    Claim(claim): AppClaim,
+    Path(app_id): Path<i32>,
+    Json(body): Json<NewBrokerSettings>,
+) -> Response {
+    save_config(db, claim.user_id, app_id, ConfigScope::App, body)
+}
+
+pub async fn save_broker_config(
+    State(db): State<Db>,
+    Claim(claim): AppClaim,
+    Path((app_id, broker_id)): Path<(i32, i32)>,
+    Json(body): Json<NewBrokerSettings>,
+) -> Response {
+    save_config(
+        db,
+        claim.user_id,
+        app_id,
+        ConfigScope::Broker(broker_id),
+        body,
+    )
+}
+
+/// Replaces the whole document with a new version, connected brokers get it right away.
+pub(super) fn save_config(
+    db: Db,
+    user_id: i32,
+    app_id: i32,
+    scope: ConfigScope,
+    body: NewBrokerSettings,
+) -> Response {
+    let settings = match body.validate() {
+        Ok(settings) => settings,
+        Err(errors) => {
+            return (StatusCode::UNPROCESSABLE_ENTITY, unwrap_json(&errors)).into_response()
+        }
+    };
+    match db.broker_configs.save(app_id, user_id, scope, settings) {
+        Ok(Some(config)) => {
+            db.events.notify();
+            unwrap_json(&config).into_response()
+        }
+        Ok(None) | Err(rusqlite::Error::QueryReturnedNoRows) => {
+            StatusCode::NOT_FOUND.into_response()
+        }
+        Err(e) => {
+            println!("handlers::brokers::save_config - {e:?}");
+            StatusCode::INTERNAL_SERVER_ERROR.into_response()
+        }
+    }
+}
+
+/// Stored log lines of the broker, newest first, see `LogQuery` for the filters.
+pub async fn logs(
+    State(db): State<Db>,
+    Claim(claim): AppClaim,
+    Path((app_id, broker_id)): Path<(i32, i32)>,
+    Query(query): Query<LogQuery>,
+) -> impl IntoResponse {
+    match db
+        .broker_logs
+        .for_broker(app_id, claim.user_id, broker_id, &query)
+    {
+        Ok(lines) => unwrap_json(&lines).into_response(),
+        Err(rusqlite::Error::QueryReturnedNoRows) => StatusCode::NOT_FOUND.into_response(),
+        Err(e) => {
+            println!("handlers::brokers::logs - {e:?}");
+            StatusCode::INTERNAL_SERVER_ERROR.into_response()
+        }
+    }
+}
+
+#[derive(Deserialize)]
+pub struct TailQuery {
+    level: Option<LogLevel>,
+}
+
+/// New log lines of the broker as they arrive, one websocket message per line.
+pub async fn tail(
+    State(db): State<Db>,
+    Claim(claim): AppClaim,
+    Path((app_id, broker_id)): Path<(i32, i32)>,
+    Query(query): Query<TailQuery>,
+    ws: WebSocketUpgrade,
+) -> Response {
+    if db.apps.has_permission(app_id, claim.user_id).is_err() {
+        return StatusCode::FORBIDDEN.into_response();
+    }
+    match db.brokers.get(app_id, broker_id) {
+        Ok(Some(_)) => {}
+        Ok(None) => return StatusCode::NOT_FOUND.into_response(),
+        Err(e) => {
+            println!("handlers::brokers::tail - {e:?}");
+            return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+        }
+    }
+    let last_id = match db.broker_logs.last_id() {
+        Ok(id) => id,
+        Err(e) => {
+            println!("handlers::brokers::tail - {e:?}");
+            return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+        }
+    };
+
+    let level = query.level.unwrap_or(LogLevel::Debug);
+    ws.on_upgrade(move |socket| {
+        follow_logs(db, claim.user_id, app_id, broker_id, level, last_id, socket)
+    })
+}
+
+/// Sends new lines until the client goes away or loses access to the app.
+async fn follow_logs(
+    db: Db,
+    user_id: i32,
+    app_id: i32,
+    broker_id: i32,
+    level: LogLevel,
+    mut last_id: i64,
+    mut socket: WebSocket,
+) {
+    let mut changes = db.broker_logs.subscribe();
+    loop {
+        if db.apps.has_permission(app_id, user_id).is_err() {
+            break;
+        }
+        let lines = match db.broker_logs.since(broker_id, last_id, level) {
+            Ok(lines) => lines,
+            Err(e) => {
+                println!("handlers::brokers::follow_logs - {e:?}");
+                break;
+            }
+        };
+        for line in lines {
+            last_id = line.id;
+            if socket
+                .send(Message::Text(unwrap_json(&line)))
+                .await
+                .is_err()
+            {
+                return;
+            }
+        }
+
+        tokio::select! {
+            changed = changes.changed() => if changed.is_err() { break },
+            message = socket.recv() => match message {
+                Some(Ok(Message::Close(_))) | Some(Err(_)) | None => return,
+                Some(Ok(_)) => {}
+            }
+        }
+    }
+    let _ = socket.send(Message::Close(None)).await;
+}
+
+/// How many brokers of the app run which version, and the range the app accepts.
+pub async fn versions(
+    State(db): State<Db>,
+    Claim(claim): AppClaim,
+    Path(app_id): Path<i32>,
+) -> impl IntoResponse {
+    match db.brokers.rollout(app_id, claim.user_id) {
+        Ok(rollout) => unwrap_json(&rollout).into_response(),
+        Err(rusqlite::Error::QueryReturnedNoRows) => StatusCode::NOT_FOUND.into_response(),
+        Err(e) => {
+            println!("handlers::brokers::versions - {e:?}");
+            StatusCode::INTERNAL_SERVER_ERROR.into_response()
+        }
+    }
+}
+
+#[derive(Deserialize)]
+pub struct ConnectQuery {
+    /// Semantic version of the broker, the stored one is checked if missing.
+    version: Option<String>,
+    /// Comma separated capabilities, the stored ones are kept if missing.
+    labels: Option<String>,
+}
+
+/**
+    Broker keeps this websocket open while it is running,
+    broker is marked active for the lifetime of the connection.
+    `409` if the app rejects brokers of this version.
+    Declared labels decide which jobs the broker gets, see `jobs::dispatch`.
+*/
+pub async fn connect(
+    State(db): State<Db>,
+    Claim(claim): AppClaim,
+    Path((app_id, broker_id)): Path<(i32, i32)>,
+    Query(query): Query<ConnectQuery>,
+    ws: WebSocketUpgrade,
+) -> Response {
+    if db.apps.has_permission(app_id, claim.user_id).is_err() {
+        return StatusCode::FORBIDDEN.into_response();
+    }
+    let reported = match query.version.as_deref().map(str::parse::<Version>) {
+        Some(Ok(version)) => Some(version),
+        Some(Err(e)) => {
+            let mut errors = ValidationErrors::default();
+            errors.add("version", "invalidVersion", &e.to_string());
+            return (StatusCode::UNPROCESSABLE_ENTITY, unwrap_json(&errors)).into_response();
+        }
+        None => None,
+    };
+    let labels = query
+        .labels
+        .map(|labels| normalize_labels(labels.split(',').map(str::to_string)));
+    if let Some(labels) = &labels {
+        let mut errors = ValidationErrors::default();
+        check_labels("labels", labels, &mut errors);
+        if !errors.is_empty() {
+            return (StatusCode::UNPROCESSABLE_ENTITY, unwrap_json(&errors)).into_response();
+        }
+    }
+
+    let broker = db.brokers.get(app_id, broker_id);
+    let policy = db.brokers.version_policy(app_id);
+    let (broker, policy) = match (broker, policy) {
+        (Ok(Some(broker)), Ok(policy)) => (broker, policy),
+        (Ok(None), _) => return StatusCode::NOT_FOUND.into_response(),
+        (Err(e), _) | (_, Err(e)) => {
+            println!("handlers::brokers::connect - {e:?}");
+            return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+        }
+    };
+    let version = reported.as_ref().map_or(broker.version, Version::to_string);
+    if policy.reject && !policy.allows(&version) {
+        let body = json!({ "version": version, "policy": policy });
+        return (StatusCode::CONFLICT, unwrap_json(&body)).into_response();
+    }
+    if let Some(reported) = reported {
+        if let Err(e) = db.brokers.set_version(app_id, broker_id, &reported) {
+            println!("handlers::brokers::connect - {e:?}");
+            return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+        }
+    }
+    if let Some(labels) = labels {
+        if let Err(e) = db.brokers.set_labels(app_id, broker_id, &labels) {
+            println!("handlers::brokers::connect - {e:?}");
+            return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+        }
+    }
+
+    ws.on_upgrade(move |socket| session(db, app_id, broker_id, socket))
+}
+
+/// What a connected broker sends, unknown messages are ignored.
+#[derive(Deserialize)]
+#[serde(tag = "type", rename_all = "camelCase")]
+enum BrokerMessage {
+    Heartbeat(Heartbeat),
+    Logs { lines: Vec<NewLogLine> },
+    Job(JobReport),
+}
+
+/**
+    Sends `{"type": "stop"}` or `{"type": "start"}` on connect and whenever
+    the broker is stopped or started, `{"type": "config", ...}` with the effective
+    configuration on connect and whenever it changes, see `EffectiveConfig`,
+    and `{"type": "warning", ...}` while its version is outside the range of the app.
+    Jobs assigned to the broker are sent once as `{"type": "job", ...}`, see `Job`,
+    and `{"type": "cancel", "jobId": ...}` when one of them is cancelled.
+    Closes the socket when the broker is deleted, was marked stale, connected again
+    or its version is rejected by the app. Takes `{"type": "heartbeat", ...}` messages, see `Heartbeat`,
+    `{"type": "logs", "lines": [...]}`, see `NewLogLine`, and `{"type": "job", ...}`, see `JobReport`.
+*/
+async fn session(db: Db, app_id: i32, broker_id: i32, mut socket: WebSocket) {
+    // wakes up the connection this one replaces, so it closes
+    let session = match db.brokers.connect(app_id, broker_id) {
+        Ok(Some(session)) => {
+            db.events.notify();
+            session
+        }
+        Ok(None) => return,
+        Err(e) => {
+            println!("handlers::brokers::session - {e:?}");
+            return;
+        }
+    };
+
+    let mut changes = db.events.subscribe();
+    let mut sent_stopped = None;
+    let mut sent_config = None;
+    let mut sent_jobs = HashSet::new();
+    let mut warned = false;
+    loop {
+        let broker = match db.brokers.get(app_id, broker_id) {
+            Ok(Some(broker)) if broker.active && broker.session == session => broker,
+            // stale brokers are already marked inactive, replaced ones belong to the newer connection
+            Ok(Some(_)) => {
+                let _ = socket.send(Message::Close(None)).await;
+                return;
+            }
+            Ok(None) => {
+                let _ = socket.send(Message::Close(None)).await;
+                break;
+            }
+            Err(e) => {
+                println!("handlers::brokers::session - {e:?}");
+                break;
+            }
+        };
+        let policy = match db.brokers.version_policy(app_id) {
+            Ok(policy) => policy,
+            Err(e) => {
+                println!("handlers::brokers::session - {e:?}");
+                break;
+            }
+        };
+        let compatible = policy.allows(&broker.version);
+        if !compatible && policy.reject {
+            let _ = socket.send(Message::Close(None)).await;
+            break;
+        }
+        if !compatible && !warned {
+            if let Err(e) = db
+                .brokers
+                .report_incompatible(app_id, broker_id, &broker.version)
+            {
+                println!("handlers::brokers::session - {e:?}");
+            }
+            db.events.notify();
+            let warning = json!({
+                "type": "warning",
+                "code": "incompatibleVersion",
+                "version": broker.version,
+                "policy": policy,
+            });
+            if socket
+                .send(Message::Text(warning.to_string()))
+                .await
+                .is_err()
+            {
+                break;
+            }
+        }
+        warned = !compatible;
+
+        let stopped = broker.stopped;
+        if sent_stopped != Some(stopped) {
+            let command = json!({ "type": if stopped { "stop" } else { "start" } });
+            if socket
+                .send(Message::Text(command.to_string()))
+                .await
+                .is_err()
+            {
+                break;
+            }
+            sent_stopped = Some(stopped);
+        }
+        let config = match db.broker_configs.effective(app_id, broker_id) {
+            Ok(config) => config,
+            Err(e) => {
+                println!("handlers::brokers::session - {e:?}");
+                break;
+            }
+        };
+        let version = (
+            config.app_version,
+            config.pool_id,
+            config.pool_version,
+            config.broker_version,
+        );
+        if sent_config != Some(version) {
+            let mut message = serde_json::to_value(&config).unwrap();
+            message["type"] = json!("config");
+            if socket
+                .send(Message::Text(message.to_string()))
+                .await
+                .is_err()
+            {
+                break;
+            }
+            sent_config = Some(version);
+        }
+        if let Err(e) = send_jobs(&db, broker_id, &mut sent_jobs, &mut socket).await {
+            println!("handlers::brokers::session - {e:?}");
+            break;
+        }
+
+        tokio::select! {
+            changed = changes.changed() => if changed.is_err() { break },
+            message = socket.recv() => match message {
+                Some(Ok(Message::Close(_))) | Some(Err(_)) | None => break,
+                Some(Ok(Message::Text(text))) => {
+                    let result = match serde_json::from_str(&text) {
+                        Ok(BrokerMessage::Heartbeat(heartbeat)) => {
+                            db.brokers.heartbeat(app_id, broker_id, &heartbeat)
+                        }
+                        Ok(BrokerMessage::Logs { lines }) => db.broker_logs.append(broker_id, &lines),
+                        Ok(BrokerMessage::Job(report)) => {
+                            let result = db.jobs.report(app_id, broker_id, &report);
+                            // a finished job makes room for the next one
+                            if matches!(result, Ok(changed) if changed > 0) {
+                                db.events.notify();
+                            }
+                            result
+                        }
+                        Err(_) => Ok(0),
+                    };
+                    if let Err(e) = result {
+                        println!("handlers::brokers::session - {e:?}");
+                    }
+                }
+                Some(Ok(_)) => {}
+            }
+        }
+    }
+
+    match db.brokers.disconnect(app_id, broker_id, session) {
+        Ok(0) => {}
+        Ok(_) => db.events.notify(),
+        Err(e) => println!("handlers::brokers::session - {e:?}"),
+    }
+}
+
+/**
+    Sends jobs newly assigned to the broker and cancels those of them that
+    were cancelled since. `sent` holds the jobs the broker has and did not finish.
+*/
+async fn send_jobs(
+    db: &Db,
+    broker_id: i32,
+    sent: &mut HashSet<i64>,
+    socket: &mut WebSocket,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let jobs = db.jobs.for_broker(broker_id)?;
+    let current: HashSet<i64> = jobs.iter().map(|job| job.id).collect();
+    for job_id in sent.difference(&current).copied().collect::<Vec<_>>() {
+        sent.remove(&job_id);
+        let cancelled = db
+            .jobs
+            .by_id(job_id)?
+            .is_some_and(|job| job.status == JobStatus::Cancelled);
+        if cancelled {
+            let command = json!({ "type": "cancel", "jobId": job_id });
+            socket.send(Message::Text(command.to_string())).await?;
+        }
+    }
+    for job in jobs {
+        if sent.contains(&job.id) {
+            continue;
+        }
+        let mut message = serde_json::to_value(&job).unwrap();
+        message["type"] = json!("job");
+        message["uploadToken"] = json!(upload_token(job.app_id, broker_id, job.id));
+        socket.send(Message::Text(message.to_string())).await?;
+        sent.insert(job.id);
+    }
+    Ok(())
+}
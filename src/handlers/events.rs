@@ -0,0 +1,173 @@
+use std::convert::Infallible;
+
+use axum::{
+    extract::{
+        ws::{Message, WebSocket},
+        Path, Query, State, WebSocketUpgrade,
+    },
+    http::{HeaderMap, StatusCode},
+    response::{
+        sse::{Event, KeepAlive},
+        IntoResponse, Response, Sse,
+    },
+};
+use axum_utils::{unwrap_json, Claim};
+use serde::Deserialize;
+use tokio::sync::mpsc;
+use tokio_stream::{wrappers::ReceiverStream, StreamExt};
+
+use crate::db::{events::StoredEvent, Db};
+
+use super::tokens::AppClaim;
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct StreamQuery {
+    last_event_id: Option<i64>,
+}
+
+/**
+    Live events of the app.
+    WebSocket if client asks for upgrade, Server-Sent Events otherwise.
+    Stream resumes after `lastEventId` query param or `Last-Event-ID` header.
+*/
+pub async fn stream(
+    State(db): State<Db>,
+    Claim(claim): AppClaim,
+    Path(app_id): Path<i32>,
+    Query(query): Query<StreamQuery>,
+    headers: HeaderMap,
+    ws: Option<WebSocketUpgrade>,
+) -> Response {
+    if db.apps.has_permission(app_id, claim.user_id).is_err() {
+        return StatusCode::FORBIDDEN.into_response();
+    }
+
+    let last_event_id = query.last_event_id.or_else(|| {
+        headers
+            .get("last-event-id")
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.parse().ok())
+    });
+    let last_id = match last_event_id {
+        Some(id) => id,
+        None => match db.events.last_id() {
+            Ok(id) => id,
+            Err(e) => {
+                println!("handlers::events::stream - {e:?}");
+                return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+            }
+        },
+    };
+
+    let (sender, receiver) = mpsc::channel(64);
+    tokio::spawn(follow(db, app_id, claim.user_id, last_id, sender));
+
+    match ws {
+        Some(ws) => ws.on_upgrade(move |socket| forward(socket, receiver)),
+        None => {
+            let events = ReceiverStream::new(receiver).map(|event| {
+                Ok::<_, Infallible>(
+                    Event::default()
+                        .id(event.id.to_string())
+                        .event(event.event.kind())
+                        .data(unwrap_json(&event)),
+                )
+            });
+            Sse::new(events)
+                .keep_alive(KeepAlive::default())
+                .into_response()
+        }
+    }
+}
+
+/// Reads new events of the app until client goes away or loses access to the app.
+async fn follow(
+    db: Db,
+    app_id: i32,
+    user_id: i32,
+    mut last_id: i64,
+    sender: mpsc::Sender<StoredEvent>,
+) {
+    let mut changes = db.events.subscribe();
+
+    loop {
+        if db.apps.has_permission(app_id, user_id).is_err() {
+            return;
+        }
+
+        let events = match db.events.since(app_id, last_id) {
+            Ok(events) => events,
+            Err(e) => {
+                println!("handlers::events::follow - {e:?}");
+                return;
+            }
+        };
+
+        if events.is_empty() {
+            tokio::select! {
+                changed = changes.changed() => if changed.is_err() { return },
+                _ = sender.closed() => return,
+            }
+            continue;
+        }
+
+        for event in events {
+            last_id = event.id;
+            if sender.send(event).await.is_err() {
+                return;
+            }
+        }
+    }
+}
+
+async fn forward(mut socket: WebSocket, mut receiver: mpsc::Receiver<StoredEvent>) {
+    loop {
+        tokio::select! {
+            event = receiver.recv() => {
+                let Some(event) = event else { break };
+                if socket.send(Message::Text(unwrap_json(&event))).await.is_err() {
+                    break;
+                }
+            }
+            message = socket.recv() => match message {
+                Some(Ok(Message::Close(_))) | Some(Err(_)) | None => break,
+                Some(Ok(_)) => {}
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{sync::Arc, time::Duration};
+
+    use tokio::time::timeout;
+
+    use super::*;
+    use crate::db::{events::AppEvent, testing};
+
+    fn record_started(db: &Db, app_id: i32, broker_id: i32) {
+        testing::event(db, app_id, &AppEvent::BrokerStarted { broker_id });
+        db.events.notify();
+    }
+
+    #[tokio::test]
+    async fn follow_sends_new_events_until_access_is_lost() {
+        let db: Db = Arc::new(testing::db());
+        let owner = testing::user(&db, "alice");
+        let app_id = testing::app(&db, owner);
+        record_started(&db, app_id, 1);
+
+        let (sender, mut receiver) = mpsc::channel(8);
+        tokio::spawn(follow(db.clone(), app_id, owner, 1, sender));
+        record_started(&db, app_id, 2);
+        let event = timeout(Duration::from_secs(5), receiver.recv()).await;
+        assert_eq!(event.unwrap().unwrap().id, 2);
+
+        db.apps.delete(app_id).unwrap();
+        record_started(&db, app_id, 3);
+        let ended = timeout(Duration::from_secs(5), receiver.recv()).await;
+        assert!(ended.unwrap().is_none());
+    }
+}
@@ -0,0 +1,106 @@
+use axum::{
+    extract::{Path, Query, State},
+    http::StatusCode,
+    response::{IntoResponse, Response},
+    Json,
+};
+use axum_utils::{unwrap_json, Claim};
+
+use crate::{
+    db::{
+        jobs::{JobQuery, NewJob},
+        Db,
+    },
+    validation::ValidationErrors,
+};
+
+use super::tokens::AppClaim;
+
+/// Jobs of the app, newest first, see `JobQuery` for the filters.
+pub async fn all(
+    State(db): State<Db>,
+    Path(app_id): Path<i32>,
+    Claim(claim): AppClaim,
+    Query(query): Query<JobQuery>,
+) -> impl IntoResponse {
+    match db.jobs.for_app(app_id, claim.user_id, &query) {
+        Ok(jobs) => unwrap_json(&jobs).into_response(),
+        Err(rusqlite::Error::QueryReturnedNoRows) => StatusCode::NOT_FOUND.into_response(),
+        Err(e) => {
+            println!("handlers::jobs::all - {e:?}");
+            StatusCode::INTERNAL_SERVER_ERROR.into_response()
+        }
+    }
+}
+
+/// Queues the job, it is routed to a broker of its pool with all its labels right after.
+pub async fn create(
+    State(db): State<Db>,
+    Path(app_id): Path<i32>,
+    Claim(claim): AppClaim,
+    Json(body): Json<NewJob>,
+) -> impl IntoResponse {
+    let new_job = match body.validate() {
+        Ok(new_job) => new_job,
+        Err(errors) => {
+            return (StatusCode::UNPROCESSABLE_ENTITY, unwrap_json(&errors)).into_response()
+        }
+    };
+    match db.jobs.create(app_id, claim.user_id, new_job) {
+        Ok(Some(job)) => {
+            db.events.notify();
+            (StatusCode::CREATED, unwrap_json(&job)).into_response()
+        }
+        Ok(None) => unknown_pool(),
+        Err(rusqlite::Error::QueryReturnedNoRows) => StatusCode::NOT_FOUND.into_response(),
+        Err(e) => {
+            println!("handlers::jobs::create - {e:?}");
+            StatusCode::INTERNAL_SERVER_ERROR.into_response()
+        }
+    }
+}
+
+/// `422` for a `poolId` that is not a pool of the app.
+pub(super) fn unknown_pool() -> Response {
+    let mut errors = ValidationErrors::default();
+    errors.add("poolId", "notFound", "the app has no pool with this id");
+    (StatusCode::UNPROCESSABLE_ENTITY, unwrap_json(&errors)).into_response()
+}
+
+pub async fn get(
+    State(db): State<Db>,
+    Claim(claim): AppClaim,
+    Path((app_id, job_id)): Path<(i32, i64)>,
+) -> impl IntoResponse {
+    match db.jobs.get(app_id, claim.user_id, job_id) {
+        Ok(Some(job)) => unwrap_json(&job).into_response(),
+        Ok(None) | Err(rusqlite::Error::QueryReturnedNoRows) => {
+            StatusCode::NOT_FOUND.into_response()
+        }
+        Err(e) => {
+            println!("handlers::jobs::get - {e:?}");
+            StatusCode::INTERNAL_SERVER_ERROR.into_response()
+        }
+    }
+}
+
+/// Finished jobs are returned unchanged.
+pub async fn cancel(
+    State(db): State<Db>,
+    Claim(claim): AppClaim,
+    Path((app_id, job_id)): Path<(i32, i64)>,
+) -> impl IntoResponse {
+    match db.jobs.cancel(app_id, claim.user_id, job_id) {
+        Ok(Some(job)) => {
+            db.events.notify();
+            unwrap_json(&job).into_response()
+        }
+        Ok(None) | Err(rusqlite::Error::QueryReturnedNoRows) => {
+            StatusCode::NOT_FOUND.into_response()
+        }
+        Err(e) => {
+            println!("handlers::jobs::cancel - {e:?}");
+            StatusCode::INTERNAL_SERVER_ERROR.into_response()
+        }
+    }
+}
@@ -0,0 +1,204 @@
+use axum::{extract::State, http::StatusCode, response::IntoResponse, Json};
+use axum_utils::{unwrap_json, Claim};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+
+use crate::{
+    db::{
+        users::{DeleteAccountError, OwnedApps, ProfileUpdate, RegistrationError},
+        Db, SqlResult,
+    },
+    validation::ValidationErrors,
+};
+
+use super::tokens::AppClaim;
+
+pub async fn get(State(db): State<Db>, Claim(claim): AppClaim) -> impl IntoResponse {
+    match db.users.profile(claim.user_id) {
+        Ok(profile) => unwrap_json(&profile).into_response(),
+        Err(e) => {
+            println!("handlers::me::get - {e:?}");
+            StatusCode::NOT_FOUND.into_response()
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct UpdateRequest {
+    #[serde(flatten)]
+    changes: ProfileUpdate,
+    /// Needed for changing the email, it receives password reset tokens.
+    #[serde(default)]
+    current_password: Option<String>,
+}
+
+pub async fn update(
+    State(db): State<Db>,
+    Claim(claim): AppClaim,
+    Json(body): Json<UpdateRequest>,
+) -> impl IntoResponse {
+    let changes = match body.changes.validate() {
+        Ok(changes) => changes,
+        Err(errors) => {
+            return (StatusCode::UNPROCESSABLE_ENTITY, unwrap_json(&errors)).into_response()
+        }
+    };
+    if changes.email.is_some() {
+        let password = body.current_password.unwrap_or_default();
+        let verified = tokio::task::spawn_blocking({
+            let db = db.clone();
+            move || db.users.verify_password(claim.user_id, &password)
+        })
+        .await
+        .unwrap();
+        match verified {
+            Ok(true) => {}
+            Ok(false) => return StatusCode::FORBIDDEN.into_response(),
+            Err(e) => {
+                println!("handlers::me::update - {e:?}");
+                return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+            }
+        }
+    }
+
+    match db.users.update_profile(claim.user_id, &changes) {
+        Ok(profile) => unwrap_json(&profile).into_response(),
+        Err(RegistrationError::UserAlreadyExists) => {
+            let mut errors = ValidationErrors::default();
+            errors.add("username", "taken", "username is already taken");
+            (StatusCode::CONFLICT, unwrap_json(&errors)).into_response()
+        }
+        Err(RegistrationError::SqliteError(e)) => {
+            println!("handlers::me::update - {e:?}");
+            StatusCode::INTERNAL_SERVER_ERROR.into_response()
+        }
+    }
+}
+
+/// Apps the user owns, operates or is an app user of.
+pub async fn apps(State(db): State<Db>, Claim(claim): AppClaim) -> impl IntoResponse {
+    match db.apps.for_user(claim.user_id) {
+        Ok(apps) => unwrap_json(&apps).into_response(),
+        Err(e) => {
+            println!("handlers::me::apps - {e:?}");
+            StatusCode::INTERNAL_SERVER_ERROR.into_response()
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy)]
+#[serde(rename_all = "camelCase")]
+pub enum OwnedAppsChoice {
+    Delete,
+    Transfer,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct DeleteRequest {
+    password: String,
+    /// Current code or recovery code, only for users with 2FA.
+    #[serde(default)]
+    code: Option<String>,
+    #[serde(default)]
+    owned_apps: Option<OwnedAppsChoice>,
+    /// Username of the new author with `ownedApps: "transfer"`.
+    #[serde(default)]
+    transfer_to: Option<String>,
+}
+
+/**
+    Deletes the account of the caller, all its sessions end.
+    Owned apps must be dealt with explicitly, without `ownedApps`
+    the response is `409` with the ids of the apps that are in the way.
+*/
+pub async fn delete(
+    State(db): State<Db>,
+    Claim(claim): AppClaim,
+    Json(body): Json<DeleteRequest>,
+) -> impl IntoResponse {
+    let confirmed = tokio::task::spawn_blocking({
+        let db = db.clone();
+        let (password, code) = (body.password.clone(), body.code.clone());
+        move || confirm_identity(&db, claim.user_id, &password, code.as_deref())
+    })
+    .await
+    .unwrap();
+    match confirmed {
+        Ok(true) => {}
+        Ok(false) => return StatusCode::FORBIDDEN.into_response(),
+        Err(e) => {
+            println!("handlers::me::delete - {e:?}");
+            return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+        }
+    }
+
+    let owned_apps = match (body.owned_apps, &body.transfer_to) {
+        (None, _) => OwnedApps::Keep,
+        (Some(OwnedAppsChoice::Delete), _) => OwnedApps::Delete,
+        (Some(OwnedAppsChoice::Transfer), Some(username)) => {
+            match db.users.find_user_by_name(username) {
+                Ok(user) if user.id != claim.user_id => OwnedApps::TransferTo(user.id),
+                _ => {
+                    let mut errors = ValidationErrors::default();
+                    errors.add(
+                        "transferTo",
+                        "unknown",
+                        "transferTo must be another existing user",
+                    );
+                    return (StatusCode::UNPROCESSABLE_ENTITY, unwrap_json(&errors))
+                        .into_response();
+                }
+            }
+        }
+        (Some(OwnedAppsChoice::Transfer), None) => {
+            let mut errors = ValidationErrors::default();
+            errors.add(
+                "transferTo",
+                "required",
+                "transferTo is needed for transferring apps",
+            );
+            return (StatusCode::UNPROCESSABLE_ENTITY, unwrap_json(&errors)).into_response();
+        }
+    };
+
+    match db.users.delete_account(claim.user_id, owned_apps) {
+        Ok(owned) if owned.is_empty() => {
+            db.events.notify();
+            StatusCode::NO_CONTENT.into_response()
+        }
+        Ok(owned) => (
+            StatusCode::CONFLICT,
+            unwrap_json(&json!({ "ownedApps": owned })),
+        )
+            .into_response(),
+        Err(DeleteAccountError::InvalidNewAuthor) => {
+            let mut errors = ValidationErrors::default();
+            errors.add(
+                "transferTo",
+                "unknown",
+                "transferTo must be another existing user",
+            );
+            (StatusCode::UNPROCESSABLE_ENTITY, unwrap_json(&errors)).into_response()
+        }
+        Err(DeleteAccountError::SqliteError(e)) => {
+            println!("handlers::me::delete - {e:?}");
+            StatusCode::INTERNAL_SERVER_ERROR.into_response()
+        }
+    }
+}
+
+/// Password, and for users with 2FA also a code.
+fn confirm_identity(db: &Db, user_id: i32, password: &str, code: Option<&str>) -> SqlResult<bool> {
+    if !db.users.verify_password(user_id, password)? {
+        return Ok(false);
+    }
+    if !db.two_factor.is_enabled(user_id)? {
+        return Ok(true);
+    }
+    match code {
+        Some(code) => db.two_factor.verify(user_id, code),
+        None => Ok(false),
+    }
+}
@@ -1,7 +1,22 @@
+pub mod access_tokens;
+pub mod admin;
 pub mod app_users;
 pub mod apps;
+pub mod artifacts;
+pub mod audit;
 pub mod auth;
+pub mod broker_pools;
 pub mod brokers;
+pub mod events;
+pub mod jobs;
+pub mod me;
+pub mod oidc;
 pub mod operators;
+pub mod passwords;
+pub mod schedules;
+pub mod share_links;
 pub mod tokens;
+pub mod trash;
+pub mod two_factor;
 pub mod users;
+pub mod webhooks;
@@ -0,0 +1,214 @@
+use axum::{
+    extract::{Path, Query, State},
+    http::StatusCode,
+    response::{IntoResponse, Redirect},
+};
+use axum_utils::{unwrap_json, Claim, VerifiebleClaim};
+use serde::Deserialize;
+use serde_json::json;
+
+use crate::{
+    crypto::random_token,
+    db::{
+        events::now,
+        identities::IdentityOwner,
+        users::{NewUser, RegistrationError},
+        Db,
+    },
+    oidc::{authorization_url, complete, Identity, OidcConfig, PENDING_TTL},
+    validation::Policy,
+};
+
+use super::auth::two_factor_required;
+use super::tokens::{AppClaim, OidcState, UserClaim};
+
+/// Signed `state` for a new login, see `OidcState`.
+fn new_state(link_user_id: Option<i32>) -> String {
+    let state = OidcState {
+        login_id: random_token(16),
+        link_user_id,
+        expires_at: now() + PENDING_TTL.as_secs(),
+    };
+    state.sign()
+}
+
+/// Sends the browser to the provider, `404` if single sign-on is not configured.
+pub async fn login() -> impl IntoResponse {
+    let Some(config) = OidcConfig::current() else {
+        return StatusCode::NOT_FOUND.into_response();
+    };
+    match authorization_url(config, &new_state(None)).await {
+        Ok(url) => Redirect::to(&url).into_response(),
+        Err(e) => {
+            println!("handlers::oidc::login - {e}");
+            StatusCode::BAD_GATEWAY.into_response()
+        }
+    }
+}
+
+#[derive(Deserialize)]
+pub struct CallbackQuery {
+    code: Option<String>,
+    state: Option<String>,
+    /// Set by the provider instead of `code` when the login failed there.
+    error: Option<String>,
+}
+
+/**
+    Where the provider redirects back to. Answers like `/login`: a session token,
+    or `202` with a 2FA challenge for users who enabled it.
+    `204` if the login was started to link the identity, the user comes from the signed `state`.
+*/
+pub async fn callback(
+    State(db): State<Db>,
+    Query(query): Query<CallbackQuery>,
+) -> impl IntoResponse {
+    let Some(config) = OidcConfig::current() else {
+        return StatusCode::NOT_FOUND.into_response();
+    };
+    let (Some(code), Some(state)) = (query.code, query.state) else {
+        println!(
+            "handlers::oidc::callback - provider error {:?}",
+            query.error
+        );
+        return StatusCode::UNAUTHORIZED.into_response();
+    };
+    let link_user_id = match OidcState::check(&state) {
+        Ok(signed) if signed.expires_at > now() => signed.link_user_id,
+        _ => return StatusCode::UNAUTHORIZED.into_response(),
+    };
+    let identity = match complete(config, &code, &state).await {
+        Ok(identity) => identity,
+        Err(e) => {
+            println!("handlers::oidc::callback - {e}");
+            return StatusCode::UNAUTHORIZED.into_response();
+        }
+    };
+
+    if let Some(user_id) = link_user_id {
+        let email = identity.email.as_deref();
+        return match db
+            .identities
+            .link(user_id, &identity.issuer, &identity.subject, email)
+        {
+            Ok(0) => StatusCode::CONFLICT.into_response(),
+            Ok(_) => StatusCode::NO_CONTENT.into_response(),
+            Err(e) => {
+                println!("handlers::oidc::callback - {e:?}");
+                StatusCode::INTERNAL_SERVER_ERROR.into_response()
+            }
+        };
+    }
+
+    let user_id = match db.identities.user_for(&identity.issuer, &identity.subject) {
+        Ok(Some(IdentityOwner::User(user_id))) => Ok(user_id),
+        Ok(Some(IdentityOwner::Deleted)) => return StatusCode::FORBIDDEN.into_response(),
+        Ok(None) if config.provision_users => {
+            let (db, identity) = (db.clone(), identity.clone());
+            tokio::task::spawn_blocking(move || provision(&db, &identity))
+                .await
+                .unwrap()
+        }
+        // existing users have to link the identity first
+        Ok(None) => return StatusCode::FORBIDDEN.into_response(),
+        Err(e) => Err(e.to_string()),
+    };
+    let admin = config.is_admin(&identity.groups);
+    let user_id = user_id.and_then(|user_id| {
+        db.identities
+            .logged_in(user_id, &identity.issuer, &identity.subject, admin)
+            .map(|_| user_id)
+            .map_err(|e| e.to_string())
+    });
+
+    let user_id = match user_id {
+        Ok(user_id) => user_id,
+        Err(e) => {
+            println!("handlers::oidc::callback - {e}");
+            return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+        }
+    };
+    // the provider's login counts as the password, 2FA of the account still applies
+    let user = match db.two_factor.is_enabled(user_id) {
+        Ok(false) => return UserClaim::for_user(user_id).sign().into_response(),
+        Ok(true) => db.users.by_id(user_id),
+        Err(e) => Err(e),
+    };
+    match user {
+        Ok(user) => two_factor_required(user.id, user.username),
+        Err(e) => {
+            println!("handlers::oidc::callback - {e:?}");
+            StatusCode::INTERNAL_SERVER_ERROR.into_response()
+        }
+    }
+}
+
+/// Creates a local user for a new identity, taking the first free username.
+fn provision(db: &Db, identity: &Identity) -> Result<i32, String> {
+    let policy = Policy::current();
+    for username in identity.usernames() {
+        let name: String = identity
+            .name
+            .as_deref()
+            .map(str::trim)
+            .filter(|name| !name.is_empty())
+            .unwrap_or(&username)
+            .chars()
+            .take(policy.name_max_length)
+            .collect();
+        // an address the policy rejects is dropped rather than failing the login
+        let user = NewUser::external(name.clone(), username.clone(), identity.email.clone())
+            .or_else(|_| NewUser::external(name, username, None))
+            .map_err(|errors| errors.to_string())?;
+
+        let email = identity.email.as_deref();
+        match db
+            .identities
+            .provision(&user, &identity.issuer, &identity.subject, email)
+        {
+            Ok(user_id) => return Ok(user_id),
+            Err(RegistrationError::UserAlreadyExists) => continue,
+            Err(RegistrationError::SqliteError(e)) => return Err(e.to_string()),
+        }
+    }
+    Err(format!("no free username for {}", identity.subject))
+}
+
+/// Starts linking a provider identity to the caller, the browser has to open the url.
+pub async fn link(Claim(claim): AppClaim) -> impl IntoResponse {
+    let Some(config) = OidcConfig::current() else {
+        return StatusCode::NOT_FOUND.into_response();
+    };
+    match authorization_url(config, &new_state(Some(claim.user_id))).await {
+        Ok(url) => unwrap_json(&json!({ "authorizationUrl": url })).into_response(),
+        Err(e) => {
+            println!("handlers::oidc::link - {e}");
+            StatusCode::BAD_GATEWAY.into_response()
+        }
+    }
+}
+
+pub async fn identities(State(db): State<Db>, Claim(claim): AppClaim) -> impl IntoResponse {
+    match db.identities.for_user(claim.user_id) {
+        Ok(identities) => unwrap_json(&identities).into_response(),
+        Err(e) => {
+            println!("handlers::oidc::identities - {e:?}");
+            StatusCode::INTERNAL_SERVER_ERROR.into_response()
+        }
+    }
+}
+
+pub async fn unlink(
+    State(db): State<Db>,
+    Claim(claim): AppClaim,
+    Path(identity_id): Path<i32>,
+) -> impl IntoResponse {
+    match db.identities.unlink(claim.user_id, identity_id) {
+        Ok(0) => StatusCode::NOT_FOUND,
+        Ok(_) => StatusCode::NO_CONTENT,
+        Err(e) => {
+            println!("handlers::oidc::unlink - {e:?}");
+            StatusCode::INTERNAL_SERVER_ERROR
+        }
+    }
+}
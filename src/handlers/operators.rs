@@ -42,7 +42,10 @@ pub async fn create(
     let sql = db.operators.create(app_id, claim.user_id, body.operator_id);
     println!("handle create");
     match sql {
-        Ok(operator) => unwrap_json(&operator).into_response(),
+        Ok(operator) => {
+            db.events.notify();
+            unwrap_json(&operator).into_response()
+        }
         Err(e) => {
             println!("error while creating operator: {e:?}");
             StatusCode::NOT_FOUND.into_response()
@@ -58,7 +61,10 @@ pub async fn delete(
     let sql = db.operators.delete(app_id, claim.user_id, operator_id);
 
     match sql {
-        Ok(_) => StatusCode::OK.into_response(),
+        Ok(_) => {
+            db.events.notify();
+            StatusCode::OK.into_response()
+        }
         Err(_) => StatusCode::NOT_FOUND.into_response(),
     }
 }
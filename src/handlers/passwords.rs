@@ -0,0 +1,198 @@
+use axum::{
+    extract::{Path, State},
+    http::StatusCode,
+    response::IntoResponse,
+    Json,
+};
+use axum_utils::{unwrap_json, Claim, VerifiebleClaim};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    crypto::random_token,
+    db::{password_resets::RESET_TTL_SECS, users::User, Db},
+    notify::{notifier, Message},
+    validation::{Policy, ValidationErrors},
+};
+
+use super::{
+    admin::require_admin,
+    tokens::{AppClaim, UserClaim},
+};
+
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ChangePasswordRequest {
+    current_password: String,
+    new_password: String,
+}
+
+/// Other sessions are revoked, response contains a new token for this one.
+pub async fn change(
+    State(db): State<Db>,
+    Claim(claim): AppClaim,
+    Json(body): Json<ChangePasswordRequest>,
+) -> impl IntoResponse {
+    let user = match db.users.by_id(claim.user_id) {
+        Ok(user) => user,
+        Err(e) => {
+            println!("handlers::passwords::change - {e:?}");
+            return StatusCode::NOT_FOUND.into_response();
+        }
+    };
+    let verified = tokio::task::spawn_blocking({
+        let db = db.clone();
+        let password = body.current_password;
+        move || db.users.verify_password(claim.user_id, &password)
+    })
+    .await
+    .unwrap();
+    match verified {
+        Ok(true) => {}
+        Ok(false) => return StatusCode::FORBIDDEN.into_response(),
+        Err(e) => {
+            println!("handlers::passwords::change - {e:?}");
+            return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+        }
+    }
+    if let Err(errors) = check_password(&body.new_password, &user) {
+        return (StatusCode::UNPROCESSABLE_ENTITY, unwrap_json(&errors)).into_response();
+    }
+
+    let user_id = user.id;
+    let changed =
+        tokio::task::spawn_blocking(move || db.users.change_password(user_id, &body.new_password))
+            .await
+            .unwrap();
+    match changed {
+        Ok(epoch) => {
+            let claim = UserClaim { user_id, epoch };
+            claim.sign().into_response()
+        }
+        Err(e) => {
+            println!("handlers::passwords::change - {e:?}");
+            StatusCode::INTERNAL_SERVER_ERROR.into_response()
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct ResetRequest {
+    username: String,
+}
+
+/**
+    Sends a reset token to the email of the user.
+    Always answers `202`, so it can't be used to find out which usernames exist.
+*/
+pub async fn request_reset(
+    State(db): State<Db>,
+    Json(body): Json<ResetRequest>,
+) -> impl IntoResponse {
+    // delivery happens in the background, so response time doesn't tell either
+    tokio::spawn(async move {
+        let Ok(user) = db.users.find_user_by_name(&body.username) else {
+            return;
+        };
+        if let Err(e) = send_reset(&db, &user, None).await {
+            println!("handlers::passwords::request_reset - {e}");
+        }
+    });
+    StatusCode::ACCEPTED
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ConfirmResetRequest {
+    token: String,
+    new_password: String,
+}
+
+/// Sets the new password, all sessions of the user are revoked.
+pub async fn confirm_reset(
+    State(db): State<Db>,
+    Json(body): Json<ConfirmResetRequest>,
+) -> impl IntoResponse {
+    let user = match db.password_resets.user_for(&body.token) {
+        Ok(Some(user_id)) => db.users.by_id(user_id),
+        Ok(None) => return StatusCode::GONE.into_response(),
+        Err(e) => Err(e),
+    };
+    let user = match user {
+        Ok(user) => user,
+        Err(e) => {
+            println!("handlers::passwords::confirm_reset - {e:?}");
+            return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+        }
+    };
+    if let Err(errors) = check_password(&body.new_password, &user) {
+        return (StatusCode::UNPROCESSABLE_ENTITY, unwrap_json(&errors)).into_response();
+    }
+
+    let completed = tokio::task::spawn_blocking(move || {
+        db.password_resets.complete(&body.token, &body.new_password)
+    })
+    .await
+    .unwrap();
+    match completed {
+        Ok(Some(_)) => StatusCode::NO_CONTENT.into_response(),
+        Ok(None) => StatusCode::GONE.into_response(),
+        Err(e) => {
+            println!("handlers::passwords::confirm_reset - {e:?}");
+            StatusCode::INTERNAL_SERVER_ERROR.into_response()
+        }
+    }
+}
+
+/// Reset started by an admin, token goes to the user, not to the admin.
+pub async fn admin_reset(
+    State(db): State<Db>,
+    Claim(claim): AppClaim,
+    Path(username): Path<String>,
+) -> impl IntoResponse {
+    if let Err(status) = require_admin(&db, claim.user_id) {
+        return status.into_response();
+    }
+    let Ok(user) = db.users.find_user_by_name(&username) else {
+        return StatusCode::NOT_FOUND.into_response();
+    };
+    if user.email.is_none() {
+        return StatusCode::CONFLICT.into_response();
+    }
+
+    match send_reset(&db, &user, Some(claim.user_id)).await {
+        Ok(()) => StatusCode::ACCEPTED.into_response(),
+        Err(e) => {
+            println!("handlers::passwords::admin_reset - {e}");
+            StatusCode::BAD_GATEWAY.into_response()
+        }
+    }
+}
+
+fn check_password(password: &str, user: &User) -> Result<(), ValidationErrors> {
+    let mut errors = ValidationErrors::default();
+    Policy::current().check_password(password, &user.username, &mut errors);
+    errors.into_result(())
+}
+
+async fn send_reset(db: &Db, user: &User, created_by: Option<i32>) -> Result<(), String> {
+    let Some(email) = &user.email else {
+        return Err(format!("user {} has no email", user.id));
+    };
+
+    let token = random_token(32);
+    db.password_resets
+        .create(user.id, &token, created_by)
+        .map_err(|e| e.to_string())?;
+
+    let message = Message {
+        to: email.clone(),
+        subject: "Password reset".to_string(),
+        body: format!(
+            "Hello {},\n\nuse this token to set a new password for {}:\n\n{token}\n\nIt expires in {} minutes. If you did not ask for it, ignore this message.",
+            user.name,
+            user.username,
+            RESET_TTL_SECS / 60
+        ),
+    };
+    notifier().send(&message).await
+}
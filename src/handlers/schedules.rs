@@ -0,0 +1,184 @@
+use axum::{
+    extract::{Path, Query, State},
+    http::StatusCode,
+    response::{IntoResponse, Response},
+    Json,
+};
+use axum_utils::{unwrap_json, Claim};
+
+use crate::db::{
+    schedules::{NewSchedule, RunQuery, ScheduleError},
+    Db,
+};
+
+use super::{jobs::unknown_pool, tokens::AppClaim};
+
+pub async fn all(
+    State(db): State<Db>,
+    Path(app_id): Path<i32>,
+    Claim(claim): AppClaim,
+) -> impl IntoResponse {
+    match db.schedules.for_app(app_id, claim.user_id) {
+        Ok(schedules) => unwrap_json(&schedules).into_response(),
+        Err(rusqlite::Error::QueryReturnedNoRows) => StatusCode::NOT_FOUND.into_response(),
+        Err(e) => {
+            println!("handlers::schedules::all - {e:?}");
+            StatusCode::INTERNAL_SERVER_ERROR.into_response()
+        }
+    }
+}
+
+pub async fn get(
+    State(db): State<Db>,
+    Claim(claim): AppClaim,
+    Path((app_id, schedule_id)): Path<(i32, i32)>,
+) -> impl IntoResponse {
+    match db.schedules.get(app_id, claim.user_id, schedule_id) {
+        Ok(Some(schedule)) => unwrap_json(&schedule).into_response(),
+        Ok(None) | Err(rusqlite::Error::QueryReturnedNoRows) => {
+            StatusCode::NOT_FOUND.into_response()
+        }
+        Err(e) => {
+            println!("handlers::schedules::get - {e:?}");
+            StatusCode::INTERNAL_SERVER_ERROR.into_response()
+        }
+    }
+}
+
+/// First run is the next time the expression matches.
+pub async fn create(
+    State(db): State<Db>,
+    Path(app_id): Path<i32>,
+    Claim(claim): AppClaim,
+    Json(body): Json<NewSchedule>,
+) -> Response {
+    let new_schedule = match body.validate() {
+        Ok(new_schedule) => new_schedule,
+        Err(errors) => {
+            return (StatusCode::UNPROCESSABLE_ENTITY, unwrap_json(&errors)).into_response()
+        }
+    };
+    match db.schedules.create(app_id, claim.user_id, new_schedule) {
+        Ok(schedule) => {
+            db.events.notify();
+            (StatusCode::CREATED, unwrap_json(&schedule)).into_response()
+        }
+        Err(ScheduleError::UnknownPool) => unknown_pool(),
+        Err(ScheduleError::SqliteError(rusqlite::Error::QueryReturnedNoRows)) => {
+            StatusCode::NOT_FOUND.into_response()
+        }
+        Err(ScheduleError::SqliteError(e)) => {
+            println!("handlers::schedules::create - {e:?}");
+            StatusCode::INTERNAL_SERVER_ERROR.into_response()
+        }
+    }
+}
+
+/// Replaces the schedule, a paused one stays paused.
+pub async fn update(
+    State(db): State<Db>,
+    Claim(claim): AppClaim,
+    Path((app_id, schedule_id)): Path<(i32, i32)>,
+    Json(body): Json<NewSchedule>,
+) -> Response {
+    let changes = match body.validate() {
+        Ok(changes) => changes,
+        Err(errors) => {
+            return (StatusCode::UNPROCESSABLE_ENTITY, unwrap_json(&errors)).into_response()
+        }
+    };
+    match db
+        .schedules
+        .update(app_id, claim.user_id, schedule_id, changes)
+    {
+        Ok(Some(schedule)) => {
+            db.events.notify();
+            unwrap_json(&schedule).into_response()
+        }
+        Err(ScheduleError::UnknownPool) => unknown_pool(),
+        Ok(None) | Err(ScheduleError::SqliteError(rusqlite::Error::QueryReturnedNoRows)) => {
+            StatusCode::NOT_FOUND.into_response()
+        }
+        Err(ScheduleError::SqliteError(e)) => {
+            println!("handlers::schedules::update - {e:?}");
+            StatusCode::INTERNAL_SERVER_ERROR.into_response()
+        }
+    }
+}
+
+/// Jobs the schedule queued are kept.
+pub async fn delete(
+    State(db): State<Db>,
+    Claim(claim): AppClaim,
+    Path((app_id, schedule_id)): Path<(i32, i32)>,
+) -> impl IntoResponse {
+    match db.schedules.delete(app_id, claim.user_id, schedule_id) {
+        Ok(0) | Err(rusqlite::Error::QueryReturnedNoRows) => StatusCode::NOT_FOUND,
+        Ok(_) => {
+            db.events.notify();
+            StatusCode::OK
+        }
+        Err(e) => {
+            println!("handlers::schedules::delete - {e:?}");
+            StatusCode::INTERNAL_SERVER_ERROR
+        }
+    }
+}
+
+pub async fn pause(
+    State(db): State<Db>,
+    Claim(claim): AppClaim,
+    Path((app_id, schedule_id)): Path<(i32, i32)>,
+) -> Response {
+    set_paused(db, claim.user_id, app_id, schedule_id, true)
+}
+
+/// Runs due while the schedule was paused are not caught up.
+pub async fn resume(
+    State(db): State<Db>,
+    Claim(claim): AppClaim,
+    Path((app_id, schedule_id)): Path<(i32, i32)>,
+) -> Response {
+    set_paused(db, claim.user_id, app_id, schedule_id, false)
+}
+
+fn set_paused(db: Db, user_id: i32, app_id: i32, schedule_id: i32, paused: bool) -> Response {
+    match db
+        .schedules
+        .set_paused(app_id, user_id, schedule_id, paused)
+    {
+        Ok(Some(schedule)) => {
+            db.events.notify();
+            unwrap_json(&schedule).into_response()
+        }
+        Ok(None) | Err(rusqlite::Error::QueryReturnedNoRows) => {
+            StatusCode::NOT_FOUND.into_response()
+        }
+        Err(e) => {
+            println!("handlers::schedules::set_paused - {e:?}");
+            StatusCode::INTERNAL_SERVER_ERROR.into_response()
+        }
+    }
+}
+
+/// Runs of the schedule, newest first, see `RunQuery` for paging.
+pub async fn runs(
+    State(db): State<Db>,
+    Claim(claim): AppClaim,
+    Path((app_id, schedule_id)): Path<(i32, i32)>,
+    Query(query): Query<RunQuery>,
+) -> impl IntoResponse {
+    match db
+        .schedules
+        .runs(app_id, claim.user_id, schedule_id, &query)
+    {
+        Ok(Some(runs)) => unwrap_json(&runs).into_response(),
+        Ok(None) | Err(rusqlite::Error::QueryReturnedNoRows) => {
+            StatusCode::NOT_FOUND.into_response()
+        }
+        Err(e) => {
+            println!("handlers::schedules::runs - {e:?}");
+            StatusCode::INTERNAL_SERVER_ERROR.into_response()
+        }
+    }
+}
@@ -0,0 +1,201 @@
+use axum::{
+    extract::{Path, State},
+    http::{header, HeaderMap, StatusCode},
+    response::{IntoResponse, Response},
+    Json,
+};
+use axum_utils::{unwrap_json, Claim};
+use data_encoding::BASE64;
+use serde::Serialize;
+
+use crate::{
+    db::{
+        events::now,
+        share_links::{
+            verify_token, NewShareLink, ShareAccess, ShareLink, ShareLinkError, MAX_LIFETIME_SECS,
+        },
+        Db,
+    },
+    validation::ValidationErrors,
+};
+
+use super::{artifacts::content, tokens::AppClaim};
+
+/// bcrypt ignores everything after 72 bytes.
+const MAX_PASSWORD_BYTES: usize = 72;
+
+/// Only response that contains the token.
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct CreatedShareLink {
+    #[serde(flatten)]
+    pub link: ShareLink,
+    pub token: String,
+    /// Where the artifact can be downloaded without an account.
+    pub path: String,
+}
+
+pub async fn all(
+    State(db): State<Db>,
+    Claim(claim): AppClaim,
+    Path((app_id, artifact_id)): Path<(i32, i64)>,
+) -> impl IntoResponse {
+    match db
+        .share_links
+        .for_artifact(app_id, claim.user_id, artifact_id)
+    {
+        Ok(Some(links)) => unwrap_json(&links).into_response(),
+        Ok(None) | Err(rusqlite::Error::QueryReturnedNoRows) => {
+            StatusCode::NOT_FOUND.into_response()
+        }
+        Err(e) => {
+            println!("handlers::share_links::all - {e:?}");
+            StatusCode::INTERNAL_SERVER_ERROR.into_response()
+        }
+    }
+}
+
+/// Only owner and operators of the app can share its artifacts.
+pub async fn create(
+    State(db): State<Db>,
+    Claim(claim): AppClaim,
+    Path((app_id, artifact_id)): Path<(i32, i64)>,
+    Json(body): Json<NewShareLink>,
+) -> Response {
+    let mut errors = ValidationErrors::default();
+    let now = now();
+    if body.expires_at <= now {
+        errors.add("expiresAt", "inPast", "expiresAt must be in the future");
+    } else if body.expires_at > now + MAX_LIFETIME_SECS {
+        let message = format!(
+            "links can be valid for at most {} days",
+            MAX_LIFETIME_SECS / 86400
+        );
+        errors.add("expiresAt", "tooLate", &message);
+    }
+    match &body.password {
+        Some(password) if password.is_empty() => {
+            errors.add("password", "required", "password must not be empty");
+        }
+        Some(password) if password.len() > MAX_PASSWORD_BYTES => {
+            let message = format!("password must be at most {MAX_PASSWORD_BYTES} bytes");
+            errors.add("password", "tooLong", &message);
+        }
+        _ => {}
+    }
+    if !errors.is_empty() {
+        return (StatusCode::UNPROCESSABLE_ENTITY, unwrap_json(&errors)).into_response();
+    }
+
+    match db
+        .share_links
+        .create(app_id, claim.user_id, artifact_id, &body)
+    {
+        Ok(Some(link)) => {
+            db.events.notify();
+            let token = link.token();
+            let created = CreatedShareLink {
+                link,
+                path: format!("/v1/shared/{token}"),
+                token,
+            };
+            (StatusCode::CREATED, unwrap_json(&created)).into_response()
+        }
+        Err(ShareLinkError::OutlivesArtifact) => {
+            let mut errors = ValidationErrors::default();
+            let message = "expiresAt must not be after the artifact expires";
+            errors.add("expiresAt", "outlivesArtifact", message);
+            (StatusCode::UNPROCESSABLE_ENTITY, unwrap_json(&errors)).into_response()
+        }
+        Ok(None) | Err(ShareLinkError::SqliteError(rusqlite::Error::QueryReturnedNoRows)) => {
+            StatusCode::NOT_FOUND.into_response()
+        }
+        Err(ShareLinkError::SqliteError(e)) => {
+            println!("handlers::share_links::create - {e:?}");
+            StatusCode::INTERNAL_SERVER_ERROR.into_response()
+        }
+    }
+}
+
+/// Link stops working right away, it stays listed with its download count.
+pub async fn revoke(
+    State(db): State<Db>,
+    Claim(claim): AppClaim,
+    Path((app_id, artifact_id, share_id)): Path<(i32, i64, i64)>,
+) -> impl IntoResponse {
+    match db
+        .share_links
+        .revoke(app_id, claim.user_id, artifact_id, share_id)
+    {
+        Ok(Some(link)) => {
+            db.events.notify();
+            unwrap_json(&link).into_response()
+        }
+        Ok(None) | Err(rusqlite::Error::QueryReturnedNoRows) => {
+            StatusCode::NOT_FOUND.into_response()
+        }
+        Err(e) => {
+            println!("handlers::share_links::revoke - {e:?}");
+            StatusCode::INTERNAL_SERVER_ERROR.into_response()
+        }
+    }
+}
+
+/**
+    Download through a share link, no account needed. Passwords of protected
+    links come as basic auth, so browsers ask for them, the user name is ignored.
+    Unknown, revoked and expired links all look the same.
+    Only requests from the first byte on count as downloads, resumed ones don't.
+*/
+pub async fn download(
+    State(db): State<Db>,
+    Path(token): Path<String>,
+    headers: HeaderMap,
+) -> Response {
+    let Some((share_id, expires_at)) = verify_token(&token) else {
+        return StatusCode::NOT_FOUND.into_response();
+    };
+    let password = basic_password(&headers);
+    let count_download = starts_at_zero(&headers);
+    match db
+        .share_links
+        .access(share_id, expires_at, password.as_deref(), count_download)
+    {
+        Ok(ShareAccess::Granted(artifact)) => content(&artifact, &headers).await,
+        Ok(ShareAccess::PasswordRequired | ShareAccess::WrongPassword) => (
+            StatusCode::UNAUTHORIZED,
+            [(header::WWW_AUTHENTICATE, "Basic realm=\"shared report\"")],
+        )
+            .into_response(),
+        Ok(ShareAccess::Gone) => StatusCode::NOT_FOUND.into_response(),
+        Err(e) => {
+            println!("handlers::share_links::download - {e:?}");
+            StatusCode::INTERNAL_SERVER_ERROR.into_response()
+        }
+    }
+}
+
+/// No `Range`, or one from the first byte like `bytes=0-`.
+fn starts_at_zero(headers: &HeaderMap) -> bool {
+    let Some(range) = headers.get(header::RANGE) else {
+        return true;
+    };
+    range
+        .to_str()
+        .ok()
+        .and_then(|range| range.trim().strip_prefix("bytes="))
+        .and_then(|range| range.split_once('-'))
+        .is_some_and(|(start, _)| start.trim() == "0")
+}
+
+/// Password part of a basic `Authorization` header.
+fn basic_password(headers: &HeaderMap) -> Option<String> {
+    let encoded = headers
+        .get(header::AUTHORIZATION)?
+        .to_str()
+        .ok()?
+        .strip_prefix("Basic ")?;
+    let decoded = String::from_utf8(BASE64.decode(encoded.trim().as_bytes()).ok()?).ok()?;
+    let (_, password) = decoded.split_once(':')?;
+    Some(password.to_string())
+}
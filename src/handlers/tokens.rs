@@ -1,7 +1,20 @@
+use std::sync::OnceLock;
+
+use axum::{
+    extract::{MatchedPath, RawPathParams, Request},
+    http::{header::AUTHORIZATION, Method, StatusCode},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
 use axum_utils::{jwt_sign, jwt_verify, Claim, VerifiebleClaim};
 
 use serde::{Deserialize, Serialize};
 
+use crate::db::{
+    access_tokens::{Scope, TokenGrant, ACCESS_TOKEN_PREFIX},
+    Db,
+};
+
 pub(crate) const KEY: &[u8] = b"super-secret";
 
 pub type AppClaim = Claim<UserClaim>;
@@ -9,9 +22,208 @@ pub type AppClaim = Claim<UserClaim>;
 #[derive(Serialize, Deserialize)]
 pub struct UserClaim {
     pub(crate) user_id: i32,
+    /// Session epoch of the user when the token was issued,
+    /// older tokens have none and count as epoch 0.
+    #[serde(default)]
+    pub(crate) epoch: u32,
+}
+
+/// Database used to check session epochs, see `track_sessions`.
+static SESSIONS: OnceLock<Db> = OnceLock::new();
+
+tokio::task_local! {
+    /**
+        Access token `token_scopes` authenticated for the request, with its grant.
+        The `AppClaim` extractor only sees the header, it finds the grant here
+        instead of looking the token up and touching `last_used_at` a second time.
+    */
+    static AUTHENTICATED: (String, TokenGrant);
+}
+
+/**
+    Makes `UserClaim::check` reject tokens issued before the last password change.
+    Called once by the server on startup, the cli does not verify tokens.
+*/
+pub fn track_sessions(db: Db) {
+    let _ = SESSIONS.set(db);
+}
+
+impl UserClaim {
+    /// Claim for a new session in the current epoch of the user.
+    pub fn for_user(user_id: i32) -> Self {
+        let epoch = SESSIONS
+            .get()
+            .and_then(|db| db.users.session_epoch(user_id).ok())
+            .unwrap_or(0);
+        Self { user_id, epoch }
+    }
 }
 
 impl VerifiebleClaim for UserClaim {
+    fn check(claim: &str) -> Result<Self, jwt::Error>
+    where
+        Self: Sized,
+    {
+        if let Some(token) = access_token(claim) {
+            let grant = authenticate(token).ok_or(jwt::Error::InvalidSignature)?;
+            return Ok(Self {
+                user_id: grant.user_id,
+                epoch: 0,
+            });
+        }
+
+        let claim: UserClaim = jwt_verify(claim, KEY)?;
+        let Some(db) = SESSIONS.get() else {
+            return Ok(claim);
+        };
+        // deleted users have no epoch, so their tokens stop working too
+        match db.users.session_epoch(claim.user_id) {
+            Ok(epoch) if epoch == claim.epoch => Ok(claim),
+            _ => Err(jwt::Error::InvalidSignature),
+        }
+    }
+
+    fn sign(self) -> String {
+        jwt_sign(self, KEY).unwrap()
+    }
+}
+
+/// Access tokens may be sent bare or as `Bearer` token.
+fn access_token(header: &str) -> Option<&str> {
+    let token = header.strip_prefix("Bearer ").unwrap_or(header).trim();
+    token.starts_with(ACCESS_TOKEN_PREFIX).then_some(token)
+}
+
+/// Grant of the token, from `token_scopes` when it already authenticated it.
+fn authenticate(token: &str) -> Option<TokenGrant> {
+    let authenticated = AUTHENTICATED
+        .try_with(|(authenticated, grant)| (authenticated == token).then(|| grant.clone()));
+    authenticated.ok().flatten().or_else(|| {
+        let db = SESSIONS.get()?;
+        db.access_tokens.authenticate(token).ok().flatten()
+    })
+}
+
+/// Routes the method doesn't tell about, websockets are upgraded from a `GET`.
+const EXPLICIT_SCOPES: &[(&str, Scope)] = &[
+    // broker sessions get secrets and report versions, logs and job results
+    (
+        "/apps/:app_id/brokers/:broker_id/connect",
+        Scope::BrokersWrite,
+    ),
+    (
+        "/apps/:app_id/brokers/:broker_id/logs/tail",
+        Scope::BrokersRead,
+    ),
+    ("/apps/:app_id/events", Scope::AppsRead),
+];
+
+/**
+    Scope a personal access token needs for a route, `None` if tokens can't use it at all.
+    Only app routes are open to tokens, account and admin routes need a session.
+*/
+fn required_scope(path: &str, method: &Method) -> Option<Scope> {
+    let path = ["/v1", "/v2"]
+        .iter()
+        .find_map(|version| path.strip_prefix(version))
+        .unwrap_or(path);
+    if let Some((_, scope)) = EXPLICIT_SCOPES.iter().find(|(route, _)| *route == path) {
+        return Some(*scope);
+    }
+    let read = matches!(*method, Method::GET | Method::HEAD);
+
+    if path.starts_with("/apps/:app_id/brokers") {
+        Some(if read {
+            Scope::BrokersRead
+        } else {
+            Scope::BrokersWrite
+        })
+    } else if path == "/apps" || path.starts_with("/apps/") {
+        Some(if read {
+            Scope::AppsRead
+        } else {
+            Scope::AppsWrite
+        })
+    } else {
+        None
+    }
+}
+
+/**
+    Keeps personal access tokens to the routes and app their scopes cover,
+    sessions pass unchanged. Invalid tokens are left to the `AppClaim` extractor.
+*/
+pub async fn token_scopes(
+    path: MatchedPath,
+    params: Option<RawPathParams>,
+    request: Request,
+    next: Next,
+) -> Response {
+    let token = request
+        .headers()
+        .get(AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(access_token)
+        .map(str::to_string);
+    let Some((token, grant)) = token.and_then(|token| {
+        let grant = authenticate(&token)?;
+        Some((token, grant))
+    }) else {
+        return next.run(request).await;
+    };
+
+    let app_id = params.and_then(|params| {
+        params
+            .iter()
+            .find(|(key, _)| *key == "app_id")
+            .and_then(|(_, value)| value.parse().ok())
+    });
+    match required_scope(path.as_str(), request.method()) {
+        Some(scope) if grant.allows(scope, app_id) => {
+            AUTHENTICATED.scope((token, grant), next.run(request)).await
+        }
+        _ => StatusCode::FORBIDDEN.into_response(),
+    }
+}
+
+/**
+    Proof that the password of a user with 2FA was correct, exchanged for `UserClaim`
+    at `/login/2fa`. Field names differ from `UserClaim`, so it is never accepted as one.
+*/
+#[derive(Serialize, Deserialize)]
+pub struct TwoFactorChallenge {
+    pub(crate) pending_user_id: i32,
+    pub(crate) username: String,
+    pub(crate) expires_at: u64,
+}
+
+impl VerifiebleClaim for TwoFactorChallenge {
+    fn check(claim: &str) -> Result<Self, jwt::Error>
+    where
+        Self: Sized,
+    {
+        jwt_verify(claim, KEY)
+    }
+
+    fn sign(self) -> String {
+        jwt_sign(self, KEY).unwrap()
+    }
+}
+
+/**
+    `state` of a single sign-on started at `/oidc/login` or `/me/identities/oidc`.
+    Signed, so the callback can trust `link_user_id` without a session,
+    which browsers don't send when the provider redirects back.
+*/
+#[derive(Serialize, Deserialize)]
+pub struct OidcState {
+    /// Random, every login gets its own state.
+    pub(crate) login_id: String,
+    pub(crate) link_user_id: Option<i32>,
+    pub(crate) expires_at: u64,
+}
+
+impl VerifiebleClaim for OidcState {
     fn check(claim: &str) -> Result<Self, jwt::Error>
     where
         Self: Sized,
@@ -23,3 +235,103 @@ impl VerifiebleClaim for UserClaim {
         jwt_sign(self, KEY).unwrap()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use axum::{body::Body, http::header};
+    use tower::ServiceExt;
+
+    use super::*;
+    use crate::db::{access_tokens::NewAccessToken, testing};
+
+    #[test]
+    fn method_decides_unless_the_route_is_listed() {
+        let scope = |path: &str, method: Method| required_scope(path, &method);
+        assert_eq!(scope("/v1/apps", Method::GET), Some(Scope::AppsRead));
+        assert_eq!(
+            scope("/apps/:app_id", Method::PATCH),
+            Some(Scope::AppsWrite)
+        );
+        let brokers = "/v2/apps/:app_id/brokers/:broker_id";
+        assert_eq!(scope(brokers, Method::GET), Some(Scope::BrokersRead));
+        assert_eq!(scope(brokers, Method::DELETE), Some(Scope::BrokersWrite));
+        assert_eq!(scope("/v1/me/tokens", Method::GET), None);
+        assert_eq!(scope("/v1/admin/audit", Method::GET), None);
+
+        let connect = "/v1/apps/:app_id/brokers/:broker_id/connect";
+        assert_eq!(scope(connect, Method::GET), Some(Scope::BrokersWrite));
+        let tail = "/v1/apps/:app_id/brokers/:broker_id/logs/tail";
+        assert_eq!(scope(tail, Method::GET), Some(Scope::BrokersRead));
+    }
+
+    #[test]
+    fn bearer_prefix_is_optional_for_access_tokens() {
+        assert_eq!(access_token("Bearer rgp_abc"), Some("rgp_abc"));
+        assert_eq!(access_token("rgp_abc "), Some("rgp_abc"));
+        assert_eq!(access_token("eyJhbGciOiJIUzI1NiJ9.e30.x"), None);
+    }
+
+    #[tokio::test]
+    async fn grant_of_the_middleware_is_reused() {
+        let grant = TokenGrant {
+            token_id: 1,
+            user_id: 7,
+            scopes: vec![Scope::AppsRead],
+            app_id: None,
+        };
+        let (same, other) = AUTHENTICATED
+            .scope(("rgp_abc".to_string(), grant), async {
+                (
+                    authenticate("rgp_abc"),
+                    UserClaim::check("Bearer rgp_other").ok(),
+                )
+            })
+            .await;
+        assert_eq!(same.map(|grant| grant.user_id), Some(7));
+        assert!(other.is_none());
+    }
+
+    /// The only test that tracks sessions, `SESSIONS` can be set once.
+    #[tokio::test]
+    async fn read_tokens_can_not_connect_brokers() {
+        let db: Db = Arc::new(testing::db());
+        track_sessions(db.clone());
+        let author = testing::user(&db, "alice");
+        let app_id = testing::app(&db, author);
+        let broker_id = testing::broker(&db, app_id);
+        let token = |scope: Scope| {
+            let token = format!("{ACCESS_TOKEN_PREFIX}{scope:?}");
+            let new_token = NewAccessToken {
+                name: format!("{scope:?}"),
+                scopes: vec![scope],
+                app_id: None,
+                expires_at: None,
+            };
+            db.access_tokens.create(author, &new_token, &token).unwrap();
+            token
+        };
+        let connect = |token: &str| {
+            Request::get(format!("/v1/apps/{app_id}/brokers/{broker_id}/connect"))
+                .header(AUTHORIZATION, token)
+                .header(header::CONNECTION, "upgrade")
+                .header(header::UPGRADE, "websocket")
+                .header(header::SEC_WEBSOCKET_VERSION, "13")
+                .header(header::SEC_WEBSOCKET_KEY, "dGhlIHNhbXBsZSBub25jZQ==")
+                .body(Body::empty())
+                .unwrap()
+        };
+        let app = crate::routes::app(db.clone());
+
+        let read = token(Scope::BrokersRead);
+        let response = app.clone().oneshot(connect(&read)).await.unwrap();
+        assert_eq!(response.status(), StatusCode::FORBIDDEN);
+
+        // past the scope check, a request without a connection can't be upgraded
+        let write = token(Scope::BrokersWrite);
+        let response = app.oneshot(connect(&write)).await.unwrap();
+        assert_eq!(response.status(), StatusCode::UPGRADE_REQUIRED);
+        assert!(!db.brokers.get(app_id, broker_id).unwrap().unwrap().active);
+    }
+}
@@ -0,0 +1,73 @@
+use axum::{
+    extract::{Path, State},
+    http::StatusCode,
+    response::IntoResponse,
+};
+use axum_utils::Claim;
+use rusqlite::ErrorCode;
+
+use crate::db::{
+    trash::{RestoreError, Trashed},
+    Db,
+};
+
+use super::tokens::AppClaim;
+
+pub async fn app(
+    State(db): State<Db>,
+    Claim(claim): AppClaim,
+    Path(app_id): Path<i32>,
+) -> impl IntoResponse {
+    restore(&db, app_id, claim.user_id, Trashed::App, app_id)
+}
+
+pub async fn operator(
+    State(db): State<Db>,
+    Claim(claim): AppClaim,
+    Path((app_id, operator_id)): Path<(i32, i32)>,
+) -> impl IntoResponse {
+    restore(&db, app_id, claim.user_id, Trashed::Operator, operator_id)
+}
+
+pub async fn app_user(
+    State(db): State<Db>,
+    Claim(claim): AppClaim,
+    Path((app_id, app_user_id)): Path<(i32, i32)>,
+) -> impl IntoResponse {
+    restore(&db, app_id, claim.user_id, Trashed::AppUser, app_user_id)
+}
+
+pub async fn broker(
+    State(db): State<Db>,
+    Claim(claim): AppClaim,
+    Path((app_id, broker_id)): Path<(i32, i32)>,
+) -> impl IntoResponse {
+    restore(&db, app_id, claim.user_id, Trashed::Broker, broker_id)
+}
+
+/**
+    404 when there is nothing to restore, 409 when the row was re-created in the meantime
+    or its user is deleted. Restored members and brokers show up in the event stream again.
+*/
+fn restore(db: &Db, app_id: i32, user_id: i32, kind: Trashed, id: i32) -> StatusCode {
+    match db.trash.restore(app_id, user_id, kind, id) {
+        Ok(0) => StatusCode::NOT_FOUND,
+        Ok(_) => {
+            db.events.notify();
+            StatusCode::OK
+        }
+        Err(RestoreError::SqliteError(rusqlite::Error::QueryReturnedNoRows)) => {
+            StatusCode::NOT_FOUND
+        }
+        Err(RestoreError::SqliteError(rusqlite::Error::SqliteFailure(e, _)))
+            if e.code == ErrorCode::ConstraintViolation =>
+        {
+            StatusCode::CONFLICT
+        }
+        Err(RestoreError::UserDeleted) => StatusCode::CONFLICT,
+        Err(RestoreError::SqliteError(e)) => {
+            println!("handlers::trash::restore - {e:?}");
+            StatusCode::INTERNAL_SERVER_ERROR
+        }
+    }
+}
@@ -0,0 +1,233 @@
+use axum::{extract::State, http::StatusCode, response::IntoResponse, Json};
+use axum_utils::{unwrap_json, Claim};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+
+use crate::{
+    crypto::{random_token, totp_secret, TOTP_PERIOD},
+    db::Db,
+    ratelimit::{too_many_requests, ClientIp, LoginThrottle},
+};
+
+use super::tokens::AppClaim;
+
+/// Shown in authenticator apps next to the username.
+const ISSUER: &str = "ReportGenerator";
+const RECOVERY_CODES: usize = 10;
+
+/// Plain recovery codes like `3f9a1-c07d2-8e4b0-51a6f`, 80 bits each, only ever shown once.
+fn recovery_codes() -> Vec<String> {
+    (0..RECOVERY_CODES)
+        .map(|_| {
+            let code = random_token(10);
+            let groups: Vec<&str> = (0..code.len())
+                .step_by(5)
+                .map(|i| &code[i..][..5])
+                .collect();
+            groups.join("-")
+        })
+        .collect()
+}
+
+/// Label of the `otpauth://` uri, usernames from a provider may contain anything.
+fn percent_encode(text: &str) -> String {
+    text.bytes()
+        .map(|byte| match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => {
+                (byte as char).to_string()
+            }
+            byte => format!("%{byte:02X}"),
+        })
+        .collect()
+}
+
+/// Starts enrolment, 2FA is enabled only after `confirm`.
+pub async fn enroll(State(db): State<Db>, Claim(claim): AppClaim) -> impl IntoResponse {
+    let user = match db.users.by_id(claim.user_id) {
+        Ok(user) => user,
+        Err(e) => {
+            println!("handlers::two_factor::enroll - {e:?}");
+            return StatusCode::NOT_FOUND.into_response();
+        }
+    };
+
+    let secret = totp_secret();
+    match db.two_factor.enroll(claim.user_id, &secret) {
+        Ok(0) => StatusCode::CONFLICT.into_response(),
+        Ok(_) => {
+            let uri = format!(
+                "otpauth://totp/{ISSUER}:{}?secret={secret}&issuer={ISSUER}&digits=6&period={TOTP_PERIOD}",
+                percent_encode(&user.username)
+            );
+            unwrap_json(&json!({ "secret": secret, "uri": uri })).into_response()
+        }
+        Err(e) => {
+            println!("handlers::two_factor::enroll - {e:?}");
+            StatusCode::INTERNAL_SERVER_ERROR.into_response()
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct CodeRequest {
+    code: String,
+}
+
+/// Enables 2FA, response contains recovery codes.
+pub async fn confirm(
+    State(db): State<Db>,
+    Claim(claim): AppClaim,
+    ClientIp(ip): ClientIp,
+    Json(body): Json<CodeRequest>,
+) -> impl IntoResponse {
+    // codes guessed with a stolen session share the lockout of `login_two_factor`
+    let username = match db.users.by_id(claim.user_id) {
+        Ok(user) => user.username,
+        Err(e) => {
+            println!("handlers::two_factor::confirm - {e:?}");
+            return StatusCode::NOT_FOUND.into_response();
+        }
+    };
+    if let Err(retry_after) = LoginThrottle::current().check(ip, &username) {
+        return too_many_requests(retry_after);
+    }
+
+    let codes = recovery_codes();
+    match db.two_factor.confirm(claim.user_id, &body.code, &codes) {
+        Ok(true) => {
+            LoginThrottle::current().succeeded(&username);
+            unwrap_json(&json!({ "recoveryCodes": codes })).into_response()
+        }
+        Ok(false) => {
+            LoginThrottle::current().failed(ip, &username);
+            StatusCode::FORBIDDEN.into_response()
+        }
+        Err(e) => {
+            println!("handlers::two_factor::confirm - {e:?}");
+            StatusCode::INTERNAL_SERVER_ERROR.into_response()
+        }
+    }
+}
+
+/// Replaces recovery codes, old ones stop working.
+pub async fn regenerate(
+    State(db): State<Db>,
+    Claim(claim): AppClaim,
+    ClientIp(ip): ClientIp,
+    Json(body): Json<CodeRequest>,
+) -> impl IntoResponse {
+    let username = match db.users.by_id(claim.user_id) {
+        Ok(user) => user.username,
+        Err(e) => {
+            println!("handlers::two_factor::regenerate - {e:?}");
+            return StatusCode::NOT_FOUND.into_response();
+        }
+    };
+    if let Err(retry_after) = LoginThrottle::current().check(ip, &username) {
+        return too_many_requests(retry_after);
+    }
+
+    match db.two_factor.verify(claim.user_id, &body.code) {
+        Ok(true) => LoginThrottle::current().succeeded(&username),
+        Ok(false) => {
+            LoginThrottle::current().failed(ip, &username);
+            return StatusCode::FORBIDDEN.into_response();
+        }
+        Err(e) => {
+            println!("handlers::two_factor::regenerate - {e:?}");
+            return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+        }
+    }
+
+    let codes = recovery_codes();
+    match db.two_factor.replace_recovery_codes(claim.user_id, &codes) {
+        Ok(_) => unwrap_json(&json!({ "recoveryCodes": codes })).into_response(),
+        Err(e) => {
+            println!("handlers::two_factor::regenerate - {e:?}");
+            StatusCode::INTERNAL_SERVER_ERROR.into_response()
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct DisableRequest {
+    password: String,
+    code: String,
+}
+
+/// Needs both the password and a current code or recovery code.
+pub async fn disable(
+    State(db): State<Db>,
+    Claim(claim): AppClaim,
+    ClientIp(ip): ClientIp,
+    Json(body): Json<DisableRequest>,
+) -> impl IntoResponse {
+    let username = match db.users.by_id(claim.user_id) {
+        Ok(user) => user.username,
+        Err(e) => {
+            println!("handlers::two_factor::disable - {e:?}");
+            return StatusCode::NOT_FOUND.into_response();
+        }
+    };
+    if let Err(retry_after) = LoginThrottle::current().check(ip, &username) {
+        return too_many_requests(retry_after);
+    }
+
+    let verified = tokio::task::spawn_blocking({
+        let db = db.clone();
+        move || {
+            db.users
+                .verify_password(claim.user_id, &body.password)
+                .and_then(|valid| match valid {
+                    true => db.two_factor.verify(claim.user_id, &body.code),
+                    false => Ok(false),
+                })
+        }
+    })
+    .await
+    .unwrap();
+
+    match verified {
+        Ok(true) => LoginThrottle::current().succeeded(&username),
+        Ok(false) => {
+            LoginThrottle::current().failed(ip, &username);
+            return StatusCode::FORBIDDEN.into_response();
+        }
+        Err(e) => {
+            println!("handlers::two_factor::disable - {e:?}");
+            return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+        }
+    }
+
+    match db.two_factor.disable(claim.user_id) {
+        Ok(_) => StatusCode::NO_CONTENT.into_response(),
+        Err(e) => {
+            println!("handlers::two_factor::disable - {e:?}");
+            StatusCode::INTERNAL_SERVER_ERROR.into_response()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn recovery_codes_have_80_bits_in_groups() {
+        let codes = recovery_codes();
+        assert_eq!(codes.len(), RECOVERY_CODES);
+        for code in &codes {
+            let groups: Vec<&str> = code.split('-').collect();
+            assert_eq!(groups.len(), 4);
+            assert!(groups.iter().all(|group| group.len() == 5));
+            assert!(code.replace('-', "").chars().all(|c| c.is_ascii_hexdigit()));
+        }
+        assert_ne!(codes[0], codes[1]);
+    }
+
+    #[test]
+    fn label_is_percent_encoded() {
+        assert_eq!(percent_encode("alice.b-2"), "alice.b-2");
+        assert_eq!(percent_encode("a:b?c&d=é"), "a%3Ab%3Fc%26d%3D%C3%A9");
+    }
+}
@@ -3,18 +3,48 @@ use axum::{
     http::StatusCode,
     response::IntoResponse,
 };
-use axum_utils::unwrap_json;
+use axum_utils::{unwrap_json, Claim};
 use serde::Deserialize;
 
-use crate::{db::Db, handlers::auth::ERROR};
+use crate::{db::Db, handlers::auth::ERROR, validation::ValidationErrors};
+
+use super::tokens::AppClaim;
+
+/// Shorter queries match too many users to be useful for anything but enumeration.
+const MIN_QUERY_LENGTH: usize = 3;
+const DEFAULT_LIMIT: usize = 10;
+const MAX_LIMIT: usize = 25;
 
 #[derive(Deserialize)]
 pub struct SearchQuery {
     query: String,
+    /// Only the user with exactly this username, for invitations.
+    #[serde(default)]
+    exact: bool,
+    limit: Option<usize>,
 }
 
-pub async fn search(State(db): State<Db>, Query(query): Query<SearchQuery>) -> impl IntoResponse {
-    match db.users.search(&query.query) {
+pub async fn search(
+    State(db): State<Db>,
+    Claim(_): AppClaim,
+    Query(query): Query<SearchQuery>,
+) -> impl IntoResponse {
+    let text = query.query.trim();
+    if !query.exact && text.chars().count() < MIN_QUERY_LENGTH {
+        let mut errors = ValidationErrors::default();
+        let message = format!("query must be at least {MIN_QUERY_LENGTH} characters");
+        errors.add("query", "tooShort", &message);
+        return (StatusCode::UNPROCESSABLE_ENTITY, unwrap_json(&errors));
+    }
+
+    let users = match query.exact {
+        true => db.users.lookup(text).map(|user| user.into_iter().collect()),
+        false => {
+            let limit = query.limit.unwrap_or(DEFAULT_LIMIT).clamp(1, MAX_LIMIT);
+            db.users.search(text, limit)
+        }
+    };
+    match users {
         Ok(users) => (StatusCode::OK, unwrap_json(&users)),
         Err(e) => {
             println!("500 ERROR while executing 'users::search': Sqlite reported error: {e}");
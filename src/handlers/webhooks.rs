@@ -0,0 +1,114 @@
+use axum::{
+    extract::{Path, State},
+    http::StatusCode,
+    response::IntoResponse,
+    Json,
+};
+use axum_utils::{unwrap_json, Claim};
+
+use crate::{
+    crypto::random_token,
+    db::{events::KINDS, webhooks::NewWebhook, Db},
+    validation::ValidationErrors,
+    webhooks::TargetPolicy,
+};
+
+use super::tokens::AppClaim;
+
+pub async fn all(
+    State(db): State<Db>,
+    Path(app_id): Path<i32>,
+    Claim(claim): AppClaim,
+) -> impl IntoResponse {
+    match db.webhooks.for_app(app_id, claim.user_id) {
+        Ok(webhooks) => unwrap_json(&webhooks).into_response(),
+        Err(e) => {
+            println!("handlers::webhooks::all - {e:?}");
+            StatusCode::BAD_REQUEST.into_response()
+        }
+    }
+}
+
+/**
+    Response contains generated secret, it is not shown again.
+    The url has to resolve to public addresses only, see `TargetPolicy`.
+*/
+pub async fn create(
+    State(db): State<Db>,
+    Path(app_id): Path<i32>,
+    Claim(claim): AppClaim,
+    Json(body): Json<NewWebhook>,
+) -> impl IntoResponse {
+    let mut errors = ValidationErrors::default();
+    if let Err(message) = TargetPolicy::current().check(&body.url).await {
+        errors.add("url", "notAllowed", &message);
+    }
+    for event in &body.events {
+        if !KINDS.contains(&event.as_str()) {
+            errors.add(
+                "events",
+                "unknown",
+                &format!("{event} is not an event type"),
+            );
+        }
+    }
+    if !errors.is_empty() {
+        return (StatusCode::UNPROCESSABLE_ENTITY, unwrap_json(&errors)).into_response();
+    }
+
+    match db
+        .webhooks
+        .create(app_id, claim.user_id, body, random_token(32))
+    {
+        Ok(webhook) => unwrap_json(&webhook).into_response(),
+        Err(e) => {
+            println!("handlers::webhooks::create - {e:?}");
+            StatusCode::BAD_REQUEST.into_response()
+        }
+    }
+}
+
+pub async fn delete(
+    State(db): State<Db>,
+    Claim(claim): AppClaim,
+    Path((app_id, webhook_id)): Path<(i32, i32)>,
+) -> impl IntoResponse {
+    match db.webhooks.delete(app_id, claim.user_id, webhook_id) {
+        Ok(0) => StatusCode::NOT_FOUND,
+        Ok(_) => StatusCode::OK,
+        Err(_) => StatusCode::BAD_REQUEST,
+    }
+}
+
+pub async fn deliveries(
+    State(db): State<Db>,
+    Claim(claim): AppClaim,
+    Path((app_id, webhook_id)): Path<(i32, i32)>,
+) -> impl IntoResponse {
+    match db.webhooks.deliveries(app_id, claim.user_id, webhook_id) {
+        Ok(deliveries) => unwrap_json(&deliveries).into_response(),
+        Err(e) => {
+            println!("handlers::webhooks::deliveries - {e:?}");
+            StatusCode::BAD_REQUEST.into_response()
+        }
+    }
+}
+
+pub async fn redeliver(
+    State(db): State<Db>,
+    Claim(claim): AppClaim,
+    Path((app_id, webhook_id, delivery_id)): Path<(i32, i32, i32)>,
+) -> impl IntoResponse {
+    let result = db
+        .webhooks
+        .redeliver(app_id, claim.user_id, webhook_id, delivery_id);
+
+    match result {
+        Ok(0) => StatusCode::NOT_FOUND,
+        Ok(_) => {
+            db.events.notify();
+            StatusCode::OK
+        }
+        Err(_) => StatusCode::BAD_REQUEST,
+    }
+}
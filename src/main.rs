@@ -1,51 +1,148 @@
-use std::sync::Arc;
+use std::{net::SocketAddr, sync::Arc, time::Duration};
 
-use axum::routing::{delete, get};
-use axum::{routing::post, Router};
+use axum::{extract::Request, middleware, ServiceExt};
+use tower::Layer;
 
-use db::SqliteDb;
-
-use handlers::apps::{self, all_apps, new_app};
-use handlers::auth::{login, register};
-use handlers::{app_users, brokers, operators, users};
+use db::{Db, SqliteDb};
 
+pub mod cli;
+pub mod cron;
+pub mod crypto;
 pub mod db;
 pub mod handlers;
+pub mod notify;
+pub mod oidc;
+pub mod ratelimit;
+pub mod routes;
+pub mod storage;
+pub mod validation;
+pub mod version;
+pub mod webhooks;
 
 #[tokio::main]
 async fn main() {
     let db = Arc::new(SqliteDb::new("sqlite.db".to_string()).unwrap());
 
-    db.init().unwrap();
-
-    let app_users_router = Router::new()
-        .route("/", get(app_users::all))
-        .route("/", post(app_users::create))
-        .route("/:user_id", delete(app_users::delete));
-
-    let brokers_router = Router::new()
-        .route("/", get(brokers::all))
-        .route("/", post(brokers::create))
-        .route("/", delete(brokers::delete));
-
-    let app = Router::new()
-        .route("/register", post(register))
-        .route("/login", post(login))
-        .route("/users/search", get(users::search))
-        .route("/apps/:app_id/operators", get(operators::all))
-        .route("/apps/:app_id/operators", post(operators::create))
-        .route(
-            "/apps/:app_id/operators/:operator_id",
-            delete(operators::delete),
-        )
-        .nest("/apps/:app_id/users", app_users_router)
-        .nest("/apps/:app_id/brokers", brokers_router)
-        .route("/apps/:app_id/info", get(apps::by_id))
-        .route("/apps/search", get(apps::search))
-        .route("/apps/", get(all_apps))
-        .route("/apps/", post(new_app))
-        .with_state(db);
+    let applied = db.init().unwrap();
+    if applied > 0 {
+        println!("applied {applied} schema migrations");
+    }
+
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    if !args.is_empty() && args[0] != "serve" {
+        if let Err(e) = cli::run(&db, &args) {
+            eprintln!("{e}");
+            std::process::exit(1);
+        }
+        return;
+    }
+
+    handlers::tokens::track_sessions(db.clone());
+    tokio::spawn(take_snapshots(db.clone()));
+    tokio::spawn(webhooks::deliver(db.clone()));
+    tokio::spawn(purge_deleted(db.clone()));
+    tokio::spawn(mark_stale_brokers(db.clone()));
+    tokio::spawn(dispatch_jobs(db.clone()));
+    tokio::spawn(run_schedules(db.clone()));
+
+    // path has to be normalized before the router picks a route
+    let app = middleware::from_fn(routes::normalize_path).layer(routes::app(db));
 
     let listener = tokio::net::TcpListener::bind("0.0.0.0:3000").await.unwrap();
-    axum::serve(listener, app).await.unwrap();
+    // peer address is needed by rate limiting
+    let service = ServiceExt::<Request>::into_make_service_with_connect_info::<SocketAddr>(app);
+    axum::serve(listener, service).await.unwrap();
+}
+
+const SNAPSHOT_INTERVAL: Duration = Duration::from_secs(24 * 60 * 60);
+
+async fn take_snapshots(db: Db) {
+    let mut interval = tokio::time::interval(SNAPSHOT_INTERVAL);
+    loop {
+        interval.tick().await;
+        let db = db.clone();
+        let result = tokio::task::spawn_blocking(move || db.snapshots.create()).await;
+        if let Ok(Err(e)) = result {
+            println!("scheduled snapshot failed: {e:?}");
+        }
+    }
+}
+
+const PURGE_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+/// Removes soft deleted rows, artifacts and broker logs past retention.
+async fn purge_deleted(db: Db) {
+    let mut interval = tokio::time::interval(PURGE_INTERVAL);
+    loop {
+        interval.tick().await;
+        let older_than = db::events::now().saturating_sub(db::trash::PURGE_AFTER_SECS);
+        // before the trash, artifacts of purged apps need their contents removed
+        match db.artifacts.expire(db::events::now(), older_than) {
+            Ok(keys) => {
+                db.events.notify();
+                handlers::artifacts::remove_contents(&db, keys).await;
+            }
+            Err(e) => println!("expiring artifacts failed: {e:?}"),
+        }
+        if let Err(e) = db.trash.purge(older_than) {
+            println!("purge of deleted rows failed: {e:?}");
+        }
+        let older_than = db::events::now().saturating_sub(db::broker_logs::LOG_RETENTION_SECS);
+        if let Err(e) = db.broker_logs.prune(older_than) {
+            println!("pruning broker logs failed: {e:?}");
+        }
+    }
+}
+
+const STALE_CHECK_INTERVAL: Duration = Duration::from_secs(30);
+
+/// Marks brokers inactive whose heartbeats stopped, their sessions close on the notify.
+async fn mark_stale_brokers(db: Db) {
+    let mut interval = tokio::time::interval(STALE_CHECK_INTERVAL);
+    loop {
+        interval.tick().await;
+        let older_than = db::events::now().saturating_sub(db::brokers::STALE_AFTER_SECS);
+        match db.brokers.mark_stale(older_than) {
+            Ok(0) => {}
+            Ok(_) => db.events.notify(),
+            Err(e) => println!("marking stale brokers failed: {e:?}"),
+        }
+    }
+}
+
+const DISPATCH_INTERVAL: Duration = Duration::from_secs(10);
+
+/**
+    Routes waiting jobs after every change, new jobs and brokers coming
+    and going all notify. The interval picks up load reported in heartbeats.
+*/
+async fn dispatch_jobs(db: Db) {
+    let mut changes = db.events.subscribe();
+    let mut interval = tokio::time::interval(DISPATCH_INTERVAL);
+    loop {
+        tokio::select! {
+            _ = interval.tick() => {}
+            changed = changes.changed() => if changed.is_err() { break },
+        }
+        match db.jobs.dispatch() {
+            Ok(0) => {}
+            Ok(_) => db.events.notify(),
+            Err(e) => println!("dispatching jobs failed: {e:?}"),
+        }
+    }
+}
+
+const SCHEDULE_INTERVAL: Duration = Duration::from_secs(15);
+
+/// Queues the jobs of due schedules, dispatch picks them up on the notify.
+async fn run_schedules(db: Db) {
+    let mut interval = tokio::time::interval(SCHEDULE_INTERVAL);
+    loop {
+        interval.tick().await;
+        match db.schedules.run_due(db::events::now()) {
+            Ok(0) => {}
+            Ok(_) => db.events.notify(),
+            Err(e) => println!("running schedules failed: {e:?}"),
+        }
+    }
 }
@@ -0,0 +1,602 @@
+use std::{
+    collections::HashMap,
+    env,
+    sync::{Arc, Mutex, OnceLock},
+    time::{Duration, Instant},
+};
+
+use data_encoding::BASE64URL_NOPAD;
+use reqwest::Url;
+use ring::signature::{self, RsaPublicKeyComponents, UnparsedPublicKey};
+use serde::Deserialize;
+use serde_json::Value;
+use sha2::{Digest, Sha256};
+
+use crate::{
+    crypto::{random_token, sha256_hex},
+    db::events::now,
+    validation::Policy,
+};
+
+const TIMEOUT: Duration = Duration::from_secs(10);
+/// Logins not finished within this time have to start over.
+pub const PENDING_TTL: Duration = Duration::from_secs(10 * 60);
+
+/**
+    Single sign-on through an OpenID Connect provider, authorization code flow with PKCE.
+    Needs `OIDC_ISSUER`, `OIDC_CLIENT_ID` and `OIDC_REDIRECT_URI` (the `/oidc/callback` route),
+    `OIDC_CLIENT_SECRET` only for confidential clients. Optional are `OIDC_SCOPES`,
+    `OIDC_GROUPS_CLAIM` (default `groups`), `OIDC_ADMIN_GROUPS` (comma separated)
+    and `OIDC_PROVISION_USERS` (default `true`).
+*/
+pub struct OidcConfig {
+    pub issuer: String,
+    pub client_id: String,
+    pub client_secret: Option<String>,
+    pub redirect_uri: String,
+    pub scopes: String,
+    pub groups_claim: String,
+    /// Members of these groups are platform admins, everyone else is not.
+    /// Empty leaves the admin flag to the cli.
+    pub admin_groups: Vec<String>,
+    /// Creates local users on first login, otherwise identities must be linked first.
+    pub provision_users: bool,
+}
+
+impl OidcConfig {
+    /// `None` if single sign-on is not configured.
+    pub fn current() -> Option<&'static OidcConfig> {
+        static CONFIG: OnceLock<Option<OidcConfig>> = OnceLock::new();
+        CONFIG.get_or_init(OidcConfig::from_env).as_ref()
+    }
+
+    fn from_env() -> Option<Self> {
+        let issuer = env::var("OIDC_ISSUER").ok()?;
+        let client_id = env::var("OIDC_CLIENT_ID").ok()?;
+        let redirect_uri = env::var("OIDC_REDIRECT_URI").ok()?;
+        let admin_groups = env::var("OIDC_ADMIN_GROUPS")
+            .map(|groups| {
+                groups
+                    .split(',')
+                    .map(|group| group.trim().to_string())
+                    .filter(|group| !group.is_empty())
+                    .collect()
+            })
+            .unwrap_or_default();
+
+        Some(Self {
+            issuer: issuer.trim_end_matches('/').to_string(),
+            client_id,
+            client_secret: env::var("OIDC_CLIENT_SECRET").ok(),
+            redirect_uri,
+            scopes: env::var("OIDC_SCOPES").unwrap_or_else(|_| "openid profile email".to_string()),
+            groups_claim: env::var("OIDC_GROUPS_CLAIM").unwrap_or_else(|_| "groups".to_string()),
+            admin_groups,
+            provision_users: env::var("OIDC_PROVISION_USERS")
+                .map_or(true, |value| value != "false"),
+        })
+    }
+
+    /// Admin flag for a user in `groups`, `None` if groups don't decide it.
+    pub fn is_admin(&self, groups: &[String]) -> Option<bool> {
+        if self.admin_groups.is_empty() {
+            return None;
+        }
+        Some(groups.iter().any(|group| self.admin_groups.contains(group)))
+    }
+}
+
+#[derive(Deserialize)]
+struct Discovery {
+    issuer: String,
+    authorization_endpoint: String,
+    token_endpoint: String,
+    userinfo_endpoint: Option<String>,
+    jwks_uri: String,
+}
+
+/// Public key of the provider from `jwks_uri`, RFC 7517.
+#[derive(Deserialize)]
+struct Jwk {
+    kty: String,
+    kid: Option<String>,
+    alg: Option<String>,
+    n: Option<String>,
+    e: Option<String>,
+    crv: Option<String>,
+    x: Option<String>,
+    y: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct JwkSet {
+    keys: Vec<Jwk>,
+}
+
+impl Jwk {
+    /// Only asymmetric algorithms, `none` and shared secrets prove nothing about the provider.
+    fn verify(&self, alg: &str, message: &[u8], signature: &[u8]) -> bool {
+        if self.alg.as_deref().is_some_and(|own| own != alg) {
+            return false;
+        }
+        let decode = |part: &Option<String>| {
+            let part = part.as_deref()?.trim_end_matches('=');
+            BASE64URL_NOPAD.decode(part.as_bytes()).ok()
+        };
+
+        match (self.kty.as_str(), alg) {
+            ("RSA", "RS256" | "RS384" | "RS512") => {
+                let algorithm = match alg {
+                    "RS256" => &signature::RSA_PKCS1_2048_8192_SHA256,
+                    "RS384" => &signature::RSA_PKCS1_2048_8192_SHA384,
+                    _ => &signature::RSA_PKCS1_2048_8192_SHA512,
+                };
+                let (Some(n), Some(e)) = (decode(&self.n), decode(&self.e)) else {
+                    return false;
+                };
+                let key = RsaPublicKeyComponents { n, e };
+                key.verify(algorithm, message, signature).is_ok()
+            }
+            ("EC", "ES256" | "ES384") => {
+                let (algorithm, curve): (&'static signature::EcdsaVerificationAlgorithm, _) =
+                    match alg {
+                        "ES256" => (&signature::ECDSA_P256_SHA256_FIXED, "P-256"),
+                        _ => (&signature::ECDSA_P384_SHA384_FIXED, "P-384"),
+                    };
+                let (Some(x), Some(y)) = (decode(&self.x), decode(&self.y)) else {
+                    return false;
+                };
+                if self.crv.as_deref() != Some(curve) {
+                    return false;
+                }
+                let point = [&[0x04][..], &x, &y].concat();
+                let key = UnparsedPublicKey::new(algorithm, point);
+                key.verify(message, signature).is_ok()
+            }
+            _ => false,
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct TokenResponse {
+    id_token: String,
+    access_token: Option<String>,
+}
+
+fn client() -> &'static reqwest::Client {
+    static CLIENT: OnceLock<reqwest::Client> = OnceLock::new();
+    CLIENT.get_or_init(|| reqwest::Client::builder().timeout(TIMEOUT).build().unwrap())
+}
+
+/// Provider metadata, fetched once per issuer from `/.well-known/openid-configuration`.
+async fn discovery(config: &OidcConfig) -> Result<Arc<Discovery>, String> {
+    static DISCOVERY: OnceLock<Mutex<HashMap<String, Arc<Discovery>>>> = OnceLock::new();
+    let cache = DISCOVERY.get_or_init(Default::default);
+    if let Some(discovery) = cache.lock().unwrap().get(&config.issuer) {
+        return Ok(discovery.clone());
+    }
+
+    let url = format!("{}/.well-known/openid-configuration", config.issuer);
+    let discovery: Discovery = client()
+        .get(url)
+        .send()
+        .await
+        .and_then(|response| response.error_for_status())
+        .map_err(|e| e.to_string())?
+        .json()
+        .await
+        .map_err(|e| e.to_string())?;
+    if discovery.issuer.trim_end_matches('/') != config.issuer {
+        return Err(format!("discovery is for issuer {}", discovery.issuer));
+    }
+    let discovery = Arc::new(discovery);
+    let mut cache = cache.lock().unwrap();
+    Ok(cache
+        .entry(config.issuer.clone())
+        .or_insert(discovery)
+        .clone())
+}
+
+/// Keys are fetched for every login, so rotation at the provider needs no restart.
+async fn keys(discovery: &Discovery) -> Result<Vec<Jwk>, String> {
+    let keys: JwkSet = client()
+        .get(&discovery.jwks_uri)
+        .send()
+        .await
+        .and_then(|response| response.error_for_status())
+        .map_err(|e| e.to_string())?
+        .json()
+        .await
+        .map_err(|e| e.to_string())?;
+    Ok(keys.keys)
+}
+
+/// Login started by `authorization_url`, kept until the provider redirects back.
+struct Pending {
+    verifier: String,
+    nonce: String,
+    created: Instant,
+}
+
+fn pending() -> &'static Mutex<HashMap<String, Pending>> {
+    static PENDING: OnceLock<Mutex<HashMap<String, Pending>>> = OnceLock::new();
+    PENDING.get_or_init(Default::default)
+}
+
+/**
+    Where the browser has to go to log in at the provider.
+    `state` comes back unchanged to the callback and is needed there for `complete`.
+*/
+pub async fn authorization_url(config: &OidcConfig, state: &str) -> Result<String, String> {
+    let discovery = discovery(config).await?;
+    let nonce = random_token(16);
+    let verifier = random_token(32);
+    let challenge = BASE64URL_NOPAD.encode(&Sha256::digest(verifier.as_bytes()));
+
+    let url = Url::parse_with_params(
+        &discovery.authorization_endpoint,
+        [
+            ("response_type", "code"),
+            ("client_id", &config.client_id),
+            ("redirect_uri", &config.redirect_uri),
+            ("scope", &config.scopes),
+            ("state", state),
+            ("nonce", &nonce),
+            ("code_challenge", &challenge),
+            ("code_challenge_method", "S256"),
+        ],
+    )
+    .map_err(|e| e.to_string())?;
+
+    let mut pending = pending().lock().unwrap();
+    pending.retain(|_, login| login.created.elapsed() < PENDING_TTL);
+    let login = Pending {
+        verifier,
+        nonce,
+        created: Instant::now(),
+    };
+    pending.insert(state.to_string(), login);
+    Ok(url.into())
+}
+
+/// Who logged in at the provider.
+#[derive(Clone)]
+pub struct Identity {
+    pub issuer: String,
+    pub subject: String,
+    pub email: Option<String>,
+    pub name: Option<String>,
+    pub preferred_username: Option<String>,
+    pub groups: Vec<String>,
+}
+
+impl Identity {
+    /**
+        Usernames to try for a provisioned user, first one that is free wins.
+        Derived from the preferred username or email, cleaned up to pass the `Policy`.
+    */
+    pub fn usernames(&self) -> Vec<String> {
+        let policy = Policy::current();
+        let wanted = self
+            .preferred_username
+            .as_deref()
+            .or(self
+                .email
+                .as_deref()
+                .and_then(|email| email.split('@').next()))
+            .unwrap_or_default();
+        let mut base: String = wanted
+            .to_lowercase()
+            .chars()
+            .map(|c| match c {
+                'a'..='z' | '0'..='9' | '_' | '.' | '-' => c,
+                _ => '-',
+            })
+            .skip_while(|c| !c.is_ascii_alphanumeric())
+            .take(policy.username_max_length.saturating_sub(3))
+            .collect();
+        if base.chars().count() < policy.username_min_length
+            || policy.reserved_usernames.contains(&base)
+        {
+            base = format!("user-{}", &sha256_hex(&self.subject)[..8]);
+        }
+
+        let mut usernames = vec![base.clone()];
+        usernames.extend((2..10).map(|n| format!("{base}-{n}")));
+        usernames
+    }
+}
+
+/**
+    Finishes a login the provider redirected back with.
+    Signature of the id token is checked against the provider keys,
+    then issuer, audience, expiry and nonce.
+*/
+pub async fn complete(config: &OidcConfig, code: &str, state: &str) -> Result<Identity, String> {
+    let login = pending()
+        .lock()
+        .unwrap()
+        .remove(state)
+        .filter(|login| login.created.elapsed() < PENDING_TTL)
+        .ok_or("unknown or expired state")?;
+    let discovery = discovery(config).await?;
+
+    let mut form = vec![
+        ("grant_type", "authorization_code"),
+        ("code", code),
+        ("redirect_uri", &config.redirect_uri),
+        ("client_id", &config.client_id),
+        ("code_verifier", &login.verifier),
+    ];
+    if let Some(secret) = &config.client_secret {
+        form.push(("client_secret", secret));
+    }
+    let tokens: TokenResponse = client()
+        .post(&discovery.token_endpoint)
+        .form(&form)
+        .send()
+        .await
+        .and_then(|response| response.error_for_status())
+        .map_err(|e| e.to_string())?
+        .json()
+        .await
+        .map_err(|e| e.to_string())?;
+
+    verify_signature(&tokens.id_token, &keys(&discovery).await?)?;
+    let claims = id_token_claims(&tokens.id_token)?;
+    let issuer = claims["iss"].as_str().unwrap_or_default();
+    if issuer != discovery.issuer {
+        return Err(format!("id token from issuer {issuer}"));
+    }
+    let audience_ok = match &claims["aud"] {
+        Value::String(audience) => *audience == config.client_id,
+        Value::Array(audiences) => audiences
+            .iter()
+            .any(|audience| *audience == *config.client_id),
+        _ => false,
+    };
+    if !audience_ok {
+        return Err("id token for another client".to_string());
+    }
+    if claims["exp"].as_u64().unwrap_or(0) <= now() {
+        return Err("id token expired".to_string());
+    }
+    if claims["nonce"].as_str() != Some(login.nonce.as_str()) {
+        return Err("id token nonce does not match".to_string());
+    }
+    let Some(subject) = claims["sub"].as_str() else {
+        return Err("id token without subject".to_string());
+    };
+
+    // groups and profile are often only served by the userinfo endpoint
+    let mut info = claims.clone();
+    if let (Some(endpoint), Some(access_token)) =
+        (&discovery.userinfo_endpoint, &tokens.access_token)
+    {
+        let userinfo = client()
+            .get(endpoint)
+            .bearer_auth(access_token)
+            .send()
+            .await
+            .and_then(|response| response.error_for_status());
+        if let Ok(userinfo) = userinfo {
+            if let Ok(Value::Object(userinfo)) = userinfo.json::<Value>().await {
+                if userinfo.get("sub").and_then(Value::as_str) == Some(subject) {
+                    info.as_object_mut().unwrap().extend(userinfo);
+                }
+            }
+        }
+    }
+
+    let string = |key: &str| info[key].as_str().map(str::to_string);
+    // unverified addresses could belong to someone else, so could ones without `email_verified`
+    let email = string("email").filter(|_| info["email_verified"].as_bool() == Some(true));
+    let groups = match &info[config.groups_claim.as_str()] {
+        Value::Array(groups) => groups
+            .iter()
+            .filter_map(|group| group.as_str().map(str::to_string))
+            .collect(),
+        Value::String(group) => vec![group.clone()],
+        _ => Vec::new(),
+    };
+
+    Ok(Identity {
+        issuer: issuer.to_string(),
+        subject: subject.to_string(),
+        email,
+        name: string("name"),
+        preferred_username: string("preferred_username"),
+        groups,
+    })
+}
+
+fn verify_signature(id_token: &str, keys: &[Jwk]) -> Result<(), String> {
+    let (signed, signature) = id_token.rsplit_once('.').ok_or("id token is not a jwt")?;
+    let header = signed.split('.').next().unwrap_or_default();
+    let header: Value = BASE64URL_NOPAD
+        .decode(header.trim_end_matches('=').as_bytes())
+        .ok()
+        .and_then(|header| serde_json::from_slice(&header).ok())
+        .ok_or("id token header is not json")?;
+    let signature = BASE64URL_NOPAD
+        .decode(signature.trim_end_matches('=').as_bytes())
+        .map_err(|e| e.to_string())?;
+
+    let alg = header["alg"].as_str().unwrap_or("none");
+    let kid = header["kid"].as_str();
+    let verified = keys
+        .iter()
+        .filter(|key| kid.is_none() || key.kid.as_deref() == kid)
+        .any(|key| key.verify(alg, signed.as_bytes(), &signature));
+    match verified {
+        true => Ok(()),
+        false => Err(format!("id token signature ({alg}) does not verify")),
+    }
+}
+
+fn id_token_claims(id_token: &str) -> Result<Value, String> {
+    let payload = id_token.split('.').nth(1).ok_or("id token is not a jwt")?;
+    let payload = BASE64URL_NOPAD
+        .decode(payload.trim_end_matches('=').as_bytes())
+        .map_err(|e| e.to_string())?;
+    serde_json::from_slice(&payload).map_err(|e| e.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::{extract::State, routing::get, routing::post, Json, Router};
+    use ring::rand::SystemRandom;
+    use ring::signature::{EcdsaKeyPair, KeyPair, ECDSA_P256_SHA256_FIXED_SIGNING};
+    use serde_json::json;
+
+    use super::*;
+
+    fn key_pair() -> EcdsaKeyPair {
+        let rng = SystemRandom::new();
+        let pkcs8 = EcdsaKeyPair::generate_pkcs8(&ECDSA_P256_SHA256_FIXED_SIGNING, &rng).unwrap();
+        EcdsaKeyPair::from_pkcs8(&ECDSA_P256_SHA256_FIXED_SIGNING, pkcs8.as_ref(), &rng).unwrap()
+    }
+
+    fn sign(key: &EcdsaKeyPair, claims: &Value) -> String {
+        let encode = |value: &Value| BASE64URL_NOPAD.encode(value.to_string().as_bytes());
+        let header = json!({ "alg": "ES256", "kid": "key-1" });
+        let signed = format!("{}.{}", encode(&header), encode(claims));
+        let signature = key.sign(&SystemRandom::new(), signed.as_bytes()).unwrap();
+        format!("{signed}.{}", BASE64URL_NOPAD.encode(signature.as_ref()))
+    }
+
+    /// Provider on a local port, `claims` go into the next id token unless `id_token` is set.
+    struct StandIn {
+        issuer: String,
+        key: EcdsaKeyPair,
+        claims: Mutex<Value>,
+        id_token: Mutex<Option<String>>,
+    }
+
+    async fn stand_in() -> (OidcConfig, Arc<StandIn>) {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let issuer = format!("http://{}", listener.local_addr().unwrap());
+        let provider = Arc::new(StandIn {
+            issuer: issuer.clone(),
+            key: key_pair(),
+            claims: Mutex::new(Value::Null),
+            id_token: Mutex::new(None),
+        });
+
+        let discovery = |State(provider): State<Arc<StandIn>>| async move {
+            let issuer = &provider.issuer;
+            Json(json!({
+                "issuer": issuer,
+                "authorization_endpoint": format!("{issuer}/authorize"),
+                "token_endpoint": format!("{issuer}/token"),
+                "jwks_uri": format!("{issuer}/jwks"),
+            }))
+        };
+        let jwks = |State(provider): State<Arc<StandIn>>| async move {
+            let point = provider.key.public_key().as_ref();
+            Json(json!({ "keys": [{
+                "kty": "EC",
+                "kid": "key-1",
+                "crv": "P-256",
+                "x": BASE64URL_NOPAD.encode(&point[1..33]),
+                "y": BASE64URL_NOPAD.encode(&point[33..]),
+            }] }))
+        };
+        let token = |State(provider): State<Arc<StandIn>>| async move {
+            let forged = provider.id_token.lock().unwrap().clone();
+            let claims = provider.claims.lock().unwrap().clone();
+            let id_token = forged.unwrap_or_else(|| sign(&provider.key, &claims));
+            Json(json!({ "id_token": id_token }))
+        };
+        let app = Router::new()
+            .route("/.well-known/openid-configuration", get(discovery))
+            .route("/jwks", get(jwks))
+            .route("/token", post(token))
+            .with_state(provider.clone());
+        tokio::spawn(async move { axum::serve(listener, app).await });
+
+        let config = OidcConfig {
+            issuer,
+            client_id: "report-generator".to_string(),
+            client_secret: None,
+            redirect_uri: "http://localhost/v1/oidc/callback".to_string(),
+            scopes: "openid".to_string(),
+            groups_claim: "groups".to_string(),
+            admin_groups: Vec::new(),
+            provision_users: true,
+        };
+        (config, provider)
+    }
+
+    /// Starts a login with `state`, returns claims the provider would put into the id token.
+    async fn start(config: &OidcConfig, state: &str) -> Value {
+        let url = authorization_url(config, state).await.unwrap();
+        let url = Url::parse(&url).unwrap();
+        let nonce = url.query_pairs().find(|(key, _)| key == "nonce").unwrap().1;
+        json!({
+            "iss": config.issuer,
+            "aud": config.client_id,
+            "sub": "subject-1",
+            "exp": now() + 60,
+            "nonce": nonce,
+            "email": "jane@example.com",
+            "email_verified": true,
+        })
+    }
+
+    #[tokio::test]
+    async fn signed_login_completes_once() {
+        let (config, provider) = stand_in().await;
+        *provider.claims.lock().unwrap() = start(&config, "state-1").await;
+
+        let identity = complete(&config, "code", "state-1").await.unwrap();
+        assert_eq!(identity.subject, "subject-1");
+        assert_eq!(identity.email.as_deref(), Some("jane@example.com"));
+        let again = complete(&config, "code", "state-1").await;
+        assert_eq!(again.err().as_deref(), Some("unknown or expired state"));
+    }
+
+    #[tokio::test]
+    async fn unknown_state_is_refused() {
+        let (config, provider) = stand_in().await;
+        *provider.claims.lock().unwrap() = start(&config, "state-1").await;
+
+        assert!(complete(&config, "code", "state-2").await.is_err());
+    }
+
+    #[tokio::test]
+    async fn nonce_and_audience_must_match() {
+        let (config, provider) = stand_in().await;
+        let mut claims = start(&config, "state-1").await;
+        claims["nonce"] = json!("replayed");
+        *provider.claims.lock().unwrap() = claims;
+        let replayed = complete(&config, "code", "state-1").await;
+        assert_eq!(
+            replayed.err().as_deref(),
+            Some("id token nonce does not match")
+        );
+
+        let mut claims = start(&config, "state-2").await;
+        claims["aud"] = json!(["another-client"]);
+        *provider.claims.lock().unwrap() = claims;
+        let other = complete(&config, "code", "state-2").await;
+        assert_eq!(other.err().as_deref(), Some("id token for another client"));
+    }
+
+    #[tokio::test]
+    async fn unsigned_and_foreign_tokens_are_refused() {
+        let (config, provider) = stand_in().await;
+        let claims = start(&config, "state-1").await;
+        let encode = |value: &Value| BASE64URL_NOPAD.encode(value.to_string().as_bytes());
+        let unsigned = format!("{}.{}.", encode(&json!({ "alg": "none" })), encode(&claims));
+        *provider.id_token.lock().unwrap() = Some(unsigned);
+        let error = complete(&config, "code", "state-1").await.err().unwrap();
+        assert!(error.contains("signature"), "{error}");
+
+        let claims = start(&config, "state-2").await;
+        *provider.id_token.lock().unwrap() = Some(sign(&key_pair(), &claims));
+        let error = complete(&config, "code", "state-2").await.err().unwrap();
+        assert!(error.contains("signature"), "{error}");
+    }
+}
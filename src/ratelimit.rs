@@ -0,0 +1,308 @@
+use std::{
+    collections::HashMap,
+    env,
+    hash::Hash,
+    net::{IpAddr, Ipv4Addr, SocketAddr},
+    sync::{Arc, Mutex, OnceLock},
+    time::{Duration, Instant},
+};
+
+use axum::{
+    async_trait,
+    extract::{ConnectInfo, FromRequestParts, Request, State},
+    http::{header, request::Parts, StatusCode},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+
+use crate::handlers::auth::ERROR;
+
+/// Maps are swept of idle entries once they grow past this.
+const SWEEP_AT: usize = 10_000;
+
+/// Address of the peer, `0.0.0.0` when the server runs without connect info.
+pub struct ClientIp(pub IpAddr);
+
+#[async_trait]
+impl<S: Send + Sync> FromRequestParts<S> for ClientIp {
+    type Rejection = StatusCode;
+
+    async fn from_request_parts(parts: &mut Parts, _state: &S) -> Result<Self, Self::Rejection> {
+        let ip = parts
+            .extensions
+            .get::<ConnectInfo<SocketAddr>>()
+            .map(|ConnectInfo(addr)| addr.ip())
+            .unwrap_or(IpAddr::V4(Ipv4Addr::UNSPECIFIED));
+        Ok(ClientIp(ip))
+    }
+}
+
+/// `capacity` requests per `period`, bursts up to `capacity` are allowed.
+#[derive(Clone, Copy, Debug)]
+pub struct Limit {
+    pub capacity: u32,
+    pub period: Duration,
+}
+
+impl Limit {
+    pub const fn per_minute(capacity: u32) -> Self {
+        Self {
+            capacity,
+            period: Duration::from_secs(60),
+        }
+    }
+
+    /// Reads `<requests>/<seconds>` from `key`, e.g. `RATE_LIMIT_API=300/60`.
+    fn from_env(key: &str, default: Limit) -> Self {
+        let Ok(value) = env::var(key) else {
+            return default;
+        };
+        let parsed = value.split_once('/').and_then(|(capacity, secs)| {
+            let capacity = capacity.trim().parse().ok()?;
+            let secs = secs.trim().parse().ok().filter(|secs| *secs > 0)?;
+            Some(Limit {
+                capacity,
+                period: Duration::from_secs(secs),
+            })
+        });
+        parsed.unwrap_or_else(|| {
+            println!("ignoring invalid {key}={value}, expected <requests>/<seconds>");
+            default
+        })
+    }
+}
+
+/**
+    Limits of the route groups, see `routes::v1`.
+    Overridden with `RATE_LIMIT_AUTH`, `RATE_LIMIT_ADMIN`, `RATE_LIMIT_API` and `RATE_LIMIT_SHARED`.
+*/
+pub struct Limits {
+    pub auth: Limit,
+    pub admin: Limit,
+    pub api: Limit,
+    pub shared: Limit,
+}
+
+impl Limits {
+    pub fn from_env() -> Self {
+        Self {
+            auth: Limit::from_env("RATE_LIMIT_AUTH", Limit::per_minute(10)),
+            admin: Limit::from_env("RATE_LIMIT_ADMIN", Limit::per_minute(60)),
+            api: Limit::from_env("RATE_LIMIT_API", Limit::per_minute(300)),
+            shared: Limit::from_env("RATE_LIMIT_SHARED", Limit::per_minute(60)),
+        }
+    }
+}
+
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+/// Token bucket per client ip.
+pub struct RateLimiter {
+    limit: Limit,
+    buckets: Mutex<HashMap<IpAddr, Bucket>>,
+}
+
+impl RateLimiter {
+    pub fn new(limit: Limit) -> Self {
+        Self {
+            limit,
+            buckets: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Takes a token, or returns how long until the next one is available.
+    pub fn check(&self, ip: IpAddr) -> Result<(), Duration> {
+        let capacity = self.limit.capacity as f64;
+        let per_sec = capacity / self.limit.period.as_secs_f64();
+        let now = Instant::now();
+        let mut buckets = self.buckets.lock().unwrap();
+
+        if buckets.len() > SWEEP_AT {
+            // a bucket that would be full again carries no information
+            buckets.retain(|_, bucket| {
+                bucket.tokens + now.duration_since(bucket.updated).as_secs_f64() * per_sec
+                    < capacity
+            });
+        }
+
+        let bucket = buckets.entry(ip).or_insert(Bucket {
+            tokens: capacity,
+            updated: now,
+        });
+        let elapsed = now.duration_since(bucket.updated).as_secs_f64();
+        bucket.tokens = (bucket.tokens + elapsed * per_sec).min(capacity);
+        bucket.updated = now;
+
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            Ok(())
+        } else {
+            Err(Duration::from_secs_f64((1.0 - bucket.tokens) / per_sec))
+        }
+    }
+}
+
+/// Middleware for a route group, use with `middleware::from_fn_with_state`.
+pub async fn rate_limit(
+    State(limiter): State<Arc<RateLimiter>>,
+    ClientIp(ip): ClientIp,
+    request: Request,
+    next: Next,
+) -> Response {
+    match limiter.check(ip) {
+        Ok(()) => next.run(request).await,
+        Err(retry_after) => too_many_requests(retry_after),
+    }
+}
+
+pub fn too_many_requests(retry_after: Duration) -> Response {
+    let secs = retry_after.as_secs_f64().ceil().max(1.0) as u64;
+    (
+        StatusCode::TOO_MANY_REQUESTS,
+        [(header::RETRY_AFTER, secs.to_string())],
+        ERROR,
+    )
+        .into_response()
+}
+
+struct Failures {
+    count: u32,
+    last: Instant,
+    locked_until: Option<Instant>,
+}
+
+/**
+    Failed attempts per key with exponential lockout.
+    First `free` failures are not punished, after that every failure
+    doubles the lockout starting at `base`, up to `max`.
+*/
+struct Lockout<K> {
+    free: u32,
+    base: Duration,
+    max: Duration,
+    failures: Mutex<HashMap<K, Failures>>,
+}
+
+/// Failures are forgotten after this long without a new one.
+const FORGET_AFTER: Duration = Duration::from_secs(60 * 60);
+
+impl<K: Hash + Eq> Lockout<K> {
+    fn new(free: u32, base: Duration, max: Duration) -> Self {
+        Self {
+            free,
+            base,
+            max,
+            failures: Mutex::new(HashMap::new()),
+        }
+    }
+
+    fn check(&self, key: &K) -> Result<(), Duration> {
+        let now = Instant::now();
+        let failures = self.failures.lock().unwrap();
+        match failures.get(key).and_then(|f| f.locked_until) {
+            Some(until) if until > now => Err(until - now),
+            _ => Ok(()),
+        }
+    }
+
+    fn failed(&self, key: K) {
+        let now = Instant::now();
+        let mut failures = self.failures.lock().unwrap();
+        if failures.len() > SWEEP_AT {
+            failures.retain(|_, f| now.duration_since(f.last) < FORGET_AFTER);
+        }
+
+        let entry = failures.entry(key).or_insert(Failures {
+            count: 0,
+            last: now,
+            locked_until: None,
+        });
+        if now.duration_since(entry.last) >= FORGET_AFTER {
+            entry.count = 0;
+        }
+        entry.count += 1;
+        entry.last = now;
+
+        if entry.count > self.free {
+            let exponent = (entry.count - self.free - 1).min(20);
+            let lockout = self.base.saturating_mul(1 << exponent).min(self.max);
+            entry.locked_until = Some(now + lockout);
+        }
+    }
+
+    fn clear(&self, key: &K) {
+        self.failures.lock().unwrap().remove(key);
+    }
+}
+
+/**
+    Brute-force protection of `/login`, by client ip and by username.
+    Ip gets more free attempts since many users can share one address.
+*/
+pub struct LoginThrottle {
+    by_ip: Lockout<IpAddr>,
+    by_username: Lockout<String>,
+}
+
+impl LoginThrottle {
+    pub fn current() -> &'static LoginThrottle {
+        static THROTTLE: OnceLock<LoginThrottle> = OnceLock::new();
+        THROTTLE.get_or_init(|| LoginThrottle {
+            by_ip: Lockout::new(20, Duration::from_secs(1), Duration::from_secs(15 * 60)),
+            by_username: Lockout::new(5, Duration::from_secs(2), Duration::from_secs(15 * 60)),
+        })
+    }
+
+    /// Returns remaining lockout if either the ip or the username is locked.
+    pub fn check(&self, ip: IpAddr, username: &str) -> Result<(), Duration> {
+        let by_ip = self.by_ip.check(&ip);
+        let by_username = self.by_username.check(&username.to_lowercase());
+        match (by_ip, by_username) {
+            (Err(a), Err(b)) => Err(a.max(b)),
+            (Err(wait), _) | (_, Err(wait)) => Err(wait),
+            _ => Ok(()),
+        }
+    }
+
+    pub fn failed(&self, ip: IpAddr, username: &str) {
+        self.by_ip.failed(ip);
+        self.by_username.failed(username.to_lowercase());
+    }
+
+    /// Only the username is cleared, one valid account must not unlock the whole ip.
+    pub fn succeeded(&self, username: &str) {
+        self.by_username.clear(&username.to_lowercase());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn lockout_starts_after_free_failures_and_doubles() {
+        let lockout = Lockout::new(3, Duration::from_secs(2), Duration::from_secs(10));
+        let locked_for = |lockout: &Lockout<&str>| match lockout.check(&"alice") {
+            Ok(()) => Duration::ZERO,
+            Err(remaining) => remaining,
+        };
+
+        for _ in 0..3 {
+            lockout.failed("alice");
+            assert_eq!(locked_for(&lockout), Duration::ZERO);
+        }
+        for expected in [2, 4, 8, 10, 10] {
+            lockout.failed("alice");
+            let remaining = locked_for(&lockout);
+            assert!(remaining <= Duration::from_secs(expected));
+            assert!(remaining > Duration::from_secs(expected) - Duration::from_secs(1));
+        }
+        assert_eq!(lockout.check(&"bob"), Ok(()));
+
+        lockout.clear(&"alice");
+        assert_eq!(locked_for(&lockout), Duration::ZERO);
+    }
+}
@@ -0,0 +1,317 @@
+use axum::{
+    extract::Request,
+    http::{header, HeaderValue, Uri},
+    middleware::{self, Next},
+    response::Response,
+    routing::{delete, get, patch, post, put},
+    Router,
+};
+use std::sync::Arc;
+
+use crate::crypto::random_token;
+use crate::db::{audit::REQUEST_ID, Db};
+use crate::handlers::apps::{self, all_apps, new_app};
+use crate::handlers::auth::{login, login_two_factor, register};
+use crate::handlers::tokens::token_scopes;
+use crate::handlers::{
+    access_tokens, admin, app_users, artifacts, audit, broker_pools, brokers, events, jobs, me,
+    oidc, operators, passwords, schedules, share_links, trash, two_factor, users, webhooks,
+};
+use crate::ratelimit::{rate_limit, Limits, RateLimiter};
+
+const REQUEST_ID_HEADER: &str = "x-request-id";
+
+/// Date after which the unprefixed legacy routes may be removed.
+pub const LEGACY_SUNSET: &str = "Fri, 01 Jan 2027 00:00:00 GMT";
+
+/**
+    Whole api surface.
+
+    `/v1/...` is the current api, `/v2/...` only contains routes
+    that changed since v1 and falls back to v1 for everything else.
+    Unprefixed paths are kept as deprecated aliases of v1.
+*/
+pub fn app(db: Db) -> Router {
+    let v1 = v1().with_state(db.clone());
+    let v2 = v2().with_state(db.clone()).fallback_service(v1.clone());
+    let legacy = v1.clone().layer(middleware::from_fn(deprecated));
+
+    Router::new()
+        .nest("/v1", v1)
+        .nest("/v2", v2)
+        .merge(legacy)
+        .layer(middleware::from_fn(request_id))
+}
+
+pub fn v1() -> Router<Db> {
+    let app_users_router = Router::new()
+        .route("/", get(app_users::all))
+        .route("/", post(app_users::create))
+        .route("/:user_id", delete(app_users::delete))
+        .route("/:user_id/restore", post(trash::app_user));
+
+    let pools_router = Router::new()
+        .route("/", get(broker_pools::all).post(broker_pools::create))
+        .route(
+            "/:pool_id",
+            get(broker_pools::get)
+                .patch(broker_pools::update)
+                .delete(broker_pools::delete),
+        )
+        .route("/:pool_id/start", post(broker_pools::start))
+        .route("/:pool_id/stop", post(broker_pools::stop))
+        .route(
+            "/:pool_id/config",
+            get(broker_pools::config).put(broker_pools::save_config),
+        )
+        .route(
+            "/:pool_id/config/versions",
+            get(broker_pools::config_versions),
+        )
+        .route("/:pool_id/brokers", get(broker_pools::brokers))
+        .route(
+            "/:pool_id/brokers/:broker_id",
+            put(broker_pools::add_broker).delete(broker_pools::remove_broker),
+        );
+
+    let brokers_router = Router::new()
+        .route("/", get(brokers::all))
+        .route("/", post(brokers::create))
+        .route(
+            "/config",
+            get(brokers::app_config).put(brokers::save_app_config),
+        )
+        .route("/config/versions", get(brokers::app_config_versions))
+        .route("/versions", get(brokers::versions))
+        .nest("/pools", pools_router)
+        .route(
+            "/:broker_id",
+            patch(brokers::update).delete(brokers::delete),
+        )
+        .route("/:broker_id/start", post(brokers::start))
+        .route("/:broker_id/stop", post(brokers::stop))
+        .route(
+            "/:broker_id/config",
+            get(brokers::broker_config).put(brokers::save_broker_config),
+        )
+        .route(
+            "/:broker_id/config/versions",
+            get(brokers::broker_config_versions),
+        )
+        .route(
+            "/:broker_id/jobs/:job_id/artifacts",
+            post(artifacts::upload),
+        )
+        .route("/:broker_id/logs", get(brokers::logs))
+        .route("/:broker_id/logs/tail", get(brokers::tail))
+        .route("/:broker_id/connect", get(brokers::connect))
+        .route("/:broker_id/restore", post(trash::broker));
+
+    let jobs_router = Router::new()
+        .route("/", get(jobs::all).post(jobs::create))
+        .route("/:job_id", get(jobs::get))
+        .route("/:job_id/cancel", post(jobs::cancel));
+
+    let schedules_router = Router::new()
+        .route("/", get(schedules::all).post(schedules::create))
+        .route(
+            "/:schedule_id",
+            get(schedules::get)
+                .put(schedules::update)
+                .delete(schedules::delete),
+        )
+        .route("/:schedule_id/pause", post(schedules::pause))
+        .route("/:schedule_id/resume", post(schedules::resume))
+        .route("/:schedule_id/runs", get(schedules::runs));
+
+    let artifacts_router = Router::new()
+        .route("/", get(artifacts::all))
+        .route("/usage", get(artifacts::usage))
+        .route("/retention", put(artifacts::set_retention))
+        .route(
+            "/:artifact_id",
+            get(artifacts::get).delete(artifacts::delete),
+        )
+        .route("/:artifact_id/content", get(artifacts::download))
+        .route(
+            "/:artifact_id/shares",
+            get(share_links::all).post(share_links::create),
+        )
+        .route(
+            "/:artifact_id/shares/:share_id",
+            delete(share_links::revoke),
+        );
+
+    let webhooks_router = Router::new()
+        .route("/", get(webhooks::all))
+        .route("/", post(webhooks::create))
+        .route("/:webhook_id", delete(webhooks::delete))
+        .route("/:webhook_id/deliveries", get(webhooks::deliveries))
+        .route(
+            "/:webhook_id/deliveries/:delivery_id/redeliver",
+            post(webhooks::redeliver),
+        );
+
+    // every group has its own bucket per client ip, shared by all its routes
+    let limits = Limits::from_env();
+    let auth_router = Router::new()
+        .route("/register", post(register))
+        .route("/login", post(login))
+        .route("/login/2fa", post(login_two_factor))
+        .route("/password-reset", post(passwords::request_reset))
+        .route("/password-reset/confirm", post(passwords::confirm_reset))
+        .route("/oidc/login", get(oidc::login))
+        .route("/oidc/callback", get(oidc::callback))
+        .route_layer(middleware::from_fn_with_state(
+            Arc::new(RateLimiter::new(limits.auth)),
+            rate_limit,
+        ));
+
+    // no account needed, the signed token is the permission
+    let shared_router = Router::new()
+        .route("/shared/:token", get(share_links::download))
+        .route_layer(middleware::from_fn_with_state(
+            Arc::new(RateLimiter::new(limits.shared)),
+            rate_limit,
+        ));
+
+    let admin_router = Router::new()
+        .route("/admin/backups", get(admin::backups))
+        .route("/admin/backups", post(admin::backup))
+        .route("/admin/backups/:name/restore", post(admin::restore))
+        .route("/admin/export", get(admin::export))
+        .route("/admin/import", post(admin::import))
+        .route("/admin/audit", get(audit::all))
+        .route(
+            "/admin/apps/:app_id/artifact-quota",
+            put(artifacts::set_quota),
+        )
+        .route(
+            "/admin/users/:username/password-reset",
+            post(passwords::admin_reset),
+        )
+        .route_layer(middleware::from_fn_with_state(
+            Arc::new(RateLimiter::new(limits.admin)),
+            rate_limit,
+        ))
+        .route_layer(middleware::from_fn(token_scopes));
+
+    let api_router = Router::new()
+        .route("/me", get(me::get).patch(me::update).delete(me::delete))
+        .route("/me/apps", get(me::apps))
+        .route(
+            "/me/tokens",
+            get(access_tokens::all).post(access_tokens::create),
+        )
+        .route("/me/tokens/:token_id", delete(access_tokens::revoke))
+        .route("/me/identities", get(oidc::identities))
+        .route("/me/identities/oidc", post(oidc::link))
+        .route("/me/identities/:identity_id", delete(oidc::unlink))
+        .route("/me/password", post(passwords::change))
+        .route("/2fa/enroll", post(two_factor::enroll))
+        .route("/2fa/confirm", post(two_factor::confirm))
+        .route("/2fa/recovery-codes", post(two_factor::regenerate))
+        .route("/2fa/disable", post(two_factor::disable))
+        .route("/users/search", get(users::search))
+        .route("/apps/:app_id/operators", get(operators::all))
+        .route("/apps/:app_id/operators", post(operators::create))
+        .route(
+            "/apps/:app_id/operators/:operator_id",
+            delete(operators::delete),
+        )
+        .route(
+            "/apps/:app_id/operators/:operator_id/restore",
+            post(trash::operator),
+        )
+        .nest("/apps/:app_id/users", app_users_router)
+        .nest("/apps/:app_id/brokers", brokers_router)
+        .nest("/apps/:app_id/jobs", jobs_router)
+        .nest("/apps/:app_id/schedules", schedules_router)
+        .nest("/apps/:app_id/artifacts", artifacts_router)
+        .nest("/apps/:app_id/webhooks", webhooks_router)
+        .route("/apps/:app_id", patch(apps::update))
+        .route("/apps/:app_id/restore", post(trash::app))
+        .route("/apps/:app_id/info", get(apps::by_id))
+        .route("/apps/:app_id/events", get(events::stream))
+        .route("/apps/:app_id/audit", get(audit::for_app))
+        .route("/apps/search", get(apps::search))
+        .route("/apps", get(all_apps))
+        .route("/apps", post(new_app))
+        .route_layer(middleware::from_fn_with_state(
+            Arc::new(RateLimiter::new(limits.api)),
+            rate_limit,
+        ))
+        .route_layer(middleware::from_fn(token_scopes));
+
+    Router::new()
+        .merge(auth_router)
+        .merge(shared_router)
+        .merge(admin_router)
+        .merge(api_router)
+}
+
+/**
+    Routes whose behaviour differs from v1.
+    Register a v2 handler here to override a single v1 route,
+    everything that is not listed is served by v1.
+*/
+pub fn v2() -> Router<Db> {
+    Router::new()
+}
+
+/**
+    Strips trailing slash, so `/apps/` and `/apps` hit the same route.
+    Has to run before routing, see `main`.
+*/
+pub async fn normalize_path(mut request: Request, next: Next) -> Response {
+    let path = request.uri().path();
+    if path.len() > 1 && path.ends_with('/') {
+        let trimmed = path.trim_end_matches('/');
+        let trimmed = if trimmed.is_empty() { "/" } else { trimmed };
+        let path_and_query = match request.uri().query() {
+            Some(query) => format!("{trimmed}?{query}"),
+            None => trimmed.to_string(),
+        };
+
+        let mut parts = request.uri().clone().into_parts();
+        parts.path_and_query = path_and_query.parse().ok();
+        if let Ok(uri) = Uri::from_parts(parts) {
+            *request.uri_mut() = uri;
+        }
+    }
+    next.run(request).await
+}
+
+/**
+    Uses `x-request-id` of the client or makes a new one.
+    Id is available to db code through `REQUEST_ID` and echoed back in the response.
+*/
+async fn request_id(request: Request, next: Next) -> Response {
+    let id = request
+        .headers()
+        .get(REQUEST_ID_HEADER)
+        .and_then(|value| value.to_str().ok())
+        .filter(|value| !value.is_empty() && value.len() <= 128)
+        .map(str::to_string)
+        .unwrap_or_else(|| random_token(16));
+
+    let mut response = REQUEST_ID.scope(id.clone(), next.run(request)).await;
+    if let Ok(value) = HeaderValue::from_str(&id) {
+        response.headers_mut().insert(REQUEST_ID_HEADER, value);
+    }
+    response
+}
+
+/// Marks response of an unprefixed route as deprecated and points to the v1 route.
+async fn deprecated(request: Request, next: Next) -> Response {
+    let successor = format!("</v1{}>; rel=\"successor-version\"", request.uri().path());
+    let mut response = next.run(request).await;
+
+    let headers = response.headers_mut();
+    headers.insert("deprecation", HeaderValue::from_static("true"));
+    headers.insert("sunset", HeaderValue::from_static(LEGACY_SUNSET));
+    if let Ok(link) = HeaderValue::from_str(&successor) {
+        headers.insert(header::LINK, link);
+    }
+    response
+}
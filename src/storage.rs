@@ -0,0 +1,470 @@
+use std::{
+    collections::HashMap,
+    env,
+    io::{ErrorKind, SeekFrom},
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex, OnceLock},
+};
+
+use axum::{async_trait, body::Body};
+use jiff::Timestamp;
+use reqwest::{Client, StatusCode, Url};
+use tokio::{
+    fs::{self, File},
+    io::{AsyncReadExt, AsyncSeekExt},
+    sync::{Mutex as AsyncMutex, OwnedMutexGuard},
+};
+use tokio_util::io::ReaderStream;
+
+use crate::crypto::{hmac_sha256, sha256_hex};
+
+/// sha256 of nothing, the payload hash of requests without a body.
+const EMPTY_SHA256: &str = "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855";
+
+/**
+    Where artifact contents live. Keys are the sha256 of the content,
+    so storing the same content twice keeps a single copy.
+    Ranges are inclusive, like in the `Range` header.
+*/
+#[async_trait]
+pub trait ArtifactStore: Send + Sync {
+    /// Moves the staged file into the store, its `sha256` has to be `key`.
+    async fn put(&self, key: &str, staged: &Path, size: u64) -> Result<(), String>;
+    async fn get(&self, key: &str, range: Option<(u64, u64)>) -> Result<Body, String>;
+    /// Deleting a missing key is not an error.
+    async fn delete(&self, key: &str) -> Result<(), String>;
+}
+
+/**
+    Store configured by the environment.
+    `ARTIFACT_S3_ENDPOINT` with `ARTIFACT_S3_BUCKET`, `ARTIFACT_S3_ACCESS_KEY`
+    and `ARTIFACT_S3_SECRET_KEY` stores in an S3 compatible service,
+    `ARTIFACT_S3_REGION` defaults to `us-east-1`. Otherwise contents are kept in
+    `ARTIFACT_DIR` (default `artifacts`), which also holds uploads being received.
+*/
+pub fn store() -> &'static dyn ArtifactStore {
+    static STORE: OnceLock<Box<dyn ArtifactStore>> = OnceLock::new();
+    STORE.get_or_init(from_env).as_ref()
+}
+
+fn from_env() -> Box<dyn ArtifactStore> {
+    if let Ok(endpoint) = env::var("ARTIFACT_S3_ENDPOINT") {
+        match S3Store::from_env(&endpoint) {
+            Some(s3) => return Box::new(s3),
+            None => println!("ignoring incomplete ARTIFACT_S3_* settings"),
+        }
+    }
+    Box::new(LocalStore {
+        root: artifact_dir().join("objects"),
+    })
+}
+
+fn artifact_dir() -> PathBuf {
+    env::var("ARTIFACT_DIR")
+        .unwrap_or_else(|_| "artifacts".to_string())
+        .into()
+}
+
+/// Uploads are written here while their checksum is computed.
+pub fn staging_dir() -> PathBuf {
+    artifact_dir().join("staging")
+}
+
+/**
+    Held while content is stored or removed, so removing content no artifact
+    refers to can't interleave with an upload of the same content.
+*/
+pub struct KeyLock {
+    key: String,
+    _guard: OwnedMutexGuard<()>,
+}
+
+fn key_locks() -> &'static Mutex<HashMap<String, Arc<AsyncMutex<()>>>> {
+    static LOCKS: OnceLock<Mutex<HashMap<String, Arc<AsyncMutex<()>>>>> = OnceLock::new();
+    LOCKS.get_or_init(Default::default)
+}
+
+/// Waits until nobody else stores or removes the content `key`.
+pub async fn lock_key(key: &str) -> KeyLock {
+    let lock = key_locks()
+        .lock()
+        .unwrap()
+        .entry(key.to_string())
+        .or_default()
+        .clone();
+    KeyLock {
+        key: key.to_string(),
+        _guard: lock.lock_owned().await,
+    }
+}
+
+impl Drop for KeyLock {
+    fn drop(&mut self) {
+        let mut locks = key_locks().lock().unwrap();
+        // the map and this guard are the only holders when nobody is waiting
+        if locks
+            .get(&self.key)
+            .is_some_and(|lock| Arc::strong_count(lock) == 2)
+        {
+            locks.remove(&self.key);
+        }
+    }
+}
+
+/// Streams `length` bytes of the file from `start`.
+async fn file_body(path: &Path, start: u64, length: u64) -> Result<Body, String> {
+    let mut file = File::open(path).await.map_err(|e| e.to_string())?;
+    file.seek(SeekFrom::Start(start))
+        .await
+        .map_err(|e| e.to_string())?;
+    Ok(Body::from_stream(ReaderStream::new(file.take(length))))
+}
+
+/// Content addressed files below `root`, `ab/cd/abcd…` keeps directories small.
+pub struct LocalStore {
+    pub root: PathBuf,
+}
+
+impl LocalStore {
+    fn path(&self, key: &str) -> PathBuf {
+        self.root.join(&key[..2]).join(&key[2..4]).join(key)
+    }
+}
+
+#[async_trait]
+impl ArtifactStore for LocalStore {
+    async fn put(&self, key: &str, staged: &Path, _size: u64) -> Result<(), String> {
+        let path = self.path(key);
+        if fs::try_exists(&path).await.unwrap_or(false) {
+            return fs::remove_file(staged).await.map_err(|e| e.to_string());
+        }
+        fs::create_dir_all(path.parent().unwrap())
+            .await
+            .map_err(|e| e.to_string())?;
+        fs::rename(staged, &path).await.map_err(|e| e.to_string())
+    }
+
+    async fn get(&self, key: &str, range: Option<(u64, u64)>) -> Result<Body, String> {
+        let path = self.path(key);
+        let (start, end) = match range {
+            Some(range) => range,
+            None => {
+                let size = fs::metadata(&path).await.map_err(|e| e.to_string())?.len();
+                (0, size.saturating_sub(1))
+            }
+        };
+        file_body(&path, start, end + 1 - start).await
+    }
+
+    async fn delete(&self, key: &str) -> Result<(), String> {
+        match fs::remove_file(self.path(key)).await {
+            Err(e) if e.kind() != ErrorKind::NotFound => Err(e.to_string()),
+            _ => Ok(()),
+        }
+    }
+}
+
+/**
+    Bucket of an S3 compatible service, addressed path style so local
+    stand-ins like MinIO work without DNS. Requests are signed with AWS signature v4.
+*/
+pub struct S3Store {
+    pub endpoint: Url,
+    pub bucket: String,
+    pub region: String,
+    pub access_key: String,
+    pub secret_key: String,
+    client: Client,
+}
+
+impl S3Store {
+    fn from_env(endpoint: &str) -> Option<Self> {
+        Some(Self {
+            endpoint: Url::parse(endpoint).ok()?,
+            bucket: env::var("ARTIFACT_S3_BUCKET").ok()?,
+            region: env::var("ARTIFACT_S3_REGION").unwrap_or_else(|_| "us-east-1".to_string()),
+            access_key: env::var("ARTIFACT_S3_ACCESS_KEY").ok()?,
+            secret_key: env::var("ARTIFACT_S3_SECRET_KEY").ok()?,
+            client: Client::new(),
+        })
+    }
+
+    fn path(&self, key: &str) -> String {
+        format!("/{}/{}", uri_encode(&self.bucket), uri_encode(key))
+    }
+
+    /// Request with the headers of a v4 signature, `payload_hash` is the hex sha256 of the body.
+    fn request(
+        &self,
+        method: reqwest::Method,
+        key: &str,
+        payload_hash: &str,
+    ) -> reqwest::RequestBuilder {
+        let path = self.path(key);
+        let host = match self.endpoint.port() {
+            Some(port) => format!("{}:{port}", self.endpoint.host_str().unwrap_or_default()),
+            None => self.endpoint.host_str().unwrap_or_default().to_string(),
+        };
+        let now = Timestamp::now();
+        let amz_date = now.strftime("%Y%m%dT%H%M%SZ").to_string();
+        let date = &amz_date[..8];
+
+        let signed_headers = "host;x-amz-content-sha256;x-amz-date";
+        let canonical_request = format!(
+            "{method}\n{path}\n\nhost:{host}\nx-amz-content-sha256:{payload_hash}\nx-amz-date:{amz_date}\n\n{signed_headers}\n{payload_hash}"
+        );
+        let scope = format!("{date}/{}/s3/aws4_request", self.region);
+        let string_to_sign = format!(
+            "AWS4-HMAC-SHA256\n{amz_date}\n{scope}\n{}",
+            sha256_hex(&canonical_request)
+        );
+        let key = [date, &self.region, "s3", "aws4_request"].iter().fold(
+            format!("AWS4{}", self.secret_key).into_bytes(),
+            |key, part| hmac_sha256(&key, part.as_bytes()),
+        );
+        let signature = hex::encode(hmac_sha256(&key, string_to_sign.as_bytes()));
+        let authorization = format!(
+            "AWS4-HMAC-SHA256 Credential={}/{scope}, SignedHeaders={signed_headers}, Signature={signature}",
+            self.access_key
+        );
+
+        let mut url = self.endpoint.clone();
+        url.set_path(&path);
+        self.client
+            .request(method, url)
+            .header("x-amz-content-sha256", payload_hash)
+            .header("x-amz-date", amz_date)
+            .header("authorization", authorization)
+    }
+}
+
+#[async_trait]
+impl ArtifactStore for S3Store {
+    async fn put(&self, key: &str, staged: &Path, size: u64) -> Result<(), String> {
+        let file = File::open(staged).await.map_err(|e| e.to_string())?;
+        let response = self
+            .request(reqwest::Method::PUT, key, key)
+            .header("content-length", size)
+            .body(reqwest::Body::wrap_stream(ReaderStream::new(file)))
+            .send()
+            .await
+            .map_err(|e| e.to_string())?;
+        if !response.status().is_success() {
+            return Err(format!("s3 put of {key} returned {}", response.status()));
+        }
+        fs::remove_file(staged).await.map_err(|e| e.to_string())
+    }
+
+    async fn get(&self, key: &str, range: Option<(u64, u64)>) -> Result<Body, String> {
+        let mut request = self.request(reqwest::Method::GET, key, EMPTY_SHA256);
+        if let Some((start, end)) = range {
+            request = request.header("range", format!("bytes={start}-{end}"));
+        }
+        let response = request.send().await.map_err(|e| e.to_string())?;
+        if !response.status().is_success() {
+            return Err(format!("s3 get of {key} returned {}", response.status()));
+        }
+        Ok(Body::from_stream(response.bytes_stream()))
+    }
+
+    async fn delete(&self, key: &str) -> Result<(), String> {
+        let response = self
+            .request(reqwest::Method::DELETE, key, EMPTY_SHA256)
+            .send()
+            .await
+            .map_err(|e| e.to_string())?;
+        match response.status() {
+            status if status.is_success() || status == StatusCode::NOT_FOUND => Ok(()),
+            status => Err(format!("s3 delete of {key} returned {status}")),
+        }
+    }
+}
+
+/// Percent encoding of a path as signature v4 expects it, `/` stays.
+pub fn uri_encode(path: &str) -> String {
+    path.bytes()
+        .map(|byte| match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' | b'/' => {
+                (byte as char).to_string()
+            }
+            _ => format!("%{byte:02X}"),
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::{
+        body::{to_bytes, Bytes},
+        http::{HeaderMap, Method, StatusCode as HttpStatus, Uri},
+        response::{IntoResponse, Response},
+        Router,
+    };
+    use sha2::{Digest, Sha256};
+
+    use super::*;
+
+    const SECRET_KEY: &str = "stand-in-secret";
+
+    type Objects = Arc<Mutex<HashMap<String, Vec<u8>>>>;
+
+    /// Signature v4 as the service computes it from the request it received.
+    fn expected_signature(method: &Method, uri: &Uri, headers: &HeaderMap) -> Option<String> {
+        let header = |name: &str| headers.get(name)?.to_str().ok();
+        let authorization = header("authorization")?;
+        let scope = authorization
+            .split("Credential=")
+            .nth(1)?
+            .split(',')
+            .next()?
+            .split_once('/')?
+            .1;
+        let (date, rest) = scope.split_once('/')?;
+        let region = rest.split('/').next()?;
+        let canonical_request = format!(
+            "{method}\n{}\n\nhost:{}\nx-amz-content-sha256:{}\nx-amz-date:{}\n\nhost;x-amz-content-sha256;x-amz-date\n{}",
+            uri.path(),
+            header("host")?,
+            header("x-amz-content-sha256")?,
+            header("x-amz-date")?,
+            header("x-amz-content-sha256")?,
+        );
+        let string_to_sign = format!(
+            "AWS4-HMAC-SHA256\n{}\n{scope}\n{}",
+            header("x-amz-date")?,
+            sha256_hex(&canonical_request)
+        );
+        let key = [date, region, "s3", "aws4_request"]
+            .iter()
+            .fold(format!("AWS4{SECRET_KEY}").into_bytes(), |key, part| {
+                hmac_sha256(&key, part.as_bytes())
+            });
+        Some(hex::encode(hmac_sha256(&key, string_to_sign.as_bytes())))
+    }
+
+    /// Just enough of S3 for `S3Store`: put, ranged get and delete of objects.
+    async fn stand_in(
+        objects: Objects,
+        method: Method,
+        uri: Uri,
+        headers: HeaderMap,
+        body: Bytes,
+    ) -> Response {
+        let signature = headers
+            .get("authorization")
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.split("Signature=").nth(1));
+        if signature.is_none()
+            || signature != expected_signature(&method, &uri, &headers).as_deref()
+        {
+            return HttpStatus::FORBIDDEN.into_response();
+        }
+        let mut objects = objects.lock().unwrap();
+        let path = uri.path().to_string();
+        match method {
+            Method::PUT => {
+                let hash = headers["x-amz-content-sha256"].to_str().unwrap();
+                if hex::encode(Sha256::digest(&body)) != hash {
+                    return HttpStatus::BAD_REQUEST.into_response();
+                }
+                objects.insert(path, body.to_vec());
+                HttpStatus::OK.into_response()
+            }
+            Method::GET => {
+                let Some(object) = objects.get(&path) else {
+                    return HttpStatus::NOT_FOUND.into_response();
+                };
+                let range = headers
+                    .get("range")
+                    .and_then(|value| value.to_str().ok()?.strip_prefix("bytes="))
+                    .and_then(|range| range.split_once('-'))
+                    .map(|(start, end)| (start.parse().unwrap(), end.parse().unwrap()));
+                match range {
+                    Some((start, end)) => {
+                        (HttpStatus::PARTIAL_CONTENT, object[start..=end].to_vec()).into_response()
+                    }
+                    None => object.clone().into_response(),
+                }
+            }
+            Method::DELETE => match objects.remove(&path) {
+                Some(_) => HttpStatus::NO_CONTENT.into_response(),
+                None => HttpStatus::NOT_FOUND.into_response(),
+            },
+            _ => HttpStatus::METHOD_NOT_ALLOWED.into_response(),
+        }
+    }
+
+    async fn serve(objects: Objects) -> Url {
+        let app = Router::new().fallback(move |method, uri, headers, body| {
+            stand_in(objects.clone(), method, uri, headers, body)
+        });
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+        Url::parse(&format!("http://{address}")).unwrap()
+    }
+
+    fn s3(endpoint: Url, secret_key: &str) -> S3Store {
+        S3Store {
+            endpoint,
+            bucket: "reports".to_string(),
+            region: "eu-central-1".to_string(),
+            access_key: "stand-in".to_string(),
+            secret_key: secret_key.to_string(),
+            client: Client::new(),
+        }
+    }
+
+    async fn staged(content: &[u8]) -> PathBuf {
+        let path = env::temp_dir().join(format!("staged-{}", crate::crypto::random_token(8)));
+        fs::write(&path, content).await.unwrap();
+        path
+    }
+
+    async fn read(body: Body) -> Vec<u8> {
+        to_bytes(body, usize::MAX).await.unwrap().to_vec()
+    }
+
+    #[tokio::test]
+    async fn s3_store_puts_gets_ranges_and_deletes() {
+        let objects = Objects::default();
+        let store = s3(serve(objects.clone()).await, SECRET_KEY);
+        let content = b"monthly report, all numbers final";
+        let key = hex::encode(Sha256::digest(content));
+
+        let path = staged(content).await;
+        store.put(&key, &path, content.len() as u64).await.unwrap();
+        assert!(!fs::try_exists(&path).await.unwrap());
+        assert!(objects
+            .lock()
+            .unwrap()
+            .contains_key(&format!("/reports/{key}")));
+
+        assert_eq!(read(store.get(&key, None).await.unwrap()).await, content);
+        assert_eq!(
+            read(store.get(&key, Some((8, 13))).await.unwrap()).await,
+            b"report"
+        );
+
+        store.delete(&key).await.unwrap();
+        assert!(objects.lock().unwrap().is_empty());
+        assert!(store.get(&key, None).await.is_err());
+        // already gone is fine
+        store.delete(&key).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn s3_store_fails_on_rejected_signature() {
+        let objects = Objects::default();
+        let store = s3(serve(objects.clone()).await, "wrong-secret");
+        let content = b"report";
+        let key = hex::encode(Sha256::digest(content));
+
+        let path = staged(content).await;
+        assert!(store.put(&key, &path, content.len() as u64).await.is_err());
+        // the staged file stays for the caller to clean up
+        assert!(fs::try_exists(&path).await.unwrap());
+        fs::remove_file(&path).await.unwrap();
+        assert!(objects.lock().unwrap().is_empty());
+    }
+}
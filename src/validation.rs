@@ -0,0 +1,335 @@
+use std::{env, fmt, sync::OnceLock};
+
+use serde::{Deserialize, Serialize};
+
+/// Well known passwords, bundled so the check works without network access.
+const COMMON_PASSWORDS: &str = include_str!("common-passwords.txt");
+
+/// bcrypt ignores everything after 72 bytes.
+const BCRYPT_MAX_BYTES: usize = 72;
+
+/**
+    Rules for new accounts and profile changes.
+    Defaults can be overridden with `PASSWORD_MIN_LENGTH`, `PASSWORD_MIN_CLASSES`,
+    `USERNAME_MIN_LENGTH`, `USERNAME_MAX_LENGTH` and `RESERVED_USERNAMES` (comma separated).
+*/
+#[derive(Clone, Debug)]
+pub struct Policy {
+    pub password_min_length: usize,
+    /// How many of lowercase, uppercase, digits and symbols a password must contain.
+    pub password_min_classes: usize,
+    pub username_min_length: usize,
+    pub username_max_length: usize,
+    pub name_max_length: usize,
+    pub bio_max_length: usize,
+    pub reserved_usernames: Vec<String>,
+}
+
+impl Default for Policy {
+    fn default() -> Self {
+        Self {
+            password_min_length: 10,
+            password_min_classes: 2,
+            username_min_length: 3,
+            username_max_length: 32,
+            name_max_length: 100,
+            bio_max_length: 500,
+            reserved_usernames: [
+                "admin",
+                "administrator",
+                "root",
+                "system",
+                "support",
+                "api",
+                "me",
+                "null",
+                "undefined",
+                "anonymous",
+            ]
+            .map(str::to_string)
+            .to_vec(),
+        }
+    }
+}
+
+impl Policy {
+    pub fn current() -> &'static Policy {
+        static POLICY: OnceLock<Policy> = OnceLock::new();
+        POLICY.get_or_init(Policy::from_env)
+    }
+
+    pub fn from_env() -> Self {
+        let mut policy = Self::default();
+        let number = |key: &str, default: usize| {
+            env::var(key)
+                .ok()
+                .and_then(|value| value.parse().ok())
+                .unwrap_or(default)
+        };
+
+        policy.password_min_length = number("PASSWORD_MIN_LENGTH", policy.password_min_length);
+        policy.password_min_classes = number("PASSWORD_MIN_CLASSES", policy.password_min_classes);
+        policy.username_min_length = number("USERNAME_MIN_LENGTH", policy.username_min_length);
+        policy.username_max_length = number("USERNAME_MAX_LENGTH", policy.username_max_length);
+        if let Ok(reserved) = env::var("RESERVED_USERNAMES") {
+            policy.reserved_usernames = reserved
+                .split(',')
+                .map(|name| name.trim().to_lowercase())
+                .filter(|name| !name.is_empty())
+                .collect();
+        }
+        policy
+    }
+
+    pub fn check_name(&self, name: &str, errors: &mut ValidationErrors) {
+        let name = name.trim();
+        if name.is_empty() {
+            errors.add("name", "required", "name must not be empty");
+        } else if name.chars().count() > self.name_max_length {
+            let message = format!("name must be at most {} characters", self.name_max_length);
+            errors.add("name", "tooLong", &message);
+        }
+    }
+
+    pub fn check_bio(&self, bio: &str, errors: &mut ValidationErrors) {
+        if bio.trim().chars().count() > self.bio_max_length {
+            let message = format!("bio must be at most {} characters", self.bio_max_length);
+            errors.add("bio", "tooLong", &message);
+        }
+    }
+
+    /// Avatars are only linked, so just `http` and `https` urls are accepted.
+    pub fn check_avatar_url(&self, url: &str, errors: &mut ValidationErrors) {
+        let url = url.trim();
+        let valid = url.len() <= 2048
+            && !url.contains(char::is_whitespace)
+            && ["https://", "http://"].iter().any(|scheme| {
+                url.strip_prefix(scheme)
+                    .is_some_and(|rest| !rest.is_empty())
+            });
+        if !valid {
+            errors.add(
+                "avatarUrl",
+                "invalid",
+                "avatarUrl must be an http or https url",
+            );
+        }
+    }
+
+    /// Only the shape is checked, the address is proven by receiving mail.
+    pub fn check_email(&self, email: &str, errors: &mut ValidationErrors) {
+        let email = email.trim();
+        let valid = email.len() <= 254
+            && !email.contains(char::is_whitespace)
+            && email.split_once('@').is_some_and(|(local, domain)| {
+                !local.is_empty()
+                    && domain.contains('.')
+                    && !domain.starts_with('.')
+                    && !domain.ends_with('.')
+            });
+        if !valid {
+            errors.add("email", "invalid", "email is not a valid address");
+        }
+    }
+
+    /// Lowercase latin letters, digits, `_`, `.` and `-`, starting with a letter or digit.
+    pub fn check_username(&self, username: &str, errors: &mut ValidationErrors) {
+        let length = username.chars().count();
+        if length < self.username_min_length {
+            let message = format!(
+                "username must be at least {} characters",
+                self.username_min_length
+            );
+            errors.add("username", "tooShort", &message);
+        } else if length > self.username_max_length {
+            let message = format!(
+                "username must be at most {} characters",
+                self.username_max_length
+            );
+            errors.add("username", "tooLong", &message);
+        }
+
+        let allowed = |c: char| c.is_ascii_lowercase() || c.is_ascii_digit() || "_.-".contains(c);
+        let starts_well = username
+            .chars()
+            .next()
+            .is_some_and(|c| c.is_ascii_lowercase() || c.is_ascii_digit());
+        if !username.chars().all(allowed) || !starts_well {
+            errors.add(
+                "username",
+                "invalidCharacters",
+                "username may contain lowercase letters, digits, '_', '.' and '-' and must start with a letter or digit",
+            );
+        }
+
+        if self
+            .reserved_usernames
+            .iter()
+            .any(|reserved| reserved == username)
+        {
+            errors.add("username", "reserved", "username is reserved");
+        }
+    }
+
+    pub fn check_password(&self, password: &str, username: &str, errors: &mut ValidationErrors) {
+        if password.chars().count() < self.password_min_length {
+            let message = format!(
+                "password must be at least {} characters",
+                self.password_min_length
+            );
+            errors.add("password", "tooShort", &message);
+        }
+        if password.len() > BCRYPT_MAX_BYTES {
+            let message = format!("password must be at most {BCRYPT_MAX_BYTES} bytes");
+            errors.add("password", "tooLong", &message);
+        }
+
+        let classes = [
+            password.chars().any(|c| c.is_lowercase()),
+            password.chars().any(|c| c.is_uppercase()),
+            password.chars().any(|c| c.is_ascii_digit()),
+            password.chars().any(|c| !c.is_alphanumeric()),
+        ];
+        if classes.iter().filter(|present| **present).count() < self.password_min_classes {
+            let message = format!(
+                "password must mix at least {} of lowercase, uppercase, digits and symbols",
+                self.password_min_classes
+            );
+            errors.add("password", "tooSimple", &message);
+        }
+
+        let lowercase = password.to_lowercase();
+        if !username.is_empty() && lowercase.contains(&username.to_lowercase()) {
+            errors.add(
+                "password",
+                "containsUsername",
+                "password must not contain username",
+            );
+        }
+        let word = common_word(&lowercase);
+        if COMMON_PASSWORDS
+            .lines()
+            .any(|common| common == lowercase || common == word)
+        {
+            errors.add(
+                "password",
+                "breached",
+                "password is too common, it appears in breach lists",
+            );
+        }
+    }
+}
+
+/**
+    Word a padded password is built from, `p@ssw0rd2024!` becomes `password`.
+    Most entries of the list are shorter than the minimum length,
+    long enough passwords only match them with the padding removed.
+*/
+fn common_word(lowercase: &str) -> String {
+    lowercase
+        .trim_matches(|c: char| !c.is_alphanumeric())
+        .trim_end_matches(|c: char| c.is_ascii_digit() || !c.is_alphanumeric())
+        .chars()
+        .map(|c| match c {
+            '0' => 'o',
+            '1' => 'i',
+            '3' => 'e',
+            '4' | '@' => 'a',
+            '5' | '$' => 's',
+            '7' => 't',
+            c => c,
+        })
+        .collect()
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct FieldError {
+    pub field: String,
+    pub code: String,
+    pub message: String,
+}
+
+/// All problems with a request, reported together so the client can show them per field.
+#[derive(Serialize, Deserialize, Debug, Default)]
+pub struct ValidationErrors {
+    pub errors: Vec<FieldError>,
+}
+
+impl ValidationErrors {
+    pub fn add(&mut self, field: &str, code: &str, message: &str) {
+        self.errors.push(FieldError {
+            field: field.to_string(),
+            code: code.to_string(),
+            message: message.to_string(),
+        });
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.errors.is_empty()
+    }
+
+    pub fn into_result<T>(self, value: T) -> Result<T, ValidationErrors> {
+        match self.is_empty() {
+            true => Ok(value),
+            false => Err(self),
+        }
+    }
+}
+
+impl fmt::Display for ValidationErrors {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let messages: Vec<&str> = self.errors.iter().map(|e| e.message.as_str()).collect();
+        write!(f, "{}", messages.join(", "))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn password_codes(password: &str, username: &str) -> Vec<String> {
+        let mut errors = ValidationErrors::default();
+        Policy::default().check_password(password, username, &mut errors);
+        errors.errors.into_iter().map(|error| error.code).collect()
+    }
+
+    #[test]
+    fn padding_and_substitutions_are_removed_from_common_words() {
+        assert_eq!(common_word("p@ssw0rd2024!"), "password");
+        assert_eq!(common_word("!!dragon99"), "dragon");
+        assert_eq!(common_word("correct horse"), "correct horse");
+    }
+
+    #[test]
+    fn password_policy_reports_every_problem() {
+        assert!(password_codes("Tr1cky-Enough", "alice").is_empty());
+        assert_eq!(password_codes("Sh0rt", "alice"), ["tooShort"]);
+        assert_eq!(password_codes("alllowercase", "alice"), ["tooSimple"]);
+        assert_eq!(
+            password_codes("Alice-2024-xyz", "alice"),
+            ["containsUsername"]
+        );
+        assert_eq!(password_codes("P@ssw0rd2024!", "alice"), ["breached"]);
+        let long = "Aa1-".repeat(20);
+        assert_eq!(password_codes(&long, "alice"), ["tooLong"]);
+    }
+
+    #[test]
+    fn usernames_are_lowercase_and_not_reserved() {
+        let codes = |username: &str| {
+            let mut errors = ValidationErrors::default();
+            Policy::default().check_username(username, &mut errors);
+            errors
+                .errors
+                .into_iter()
+                .map(|error| error.code)
+                .collect::<Vec<_>>()
+        };
+        assert!(codes("alice.b-2").is_empty());
+        assert_eq!(codes("al"), ["tooShort"]);
+        assert_eq!(codes("Alice"), ["invalidCharacters"]);
+        assert_eq!(codes("_alice"), ["invalidCharacters"]);
+        assert_eq!(codes("admin"), ["reserved"]);
+    }
+}
@@ -0,0 +1,226 @@
+use std::{cmp::Ordering, fmt, str::FromStr};
+
+use serde::{de, Deserialize, Deserializer, Serialize, Serializer};
+
+/**
+    Semantic version as apps and brokers report it.
+    Parsing is lenient where it is harmless: a leading `v` is dropped and
+    missing minor or patch numbers are 0, so `v2` is `2.0.0`.
+    Build metadata after `+` is ignored, also when comparing.
+*/
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct Version {
+    pub major: u64,
+    pub minor: u64,
+    pub patch: u64,
+    /// Dot separated pre-release identifiers, `rc.1` of `1.0.0-rc.1`.
+    pub pre: Vec<String>,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub struct VersionError(String);
+
+impl fmt::Display for VersionError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "'{}' is not a semantic version", self.0)
+    }
+}
+
+impl FromStr for Version {
+    type Err = VersionError;
+
+    fn from_str(text: &str) -> Result<Self, Self::Err> {
+        let error = || VersionError(text.to_string());
+        let trimmed = text.trim();
+        let trimmed = trimmed.strip_prefix('v').unwrap_or(trimmed);
+        let without_build = trimmed.split('+').next().unwrap_or_default();
+        let (core, pre) = match without_build.split_once('-') {
+            Some((core, pre)) => (core, Some(pre)),
+            None => (without_build, None),
+        };
+
+        let numbers = core
+            .split('.')
+            .map(|number| match number.len() > 1 && number.starts_with('0') {
+                true => None,
+                false => number.parse::<u64>().ok(),
+            })
+            .collect::<Option<Vec<_>>>()
+            .filter(|numbers| (1..=3).contains(&numbers.len()))
+            .ok_or_else(error)?;
+
+        let pre = match pre {
+            Some(pre) => {
+                let identifiers: Vec<String> = pre.split('.').map(str::to_string).collect();
+                let valid = identifiers.iter().all(|identifier| {
+                    !identifier.is_empty()
+                        && identifier
+                            .chars()
+                            .all(|c| c.is_ascii_alphanumeric() || c == '-')
+                });
+                if !valid {
+                    return Err(error());
+                }
+                identifiers
+            }
+            None => Vec::new(),
+        };
+
+        Ok(Self {
+            major: numbers[0],
+            minor: numbers.get(1).copied().unwrap_or(0),
+            patch: numbers.get(2).copied().unwrap_or(0),
+            pre,
+        })
+    }
+}
+
+impl fmt::Display for Version {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}.{}.{}", self.major, self.minor, self.patch)?;
+        if !self.pre.is_empty() {
+            write!(f, "-{}", self.pre.join("."))?;
+        }
+        Ok(())
+    }
+}
+
+/// Precedence of semver 2.0.0, a pre-release comes before its release.
+impl Ord for Version {
+    fn cmp(&self, other: &Self) -> Ordering {
+        (self.major, self.minor, self.patch)
+            .cmp(&(other.major, other.minor, other.patch))
+            .then_with(|| match (self.pre.is_empty(), other.pre.is_empty()) {
+                (true, true) => Ordering::Equal,
+                (true, false) => Ordering::Greater,
+                (false, true) => Ordering::Less,
+                (false, false) => compare_pre(&self.pre, &other.pre),
+            })
+    }
+}
+
+impl PartialOrd for Version {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+/// Numeric identifiers compare as numbers and before alphanumeric ones.
+fn compare_pre(left: &[String], right: &[String]) -> Ordering {
+    for (left, right) in left.iter().zip(right) {
+        let ordering = match (left.parse::<u64>(), right.parse::<u64>()) {
+            (Ok(left), Ok(right)) => left.cmp(&right),
+            (Ok(_), Err(_)) => Ordering::Less,
+            (Err(_), Ok(_)) => Ordering::Greater,
+            (Err(_), Err(_)) => left.cmp(right),
+        };
+        if ordering != Ordering::Equal {
+            return ordering;
+        }
+    }
+    left.len().cmp(&right.len())
+}
+
+impl Serialize for Version {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+impl<'de> Deserialize<'de> for Version {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let text = String::deserialize(deserializer)?;
+        text.parse().map_err(de::Error::custom)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn version(text: &str) -> Version {
+        text.parse().unwrap()
+    }
+
+    #[test]
+    fn parses_semantic_versions() {
+        let parsed = version("1.2.3-rc.1");
+        assert_eq!((parsed.major, parsed.minor, parsed.patch), (1, 2, 3));
+        assert_eq!(parsed.pre, ["rc", "1"]);
+        assert_eq!(parsed.to_string(), "1.2.3-rc.1");
+        assert_eq!(version("1.2.3-x-y.0a").pre, ["x-y", "0a"]);
+    }
+
+    #[test]
+    fn prefix_missing_numbers_and_build_are_lenient() {
+        assert_eq!(version("v2"), version("2.0.0"));
+        assert_eq!(version(" 2.1 "), version("2.1.0"));
+        assert_eq!(version("1.0.0+build.5"), version("1.0.0"));
+        assert_eq!(
+            version("1.0.0-beta+exp.sha.5114f85").to_string(),
+            "1.0.0-beta"
+        );
+    }
+
+    #[test]
+    fn rejects_what_is_not_a_version() {
+        for text in [
+            "",
+            "v",
+            "1.",
+            "1.2.3.4",
+            "a.b.c",
+            "-1.0.0",
+            "1.0.0-",
+            "1.0.0-rc..1",
+            "1.0.0-rc_1",
+            "vv1",
+            "1.0.0 beta",
+        ] {
+            assert!(text.parse::<Version>().is_err(), "{text}");
+        }
+        let error = "x".parse::<Version>().unwrap_err();
+        assert_eq!(error.to_string(), "'x' is not a semantic version");
+    }
+
+    #[test]
+    fn numbers_with_leading_zeros_are_rejected() {
+        assert!("01.0.0".parse::<Version>().is_err());
+        assert!("1.00.0".parse::<Version>().is_err());
+        assert!("1.0.01".parse::<Version>().is_err());
+        assert_eq!(version("0.0.0"), version("0"));
+        assert_eq!(version("10.0.0").major, 10);
+    }
+
+    #[test]
+    fn precedence_follows_semver() {
+        // the example of semver 2.0.0, section 11
+        let ordered = [
+            "1.0.0-alpha",
+            "1.0.0-alpha.1",
+            "1.0.0-alpha.beta",
+            "1.0.0-beta",
+            "1.0.0-beta.2",
+            "1.0.0-beta.11",
+            "1.0.0-rc.1",
+            "1.0.0",
+            "1.0.1",
+            "1.1.0",
+            "2.0.0",
+            "10.0.0",
+        ];
+        for pair in ordered.windows(2) {
+            assert!(version(pair[0]) < version(pair[1]), "{pair:?}");
+        }
+        assert_eq!(version("1.0.0+a").cmp(&version("1.0.0+b")), Ordering::Equal);
+    }
+
+    #[test]
+    fn serializes_as_string() {
+        let value = serde_json::to_value(version("v1.2")).unwrap();
+        assert_eq!(value, "1.2.0");
+        let parsed: Version = serde_json::from_value(value).unwrap();
+        assert_eq!(parsed, version("1.2.0"));
+        assert!(serde_json::from_str::<Version>("\"1.x\"").is_err());
+    }
+}
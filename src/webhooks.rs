@@ -0,0 +1,404 @@
+use std::{
+    env,
+    net::{IpAddr, Ipv4Addr, SocketAddr},
+    sync::{Arc, OnceLock},
+    time::Duration,
+};
+
+use reqwest::{
+    dns::{Addrs, Name, Resolve, Resolving},
+    redirect, Url,
+};
+
+use crate::{
+    crypto::hmac_sha256,
+    db::{webhooks::PendingDelivery, Db},
+};
+
+/// How often the outbox is checked for retries when nothing new happens.
+const POLL_INTERVAL: Duration = Duration::from_secs(10);
+const TIMEOUT: Duration = Duration::from_secs(10);
+const BATCH: usize = 32;
+
+/**
+    Which addresses webhooks may point to. Loopback, private, link-local and other special
+    purpose addresses are refused, so operators can't reach services next to the server
+    through webhooks, cloud metadata endpoints for one. Installs with receivers in their
+    internal network set `WEBHOOK_ALLOW_PRIVATE_TARGETS=true`.
+*/
+#[derive(Clone, Copy)]
+pub struct TargetPolicy {
+    pub allow_private: bool,
+}
+
+impl TargetPolicy {
+    pub fn current() -> TargetPolicy {
+        static POLICY: OnceLock<TargetPolicy> = OnceLock::new();
+        *POLICY.get_or_init(|| TargetPolicy {
+            allow_private: env::var("WEBHOOK_ALLOW_PRIVATE_TARGETS").is_ok_and(|v| v == "true"),
+        })
+    }
+
+    pub fn allows(self, ip: IpAddr) -> bool {
+        self.allow_private || is_public(ip)
+    }
+
+    /// Every address the host of `url` resolves to has to be allowed, the error says why not.
+    pub async fn check(self, url: &str) -> Result<(), String> {
+        let url = Url::parse(url).map_err(|_| "url is not valid".to_string())?;
+        if !matches!(url.scheme(), "http" | "https") {
+            return Err("url must start with http:// or https://".to_string());
+        }
+        let Some(host) = url.host_str() else {
+            return Err("url has no host".to_string());
+        };
+        // ipv6 hosts come in brackets
+        let host = host.trim_start_matches('[').trim_end_matches(']');
+        let port = url.port_or_known_default().unwrap_or(80);
+        let addrs: Vec<SocketAddr> = match tokio::net::lookup_host((host, port)).await {
+            Ok(addrs) => addrs.collect(),
+            Err(_) => return Err(format!("{host} can't be resolved")),
+        };
+        match !addrs.is_empty() && addrs.iter().all(|addr| self.allows(addr.ip())) {
+            true => Ok(()),
+            false => Err(format!("{host} is not a public address")),
+        }
+    }
+}
+
+/// Globally reachable unicast address, everything else belongs to networks of the server.
+fn is_public(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => {
+            let [a, b, ..] = ip.octets();
+            !(ip.is_unspecified()
+                || ip.is_loopback()
+                || ip.is_private()
+                || ip.is_link_local()
+                || ip.is_broadcast()
+                || ip.is_multicast()
+                || ip.is_documentation()
+                || a == 0
+                || a >= 240
+                // shared address space of carrier-grade NAT
+                || (a == 100 && b & 0xc0 == 64)
+                // benchmarking
+                || (a == 198 && b & 0xfe == 18))
+        }
+        IpAddr::V6(ip) => {
+            if let Some(ip) = ip.to_ipv4_mapped() {
+                return is_public(IpAddr::V4(ip));
+            }
+            let segments = ip.segments();
+            // NAT64 embeds the IPv4 address in the last 32 bits
+            if segments[..6] == [0x64, 0xff9b, 0, 0, 0, 0] {
+                let [.., a, b, c, d] = ip.octets();
+                return is_public(IpAddr::V4(Ipv4Addr::new(a, b, c, d)));
+            }
+            !(ip.is_unspecified()
+                || ip.is_loopback()
+                || ip.is_multicast()
+                // unique local
+                || segments[0] & 0xfe00 == 0xfc00
+                // link-local
+                || segments[0] & 0xffc0 == 0xfe80
+                // documentation
+                || (segments[0] == 0x2001 && segments[1] == 0xdb8))
+        }
+    }
+}
+
+/**
+    Resolver of the delivery client. Addresses are checked on every connect,
+    so a name can't be pointed at an internal address after the webhook was created.
+*/
+struct PublicResolver(TargetPolicy);
+
+impl Resolve for PublicResolver {
+    fn resolve(&self, name: Name) -> Resolving {
+        let policy = self.0;
+        Box::pin(async move {
+            let addrs: Vec<SocketAddr> = tokio::net::lookup_host((name.as_str(), 0))
+                .await?
+                .filter(|addr| policy.allows(addr.ip()))
+                .collect();
+            if addrs.is_empty() {
+                return Err(format!("{} is not a public address", name.as_str()).into());
+            }
+            Ok(Box::new(addrs.into_iter()) as Addrs)
+        })
+    }
+}
+
+/// Redirects are not followed, they could lead anywhere.
+fn client(policy: TargetPolicy) -> reqwest::Client {
+    reqwest::Client::builder()
+        .timeout(TIMEOUT)
+        .redirect(redirect::Policy::none())
+        .dns_resolver(Arc::new(PublicResolver(policy)))
+        .build()
+        .unwrap()
+}
+
+/**
+    Sends queued webhook deliveries.
+    Wakes up on new events and every `POLL_INTERVAL` to pick up retries.
+*/
+pub async fn deliver(db: Db) {
+    let policy = TargetPolicy::current();
+    let client = client(policy);
+    let mut changes = db.events.subscribe();
+
+    loop {
+        match db.webhooks.due(BATCH) {
+            Ok(pending) => {
+                let full = pending.len() == BATCH;
+                for delivery in pending {
+                    send(&db, &client, policy, delivery).await;
+                }
+                if full {
+                    continue;
+                }
+            }
+            Err(e) => println!("webhooks::deliver - {e:?}"),
+        }
+
+        let _ = tokio::time::timeout(POLL_INTERVAL, changes.changed()).await;
+    }
+}
+
+/// `X-Signature-256` header value, receivers recompute it over the raw body with their secret.
+pub fn signature(secret: &str, body: &str) -> String {
+    format!(
+        "sha256={}",
+        hex::encode(hmac_sha256(secret.as_bytes(), body.as_bytes()))
+    )
+}
+
+/// Names are checked again by `PublicResolver`, addresses in the url only here.
+async fn send(db: &Db, client: &reqwest::Client, policy: TargetPolicy, delivery: PendingDelivery) {
+    if let Err(e) = policy.check(&delivery.url).await {
+        if let Err(e) = db.webhooks.failed(delivery.id, None, &e) {
+            println!(
+                "webhooks::send - could not update delivery {}: {e:?}",
+                delivery.id
+            );
+        }
+        return;
+    }
+    let response = client
+        .post(&delivery.url)
+        .header("content-type", "application/json")
+        .header("x-webhook-event", &delivery.kind)
+        .header("x-webhook-delivery", delivery.id.to_string())
+        .header(
+            "x-signature-256",
+            signature(&delivery.secret, &delivery.payload),
+        )
+        .body(delivery.payload)
+        .send()
+        .await;
+
+    let result = match response {
+        Ok(response) if response.status().is_success() => db
+            .webhooks
+            .delivered(delivery.id, response.status().as_u16()),
+        Ok(response) => db.webhooks.failed(
+            delivery.id,
+            Some(response.status().as_u16()),
+            "receiver responded with error status",
+        ),
+        Err(e) => db.webhooks.failed(delivery.id, None, &e.to_string()),
+    };
+
+    if let Err(e) = result {
+        println!(
+            "webhooks::send - could not update delivery {}: {e:?}",
+            delivery.id
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use axum::{http::HeaderMap, routing::post, Router};
+
+    use super::*;
+    use crate::db::{events::AppEvent, testing, webhooks::NewWebhook};
+
+    const PUBLIC_ONLY: TargetPolicy = TargetPolicy {
+        allow_private: false,
+    };
+    const ALLOW_PRIVATE: TargetPolicy = TargetPolicy {
+        allow_private: true,
+    };
+
+    #[test]
+    fn signature_is_hmac_sha256_of_body() {
+        // test case 2 of RFC 4231
+        assert_eq!(
+            signature("Jefe", "what do ya want for nothing?"),
+            "sha256=5bdcc146bf60754e6a042426089575c75a003f089d2739839dec58b964ec3843"
+        );
+    }
+
+    #[test]
+    fn only_global_unicast_addresses_are_public() {
+        for ip in [
+            "93.184.216.34",
+            "8.8.8.8",
+            "2606:4700::1111",
+            "::ffff:1.1.1.1",
+        ] {
+            assert!(is_public(ip.parse().unwrap()), "{ip}");
+        }
+        for ip in [
+            "127.0.0.1",
+            "10.1.2.3",
+            "172.16.0.1",
+            "192.168.1.1",
+            "169.254.169.254",
+            "100.64.0.1",
+            "0.0.0.0",
+            "255.255.255.255",
+            "224.0.0.1",
+            "198.18.0.1",
+            "::1",
+            "::",
+            "fd00::1",
+            "fe80::1",
+            "::ffff:127.0.0.1",
+            "64:ff9b::a00:1",
+            "2001:db8::1",
+        ] {
+            assert!(!is_public(ip.parse().unwrap()), "{ip}");
+        }
+    }
+
+    #[tokio::test]
+    async fn check_refuses_internal_targets() {
+        for url in [
+            "http://127.0.0.1:8080/hook",
+            "http://localhost/hook",
+            "http://169.254.169.254/latest/meta-data",
+            "https://[::1]/hook",
+            "http://10.0.0.5/hook",
+            "ftp://93.184.216.34/hook",
+            "not a url",
+        ] {
+            assert!(PUBLIC_ONLY.check(url).await.is_err(), "{url}");
+        }
+        assert!(PUBLIC_ONLY
+            .check("https://93.184.216.34/hook")
+            .await
+            .is_ok());
+        assert!(ALLOW_PRIVATE
+            .check("http://127.0.0.1:8080/hook")
+            .await
+            .is_ok());
+    }
+
+    type Received = Arc<Mutex<Vec<(HeaderMap, String)>>>;
+
+    /// Receiver that answers every post with `status`.
+    async fn receiver(status: u16) -> (String, Received) {
+        let received = Received::default();
+        let app = Router::new().route(
+            "/hook",
+            post({
+                let received = received.clone();
+                move |headers: HeaderMap, body: String| async move {
+                    received.lock().unwrap().push((headers, body));
+                    axum::http::StatusCode::from_u16(status).unwrap()
+                }
+            }),
+        );
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/hook", listener.local_addr().unwrap());
+        tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+        (url, received)
+    }
+
+    /// App with a webhook to `url` and one recorded event, returns app and webhook ids.
+    fn subscribed(db: &Db, url: &str) -> (i32, i32, i32) {
+        let user_id = testing::user(db, "alice");
+        let app_id = testing::app(db, user_id);
+        let new_webhook = NewWebhook {
+            url: url.to_string(),
+            events: vec!["brokerStarted".to_string()],
+        };
+        let webhook = db
+            .webhooks
+            .create(app_id, user_id, new_webhook, "secret".to_string())
+            .unwrap();
+        testing::event(db, app_id, &AppEvent::BrokerStopped { broker_id: 1 });
+        testing::event(db, app_id, &AppEvent::BrokerStarted { broker_id: 1 });
+        (user_id, app_id, webhook.id)
+    }
+
+    async fn send_due(db: &Db, policy: TargetPolicy) {
+        for delivery in db.webhooks.due(BATCH).unwrap() {
+            send(db, &client(policy), policy, delivery).await;
+        }
+    }
+
+    #[tokio::test]
+    async fn delivers_signed_events_the_webhook_subscribed_to() {
+        let (url, received) = receiver(204).await;
+        let db: Db = Arc::new(testing::db());
+        let (user_id, app_id, webhook_id) = subscribed(&db, &url);
+
+        send_due(&db, ALLOW_PRIVATE).await;
+
+        let received = received.lock().unwrap();
+        assert_eq!(received.len(), 1);
+        let (headers, body) = &received[0];
+        assert_eq!(headers["x-webhook-event"], "brokerStarted");
+        assert_eq!(
+            headers["x-signature-256"],
+            signature("secret", body).as_str()
+        );
+        let event: serde_json::Value = serde_json::from_str(body).unwrap();
+        assert_eq!(event["type"], "brokerStarted");
+        assert_eq!(event["appId"], app_id);
+
+        let deliveries = db.webhooks.deliveries(app_id, user_id, webhook_id).unwrap();
+        assert_eq!(deliveries.len(), 1);
+        assert_eq!(deliveries[0].status, "delivered");
+        assert_eq!(deliveries[0].last_status_code, Some(204));
+        assert!(db.webhooks.due(BATCH).unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn error_responses_are_retried_later() {
+        let (url, received) = receiver(500).await;
+        let db: Db = Arc::new(testing::db());
+        let (user_id, app_id, webhook_id) = subscribed(&db, &url);
+
+        send_due(&db, ALLOW_PRIVATE).await;
+
+        assert_eq!(received.lock().unwrap().len(), 1);
+        let delivery = &db.webhooks.deliveries(app_id, user_id, webhook_id).unwrap()[0];
+        assert_eq!(delivery.status, "pending");
+        assert_eq!(delivery.attempts, 1);
+        assert_eq!(delivery.last_status_code, Some(500));
+        assert!(delivery.next_attempt_at.unwrap() > crate::db::events::now());
+    }
+
+    #[tokio::test]
+    async fn internal_targets_are_not_sent_to() {
+        let (url, received) = receiver(204).await;
+        let db: Db = Arc::new(testing::db());
+        let (user_id, app_id, webhook_id) = subscribed(&db, &url);
+
+        send_due(&db, PUBLIC_ONLY).await;
+
+        assert!(received.lock().unwrap().is_empty());
+        let delivery = &db.webhooks.deliveries(app_id, user_id, webhook_id).unwrap()[0];
+        assert_eq!(delivery.status, "pending");
+        let error = delivery.last_error.as_deref().unwrap();
+        assert!(error.contains("not a public address"));
+    }
+}